@@ -0,0 +1,108 @@
+//! Memory access through `SparseAddressSpace` the way each core uses it, on boards with RAM,
+//! ROM and a device mapped, looked up through the page table and by binary search. Run with
+//! `cargo bench`.
+use micro16::microvm::bits::Endianness;
+use micro16::microvm::device::{InterruptLines, Timer};
+use micro16::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+use micro16::microvm::memory::rom::ROM;
+use micro16::microvm::memory::sparse::SparseAddressSpace;
+use micro16::risc_v_emu::core::{Core, CoreSettings};
+use micro16::risc_v_emu::extensions::Extensions;
+use micro16::risc_v_emu::types::DoubleWord;
+use std::hint::black_box;
+use std::time::Instant;
+
+const RAM: u32 = 0x8000_0000;
+
+struct Rv32;
+impl CoreSettings for Rv32 {
+    type XLen = DoubleWord;
+    type RegType = DoubleWord;
+    type Address = u32;
+}
+
+//Prints and returns the time per iteration
+fn bench(name: &str, iterations: u64, mut f: impl FnMut()) -> f64 {
+    for _ in 0..iterations / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let nanos = start.elapsed().as_nanos() as f64 / iterations as f64;
+    println!("{:<40}{:>10.1} ns/iter", name, nanos);
+    nanos
+}
+fn speedup(name: &str, paged: f64, searched: f64) {
+    println!("{:<40}{:>10.2}x faster through the page table", name, searched / paged);
+}
+//32K of RAM, a timer at 0xB000 and 16K of ROM, like a 6502 board
+fn mos6502_board(mut space: SparseAddressSpace<u16>) -> SparseAddressSpace<u16> {
+    space.add_space(0, Box::new(DenseStaticMemory::new(0x8000u16))).unwrap();
+    space.add_device(0xB000, Box::new(Timer::new(InterruptLines::new().line(0)))).unwrap();
+    space.add_space(0xC000, Box::new(ROM::new(0x4000u16))).unwrap();
+    space
+}
+//The accesses of LDA $0200,X / STA $0300,X / INX with the stack and a device in the mix
+fn mos6502(space: &mut SparseAddressSpace<u16>, x: &mut u8) {
+    for pc in 0xC000..0xC007u16 {
+        black_box(space.read_byte(pc).unwrap());
+    }
+    let value = space.read_byte(0x0200 + *x as u16).unwrap();
+    space.write_byte(0x0300 + *x as u16, value).unwrap();
+    space.write_byte(0x01FF - (*x as u16 & 0xF), *x).unwrap();
+    black_box(space.read_byte_mut(0xB000).unwrap());
+    *x = x.wrapping_add(1);
+}
+fn riscv(mut space: SparseAddressSpace<u32>) -> Core<Rv32> {
+    let program = [
+        0x80001537u32, //start: lui a0, 0x80001
+        0x800025b7, //lui a1, 0x80002
+        0x10000293, //li t0, 256
+        0x00052303, //loop: lw t1, 0(a0)
+        0x0065a023, //sw t1, 0(a1)
+        0x00450513, //addi a0, a0, 4
+        0x00458593, //addi a1, a1, 4
+        0xfff28293, //addi t0, t0, -1
+        0xfe0296e3, //bnez t0, loop
+        0xfddff06f, //j start
+    ];
+    space.add_space(RAM, Box::new(DenseStaticMemory::new(0x10000u32))).unwrap();
+    space.add_device(0x0200_0000, Box::new(Timer::new(InterruptLines::new().line(0)))).unwrap();
+    let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+    space.write_bytes(RAM, &bytes).unwrap();
+    let mut core = Core::<Rv32>::new(space, Extensions::from_letters("i").unwrap());
+    core.set_pc(RAM as u64);
+    core
+}
+
+fn main() {
+    let lookups: [(&str, fn() -> SparseAddressSpace<u16>, fn() -> SparseAddressSpace<u32>); 2] = [
+        ("paged", SparseAddressSpace::full, SparseAddressSpace::full),
+        ("searched", SparseAddressSpace::full_without_pages, SparseAddressSpace::full_without_pages),
+    ];
+    let mut times = Vec::new();
+    for (lookup, space16, space32) in lookups.iter() {
+        let mut space = mos6502_board(space16());
+        let mut x = 0;
+        let accesses = bench(&format!("6502 board accesses, {}", lookup), 2_000_000, || mos6502(&mut space, &mut x));
+        let space = mos6502_board(space16());
+        let mut address = 0u16;
+        let read_u16 = bench(&format!("6502 board read_u16, {}", lookup), 2_000_000, || {
+            black_box(space.read_u16(address & 0x7FFE, Endianness::Little).unwrap());
+            address = address.wrapping_add(2);
+        });
+        let mut core = riscv(space32());
+        let step = bench(&format!("rv32 copy loop step, {}", lookup), 2_000_000, || core.step().unwrap());
+        let mut bytes = [0; 64];
+        let core = riscv(space32());
+        let read_bytes = bench(&format!("rv32 64 byte read_bytes, {}", lookup), 1_000_000,
+                               || core.space().read_bytes(RAM + 0x1000, black_box(&mut bytes)).unwrap());
+        times.push([accesses, read_u16, step, read_bytes]);
+    }
+    let names = ["6502 board accesses", "6502 board read_u16", "rv32 copy loop step", "rv32 64 byte read_bytes"];
+    for (i, name) in names.iter().enumerate() {
+        speedup(name, times[0][i], times[1][i]);
+    }
+}
@@ -0,0 +1,19 @@
+pub mod monitor;
+pub mod options;
+pub mod run;
+
+/// Runs the command line in `args`, without the program name, returning the exit code.
+pub fn main(args: Vec<String>) -> i32 {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", options::USAGE);
+        return 0;
+    }
+    let result = options::parse(args).and_then(|options| run::run(&options));
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("micro16: {}", e);
+            run::EXIT_ERROR
+        },
+    }
+}
@@ -0,0 +1,463 @@
+//! An interactive monitor for stepping through a program and inspecting the machine, which
+//! works for any core through the `Debuggable` trait. Commands can also come from a script.
+use super::options::{parse_number, Limit};
+use super::run::{has_extension, registers, HEX_EXTENSIONS, SREC_EXTENSIONS};
+use micro16::microvm::image::{ihex, srec, Image};
+use micro16::microvm::debug::{Debuggable, StepResult};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+pub const HELP: &str = "\
+  s, step [N]               run N instructions, default 1
+  n, next                   step, running a whole subroutine call as one instruction
+  c, continue               run until a breakpoint, the program stops or a limit
+  r, regs [NAME VALUE]      show the registers, or set one, pc included
+  m, mem [ADDRESS] [LEN]    hexdump LEN bytes, default 64
+  f, fill ADDRESS LEN BYTE  fill memory with BYTE
+  w, write ADDRESS BYTE...  write bytes to memory
+  d, dis [ADDRESS] [COUNT]  disassemble COUNT instructions, default 8 from pc
+  b, break [ADDRESS]        set a breakpoint, or list them
+  del [ADDRESS]             delete a breakpoint, or all of them
+  load FILE [ADDRESS]       write FILE to memory, Intel HEX and S-record files carry
+                            their own addresses, anything else is raw
+  save FILE ADDRESS LEN     write memory to FILE, as Intel HEX or S-records if the
+                            extension is one of theirs
+  source FILE               run the commands in FILE, which may source others up to
+                            16 deep
+  history                   list the commands so far, !N runs the Nth again
+  q, quit                   exit with the program's exit code, 0 if it hasn't exited
+
+Numbers are decimal or 0x hex and addresses can be register names. An empty line repeats the
+last command and # starts a comment.";
+
+//How deep `source` can nest, which stops a script that sources itself
+const MAX_SOURCE_DEPTH: usize = 16;
+
+pub struct Monitor<'a, W: Write> {
+    machine: &'a mut dyn Debuggable,
+    out: W,
+    /// How far `continue` runs before giving up.
+    limit: Limit,
+    breakpoints: BTreeSet<u64>,
+    history: Vec<String>,
+    exited: Option<i32>,
+    //How many `source` commands are running
+    depth: usize,
+    //Where `mem` and `dis` without an address carry on from
+    next_memory: u64,
+    next_disassembly: Option<u64>,
+}
+impl<'a, W: Write> Monitor<'a, W> {
+    pub fn new(machine: &'a mut dyn Debuggable, out: W, limit: Limit) -> Monitor<'a, W> {
+        Monitor {
+            machine,
+            out,
+            limit,
+            breakpoints: BTreeSet::new(),
+            history: Vec::new(),
+            exited: None,
+            depth: 0,
+            next_memory: 0,
+            next_disassembly: None,
+        }
+    }
+    /// The program's exit code, once it has exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exited
+    }
+    /// Runs the commands from `input` until it ends or `quit`, which returns the exit code.
+    /// Interactive input gets a prompt and an empty line repeats the last command.
+    pub fn run(&mut self, input: &mut dyn BufRead, interactive: bool) -> Option<i32> {
+        let mut last: Option<String> = None;
+        loop {
+            if interactive {
+                let _ = write!(self.out, "(micro16) ");
+                let _ = self.out.flush();
+            }
+            let mut line = String::new();
+            if !matches!(input.read_line(&mut line), Ok(n) if n > 0) {
+                return None;
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                match &last {
+                    Some(last) if interactive => line = last.clone(),
+                    _ => continue,
+                }
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some(n) = line.strip_prefix('!') {
+                match n.parse::<usize>().ok().and_then(|n| self.history.get(n.checked_sub(1)?)) {
+                    Some(command) => line = command.clone(),
+                    None => {
+                        self.say(format!("error: no command {} in the history", n));
+                        continue;
+                    },
+                }
+            }
+            self.history.push(line.clone());
+            last = Some(line.clone());
+            match self.command(&line) {
+                Ok(Some(code)) => return Some(code),
+                Ok(None) => (),
+                Err(e) => self.say(format!("error: {}", e)),
+            }
+        }
+    }
+    /// Runs one command, returning the exit code if it was `quit`.
+    pub fn command(&mut self, line: &str) -> Result<Option<i32>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(None),
+        };
+        let arg = |i: usize| args.get(i).ok_or_else(|| format!("`{}` needs more arguments, see `help`", name));
+        match name {
+            "s" | "step" => {
+                let count = args.first().map_or(Ok(1), |count| self.value(count))?;
+                self.resume(Limit { instructions: Some(count), cycles: None }, None);
+            },
+            "n" | "next" => {
+                let pc = self.machine.pc();
+                match self.machine.disassemble(pc).and_then(|(len, _)| pc.checked_add(len)) {
+                    Some(after) if self.machine.is_call(pc) => {
+                        if let Some(reason) = self.resume(self.limit, Some(after)) {
+                            self.say(reason);
+                        }
+                    },
+                    _ => {
+                        self.resume(Limit { instructions: Some(1), cycles: None }, None);
+                    },
+                }
+            },
+            "c" | "continue" => {
+                if let Some(reason) = self.resume(self.limit, None) {
+                    self.say(reason);
+                }
+            },
+            "r" | "regs" if args.is_empty() => {
+                let text = registers(self.machine);
+                let _ = write!(self.out, "{}", text);
+            },
+            "r" | "regs" => {
+                let value = self.value(arg(1)?)?;
+                match *arg(0)? {
+                    "pc" => self.machine.set_pc(value),
+                    register => self.machine.set_register(register, value)
+                        .ok_or_else(|| format!("no register `{}` that can hold {:#x}", register, value))?,
+                }
+            },
+            "m" | "mem" => {
+                let address = args.first().map_or(Ok(self.next_memory), |address| self.value(address))?;
+                let len = args.get(1).map_or(Ok(64), |len| self.value(len))?;
+                self.dump(address, len);
+            },
+            "f" | "fill" => {
+                let (address, len) = (self.value(arg(0)?)?, self.value(arg(1)?)?);
+                let byte = self.byte(arg(2)?)?;
+                //One byte at a time, so a huge LEN stops at the first unmapped byte rather than
+                //allocating it all up front
+                self.write(address, std::iter::repeat(byte).take(usize::try_from(len).unwrap_or(usize::MAX)))?;
+            },
+            "w" | "write" => {
+                let address = self.value(arg(0)?)?;
+                arg(1)?;
+                let bytes = args[1..].iter().map(|byte| self.byte(byte)).collect::<Result<Vec<u8>, String>>()?;
+                self.write(address, bytes)?;
+            },
+            "d" | "dis" => {
+                let start = self.next_disassembly.unwrap_or_else(|| self.machine.pc());
+                let address = args.first().map_or(Ok(start), |address| self.value(address))?;
+                let count = args.get(1).map_or(Ok(8), |count| self.value(count))?;
+                self.disassemble(address, count);
+            },
+            "b" | "break" if args.is_empty() => {
+                let lines: Vec<String> = self.breakpoints.iter().map(|address| format!("{:#x}", address)).collect();
+                for line in lines {
+                    self.say(line);
+                }
+            },
+            "b" | "break" => {
+                let address = self.value(arg(0)?)?;
+                self.breakpoints.insert(address);
+                self.say(format!("breakpoint at {:#x}", address));
+            },
+            "del" if args.is_empty() => self.breakpoints.clear(),
+            "del" => {
+                let address = self.value(arg(0)?)?;
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("no breakpoint at {:#x}", address));
+                }
+            },
+            "load" => {
+                let path = arg(0)?;
+                let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+                let text = String::from_utf8_lossy(&bytes);
+                let image = if has_extension(Path::new(path), &HEX_EXTENSIONS) {
+                    ihex::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+                } else if has_extension(Path::new(path), &SREC_EXTENSIONS) {
+                    srec::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+                } else {
+                    Image::from_raw(self.value(arg(1)?)?, &bytes)
+                };
+                for (address, bytes) in image.chunks.iter() {
+                    self.write(*address, bytes.iter().copied())?;
+                    self.say(format!("loaded {} bytes at {:#x}", bytes.len(), address));
+                }
+            },
+            "save" => {
+                let path = arg(0)?;
+                let (address, len) = (self.value(arg(1)?)?, self.value(arg(2)?)?);
+                let bytes = (address..address.saturating_add(len))
+                    .map(|address| self.machine.read_byte(address).ok_or_else(|| format!("nothing is mapped at {:#x}", address)))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                let image = Image::from_raw(address, &bytes);
+                let contents = if has_extension(Path::new(path), &HEX_EXTENSIONS) {
+                    ihex::write(&image).ok_or("Intel HEX addresses are 32 bits")?.into_bytes()
+                } else if has_extension(Path::new(path), &SREC_EXTENSIONS) {
+                    srec::write(&image).ok_or("S-record addresses are 32 bits")?.into_bytes()
+                } else {
+                    bytes
+                };
+                fs::write(path, contents).map_err(|e| format!("{}: {}", path, e))?;
+            },
+            "source" => {
+                let path = arg(0)?;
+                if self.depth == MAX_SOURCE_DEPTH {
+                    return Err(format!("`source` is nested more than {} deep", MAX_SOURCE_DEPTH));
+                }
+                let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                self.depth += 1;
+                let code = self.run(&mut text.as_bytes(), false);
+                self.depth -= 1;
+                return Ok(code);
+            },
+            "history" => {
+                let lines: Vec<String> = self.history.iter().enumerate().map(|(i, line)| format!("{:>4}  {}", i + 1, line)).collect();
+                for line in lines {
+                    self.say(line);
+                }
+            },
+            "h" | "help" => self.say(HELP),
+            "q" | "quit" => return Ok(Some(self.exited.unwrap_or(0))),
+            _ => return Err(format!("unknown command `{}`, see `help`", name)),
+        }
+        Ok(None)
+    }
+    fn say(&mut self, text: impl Display) {
+        let _ = writeln!(self.out, "{}", text);
+    }
+    //A number or the value of a register
+    fn value(&self, text: &str) -> Result<u64, String> {
+        if text == "pc" {
+            return Ok(self.machine.pc());
+        }
+        parse_number(text)
+            .or_else(|| self.machine.registers().iter().find(|&&(name, _)| name == text).map(|&(_, value)| value))
+            .ok_or_else(|| format!("`{}` is not a number or a register", text))
+    }
+    fn byte(&self, text: &str) -> Result<u8, String> {
+        let value = self.value(text)?;
+        u8::try_from(value).map_err(|_| format!("{:#x} doesn't fit in a byte", value))
+    }
+    fn write(&mut self, address: u64, bytes: impl IntoIterator<Item = u8>) -> Result<(), String> {
+        for (i, byte) in bytes.into_iter().enumerate() {
+            let address = address.checked_add(i as u64).ok_or("the write runs past the end of the address space")?;
+            self.machine.write_byte(address, byte).ok_or_else(|| format!("can't write to {:#x}", address))?;
+        }
+        Ok(())
+    }
+    fn show_pc(&mut self) {
+        let pc = self.machine.pc();
+        self.disassemble(pc, 1);
+        self.next_disassembly = None;
+    }
+    //Runs up to `limit`, stopping early at `until`, a breakpoint, an exit or a fault. Returns
+    //why it stopped if it ran into the limit.
+    fn resume(&mut self, limit: Limit, until: Option<u64>) -> Option<String> {
+        if let Some(code) = self.exited {
+            self.say(format!("the program has exited with code {}", code));
+            return None;
+        }
+        let start = self.machine.cycles();
+        let mut steps = 0;
+        loop {
+            let cycles = self.machine.cycles().zip(start).map(|(now, start)| now - start);
+            if let Some(reason) = limit.reached(steps, cycles) {
+                self.show_pc();
+                return Some(reason);
+            }
+            match self.machine.step() {
+                StepResult::Running => (),
+                StepResult::Exited(code) => {
+                    self.exited = Some(code);
+                    self.say(format!("exited with code {}", code));
+                    return None;
+                },
+                StepResult::Fault(e) => {
+                    let pc = self.machine.pc();
+                    self.say(format!("fault: {} at pc {:#x}", e, pc));
+                    return None;
+                },
+            }
+            steps += 1;
+            let pc = self.machine.pc();
+            if until == Some(pc) {
+                self.show_pc();
+                return None;
+            }
+            if self.breakpoints.contains(&pc) {
+                self.say(format!("breakpoint at {:#x}", pc));
+                self.show_pc();
+                return None;
+            }
+        }
+    }
+    fn dump(&mut self, address: u64, len: u64) {
+        let end = address.saturating_add(len);
+        for start in (address..end).step_by(16) {
+            let bytes: Vec<Option<u8>> = (start..end.min(start + 16)).map(|address| self.machine.read_byte(address)).collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| byte.map_or("??".to_string(), |byte| format!("{:02x}", byte))).collect();
+            let text: String = bytes.iter().map(|byte| match byte {
+                Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                _ => '.',
+            }).collect();
+            self.say(format!("{:08x}  {:<47}  |{}|", start, hex.join(" "), text));
+        }
+        self.next_memory = end;
+    }
+    fn disassemble(&mut self, mut address: u64, count: u64) {
+        let pc = self.machine.pc();
+        for _ in 0..count {
+            let marker = if address == pc { "=>" } else if self.breakpoints.contains(&address) { " *" } else { "  " };
+            let (len, text) = self.machine.disassemble(address).unwrap_or((1, "??".to_string()));
+            self.say(format!("{} {:#010x}  {}", marker, address, text));
+            address = match address.checked_add(len) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        self.next_disassembly = Some(address);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::run::Rv32;
+    use micro16::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+    use micro16::microvm::memory::sparse::SparseAddressSpace;
+    use micro16::risc_v_emu::core::Core;
+    use micro16::risc_v_emu::extensions::Extensions;
+
+    fn core() -> Core<Rv32> {
+        let program: [u32; 6] = [
+            0x00a00513, //li a0, 10
+            0x00c000ef, //jal ra, 0x10
+            0x00100593, //li a1, 1
+            0x00000000, //illegal
+            0x00150513, //addi a0, a0, 1
+            0x00008067, //ret
+        ];
+        let mut space = SparseAddressSpace::new(0x1000u32).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x1000u16))).unwrap();
+        let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        space.write_bytes(0, &bytes).unwrap();
+        Core::new(space, Extensions::from_letters("i").unwrap())
+    }
+    fn session(script: &str, interactive: bool) -> (Option<i32>, String) {
+        let mut core = core();
+        let mut out = Vec::new();
+        let code = Monitor::new(&mut core, &mut out, Limit { instructions: Some(100), cycles: None }).run(&mut script.as_bytes(), interactive);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let (code, out) = session("# a comment\nb 0x8\nc\nr a0 5\nr\ns\ns\nbogus\nq\n", false);
+        assert_eq!(code, Some(0));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..3], ["breakpoint at 0x8", "breakpoint at 0x8", "=> 0x00000008  addi    a1, zero, 1"]);
+        assert_eq!(lines[3], "  pc 00000008");
+        assert!(lines[6].contains("  a0 00000005"), "{}", out);
+        assert_eq!(lines[12..], [
+            "=> 0x0000000c  .half   0x0000",
+            "fault: IllegalInstruction(0) at pc 0xc",
+            "error: unknown command `bogus`, see `help`",
+        ]);
+    }
+    #[test]
+    fn next_and_limits() {
+        let (_, out) = session("n\nn\nd 0 3\nr pc 0xc\nc\nr pc 0x14\nr ra 0x14\nc\n", false);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..5], [
+            "=> 0x00000004  jal     ra, 0x10",
+            "=> 0x00000008  addi    a1, zero, 1",
+            "   0x00000000  addi    a0, zero, 10",
+            "   0x00000004  jal     ra, 0x10",
+            "=> 0x00000008  addi    a1, zero, 1",
+        ]);
+        assert_eq!(lines[5], "fault: IllegalInstruction(0) at pc 0xc");
+        assert_eq!(lines[6..], ["=> 0x00000014  jalr    zero, 0(ra)", "stopped after 100 instructions"]);
+    }
+    #[test]
+    fn memory() {
+        let path = std::env::temp_dir().join(format!("micro16-monitor-{}", std::process::id()));
+        let srec = path.with_extension("s19");
+        let script = format!(
+            "f 0x100 20 0x2e\nw 0x100 0x41 0x42 a0\nm 0x100 20\nsave {0} 0x100 3\nload {0} 0x200\nm 0x200 3\nm 0xffe 4\nw 0x1000 1\n\
+            save {1} 0x100 2\nload {1}\n",
+            path.display(), srec.display());
+        let (code, out) = session(&script, false);
+        assert_eq!(fs::read_to_string(&srec).unwrap(), "S0030000FC\nS1050100414276\nS5030001FB\nS9030000FC\n");
+        fs::remove_file(path).unwrap();
+        fs::remove_file(srec).unwrap();
+        assert_eq!(code, None);
+        assert_eq!(out.lines().collect::<Vec<&str>>(), [
+            "00000100  41 42 00 2e 2e 2e 2e 2e 2e 2e 2e 2e 2e 2e 2e 2e  |AB..............|",
+            "00000110  2e 2e 2e 2e                                      |....|",
+            "loaded 3 bytes at 0x200",
+            "00000200  41 42 00                                         |AB.|",
+            "00000ffe  00 00 ?? ??                                      |....|",
+            "error: can't write to 0x1000",
+            "loaded 2 bytes at 0x100",
+        ]);
+    }
+    #[test]
+    fn bounded_arguments() {
+        let path = std::env::temp_dir().join(format!("micro16-monitor-source-{}", std::process::id()));
+        fs::write(&path, format!("s\nsource {}\n", path.display())).unwrap();
+        let (code, out) = session(&format!("f 0x100 0xffffffffffffffff 0x2e\nm 0xfff 1\nsource {}\n", path.display()), false);
+        fs::remove_file(path).unwrap();
+        assert_eq!(code, None);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..3], [
+            "error: can't write to 0x1000",
+            "00000fff  2e                                               |.|",
+            "=> 0x00000004  jal     ra, 0x10",
+        ]);
+        assert_eq!(lines.len(), 3 + 16, "{}", out);
+        assert_eq!(lines[18], "error: `source` is nested more than 16 deep");
+    }
+    #[test]
+    fn history() {
+        let (code, out) = session("s\n\nhistory\n!1\n!9\nquit\n", true);
+        assert_eq!(code, Some(0));
+        let out = out.replace("(micro16) ", "");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines, [
+            "=> 0x00000004  jal     ra, 0x10",
+            "=> 0x00000010  addi    a0, a0, 1",
+            "   1  s",
+            "   2  s",
+            "   3  history",
+            "=> 0x00000014  jalr    zero, 0(ra)",
+            "error: no command 9 in the history",
+        ]);
+    }
+}
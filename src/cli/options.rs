@@ -0,0 +1,255 @@
+//! Command line options. `--config FILE` reads more options from a file, whitespace separated
+//! with `#` comments, so a machine setup can be kept next to its firmware.
+use micro16::microvm::memory::address_space::Fill;
+use micro16::risc_v_emu::extensions::Extensions;
+use std::fs;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: micro16 --arch ARCH [OPTIONS] [FILE[@ADDRESS]]... [-- GUEST ARGS]
+
+  --arch ARCH             6502, 65c02 or a RISC-V ISA string such as rv32imac or rv64gc
+  --load FILE[@ADDRESS]   ELF, Intel HEX, S-records, or a raw binary loaded at ADDRESS (default 0)
+  --entry ADDRESS         start here instead of the image's entry point
+  --ram BASE:SIZE         map RAM, without any the first 64K are RAM unless an ELF is
+                          loaded, which maps its own segments
+  --map FILE              build the address space from a TOML memory map instead, with a
+                          6502 putchar and exit port available as device `trap` and a
+                          timer raising IRQ or machine external interrupts as `timer`
+  --fill PATTERN          what RAM holds before it's written: zero, ff, checkerboard,
+                          random or random:SEED (default zero), ELF segments stay zeroed
+  --strict                report reads of RAM that was never written, with the pc
+  --config FILE           read more options from FILE
+  --max-instructions N    stop after N instructions
+  --max-cycles N          stop after N cycles, RISC-V cores retire one instruction a cycle
+                          and the 6502 cores count each opcode's cycles
+  --trace                 print each instruction's address and encoding to stderr
+  --linux                 run a RISC-V ELF as a Linux user process
+  --env NAME=VALUE        environment variable for --linux
+  --sandbox DIR           directory guest file access is confined to
+  --trap ADDRESS          6502 putchar at ADDRESS and exit at ADDRESS + 1
+  --monitor               stop at the entry point in the monitor, `help` lists its commands
+  --script FILE           run monitor commands from FILE, then exit unless --monitor is given
+
+Exits with the guest's exit code, 124 at the instruction or cycle limit, 125 when the
+machine can't be set up and 126 when the guest faults.";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Arch {
+    Mos6502,
+    Wdc65C02,
+    RiscV32(Extensions),
+    RiscV64(Extensions),
+}
+impl Arch {
+    pub fn parse(name: &str) -> Option<Arch> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "6502" => Some(Arch::Mos6502),
+            "65c02" => Some(Arch::Wdc65C02),
+            _ if name.starts_with("rv32") => Extensions::from_letters(&name[4..]).map(Arch::RiscV32),
+            _ if name.starts_with("rv64") => Extensions::from_letters(&name[4..]).map(Arch::RiscV64),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Load {
+    pub path: PathBuf,
+    /// Where a raw binary goes, the other formats carry their own addresses.
+    pub address: Option<u64>,
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Options {
+    pub arch: Arch,
+    pub loads: Vec<Load>,
+    pub entry: Option<u64>,
+    /// RAM regions as base and size.
+    pub ram: Vec<(u64, u64)>,
+    pub map: Option<PathBuf>,
+    pub fill: Fill,
+    pub strict: bool,
+    pub max_instructions: Option<u64>,
+    pub max_cycles: Option<u64>,
+    pub trace: bool,
+    pub linux: bool,
+    pub env: Vec<String>,
+    pub sandbox: Option<PathBuf>,
+    pub trap: Option<u64>,
+    pub monitor: bool,
+    pub script: Option<PathBuf>,
+    /// Arguments after `--`, passed to a `--linux` program after its name.
+    pub args: Vec<String>,
+}
+impl Options {
+    pub fn limit(&self) -> Limit {
+        Limit { instructions: self.max_instructions, cycles: self.max_cycles }
+    }
+}
+/// How far a run goes before giving up.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Limit {
+    pub instructions: Option<u64>,
+    pub cycles: Option<u64>,
+}
+impl Limit {
+    /// Why a run that got through `instructions` and `cycles` has to stop, if it does. Cores
+    /// that don't count cycles take one per instruction.
+    pub fn reached(&self, instructions: u64, cycles: Option<u64>) -> Option<String> {
+        let cycles = cycles.unwrap_or(instructions);
+        if self.instructions.is_some_and(|max| instructions >= max) {
+            Some(format!("stopped after {} instructions", instructions))
+        } else if self.cycles.is_some_and(|max| cycles >= max) {
+            Some(format!("stopped after {} cycles", cycles))
+        } else {
+            None
+        }
+    }
+}
+/// Parses `0x` hex or decimal, with an optional `k` or `m` suffix for sizes.
+pub fn parse_number(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    let (digits, scale) = match text.chars().last()? {
+        'k' | 'K' => (&text[..text.len() - 1], 1 << 10),
+        'm' | 'M' => (&text[..text.len() - 1], 1 << 20),
+        _ => (&text[..], 1),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(scale)
+}
+fn load(text: &str) -> Result<Load, String> {
+    match text.rfind('@') {
+        Some(at) => Ok(Load {
+            path: PathBuf::from(&text[..at]),
+            address: Some(parse_number(&text[at + 1..]).ok_or_else(|| format!("invalid load address in `{}`", text))?),
+        }),
+        None => Ok(Load { path: PathBuf::from(text), address: None }),
+    }
+}
+/// Parses the arguments after the program name.
+pub fn parse(args: Vec<String>) -> Result<Options, String> {
+    let mut args: Vec<String> = args.into_iter().rev().collect();
+    let mut arch = None;
+    let mut options = Options {
+        arch: Arch::Mos6502,
+        loads: Vec::new(),
+        entry: None,
+        ram: Vec::new(),
+        map: None,
+        fill: Fill::Zero,
+        strict: false,
+        max_instructions: None,
+        max_cycles: None,
+        trace: false,
+        linux: false,
+        env: Vec::new(),
+        sandbox: None,
+        trap: None,
+        monitor: false,
+        script: None,
+        args: Vec::new(),
+    };
+    while let Some(arg) = args.pop() {
+        let mut value = || args.pop().ok_or_else(|| format!("{} needs a value", arg));
+        let number = |text: String| parse_number(&text).ok_or_else(|| format!("`{}` is not a number", text));
+        match arg.as_str() {
+            "--arch" => {
+                let name = value()?;
+                arch = Some(Arch::parse(&name).ok_or_else(|| format!("unknown architecture `{}`", name))?);
+            },
+            "--load" => options.loads.push(load(&value()?)?),
+            "--entry" => options.entry = Some(number(value()?)?),
+            "--ram" => {
+                let region = value()?;
+                let mut parts = region.splitn(2, ':');
+                match (parts.next().and_then(parse_number), parts.next().and_then(parse_number)) {
+                    (Some(base), Some(size)) => options.ram.push((base, size)),
+                    _ => return Err(format!("--ram takes BASE:SIZE, not `{}`", region)),
+                }
+            },
+            "--map" => options.map = Some(PathBuf::from(value()?)),
+            "--fill" => {
+                let pattern = value()?;
+                options.fill = Fill::parse(&pattern).ok_or_else(|| format!("unknown fill pattern `{}`", pattern))?;
+            },
+            "--strict" => options.strict = true,
+            "--config" => {
+                let path = value()?;
+                let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                let words: Vec<String> = text.lines()
+                    .map(|line| line.split('#').next().unwrap_or(""))
+                    .flat_map(str::split_whitespace)
+                    .map(String::from)
+                    .collect();
+                args.extend(words.into_iter().rev());
+            },
+            "--max-instructions" => options.max_instructions = Some(number(value()?)?),
+            "--max-cycles" => options.max_cycles = Some(number(value()?)?),
+            "--trace" => options.trace = true,
+            "--linux" => options.linux = true,
+            "--env" => options.env.push(value()?),
+            "--sandbox" => options.sandbox = Some(PathBuf::from(value()?)),
+            "--trap" => options.trap = Some(number(value()?)?),
+            "--monitor" => options.monitor = true,
+            "--script" => options.script = Some(PathBuf::from(value()?)),
+            "--" => options.args.extend(args.drain(..).rev()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => options.loads.push(load(&arg)?),
+        }
+    }
+    options.arch = arch.ok_or("--arch is required")?;
+    Ok(options)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse(args("--arch rv32imac a.elf --load b.bin@0x100 --ram 0:64k --max-cycles 10 \
+            --max-instructions 20 --map board.toml --trace --monitor --script init.txt --fill random:42 --strict -- -v x")).unwrap();
+        assert_eq!(options.arch, Arch::RiscV32(Extensions::from_letters("imac").unwrap()));
+        assert_eq!(options.loads, vec![
+            Load { path: PathBuf::from("a.elf"), address: None },
+            Load { path: PathBuf::from("b.bin"), address: Some(0x100) },
+        ]);
+        assert_eq!(options.ram, vec![(0, 0x10000)]);
+        assert_eq!(options.map, Some(PathBuf::from("board.toml")));
+        assert_eq!(options.limit(), Limit { instructions: Some(20), cycles: Some(10) });
+        assert_eq!(options.limit().reached(10, None), Some("stopped after 10 cycles".to_string()));
+        assert_eq!(options.limit().reached(9, Some(4)), None);
+        assert!(options.trace && options.monitor && options.strict);
+        assert_eq!(options.fill, Fill::Random(42));
+        assert_eq!(options.script, Some(PathBuf::from("init.txt")));
+        assert_eq!(options.args, args("-v x"));
+        assert_eq!(parse(args("--arch 65C02")).unwrap().arch, Arch::Wdc65C02);
+        assert_eq!(parse(args("--arch rv64gc")).unwrap().arch, Arch::RiscV64(Extensions::from_letters("gc").unwrap()));
+    }
+    #[test]
+    fn reports_bad_options() {
+        assert_eq!(parse(args("a.elf")), Err("--arch is required".to_string()));
+        assert_eq!(parse(args("--arch z80")), Err("unknown architecture `z80`".to_string()));
+        assert_eq!(parse(args("--arch 6502 --ram 10")), Err("--ram takes BASE:SIZE, not `10`".to_string()));
+        assert_eq!(parse(args("--arch 6502 --trap")), Err("--trap needs a value".to_string()));
+        assert_eq!(parse(args("--arch 6502 --bogus")), Err("unknown option `--bogus`".to_string()));
+        assert_eq!(parse(args("--arch 6502 --fill stripes")), Err("unknown fill pattern `stripes`".to_string()));
+        assert_eq!(parse_number("0x1_000"), Some(0x1000));
+        assert_eq!(parse_number("2M"), Some(2 << 20));
+        assert_eq!(parse_number("x"), None);
+    }
+    #[test]
+    fn reads_config_files() {
+        let path = std::env::temp_dir().join(format!("micro16-options-{}", std::process::id()));
+        fs::write(&path, "--arch rv64gc # the core\n--ram 0x1000:4k\n").unwrap();
+        let options = parse(args(&format!("--config {} --ram 0x2000:1k", path.display()))).unwrap();
+        assert_eq!(options.ram, vec![(0x1000, 0x1000), (0x2000, 0x400)]);
+        fs::remove_file(path).unwrap();
+    }
+}
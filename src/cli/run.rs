@@ -0,0 +1,384 @@
+//! Builds a machine from the options, loads its images and runs it.
+use super::monitor::Monitor;
+use super::options::{Arch, Load, Options};
+use micro16::microvm::debug::{Debuggable, StepResult};
+use micro16::microvm::device::{DeviceSpace, InterruptLines, Timer};
+use micro16::microvm::image::{ihex, srec, Image};
+use micro16::microvm::loader::{self, Machine};
+use micro16::microvm::memory::address::AddressType;
+use micro16::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+use micro16::microvm::memory::map::{Devices, MemoryMap};
+use micro16::microvm::memory::sparse::SparseAddressSpace;
+use micro16::microvm::memory::strict::{StrictRam, UninitializedReads};
+use micro16::r650x::{self, decoder::Variant, semihost::{ExitStatus, TrapPort}};
+use micro16::risc_v_emu::conformance::Outcome;
+use micro16::risc_v_emu::core::{Core, CoreSettings};
+use micro16::risc_v_emu::extensions::Extensions;
+use micro16::risc_v_emu::linux::{Process, ProcessConfig};
+use micro16::risc_v_emu::semihosting::Semihost;
+use micro16::risc_v_emu::types::{DoubleWord, QuadWord};
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::path::Path;
+
+pub const EXIT_LIMIT: i32 = 124;
+pub const EXIT_ERROR: i32 = 125;
+pub const EXIT_FAULT: i32 = 126;
+//Without --ram or --map, RAM covers this much
+const DEFAULT_RAM: u64 = 0x10000;
+
+pub struct Rv32;
+impl CoreSettings for Rv32 {
+    type XLen = DoubleWord;
+    type RegType = DoubleWord;
+    type Address = u32;
+}
+pub struct Rv64;
+impl CoreSettings for Rv64 {
+    type XLen = QuadWord;
+    type RegType = QuadWord;
+    type Address = u64;
+}
+
+pub const HEX_EXTENSIONS: [&str; 2] = ["hex", "ihex"];
+pub const SREC_EXTENSIONS: [&str; 5] = ["srec", "s19", "s28", "s37", "mot"];
+
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| extensions.contains(&e.to_ascii_lowercase().as_str()))
+}
+enum Format {
+    Elf,
+    Hex,
+    Srec,
+    Raw(u64),
+}
+struct File {
+    load: Load,
+    bytes: Vec<u8>,
+    format: Format,
+}
+fn read(load: &Load) -> Result<File, String> {
+    let bytes = fs::read(&load.path).map_err(|e| format!("{}: {}", load.path.display(), e))?;
+    let text = bytes.iter().position(|b| !b.is_ascii_whitespace()).map_or(&[][..], |i| &bytes[i..]);
+    let hex = has_extension(&load.path, &HEX_EXTENSIONS) || text.starts_with(b":");
+    let srec = has_extension(&load.path, &SREC_EXTENSIONS) || text.starts_with(b"S0");
+    let format = match load.address {
+        Some(address) => Format::Raw(address),
+        None if bytes.starts_with(b"\x7FELF") => Format::Elf,
+        None if hex => Format::Hex,
+        None if srec => Format::Srec,
+        None => Format::Raw(0),
+    };
+    Ok(File { load: load.clone(), bytes, format })
+}
+//Loads a HEX, S-record or raw file, returning its entry point
+fn write_image<Address: AddressType>(space: &mut dyn AddressSpace<Address>, file: &File) -> Result<Option<u64>, String> {
+    let name = file.load.path.display();
+    let image = match file.format {
+        Format::Hex => ihex::parse(&String::from_utf8_lossy(&file.bytes)).map_err(|e| format!("{}: {}", name, e))?,
+        Format::Srec => srec::parse(&String::from_utf8_lossy(&file.bytes)).map_err(|e| format!("{}: {}", name, e))?,
+        Format::Raw(address) => Image { chunks: vec![(address, file.bytes.clone())], entry: Some(address) },
+        Format::Elf => return Ok(None),
+    };
+    image.write_to(space).map_err(|e| format!("{}: writing to memory failed: {:?}", name, e))?;
+    Ok(image.entry)
+}
+//RAM filled as --fill says, reporting reads before writes to `reads` with --strict
+fn ram<Address: AddressType>(options: &Options, reads: &UninitializedReads, base: u64, size: u64) -> Box<dyn AddressSpace<Address>> {
+    if options.strict {
+        Box::new(StrictRam::new(size, options.fill, base, reads))
+    } else {
+        Box::new(DenseStaticMemory::filled(size, options.fill))
+    }
+}
+//The --map address space, the --ram regions, or RAM over the first 64K except `holes` when
+//there are neither
+fn address_space<Address: AddressType + 'static>(options: &Options, default: bool, holes: &[(u64, u64)],
+                                                devices: &mut Devices<Address>, reads: &UninitializedReads)
+                                                -> Result<SparseAddressSpace<Address>, String> {
+    if let Some(path) = &options.map {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let root = path.parent().unwrap_or_else(|| Path::new("."));
+        return MemoryMap::parse(&text)
+            .and_then(|map| map.build_with_ram(root, devices, &mut |base, size| ram(options, reads, base, size)))
+            .map_err(|e| format!("{}: {}", path.display(), e));
+    }
+    let mut space = SparseAddressSpace::full();
+    let mut regions = options.ram.clone();
+    if regions.is_empty() && default {
+        let mut start = 0;
+        for &(base, size) in holes.iter() {
+            let base = base.min(DEFAULT_RAM);
+            regions.push((start, base.saturating_sub(start)));
+            start = start.max(base.saturating_add(size).min(DEFAULT_RAM));
+        }
+        regions.push((start, DEFAULT_RAM - start));
+    }
+    for &(base, size) in regions.iter().filter(|&&(_, size)| size > 0) {
+        let error = |e: String| format!("RAM at {:#x} with size {:#x}: {}", base, size, e);
+        let address = Address::from_u64(base).ok_or_else(|| error("doesn't fit in the address space".to_string()))?;
+        space.add_space(address, ram(options, reads, base, size)).map_err(|e| error(format!("{:?}", e)))?;
+    }
+    Ok(space)
+}
+pub fn run(options: &Options) -> Result<i32, String> {
+    let files = options.loads.iter().map(read).collect::<Result<Vec<File>, String>>()?;
+    let reads = UninitializedReads::new();
+    let mut machine = match options.arch {
+        Arch::RiscV32(extensions) => riscv::<Rv32>(options, &files, extensions, &reads)?,
+        Arch::RiscV64(extensions) => riscv::<Rv64>(options, &files, extensions, &reads)?,
+        Arch::Mos6502 | Arch::Wdc65C02 => mos6502(options, &files, &reads)?,
+    };
+    if options.monitor || options.script.is_some() {
+        let stdout = std::io::stdout();
+        let mut monitor = Monitor::new(machine.as_mut(), stdout.lock(), options.limit());
+        if let Some(script) = &options.script {
+            let text = fs::read_to_string(script).map_err(|e| format!("{}: {}", script.display(), e))?;
+            if let Some(code) = monitor.run(&mut text.as_bytes(), false) {
+                return Ok(code);
+            }
+        }
+        if options.monitor {
+            let stdin = std::io::stdin();
+            if let Some(code) = monitor.run(&mut stdin.lock(), true) {
+                return Ok(code);
+            }
+        }
+        return Ok(monitor.exit_code().unwrap_or(0));
+    }
+    let limit = options.limit();
+    let start = machine.cycles();
+    let mut instructions = 0;
+    loop {
+        let cycles = machine.cycles().zip(start).map(|(now, start)| now - start);
+        if let Some(reason) = limit.reached(instructions, cycles) {
+            eprintln!("{}", reason);
+            return Ok(EXIT_LIMIT);
+        }
+        if options.trace {
+            trace(machine.as_ref());
+        }
+        let pc = machine.pc();
+        let step = machine.step();
+        for (address, len) in reads.take() {
+            eprintln!("uninitialized read of {:#x} ({} bytes) at pc {:#x}", address, len, pc);
+        }
+        match step {
+            StepResult::Running => (),
+            StepResult::Exited(code) => return Ok(code),
+            StepResult::Fault(e) => {
+                eprintln!("fault: {} at pc {:#x}", e, machine.pc());
+                eprint!("{}", registers(machine.as_ref()));
+                return Ok(EXIT_FAULT);
+            },
+        }
+        instructions += 1;
+    }
+}
+/// The registers with pc first, four to a line.
+pub fn registers(machine: &dyn Debuggable) -> String {
+    let width = machine.register_bits() as usize / 4;
+    let mut out = format!("{:>4} {:0width$x}\n", "pc", machine.pc(), width = width);
+    let registers = machine.registers();
+    for (i, (name, value)) in registers.iter().enumerate() {
+        out += &format!("{:>4} {:0width$x}", name, value, width = width);
+        out += if i % 4 == 3 || i + 1 == registers.len() { "\n" } else { "  " };
+    }
+    out
+}
+fn trace(machine: &dyn Debuggable) {
+    let pc = machine.pc();
+    let line = machine.disassemble(pc).and_then(|(len, text)| {
+        let encoding = (0..len).rev().map(|i| machine.read_byte(pc + i).map(|b| format!("{:02x}", b))).collect::<Option<String>>()?;
+        Some(format!("{}  {}", encoding, text))
+    });
+    eprintln!("{:#010x}  {}", pc, line.as_deref().unwrap_or("????????"));
+}
+//Semihosting calls and the riscv-tests `tohost` report on top of the core
+struct BareMetal<Settings: CoreSettings> {
+    core: Core<Settings>,
+    semihost: Semihost,
+    tohost: Option<u64>,
+}
+impl<Settings: CoreSettings> BareMetal<Settings> {
+    fn tohost(&self) -> Option<u64> {
+        let bytes: Option<Vec<u8>> = (0..8).map(|i| self.core.read_byte(self.tohost? + i)).collect();
+        Some(u64::from_le_bytes(bytes?[..].try_into().ok()?))
+    }
+}
+impl<Settings: CoreSettings> Debuggable for BareMetal<Settings> {
+    fn register_bits(&self) -> u32 {
+        self.core.register_bits()
+    }
+    fn pc(&self) -> u64 {
+        self.core.pc()
+    }
+    fn set_pc(&mut self, pc: u64) {
+        self.core.set_pc(pc)
+    }
+    fn registers(&self) -> Vec<(&'static str, u64)> {
+        self.core.registers()
+    }
+    fn set_register(&mut self, name: &str, value: u64) -> Option<()> {
+        self.core.set_register(name, value)
+    }
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        self.core.read_byte(address)
+    }
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()> {
+        self.core.write_byte(address, value)
+    }
+    fn step(&mut self) -> StepResult {
+        if Semihost::is_call(&self.core) {
+            if let Some(code) = self.semihost.call(&mut self.core) {
+                return StepResult::Exited(code);
+            }
+        } else if let StepResult::Fault(e) = Debuggable::step(&mut self.core) {
+            return StepResult::Fault(e);
+        }
+        //1 is a pass and anything else the failing test
+        match self.tohost().map(Outcome::from_tohost) {
+            None | Some(Outcome::Fail(0)) => StepResult::Running,
+            Some(Outcome::Pass) => StepResult::Exited(0),
+            Some(Outcome::Fail(test)) => {
+                eprintln!("failed test {}", test);
+                StepResult::Exited(test as i32)
+            },
+            Some(Outcome::Timeout) => unreachable!(),
+        }
+    }
+    fn disassemble(&self, address: u64) -> Option<(u64, String)> {
+        self.core.disassemble(address)
+    }
+    fn is_call(&self, address: u64) -> bool {
+        self.core.is_call(address)
+    }
+}
+fn riscv<Settings: CoreSettings + 'static>(options: &Options, files: &[File], extensions: Extensions,
+                                           reads: &UninitializedReads) -> Result<Box<dyn Debuggable>, String> {
+    let elf = files.iter().find(|file| matches!(file.format, Format::Elf));
+    let lines = InterruptLines::new();
+    let space = address_space(options, elf.is_none(), &[], &mut |name, _| match name {
+        "timer" => Some(Box::new(DeviceSpace::new(Box::new(Timer::new(lines.line(0)))))),
+        _ => None,
+    }, reads)?;
+    let mut core = Core::<Settings>::new(space, extensions);
+    core.connect_interrupts(lines);
+    let mut entry = None;
+    let mut tohost = None;
+    for file in files.iter() {
+        match file.format {
+            Format::Elf if options.linux => (),
+            Format::Elf => {
+                let image = core.load_elf(&file.bytes).map_err(|e| format!("{}: {}", file.load.path.display(), e))?;
+                entry = entry.or(Some(image.entry));
+                tohost = tohost.or_else(|| image.symbol("tohost").map(|symbol| symbol.value));
+            },
+            _ => entry = entry.or(write_image(core.space_mut(), file)?),
+        }
+    }
+    if options.linux {
+        let elf = elf.ok_or("--linux needs an ELF executable")?;
+        let mut args = vec![elf.load.path.display().to_string()];
+        args.extend(options.args.iter().cloned());
+        let config = ProcessConfig { args, env: options.env.clone(), sandbox: options.sandbox.clone(), ..ProcessConfig::default() };
+        let mut process = Process::new(core, &elf.bytes, config).map_err(|e| format!("{}: {:?}", elf.load.path.display(), e))?;
+        if let Some(entry) = options.entry {
+            process.core_mut().set_pc(entry);
+        }
+        return Ok(Box::new(process));
+    }
+    if let Some(entry) = options.entry.or(entry) {
+        core.set_pc(entry);
+    }
+    Ok(Box::new(BareMetal { core, semihost: Semihost::new(options.sandbox.clone()), tohost }))
+}
+//Ends the run once the program stores to the exit byte of a trap port
+struct Mos6502 {
+    core: r650x::core::Core,
+    exits: Vec<ExitStatus>,
+}
+impl Debuggable for Mos6502 {
+    fn register_bits(&self) -> u32 {
+        self.core.register_bits()
+    }
+    fn pc(&self) -> u64 {
+        self.core.pc()
+    }
+    fn set_pc(&mut self, pc: u64) {
+        self.core.set_pc(pc)
+    }
+    fn registers(&self) -> Vec<(&'static str, u64)> {
+        self.core.registers()
+    }
+    fn set_register(&mut self, name: &str, value: u64) -> Option<()> {
+        self.core.set_register(name, value)
+    }
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        self.core.read_byte(address)
+    }
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()> {
+        self.core.write_byte(address, value)
+    }
+    fn step(&mut self) -> StepResult {
+        match Debuggable::step(&mut self.core) {
+            StepResult::Running => match self.exits.iter().find_map(ExitStatus::get) {
+                Some(code) => StepResult::Exited(code.into()),
+                None => StepResult::Running,
+            },
+            step => step,
+        }
+    }
+    fn cycles(&self) -> Option<u64> {
+        Debuggable::cycles(&self.core)
+    }
+    fn disassemble(&self, address: u64) -> Option<(u64, String)> {
+        self.core.disassemble(address)
+    }
+    fn is_call(&self, address: u64) -> bool {
+        self.core.is_call(address)
+    }
+}
+fn mos6502(options: &Options, files: &[File], reads: &UninitializedReads) -> Result<Box<dyn Debuggable>, String> {
+    //The port's two bytes have to fit below 64K
+    let trap = match options.trap {
+        Some(trap) => Some(u16::try_from(trap).ok().filter(|&trap| trap < u16::MAX).ok_or("--trap has to be below 0xffff")?),
+        None => None,
+    };
+    let holes: Vec<(u64, u64)> = trap.iter().map(|&trap| (trap as u64, 2)).collect();
+    let mut exits = Vec::new();
+    let lines = InterruptLines::new();
+    let mut space = address_space(options, true, &holes, &mut |name, _| match name {
+        "trap" => {
+            let (port, exit) = TrapPort::new(Box::new(std::io::stdout()));
+            exits.push(exit);
+            Some(Box::new(port))
+        },
+        "timer" => Some(Box::new(DeviceSpace::new(Box::new(Timer::new(lines.line(0)))))),
+        _ => None,
+    }, reads)?;
+    if let Some(trap) = trap {
+        exits.push(TrapPort::install(&mut space, trap, Box::new(std::io::stdout())).map_err(|e| format!("--trap: {:?}", e))?);
+    }
+    let mut entry = None;
+    for file in files.iter() {
+        match file.format {
+            Format::Elf => {
+                let image = loader::load(&file.bytes, Machine::Mos6502, &mut space).map_err(|e| format!("{}: {}", file.load.path.display(), e))?;
+                entry = entry.or(Some(image.entry));
+            },
+            _ => entry = entry.or(write_image(&mut space, file)?),
+        }
+    }
+    //Without an entry point the reset vector is used
+    let variant = match options.arch {
+        Arch::Wdc65C02 => Variant::Cmos,
+        _ => Variant::Nmos,
+    };
+    let mut core = r650x::core::Core::new(space, variant);
+    core.connect_interrupts(lines);
+    if let Some(entry) = options.entry.or(entry) {
+        core.set_pc(entry);
+    }
+    Ok(Box::new(Mos6502 { core, exits }))
+}
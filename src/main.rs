@@ -1,7 +1,5 @@
-
-pub mod microvm;
-pub mod risc_v_emu;
-pub mod r650x;
+mod cli;
 
 fn main() {
+    std::process::exit(cli::main(std::env::args().skip(1).collect()));
 }
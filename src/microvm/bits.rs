@@ -39,10 +39,32 @@ pub trait Bits<'a>: Clone + BitOps + ShiftOps + Default {
     fn clear_bit(&mut self, bit: usize);
     fn get_bit(&self, bit: usize) -> bool;
 }
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Endianness {
     Little,
     Big
 }
+impl Endianness {
+    /// The value of up to 8 bytes in this byte order.
+    pub fn read(self, bytes: &[u8]) -> u64 {
+        let fold = |value: u64, &byte: &u8| value << 8 | byte as u64;
+        match self {
+            Endianness::Little => bytes.iter().rev().fold(0, fold),
+            Endianness::Big => bytes.iter().fold(0, fold),
+        }
+    }
+    /// Fills `out` with the low bytes of `value` in this byte order.
+    pub fn write(self, value: u64, out: &mut [u8]) {
+        let len = out.len();
+        for (i, byte) in out.iter_mut().enumerate() {
+            let shift = match self {
+                Endianness::Little => i,
+                Endianness::Big => len - 1 - i,
+            };
+            *byte = (value >> (shift * 8)) as u8;
+        }
+    }
+}
 macro_rules! bit_impl {
     ($($t:ty),*) => ($(
         impl BitOps for $t {
@@ -0,0 +1,33 @@
+//! A common view of the emulated cores for debugging front ends like the command line monitor.
+
+/// What happened when a machine ran an instruction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StepResult {
+    Running,
+    /// The program exited with the code.
+    Exited(i32),
+    /// The instruction couldn't run, the machine is left as it was before it.
+    Fault(String),
+}
+pub trait Debuggable {
+    /// The width of the registers and addresses in bits.
+    fn register_bits(&self) -> u32;
+    fn pc(&self) -> u64;
+    fn set_pc(&mut self, pc: u64);
+    /// The registers other than pc, in the order they should be shown.
+    fn registers(&self) -> Vec<(&'static str, u64)>;
+    /// Sets the register called `name`, `None` if there's no such register.
+    fn set_register(&mut self, name: &str, value: u64) -> Option<()>;
+    /// Reads physical memory without side effects, `None` if nothing is mapped at `address`.
+    fn read_byte(&self, address: u64) -> Option<u8>;
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()>;
+    fn step(&mut self) -> StepResult;
+    /// The cycles run since reset, `None` for cores that take one cycle per instruction.
+    fn cycles(&self) -> Option<u64> {
+        None
+    }
+    /// The length and text of the instruction at `address`.
+    fn disassemble(&self, address: u64) -> Option<(u64, String)>;
+    /// Whether the instruction at `address` is a subroutine call, which `next` runs in one go.
+    fn is_call(&self, address: u64) -> bool;
+}
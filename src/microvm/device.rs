@@ -0,0 +1,208 @@
+//! Memory-mapped I/O devices, whose reads and writes can have side effects and which see time
+//! pass through `tick`, and the interrupt lines they raise toward a core.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::MemoryError;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// A device's registers, addressed by their offset from where the device is mapped.
+pub trait Device {
+    fn size(&self) -> u64;
+    /// A read by the machine, which may change the device's state, such as popping a FIFO.
+    fn read(&mut self, offset: u64) -> Result<u8, MemoryError>;
+    /// A read by a debugger, which must leave the device alone. Devices that can't do that
+    /// refuse.
+    fn peek(&self, _offset: u64) -> Result<u8, MemoryError> {
+        Err(MemoryError::InvalidAccess)
+    }
+    fn write(&mut self, offset: u64, value: u8) -> Result<(), MemoryError>;
+    fn tick(&mut self, _cycles: u64) {}
+}
+/// Maps a `Device` into an address space.
+pub struct DeviceSpace {
+    device: Box<dyn Device>,
+}
+impl DeviceSpace {
+    pub fn new(device: Box<dyn Device>) -> DeviceSpace {
+        DeviceSpace { device }
+    }
+    fn offset<Address: AddressType>(&self, address: Address) -> Result<u64, MemoryError> {
+        let offset = address.as_u64();
+        if offset < self.device.size() { Ok(offset) } else { Err(MemoryError::OutOfBounds) }
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for DeviceSpace {
+    fn size(&self) -> u64 {
+        self.device.size()
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        self.device.peek(self.offset(address)?)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let offset = self.offset(address)?;
+        self.device.read(offset)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        let offset = self.offset(address)?;
+        if offset + bytes.len() as u64 > self.device.size() {
+            return Err(MemoryError::OutOfBounds);
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.device.write(offset + i as u64, byte)?;
+        }
+        Ok(())
+    }
+    fn tick(&mut self, cycles: u64) {
+        self.device.tick(cycles)
+    }
+}
+/// Up to 64 interrupt lines shared between devices and the core that samples them.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptLines(Rc<Cell<u64>>);
+impl InterruptLines {
+    pub fn new() -> InterruptLines {
+        InterruptLines::default()
+    }
+    pub fn line(&self, number: u32) -> InterruptLine {
+        assert!(number < 64, "there are only 64 interrupt lines");
+        InterruptLine { lines: self.clone(), mask: 1 << number }
+    }
+    /// The raised lines, line n as bit n.
+    pub fn pending(&self) -> u64 {
+        self.0.get()
+    }
+}
+/// One of the `InterruptLines`, held by the device that drives it.
+#[derive(Clone, Debug)]
+pub struct InterruptLine {
+    lines: InterruptLines,
+    mask: u64,
+}
+impl InterruptLine {
+    pub fn set(&self, raised: bool) {
+        let pending = self.lines.pending();
+        self.lines.0.set(if raised { pending | self.mask } else { pending & !self.mask });
+    }
+    pub fn raise(&self) {
+        self.set(true)
+    }
+    pub fn lower(&self) {
+        self.set(false)
+    }
+    pub fn is_raised(&self) -> bool {
+        self.lines.pending() & self.mask != 0
+    }
+}
+pub const TIMER_COUNT: u64 = 0x0;
+pub const TIMER_COMPARE: u64 = 0x4;
+pub const TIMER_CONTROL: u64 = 0x8;
+pub const TIMER_STATUS: u64 = 0xC;
+pub const TIMER_ENABLE: u8 = 1 << 0;
+//The count restarts from 0 when it reaches the compare value
+pub const TIMER_RELOAD: u8 = 1 << 1;
+pub const TIMER_MATCHED: u8 = 1 << 0;
+/// A 32 bit timer counting cycles. Reaching the compare value sets the status, which is cleared
+/// by reading it, and raises the interrupt line until then.
+///
+/// | Offset | Register                                |
+/// |--------|-----------------------------------------|
+/// | 0x0    | count, little endian                    |
+/// | 0x4    | compare, little endian                  |
+/// | 0x8    | control: enable and reload bits         |
+/// | 0xC    | status: matched, cleared on read        |
+pub struct Timer {
+    count: u32,
+    compare: u32,
+    control: u8,
+    status: u8,
+    interrupt: InterruptLine,
+}
+impl Timer {
+    pub const SIZE: u64 = 0x10;
+    pub fn new(interrupt: InterruptLine) -> Timer {
+        Timer { count: 0, compare: u32::MAX, control: 0, status: 0, interrupt }
+    }
+}
+impl Device for Timer {
+    fn size(&self) -> u64 {
+        Timer::SIZE
+    }
+    fn read(&mut self, offset: u64) -> Result<u8, MemoryError> {
+        let value = self.peek(offset)?;
+        if offset == TIMER_STATUS {
+            self.status = 0;
+            self.interrupt.lower();
+        }
+        Ok(value)
+    }
+    fn peek(&self, offset: u64) -> Result<u8, MemoryError> {
+        let byte = (offset % 4) as usize;
+        match offset & !3 {
+            TIMER_COUNT => Ok(self.count.to_le_bytes()[byte]),
+            TIMER_COMPARE => Ok(self.compare.to_le_bytes()[byte]),
+            TIMER_CONTROL if byte == 0 => Ok(self.control),
+            TIMER_STATUS if byte == 0 => Ok(self.status),
+            TIMER_CONTROL | TIMER_STATUS => Ok(0),
+            _ => Err(MemoryError::OutOfBounds),
+        }
+    }
+    fn write(&mut self, offset: u64, value: u8) -> Result<(), MemoryError> {
+        let set_byte = |register: &mut u32| {
+            let mut bytes = register.to_le_bytes();
+            bytes[(offset % 4) as usize] = value;
+            *register = u32::from_le_bytes(bytes);
+        };
+        match offset {
+            0x0..=0x3 => set_byte(&mut self.count),
+            0x4..=0x7 => set_byte(&mut self.compare),
+            TIMER_CONTROL => self.control = value,
+            0x9..=0xF => (),
+            _ => return Err(MemoryError::OutOfBounds),
+        }
+        Ok(())
+    }
+    fn tick(&mut self, cycles: u64) {
+        if self.control & TIMER_ENABLE == 0 {
+            return;
+        }
+        let until_match = self.compare.wrapping_sub(self.count) as u64;
+        if cycles < until_match {
+            self.count = self.count.wrapping_add(cycles as u32);
+            return;
+        }
+        self.status |= TIMER_MATCHED;
+        self.interrupt.raise();
+        self.count = if self.control & TIMER_RELOAD != 0 && self.compare != 0 {
+            ((cycles - until_match) % self.compare as u64) as u32
+        } else {
+            self.count.wrapping_add(cycles as u32)
+        };
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+
+    #[test]
+    fn timer() {
+        let lines = InterruptLines::new();
+        let mut space = SparseAddressSpace::new(0x1000u16).unwrap();
+        space.add_device(0x100, Box::new(Timer::new(lines.line(3)))).unwrap();
+        space.write_bytes(0x104, &10u32.to_le_bytes()).unwrap();
+        space.write_byte(0x108, TIMER_ENABLE | TIMER_RELOAD).unwrap();
+        space.tick(9);
+        assert_eq!(space.read_byte(0x100), Ok(9));
+        assert_eq!(lines.pending(), 0);
+        space.tick(3);
+        assert_eq!(space.read_byte(0x100), Ok(2));
+        assert_eq!(lines.pending(), 1 << 3);
+        //A debugger looking at the status leaves it set
+        assert_eq!(space.read_byte(0x10C), Ok(TIMER_MATCHED));
+        assert_eq!(space.read_byte_mut(0x10C), Ok(TIMER_MATCHED));
+        assert_eq!(space.read_byte_mut(0x10C), Ok(0));
+        assert_eq!(lines.pending(), 0);
+        assert_eq!(space.write_bytes(0x10E, &[0, 0, 0]), Err(MemoryError::InvalidAccess));
+    }
+}
@@ -0,0 +1,159 @@
+//! Intel HEX, records 00 to 05.
+use super::{hex_bytes, hex_string, Image, ImageError, ImageErrorKind};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Parses an Intel HEX file. Blank lines are skipped and anything after the end of file record
+/// is ignored.
+pub fn parse(text: &str) -> Result<Image, ImageError> {
+    let mut image = Image::default();
+    let mut base = 0u64;
+    for (i, line) in text.lines().enumerate() {
+        let error = |kind| ImageError { line: i + 1, kind };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let digits = line.strip_prefix(':').ok_or_else(|| error(ImageErrorKind::MissingStart))?;
+        let bytes = hex_bytes(digits).ok_or_else(|| error(ImageErrorKind::InvalidHex))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error(ImageErrorKind::Length));
+        }
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)).wrapping_neg();
+        if expected != checksum[0] {
+            return Err(error(ImageErrorKind::Checksum { expected, found: checksum[0] }));
+        }
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u64;
+        let data = &record[4..];
+        let value = || data.iter().fold(0u64, |value, &b| value << 8 | b as u64);
+        let sized = |len: usize| if data.len() == len { Ok(()) } else { Err(error(ImageErrorKind::InvalidRecord)) };
+        match record[3] {
+            DATA => image.insert(base + offset, data),
+            END_OF_FILE => return Ok(image),
+            EXTENDED_SEGMENT_ADDRESS => {
+                sized(2)?;
+                base = value() << 4;
+            },
+            START_SEGMENT_ADDRESS => {
+                sized(4)?;
+                image.entry = Some((value() >> 16 << 4) + (value() & 0xFFFF));
+            },
+            EXTENDED_LINEAR_ADDRESS => {
+                sized(2)?;
+                base = value() << 16;
+            },
+            START_LINEAR_ADDRESS => {
+                sized(4)?;
+                image.entry = Some(value());
+            },
+            kind => return Err(error(ImageErrorKind::UnknownRecord(kind))),
+        }
+    }
+    Err(ImageError { line: text.lines().count(), kind: ImageErrorKind::MissingEnd })
+}
+fn record(out: &mut String, offset: u16, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)).wrapping_neg();
+    bytes.push(checksum);
+    out.push(':');
+    out.push_str(&hex_string(&bytes));
+    out.push('\n');
+}
+/// Writes the image with 16 bytes to a data record, extended linear address records above
+/// 64K and a start linear address record for the entry point. Returns `None` if an address
+/// doesn't fit in 32 bits.
+pub fn write(image: &Image) -> Option<String> {
+    if image.end() > 1 << 32 || image.entry.is_some_and(|entry| entry > u32::MAX as u64) {
+        return None;
+    }
+    let mut out = String::new();
+    let mut upper = 0;
+    for (start, bytes) in image.chunks.iter() {
+        let mut address = *start;
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            if address >> 16 != upper {
+                upper = address >> 16;
+                record(&mut out, 0, EXTENDED_LINEAR_ADDRESS, &(upper as u16).to_be_bytes());
+            }
+            //Records can't cross into the next 64K
+            let len = rest.len().min(16).min((0x10000 - (address & 0xFFFF)) as usize);
+            record(&mut out, address as u16, DATA, &rest[..len]);
+            address += len as u64;
+            rest = &rest[len..];
+        }
+    }
+    if let Some(entry) = image.entry {
+        record(&mut out, 0, START_LINEAR_ADDRESS, &(entry as u32).to_be_bytes());
+    }
+    record(&mut out, 0, END_OF_FILE, &[]);
+    Some(out)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_records() {
+        let text = "\
+:0400100001020304E2
+:020000040001F9
+:02000000AABB99
+:0400000500001000E7
+:00000001FF
+";
+        let image = parse(text).unwrap();
+        assert_eq!(image.chunks, vec![(0x10, vec![1, 2, 3, 4]), (0x10000, vec![0xAA, 0xBB])]);
+        assert_eq!(image.entry, Some(0x1000));
+        let segmented = parse(":020000021000EC\n:0100040055A6\n:0400000312340010A3\n:00000001FF").unwrap();
+        assert_eq!(segmented.chunks, vec![(0x10004, vec![0x55])]);
+        assert_eq!(segmented.entry, Some(0x12350));
+    }
+    #[test]
+    fn reports_the_line() {
+        let error = |text: &str| parse(text).unwrap_err();
+        assert_eq!(error("\n:0400100001020304E3\n"), ImageError {
+            line: 2,
+            kind: ImageErrorKind::Checksum { expected: 0xE2, found: 0xE3 },
+        });
+        assert_eq!(error("0400100001020304E2").kind, ImageErrorKind::MissingStart);
+        assert_eq!(error(":0400100001020304").kind, ImageErrorKind::Length);
+        assert_eq!(error(":04001G0001020304E2").kind, ImageErrorKind::InvalidHex);
+        assert_eq!(error(":00000006FA").kind, ImageErrorKind::UnknownRecord(6));
+        assert_eq!(error(":0100000400FB").kind, ImageErrorKind::InvalidRecord);
+        assert_eq!(error(":0100000055AA\n:0100010055A9").kind, ImageErrorKind::MissingEnd);
+    }
+    #[test]
+    fn writes_records() {
+        //objcopy -I binary -O ihex --change-addresses 0x1fff8
+        let objcopy = "\
+:020000021000EC
+:08FFF80048656C6C6F2C20774A
+:020000022000DC
+:100000006F726C6421000102030405060708090AE7
+:040000031000FFF8F2
+:00000001FF
+";
+        let image = parse(objcopy).unwrap();
+        assert_eq!(image.to_raw(0).1, b"Hello, world!\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a");
+        assert_eq!(write(&image).unwrap(), "\
+:020000040001F9
+:08FFF80048656C6C6F2C20774A
+:020000040002F8
+:100000006F726C6421000102030405060708090AE7
+:040000050001FFF8FF
+:00000001FF
+");
+        assert_eq!(parse(&write(&image).unwrap()).unwrap(), image);
+        assert_eq!(write(&Image::from_raw(u32::MAX as u64, &[1, 2])), None);
+    }
+}
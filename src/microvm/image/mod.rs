@@ -0,0 +1,157 @@
+//! Memory images from firmware file formats, as runs of bytes at addresses.
+pub mod ihex;
+pub mod srec;
+
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::MemoryError;
+use std::fmt;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Image {
+    /// Contiguous runs of bytes sorted by address, adjacent records are merged.
+    pub chunks: Vec<(u64, Vec<u8>)>,
+    /// The start address, if the file gives one.
+    pub entry: Option<u64>,
+}
+impl Image {
+    /// Adds `data` at `address`, extending the run it continues.
+    pub fn insert(&mut self, address: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let position = self.chunks.partition_point(|(start, _)| *start <= address);
+        if position > 0 {
+            let (start, bytes) = &mut self.chunks[position - 1];
+            if *start + bytes.len() as u64 == address {
+                bytes.extend_from_slice(data);
+                return;
+            }
+        }
+        self.chunks.insert(position, (address, data.to_vec()));
+    }
+    /// A raw binary at `address`, without an entry point.
+    pub fn from_raw(address: u64, bytes: &[u8]) -> Image {
+        let mut image = Image::default();
+        image.insert(address, bytes);
+        image
+    }
+    /// The bytes from the lowest address to the end of the highest chunk with the gaps filled
+    /// with `fill`, and the address they start at.
+    pub fn to_raw(&self, fill: u8) -> (u64, Vec<u8>) {
+        let start = self.chunks.first().map_or(0, |(start, _)| *start);
+        let mut raw = Vec::new();
+        for (address, bytes) in self.chunks.iter() {
+            raw.resize((address - start) as usize, fill);
+            raw.extend_from_slice(bytes);
+        }
+        (start, raw)
+    }
+    /// Reads `len` bytes from `address` in `space`, leaving out the ones that aren't mapped.
+    pub fn read_from<Address: AddressType>(space: &dyn AddressSpace<Address>, address: u64, len: u64) -> Image {
+        let mut image = Image::default();
+        for address in address..address.saturating_add(len) {
+            if let Some(byte) = Address::from_u64(address).and_then(|a| space.read_byte(a).ok()) {
+                image.insert(address, &[byte]);
+            }
+        }
+        image
+    }
+    /// The end of the highest chunk.
+    fn end(&self) -> u64 {
+        self.chunks.last().map_or(0, |(start, bytes)| start + bytes.len() as u64)
+    }
+    /// Writes every chunk into `space`. Chunks may span several regions of a sparse space.
+    pub fn write_to<Address: AddressType>(&self, space: &mut dyn AddressSpace<Address>) -> Result<(), MemoryError> {
+        for (start, bytes) in self.chunks.iter() {
+            for (address, &byte) in (*start..).zip(bytes) {
+                space.write_byte(Address::from_u64(address).ok_or(MemoryError::OutOfBounds)?, byte)?;
+            }
+        }
+        Ok(())
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ImageErrorKind {
+    /// A record that doesn't start with the format's start character.
+    MissingStart,
+    InvalidHex,
+    /// The record is shorter or longer than its byte count says.
+    Length,
+    Checksum { expected: u8, found: u8 },
+    UnknownRecord(u8),
+    /// A record type's fields don't have the size the format requires.
+    InvalidRecord,
+    /// No end of file record.
+    MissingEnd,
+    /// A count record that doesn't match the number of data records before it.
+    RecordCount { expected: u64, found: u64 },
+}
+/// An error in a text image, with the 1-based line it was found on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImageError {
+    pub line: usize,
+    pub kind: ImageErrorKind,
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ImageErrorKind::MissingStart => write!(f, "record doesn't start with its start code"),
+            ImageErrorKind::InvalidHex => write!(f, "invalid hex digits"),
+            ImageErrorKind::Length => write!(f, "record length doesn't match its byte count"),
+            ImageErrorKind::Checksum { expected, found } => write!(f, "checksum is {:02X}, expected {:02X}", found, expected),
+            ImageErrorKind::UnknownRecord(kind) => write!(f, "unknown record type {:02X}", kind),
+            ImageErrorKind::InvalidRecord => write!(f, "record has the wrong size for its type"),
+            ImageErrorKind::MissingEnd => write!(f, "no end of file record"),
+            ImageErrorKind::RecordCount { expected, found } => write!(f, "record count is {}, expected {}", found, expected),
+        }
+    }
+}
+//Hex byte pairs of a record after its start code
+pub(crate) fn hex_bytes(digits: &str) -> Option<Vec<u8>> {
+    if !digits.is_ascii() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect()
+}
+pub(crate) fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+
+    #[test]
+    fn merges_and_writes_across_regions() {
+        let mut image = Image::default();
+        image.insert(0x14, &[3]);
+        image.insert(0x10, &[1, 2]);
+        image.insert(0x12, &[5, 6]);
+        assert_eq!(image.chunks, vec![(0x10, vec![1, 2, 5, 6]), (0x14, vec![3])]);
+        let mut space = SparseAddressSpace::new(0x40u16).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x12u16))).unwrap();
+        space.add_space(0x12, Box::new(DenseStaticMemory::new(0x10u16))).unwrap();
+        image.write_to(&mut space).unwrap();
+        assert_eq!((space.read_byte(0x11), space.read_byte(0x12), space.read_byte(0x14)), (Ok(2), Ok(5), Ok(3)));
+        image.insert(0x30, &[1]);
+        assert!(image.write_to(&mut space).is_err());
+    }
+    #[test]
+    fn raw_and_read_back() {
+        let mut image = Image::from_raw(2, &[1, 2]);
+        image.insert(6, &[3]);
+        assert_eq!(image.to_raw(0xFF), (2, vec![1, 2, 0xFF, 0xFF, 3]));
+        assert_eq!(Image::default().to_raw(0), (0, vec![]));
+        let mut space = SparseAddressSpace::new(0x40u16).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x8u16))).unwrap();
+        space.add_space(0x10, Box::new(DenseStaticMemory::new(0x8u16))).unwrap();
+        image.write_to(&mut space).unwrap();
+        space.write_byte(0x10, 9).unwrap();
+        let read = Image::read_from(&space, 4, 0x10);
+        assert_eq!(read.chunks, vec![(4, vec![0, 0, 3, 0]), (0x10, vec![9, 0, 0, 0])]);
+        assert_eq!(read.end(), 0x14);
+    }
+}
@@ -0,0 +1,137 @@
+//! Motorola S-records, S0 to S9.
+use super::{hex_bytes, hex_string, Image, ImageError, ImageErrorKind};
+
+//The address size of each record type, S4 is reserved
+const ADDRESS_SIZES: [usize; 10] = [2, 2, 3, 4, 0, 2, 3, 4, 3, 2];
+
+/// Parses an S-record file. The S0 header is checked but otherwise ignored, count records
+/// must match the data records before them and the file ends at the S7, S8 or S9 record.
+pub fn parse(text: &str) -> Result<Image, ImageError> {
+    let mut image = Image::default();
+    let mut data_records = 0u64;
+    for (i, line) in text.lines().enumerate() {
+        let error = |kind| ImageError { line: i + 1, kind };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let rest = line.strip_prefix('S').ok_or_else(|| error(ImageErrorKind::MissingStart))?;
+        let kind = rest.chars().next().and_then(|c| c.to_digit(10)).ok_or_else(|| error(ImageErrorKind::InvalidHex))? as u8;
+        let bytes = hex_bytes(&rest[1..]).ok_or_else(|| error(ImageErrorKind::InvalidHex))?;
+        let address_size = ADDRESS_SIZES[kind as usize];
+        if address_size == 0 {
+            return Err(error(ImageErrorKind::UnknownRecord(kind)));
+        }
+        if bytes.len() < address_size + 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error(ImageErrorKind::Length));
+        }
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = !record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if expected != checksum[0] {
+            return Err(error(ImageErrorKind::Checksum { expected, found: checksum[0] }));
+        }
+        let address = record[1..=address_size].iter().fold(0u64, |value, &b| value << 8 | b as u64);
+        let data = &record[address_size + 1..];
+        match kind {
+            0 => (),
+            1..=3 => {
+                image.insert(address, data);
+                data_records += 1;
+            },
+            5 | 6 if !data.is_empty() => return Err(error(ImageErrorKind::InvalidRecord)),
+            5 | 6 if address != data_records => {
+                return Err(error(ImageErrorKind::RecordCount { expected: data_records, found: address }));
+            },
+            5 | 6 => (),
+            _ if !data.is_empty() => return Err(error(ImageErrorKind::InvalidRecord)),
+            _ => {
+                image.entry = Some(address);
+                return Ok(image);
+            },
+        }
+    }
+    Err(ImageError { line: text.lines().count(), kind: ImageErrorKind::MissingEnd })
+}
+fn record(out: &mut String, kind: usize, address: u64, data: &[u8]) {
+    let address_size = ADDRESS_SIZES[kind];
+    let mut bytes = vec![(address_size + data.len() + 1) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes()[8 - address_size..]);
+    bytes.extend_from_slice(data);
+    bytes.push(!bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+    out.push_str(&format!("S{}{}\n", kind, hex_string(&bytes)));
+}
+/// Writes the image with an empty S0 header, 16 bytes to a data record and the smallest
+/// address size that fits, then a count record and the entry point, 0 without one. Returns
+/// `None` if an address doesn't fit in 32 bits.
+pub fn write(image: &Image) -> Option<String> {
+    let highest = (image.end().max(1) - 1).max(image.entry.unwrap_or(0));
+    let data = match highest {
+        0..=0xFFFF => 1,
+        0x1_0000..=0xFF_FFFF => 2,
+        0x100_0000..=0xFFFF_FFFF => 3,
+        _ => return None,
+    };
+    let mut out = String::new();
+    record(&mut out, 0, 0, &[]);
+    let mut count = 0u64;
+    for (start, bytes) in image.chunks.iter() {
+        for (i, data_bytes) in bytes.chunks(16).enumerate() {
+            record(&mut out, data, start + 16 * i as u64, data_bytes);
+            count += 1;
+        }
+    }
+    match count {
+        0..=0xFFFF => record(&mut out, 5, count, &[]),
+        0x1_0000..=0xFF_FFFF => record(&mut out, 6, count, &[]),
+        _ => (),
+    }
+    //S7 ends S3 files, S8 S2 and S9 S1
+    record(&mut out, 10 - data, image.entry.unwrap_or(0), &[]);
+    Some(out)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_writes() {
+        //objcopy -I binary -O srec --change-addresses 0x1000
+        let objcopy = "\
+S0090000622E73726563B9
+S113100048656C6C6F2C20776F726C642100010250
+S10B1010030405060708090AA0
+S9031000EC
+";
+        let image = parse(objcopy).unwrap();
+        assert_eq!(image.chunks, vec![(0x1000, b"Hello, world!\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a".to_vec())]);
+        assert_eq!(image.entry, Some(0x1000));
+        let written = write(&image).unwrap();
+        assert_eq!(written, "\
+S0030000FC
+S113100048656C6C6F2C20776F726C642100010250
+S10B1010030405060708090AA0
+S5030002FA
+S9031000EC
+");
+        assert_eq!(parse(&written).unwrap(), image);
+        let wide = Image::from_raw(0x12_3456, &[0xAB]);
+        assert_eq!(write(&wide).unwrap().lines().nth(1), Some("S205123456ABB3"));
+        assert_eq!(parse(&write(&wide).unwrap()).unwrap().chunks, wide.chunks);
+        assert!(write(&Image::from_raw(0xFFFF_FFFF, &[1])).unwrap().ends_with("S70500000000FA\n"));
+        assert_eq!(write(&Image::from_raw(0x1_0000_0000, &[1])), None);
+    }
+    #[test]
+    fn reports_the_line() {
+        let error = |text: &str| parse(text).unwrap_err();
+        assert_eq!(error("S0030000FC\nS10B1010030405060708090AA1\n"), ImageError {
+            line: 2,
+            kind: ImageErrorKind::Checksum { expected: 0xA0, found: 0xA1 },
+        });
+        assert_eq!(error(":0300").kind, ImageErrorKind::MissingStart);
+        assert_eq!(error("S4030000FC").kind, ImageErrorKind::UnknownRecord(4));
+        assert_eq!(error("S10B1010").kind, ImageErrorKind::Length);
+        assert_eq!(error("SX030000FC").kind, ImageErrorKind::InvalidHex);
+        assert_eq!(error("S5030005F7").kind, ImageErrorKind::RecordCount { expected: 0, found: 5 });
+        assert_eq!(error("S104100001EA").kind, ImageErrorKind::MissingEnd);
+    }
+}
@@ -0,0 +1,411 @@
+//! ELF32/ELF64 little endian executable loader.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+use crate::microvm::memory::rom::ROM;
+use crate::microvm::memory::sparse::SparseAddressSpace;
+use crate::microvm::memory::zero::ZerosSpace;
+use crate::microvm::memory::MemoryError;
+use std::fmt;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 1 << 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ElfClass {
+    Elf32,
+    Elf64,
+}
+/// The core an image is loaded for, which fixes the expected `e_machine` and class.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Machine {
+    RiscV32,
+    RiscV64,
+    Mos6502,
+}
+impl Machine {
+    pub fn e_machine(self) -> u16 {
+        match self {
+            Machine::RiscV32 | Machine::RiscV64 => 243,
+            Machine::Mos6502 => 6502,
+        }
+    }
+    pub fn class(self) -> ElfClass {
+        if self == Machine::RiscV64 { ElfClass::Elf64 } else { ElfClass::Elf32 }
+    }
+}
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LoaderError {
+    NotElf,
+    Truncated,
+    BigEndian,
+    NotExecutable(u16),
+    WrongClass { expected: ElfClass, found: ElfClass },
+    WrongMachine { expected: u16, found: u16 },
+    //Segment index whose file size is larger than its memory size
+    InvalidSegment(usize),
+    //Segment index that doesn't fit in the address space
+    AddressOutOfRange(usize),
+    Memory(MemoryError),
+}
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoaderError::NotElf => write!(f, "not an ELF file"),
+            LoaderError::Truncated => write!(f, "ELF file is truncated"),
+            LoaderError::BigEndian => write!(f, "big endian ELF files aren't supported"),
+            LoaderError::NotExecutable(e_type) => write!(f, "ELF type {} is not an executable", e_type),
+            LoaderError::WrongClass { expected, found } => write!(f, "expected an {:?} file, found {:?}", expected, found),
+            LoaderError::WrongMachine { expected, found } =>
+                write!(f, "ELF machine {} doesn't match the core (expected {})", found, expected),
+            LoaderError::InvalidSegment(i) => write!(f, "segment {} has a file size larger than its memory size", i),
+            LoaderError::AddressOutOfRange(i) => write!(f, "segment {} doesn't fit in the address space", i),
+            LoaderError::Memory(e) => write!(f, "mapping a segment failed: {:?}", e),
+        }
+    }
+}
+impl From<MemoryError> for LoaderError {
+    fn from(e: MemoryError) -> Self {
+        LoaderError::Memory(e)
+    }
+}
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+}
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    pub address: u64,
+    pub data: Vec<u8>,
+    //Memory size, the bytes past `data` are zero filled
+    pub size: u64,
+    pub writable: bool,
+}
+/// A parsed executable: its loadable segments, entry point and symbol table.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ElfImage {
+    pub class: ElfClass,
+    pub machine: u16,
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+    /// Where the program header table ends up in memory, if a segment loads it.
+    pub program_headers: Option<u64>,
+    pub program_header_size: u64,
+    pub program_header_count: u64,
+}
+impl ElfImage {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+struct Reader<'a> {
+    bytes: &'a [u8],
+    class: ElfClass,
+}
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: u64, len: u64) -> Result<&'a [u8], LoaderError> {
+        let end = offset.checked_add(len).ok_or(LoaderError::Truncated)?;
+        self.bytes.get(offset as usize..end as usize).ok_or(LoaderError::Truncated)
+    }
+    fn uint(&self, offset: u64, len: usize) -> Result<u64, LoaderError> {
+        let mut out = [0u8; 8];
+        out[..len].copy_from_slice(self.slice(offset, len as u64)?);
+        Ok(u64::from_le_bytes(out))
+    }
+    fn u8(&self, offset: u64) -> Result<u8, LoaderError> {
+        Ok(self.uint(offset, 1)? as u8)
+    }
+    fn u16(&self, offset: u64) -> Result<u16, LoaderError> {
+        Ok(self.uint(offset, 2)? as u16)
+    }
+    fn u32(&self, offset: u64) -> Result<u32, LoaderError> {
+        Ok(self.uint(offset, 4)? as u32)
+    }
+    //Address and offset sized field
+    fn word(&self, offset: u64) -> Result<u64, LoaderError> {
+        match self.class {
+            ElfClass::Elf32 => self.uint(offset, 4),
+            ElfClass::Elf64 => self.uint(offset, 8),
+        }
+    }
+    fn is_64(&self) -> bool {
+        self.class == ElfClass::Elf64
+    }
+    fn string(&self, offset: u64) -> Result<String, LoaderError> {
+        let rest = self.bytes.get(offset as usize..).ok_or(LoaderError::Truncated)?;
+        let end = rest.iter().position(|&b| b == 0).ok_or(LoaderError::Truncated)?;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+/// Parses an executable for `machine` without mapping it anywhere.
+pub fn parse(bytes: &[u8], machine: Machine) -> Result<ElfImage, LoaderError> {
+    if bytes.len() < 16 || bytes[..4] != [0x7F, b'E', b'L', b'F'] {
+        return Err(LoaderError::NotElf);
+    }
+    let class = match bytes[4] {
+        1 => ElfClass::Elf32,
+        2 => ElfClass::Elf64,
+        _ => return Err(LoaderError::NotElf),
+    };
+    if bytes[5] != 1 {
+        return Err(LoaderError::BigEndian);
+    }
+    if class != machine.class() {
+        return Err(LoaderError::WrongClass { expected: machine.class(), found: class });
+    }
+    let r = Reader { bytes, class };
+    let e_type = r.u16(16)?;
+    if e_type != ET_EXEC {
+        return Err(LoaderError::NotExecutable(e_type));
+    }
+    let e_machine = r.u16(18)?;
+    if e_machine != machine.e_machine() {
+        return Err(LoaderError::WrongMachine { expected: machine.e_machine(), found: e_machine });
+    }
+    let entry = r.word(24)?;
+    let (phoff, shoff) = if r.is_64() { (r.word(32)?, r.word(40)?) } else { (r.word(28)?, r.word(32)?) };
+    let header = if r.is_64() { 54 } else { 42 };
+    let (phentsize, phnum) = (r.u16(header)? as u64, r.u16(header + 2)? as u64);
+    let (shentsize, shnum) = (r.u16(header + 4)? as u64, r.u16(header + 6)? as u64);
+
+    let mut segments = Vec::new();
+    let mut program_headers = None;
+    //Offsets and sizes come from the file, so anything past the end of it is `Truncated`
+    //rather than overflowing. Once an entry is known to be in the file its fields are too
+    for i in 0..phnum {
+        let ph = phoff.checked_add(i * phentsize).ok_or(LoaderError::Truncated)?;
+        r.slice(ph, phentsize)?;
+        if r.u32(ph)? != PT_LOAD {
+            continue;
+        }
+        let (flags, offset, paddr, filesz, memsz) = if r.is_64() {
+            (r.u32(ph + 4)?, r.word(ph + 8)?, r.word(ph + 24)?, r.word(ph + 32)?, r.word(ph + 40)?)
+        } else {
+            (r.u32(ph + 24)?, r.word(ph + 4)?, r.word(ph + 12)?, r.word(ph + 16)?, r.word(ph + 20)?)
+        };
+        if filesz > memsz {
+            return Err(LoaderError::InvalidSegment(i as usize));
+        }
+        if memsz == 0 {
+            continue;
+        }
+        let data = r.slice(offset, filesz)?;
+        if offset <= phoff && phoff.checked_add(phentsize * phnum).is_some_and(|end| end <= offset + filesz) {
+            program_headers = paddr.checked_add(phoff - offset);
+        }
+        segments.push(Segment {
+            address: paddr,
+            data: data.to_vec(),
+            size: memsz,
+            writable: flags & PF_W != 0,
+        });
+    }
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let sh = shoff.checked_add(i * shentsize).ok_or(LoaderError::Truncated)?;
+        r.slice(sh, shentsize)?;
+        if r.u32(sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let (offset, size, link, entsize) = if r.is_64() {
+            (r.word(sh + 24)?, r.word(sh + 32)?, r.u32(sh + 40)? as u64, r.word(sh + 56)?)
+        } else {
+            (r.word(sh + 16)?, r.word(sh + 20)?, r.u32(sh + 24)? as u64, r.word(sh + 36)?)
+        };
+        let strtab = shoff.checked_add(link * shentsize).ok_or(LoaderError::Truncated)?;
+        r.slice(strtab, shentsize)?;
+        let strings = r.word(strtab + if r.is_64() { 24 } else { 16 })?;
+        let entsize = if entsize == 0 { if r.is_64() { 24 } else { 16 } } else { entsize };
+        r.slice(offset, size)?;
+        for sym in (offset..offset + size).step_by(entsize as usize) {
+            let (name, info, value, size) = if r.is_64() {
+                (r.u32(sym)?, r.u8(sym + 4)?, r.word(sym + 8)?, r.word(sym + 16)?)
+            } else {
+                (r.u32(sym)?, r.u8(sym + 12)?, r.word(sym + 4)?, r.word(sym + 8)?)
+            };
+            if name == 0 || matches!(info & 0xF, STT_SECTION | STT_FILE) {
+                continue;
+            }
+            let name = strings.checked_add(name as u64).ok_or(LoaderError::Truncated)?;
+            symbols.push(Symbol { name: r.string(name)?, value, size });
+        }
+    }
+    Ok(ElfImage {
+        class,
+        machine: e_machine,
+        entry,
+        segments,
+        symbols,
+        program_headers,
+        program_header_size: phentsize,
+        program_header_count: phnum,
+    })
+}
+/// Parses an executable and maps each PT_LOAD segment at its physical address. Read-only
+/// segments become `ROM` (or `ZerosSpace` if they have no file contents) and writable ones RAM
+/// with their .bss zero filled.
+pub fn load<Address: AddressType>(bytes: &[u8], machine: Machine, space: &mut SparseAddressSpace<Address>) -> Result<ElfImage, LoaderError> {
+    let image = parse(bytes, machine)?;
+    for (i, segment) in image.segments.iter().enumerate() {
+        let offset = Address::from_u64(segment.address).ok_or(LoaderError::AddressOutOfRange(i))?;
+        let size = Address::from_u64(segment.size).ok_or(LoaderError::AddressOutOfRange(i))?;
+        segment.address.checked_add(segment.size - 1).and_then(Address::from_u64).ok_or(LoaderError::AddressOutOfRange(i))?;
+        let region: Box<dyn AddressSpace<Address>> = if segment.writable {
+            let mut ram = DenseStaticMemory::new(size);
+            ram.as_mut_slice()[..segment.data.len()].copy_from_slice(&segment.data);
+            Box::new(ram)
+        } else if segment.data.is_empty() {
+            Box::new(ZerosSpace::new(segment.size))
+        } else {
+            let mut rom = ROM::new(size);
+            rom.get_mut().as_mut_slice()[..segment.data.len()].copy_from_slice(&segment.data);
+            Box::new(rom)
+        };
+        space.add_space(offset, region)?;
+    }
+    Ok(image)
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    //Minimal ELF32 executable: a read-only text segment, a data segment with .bss and a
+    //symbol table with `_start` and `tohost`
+    pub fn elf32(machine: u16, text_address: u32, text: &[u8], data_address: u32, data: &[u8], bss: u32) -> Vec<u8> {
+        let mut out = vec![0u8; 52];
+        out[..7].copy_from_slice(&[0x7F, b'E', b'L', b'F', 1, 1, 1]);
+        let put16 = |out: &mut Vec<u8>, at: usize, v: u16| out[at..at + 2].copy_from_slice(&v.to_le_bytes());
+        let put32 = |out: &mut Vec<u8>, at: usize, v: u32| out[at..at + 4].copy_from_slice(&v.to_le_bytes());
+        put16(&mut out, 16, ET_EXEC);
+        put16(&mut out, 18, machine);
+        put32(&mut out, 20, 1);
+        put32(&mut out, 24, text_address);
+        put32(&mut out, 28, 52);
+        put16(&mut out, 40, 52);
+        put16(&mut out, 42, 32);
+        put16(&mut out, 44, 2);
+        put16(&mut out, 46, 40);
+        let contents = 52 + 2 * 32;
+        let strtab = b"\0_start\0tohost\0";
+        let symtab_offset = contents + text.len() + data.len();
+        let strtab_offset = symtab_offset + 48;
+        let shoff = strtab_offset + strtab.len();
+        for (i, &(offset, address, filesz, memsz, flags)) in [
+            (contents, text_address, text.len(), text.len(), 5),
+            (contents + text.len(), data_address, data.len(), data.len() + bss as usize, 6),
+        ].iter().enumerate() {
+            let ph = 52 + i * 32;
+            out.resize(ph + 32, 0);
+            put32(&mut out, ph, PT_LOAD);
+            put32(&mut out, ph + 4, offset as u32);
+            put32(&mut out, ph + 8, address);
+            put32(&mut out, ph + 12, address);
+            put32(&mut out, ph + 16, filesz as u32);
+            put32(&mut out, ph + 20, memsz as u32);
+            put32(&mut out, ph + 24, flags);
+        }
+        out.extend_from_slice(text);
+        out.extend_from_slice(data);
+        let mut symbols = vec![0u8; 16];
+        for &(name, value) in [(1u32, text_address), (8, data_address)].iter() {
+            let mut sym = vec![0u8; 16];
+            sym[0..4].copy_from_slice(&name.to_le_bytes());
+            sym[4..8].copy_from_slice(&value.to_le_bytes());
+            sym[12] = 0x10;
+            symbols.extend(sym);
+        }
+        out.extend(symbols);
+        out.extend_from_slice(strtab);
+        put32(&mut out, 32, shoff as u32);
+        put16(&mut out, 48, 3);
+        //Null section, symbol table linked to the string table at index 2
+        out.resize(shoff + 3 * 40, 0);
+        let sh = shoff + 40;
+        put32(&mut out, sh + 4, SHT_SYMTAB);
+        put32(&mut out, sh + 16, symtab_offset as u32);
+        put32(&mut out, sh + 20, 48);
+        put32(&mut out, sh + 24, 2);
+        put32(&mut out, sh + 36, 16);
+        let sh = shoff + 80;
+        put32(&mut out, sh + 4, 3);
+        put32(&mut out, sh + 16, strtab_offset as u32);
+        put32(&mut out, sh + 20, strtab.len() as u32);
+        out
+    }
+
+    #[test]
+    fn loads_segments_and_symbols() {
+        let elf = elf32(243, 0x100, &[0x13, 0, 0, 0], 0x200, &[1, 2], 6);
+        let mut space = SparseAddressSpace::new(0x1000u16).unwrap();
+        let image = load(&elf, Machine::RiscV32, &mut space).unwrap();
+        assert_eq!(image.entry, 0x100);
+        assert_eq!(image.symbol("tohost").map(|s| s.value), Some(0x200));
+        assert_eq!(image.symbols.len(), 2);
+        assert_eq!(space.read_byte(0x100), Ok(0x13));
+        assert_eq!(space.write_byte(0x100, 0), Err(MemoryError::ReadOnly));
+        assert_eq!((space.read_byte(0x201), space.read_byte(0x207)), (Ok(2), Ok(0)));
+        space.write_byte(0x207, 9).unwrap();
+        assert!(space.read_byte(0x208).is_err());
+    }
+    #[test]
+    fn rejects_mismatched_files() {
+        let elf = elf32(243, 0x100, &[0; 4], 0x200, &[], 0);
+        let mut space = SparseAddressSpace::new(0x1000u16).unwrap();
+        assert_eq!(load(&elf, Machine::Mos6502, &mut space), Err(LoaderError::WrongMachine { expected: 6502, found: 243 }));
+        assert_eq!(load(&elf, Machine::RiscV64, &mut space),
+                   Err(LoaderError::WrongClass { expected: ElfClass::Elf64, found: ElfClass::Elf32 }));
+        assert_eq!(load(&elf[..40], Machine::RiscV32, &mut space), Err(LoaderError::Truncated));
+        assert_eq!(load(b"#!/bin/sh", Machine::RiscV32, &mut space), Err(LoaderError::NotElf));
+        let far = elf32(243, 0x100, &[0; 4], 0xF000, &[0; 4], 0x2000);
+        assert_eq!(load(&far, Machine::RiscV32, &mut space), Err(LoaderError::AddressOutOfRange(1)));
+    }
+    #[test]
+    fn rejects_huge_offsets_and_sizes() {
+        //An ELF64 header with one program header and two section headers after it
+        let header = |phoff: u64, shoff: u64| {
+            let mut out = vec![0u8; 64 + 56 + 2 * 64];
+            out[..7].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1]);
+            out[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+            out[18..20].copy_from_slice(&Machine::RiscV64.e_machine().to_le_bytes());
+            out[32..40].copy_from_slice(&phoff.to_le_bytes());
+            out[40..48].copy_from_slice(&shoff.to_le_bytes());
+            for (at, value) in [(54, 56u16), (56, 1), (58, 64), (60, 2)].iter() {
+                out[*at..*at + 2].copy_from_slice(&value.to_le_bytes());
+            }
+            out
+        };
+        let put64 = |out: &mut Vec<u8>, at: usize, value: u64| out[at..at + 8].copy_from_slice(&value.to_le_bytes());
+        assert_eq!(parse(&header(u64::MAX - 2, 0), Machine::RiscV64), Err(LoaderError::Truncated));
+        assert_eq!(parse(&header(0, u64::MAX - 2), Machine::RiscV64), Err(LoaderError::Truncated));
+
+        //A segment reaching past the end of a 64 bit space
+        let mut elf = header(64, 0);
+        elf[64..68].copy_from_slice(&PT_LOAD.to_le_bytes());
+        put64(&mut elf, 64 + 8, 1);
+        put64(&mut elf, 64 + 32, u64::MAX);
+        put64(&mut elf, 64 + 40, u64::MAX);
+        assert_eq!(parse(&elf, Machine::RiscV64), Err(LoaderError::Truncated));
+
+        //A symbol table with a huge size, then one whose names are past the end of the file
+        let mut elf = header(0, 120);
+        let sh = 120 + 64;
+        elf[sh + 4..sh + 8].copy_from_slice(&SHT_SYMTAB.to_le_bytes());
+        put64(&mut elf, sh + 24, 8);
+        put64(&mut elf, sh + 32, u64::MAX);
+        elf[sh + 40..sh + 44].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(parse(&elf, Machine::RiscV64), Err(LoaderError::Truncated));
+        put64(&mut elf, sh + 24, 64);
+        put64(&mut elf, sh + 32, 24);
+        elf[sh + 40..sh + 44].copy_from_slice(&0u32.to_le_bytes());
+        put64(&mut elf, 120 + 24, u64::MAX);
+        elf[64..68].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(parse(&elf, Machine::RiscV64), Err(LoaderError::Truncated));
+    }
+}
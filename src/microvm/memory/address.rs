@@ -1,5 +1,14 @@
-pub trait AddressType: Copy + ::num::Unsigned + Into<usize> + Clone + Ord + Sized + PartialEq + PartialOrd +
-::num::traits::FromPrimitive + ::num::traits::CheckedAdd + ::num::traits::CheckedSub {}
-impl AddressType for u16 {}
-impl AddressType for u8 {}
-
+pub trait AddressType: Copy + ::num::Unsigned + Clone + Ord + Sized + PartialEq + PartialOrd + ::std::fmt::Debug +
+::num::traits::FromPrimitive + ::num::traits::ToPrimitive + ::num::traits::Bounded + ::num::traits::CheckedAdd + ::num::traits::CheckedSub {
+    fn as_u64(self) -> u64;
+}
+macro_rules! address_impl {
+    ($($t:ty),*) => ($(
+        impl AddressType for $t {
+            fn as_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    )*)
+}
+address_impl!(u8, u16, u32, u64);
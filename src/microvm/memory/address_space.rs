@@ -1,75 +1,242 @@
 use std;
 use std::ops::{Range, Deref, DerefMut, Add};
 use crate::microvm::memory::MemoryError;
+use crate::microvm::memory::permissions::Access;
 use crate::microvm::memory::address::*;
 use crate::microvm::vm::VMError;
+use crate::microvm::bits::Endianness;
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 
 pub trait AddressSpace<Address: AddressType>{
-    fn size(&self) -> Address;
+    /// The number of addresses in the space. A 64 bit space covering every address is one
+    /// more than fits, so it saturates at `u64::MAX`; `max_address` is exact.
+    fn size(&self) -> u64;
+    /// The highest address in the space, `None` if it's empty.
+    fn max_address(&self) -> Option<u64> {
+        self.size().checked_sub(1)
+    }
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError>;
-    fn write_bytes(&mut self, addr: Address, _bytes: &[u8]) -> Result<(), MemoryError> {
+    /// A read made by the machine rather than a debugger, which a device may act on.
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        self.read_byte(address)
+    }
+    fn write_bytes(&mut self, _addr: Address, _bytes: &[u8]) -> Result<(), MemoryError> {
         Err(MemoryError::ReadOnly)
     }
     fn write_byte(&mut self, addr: Address, byte: u8) -> Result<(), MemoryError> {
         self.write_bytes(addr, &[byte])
     }
+    /// Fills `out` with the bytes from `address` on, leaving devices alone.
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.read_byte(offset_address(address, i)?)?;
+        }
+        Ok(())
+    }
+    /// `read_bytes` made by the machine, see `read_byte_mut`.
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.read_byte_mut(offset_address(address, i)?)?;
+        }
+        Ok(())
+    }
+    /// A fetch or load by a core, failing with an `AccessFault` or `PermissionFault`. Spaces
+    /// holding regions check the regions' permissions.
+    fn read_bytes_checked(&mut self, address: Address, out: &mut [u8], access: Access) -> Result<(), MemoryError> {
+        self.read_bytes_mut(address, out).map_err(|e| e.fault(address.as_u64(), access.kind))
+    }
+    /// A store by a core, see `read_bytes_checked`.
+    fn write_bytes_checked(&mut self, address: Address, bytes: &[u8], access: Access) -> Result<(), MemoryError> {
+        self.write_bytes(address, bytes).map_err(|e| e.fault(address.as_u64(), access.kind))
+    }
+    fn read_u16(&self, address: Address, endianness: Endianness) -> Result<u16, MemoryError> {
+        read_value(self, address, 2, endianness).map(|value| value as u16)
+    }
+    fn read_u32(&self, address: Address, endianness: Endianness) -> Result<u32, MemoryError> {
+        read_value(self, address, 4, endianness).map(|value| value as u32)
+    }
+    fn read_u64(&self, address: Address, endianness: Endianness) -> Result<u64, MemoryError> {
+        read_value(self, address, 8, endianness)
+    }
+    fn write_u16(&mut self, address: Address, value: u16, endianness: Endianness) -> Result<(), MemoryError> {
+        write_value(self, address, 2, value.into(), endianness)
+    }
+    fn write_u32(&mut self, address: Address, value: u32, endianness: Endianness) -> Result<(), MemoryError> {
+        write_value(self, address, 4, value.into(), endianness)
+    }
+    fn write_u64(&mut self, address: Address, value: u64, endianness: Endianness) -> Result<(), MemoryError> {
+        write_value(self, address, 8, value, endianness)
+    }
     fn address_in_space(&self, address: Address) -> bool {
-        address < self.size()
+        self.max_address().is_some_and(|max| address.as_u64() <= max)
     }
+    /// All of the space's bytes when it's plain memory, which reads without side effects, so a
+    /// space holding it can skip the calls through it.
+    fn memory(&self) -> Option<&[u8]> {
+        None
+    }
+    /// `memory` for writing, for plain memory that can be written.
+    fn memory_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    /// Advances anything time-aware in the space by `cycles`.
+    fn tick(&mut self, _cycles: u64) {}
+}
+/// `address` moved up by `offset` bytes.
+pub fn offset_address<Address: AddressType>(address: Address, offset: usize) -> Result<Address, MemoryError> {
+    address.checked_add(&Address::from_usize(offset).ok_or(MemoryError::Overflow)?).ok_or(MemoryError::Overflow)
+}
+//Wide accesses have to be aligned to their size
+fn check_alignment<Address: AddressType>(address: Address, size: usize) -> Result<(), MemoryError> {
+    if address.as_u64().is_multiple_of(size as u64) { Ok(()) } else { Err(MemoryError::Misaligned) }
 }
-/*
+fn read_value<Address: AddressType, Space: AddressSpace<Address> + ?Sized>(space: &Space, address: Address, size: usize,
+                                                                          endianness: Endianness) -> Result<u64, MemoryError> {
+    check_alignment(address, size)?;
+    let mut bytes = [0u8; 8];
+    space.read_bytes(address, &mut bytes[..size])?;
+    Ok(endianness.read(&bytes[..size]))
+}
+fn write_value<Address: AddressType, Space: AddressSpace<Address> + ?Sized>(space: &mut Space, address: Address, size: usize,
+                                                                           value: u64, endianness: Endianness) -> Result<(), MemoryError> {
+    check_alignment(address, size)?;
+    let mut bytes = [0u8; 8];
+    endianness.write(value, &mut bytes[..size]);
+    space.write_bytes(address, &bytes[..size])
+}
+/// `size` bytes of another space from `start` on, seen from address 0.
 pub struct MemoryView<'a, Address: AddressType> {
-    range: Range<Address>,
+    start: Address,
+    size: u64,
     parent: &'a dyn AddressSpace<Address>
 }
+/// A `MemoryView` that can also write.
 pub struct MemoryViewMut<'a, Address: AddressType> {
-    range: Range<Address>,
+    start: Address,
+    size: u64,
     parent: &'a mut dyn AddressSpace<Address>
 }
+//Views have to lie inside their parent
+fn check_view<Address: AddressType>(parent: &dyn AddressSpace<Address>, start: Address, size: u64) -> Result<(), MemoryError> {
+    if size == 0 {
+        return Ok(());
+    }
+    let last = start.as_u64().checked_add(size - 1).and_then(Address::from_u64).ok_or(MemoryError::OutOfBounds)?;
+    if parent.address_in_space(start) && parent.address_in_space(last) { Ok(()) } else { Err(MemoryError::OutOfBounds) }
+}
+//The parent's address for a `len` byte access at `address` in a view
+fn view_address<Address: AddressType>(start: Address, size: u64, address: Address, len: usize) -> Result<Address, MemoryError> {
+    match address.as_u64().checked_add(len as u64) {
+        Some(end) if end <= size => Address::from_u64(start.as_u64() + address.as_u64()).ok_or(MemoryError::OutOfBounds),
+        _ => Err(MemoryError::OutOfBounds),
+    }
+}
+impl<'a, Address: AddressType> MemoryView<'a, Address> {
+    pub fn new(parent: &'a dyn AddressSpace<Address>, start: Address, size: u64) -> Result<MemoryView<'a, Address>, MemoryError> {
+        check_view(parent, start, size)?;
+        Ok(MemoryView { start, size, parent })
+    }
+}
+impl<'a, Address: AddressType> MemoryViewMut<'a, Address> {
+    pub fn new(parent: &'a mut dyn AddressSpace<Address>, start: Address, size: u64) -> Result<MemoryViewMut<'a, Address>, MemoryError> {
+        check_view(parent, start, size)?;
+        Ok(MemoryViewMut { start, size, parent })
+    }
+}
 impl<'a, Address: AddressType> AddressSpace<Address> for MemoryView<'a, Address> {
-    fn size(&self) -> Address {
-        (self.range.end - self.range.start)
+    fn size(&self) -> u64 {
+        self.size
     }
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
-        self.parent.read_byte(self.range.start + address)
+        self.parent.read_byte(view_address(self.start, self.size, address, 1)?)
     }
-    fn address_in_space(&self, address: Address) -> bool {
-        self.range.contains(&address)
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.parent.read_bytes(view_address(self.start, self.size, address, out.len())?, out)
     }
 }
-
 impl<'a, Address: AddressType> AddressSpace<Address> for MemoryViewMut<'a, Address> {
-    fn size(&self) -> Address {
-        (self.range.end.clone() - self.range.start)
+    fn size(&self) -> u64 {
+        self.size
     }
-
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
-        if &self.range.start.checked_add(&range.end).ok_or(MemoryError::Overflow)? > &self.range.end {
-            Err(MemoryError::OutOfBounds)
-        } else {
-            self.parent.read_bytes(Range {end: self.range.start+range.end.clone(), ..range })
+        self.parent.read_byte(view_address(self.start, self.size, address, 1)?)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        self.parent.read_byte_mut(view_address(self.start, self.size, address, 1)?)
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.parent.read_bytes(view_address(self.start, self.size, address, out.len())?, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.parent.read_bytes_mut(view_address(self.start, self.size, address, out.len())?, out)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        self.parent.write_bytes(view_address(self.start, self.size, address, bytes.len())?, bytes)
+    }
+}
+
+/// What RAM holds before it's written, standing in for its contents at power on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fill {
+    Zero,
+    Ones,
+    /// The same bytes every run with the same seed.
+    Random(u64),
+    /// 0x55 at even offsets and 0xAA at odd ones.
+    Checkerboard,
+}
+impl Fill {
+    /// Parses "zero", "ff", "checkerboard", "random" or "random:SEED", `None` for anything else.
+    pub fn parse(text: &str) -> Option<Fill> {
+        match text {
+            "zero" => Some(Fill::Zero),
+            "ff" => Some(Fill::Ones),
+            "checkerboard" => Some(Fill::Checkerboard),
+            "random" => Some(Fill::Random(0)),
+            _ => {
+                let seed = text.strip_prefix("random:")?;
+                match seed.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => seed.parse().ok(),
+                }.map(Fill::Random)
+            },
         }
     }
-    fn write_bytes(&mut self, addr: Address, bytes: &[u8]) -> Result<(), MemoryError> {
-        let end = addr.clone() + self.range.start+Address::from_usize(bytes.len()).ok_or(MemoryError::Overflow)?;
-        if &end > &self.range.end {
-            Err(MemoryError::OutOfBounds)
-        } else {
-            self.parent.write_bytes(self.range.start+addr.clone(), bytes)
+    /// The byte at `offset` in the memory.
+    pub fn byte(self, offset: u64) -> u8 {
+        match self {
+            Fill::Zero => 0,
+            Fill::Ones => 0xFF,
+            Fill::Checkerboard if offset.is_multiple_of(2) => 0x55,
+            Fill::Checkerboard => 0xAA,
+            Fill::Random(seed) => {
+                //SplitMix64 of the seed and offset
+                let mut z = seed.wrapping_add(offset.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                (z ^ (z >> 31)) as u8
+            },
         }
     }
-}*/
-
+}
 pub struct DenseStaticMemory {
     data: Vec<u8>
 }
 impl DenseStaticMemory {
     pub fn new<Address: AddressType>(size: Address) -> DenseStaticMemory {
-        DenseStaticMemory { data: vec![0; size.into()] }
+        DenseStaticMemory::filled(size, Fill::Zero)
+    }
+    pub fn filled<Address: AddressType>(size: Address, fill: Fill) -> DenseStaticMemory {
+        let size = usize::try_from(size.as_u64()).expect("memory too big for this host");
+        let data = match fill {
+            Fill::Zero => vec![0; size],
+            fill => (0..size as u64).map(|offset| fill.byte(offset)).collect(),
+        };
+        DenseStaticMemory { data }
     }
-    pub fn size<Address: AddressType>(&self) -> Address {
-        Address::from_usize(self.data.len()).unwrap()
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
     }
     pub fn as_slice(&self) -> &[u8] {
         self.data.as_slice()
@@ -78,23 +245,37 @@ impl DenseStaticMemory {
         self.data.as_mut_slice()
     }
 }
-impl<'a, Address: AddressType> AddressSpace<Address> for DenseStaticMemory {
-    fn size(&self) -> Address {
+impl<Address: AddressType> AddressSpace<Address> for DenseStaticMemory {
+    fn size(&self) -> u64 {
         self.size()
     }
+    fn memory(&self) -> Option<&[u8]> {
+        Some(self.as_slice())
+    }
+    fn memory_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.as_mut_slice())
+    }
 
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
         if self.address_in_space(address) {
-            Ok(self.data[address.into()])
+            Ok(self.data[address.as_u64() as usize])
         } else {
             Err(MemoryError::OutOfBounds)
         }
     }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        let start = usize::try_from(address.as_u64()).map_err(|_| MemoryError::OutOfBounds)?;
+        let end = start.checked_add(out.len()).ok_or(MemoryError::Overflow)?;
+        out.copy_from_slice(self.data.get(start..end).ok_or(MemoryError::OutOfBounds)?);
+        Ok(())
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        AddressSpace::read_bytes(self, address, out)
+    }
     fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
-        if Address::from_usize(bytes.len()).ok_or(MemoryError::Overflow)? < self.size() {
-            Ok(self.data[address.into()..bytes.len()+address.into()].clone_from_slice(bytes))
-        } else {
-            Err(MemoryError::OutOfBounds)
-        }
+        let start = usize::try_from(address.as_u64()).map_err(|_| MemoryError::OutOfBounds)?;
+        let end = start.checked_add(bytes.len()).ok_or(MemoryError::Overflow)?;
+        self.data.get_mut(start..end).ok_or(MemoryError::OutOfBounds)?.copy_from_slice(bytes);
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,182 @@
+//! Bank switching: a fixed window showing one of several equally sized banks of a larger
+//! store, like a cartridge mapper. Several windows can switch through the same store on their
+//! own.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::mirror::SharedSpace;
+use crate::microvm::memory::MemoryError;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// The bank a window shows, shared by the window, its register and the host.
+#[derive(Clone, Debug)]
+pub struct BankSelect {
+    selected: Rc<Cell<usize>>,
+    banks: usize,
+}
+impl BankSelect {
+    pub fn banks(&self) -> usize {
+        self.banks
+    }
+    pub fn selected(&self) -> usize {
+        self.selected.get()
+    }
+    pub fn select(&self, bank: usize) -> Result<(), MemoryError> {
+        if bank >= self.banks {
+            return Err(MemoryError::OutOfBounds);
+        }
+        self.selected.set(bank);
+        Ok(())
+    }
+    /// The window's part of a machine's saved state.
+    pub fn save(&self) -> BankState {
+        BankState { selected: self.selected() }
+    }
+    pub fn restore(&self, state: &BankState) -> Result<(), MemoryError> {
+        self.select(state.selected)
+    }
+}
+/// The saved state of a window, see `BankSelect::save`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BankState {
+    pub selected: usize,
+}
+/// A window onto bank `n` of a store, which starts at `n * bank_size` in the store. Like a
+/// `Mirror`, it never ticks the store.
+pub struct BankedSpace<Address: AddressType> {
+    store: SharedSpace<Address>,
+    bank_size: u64,
+    select: BankSelect,
+}
+impl<Address: AddressType> BankedSpace<Address> {
+    /// Splits the store into as many whole banks as fit, showing bank 0. There has to be at
+    /// least one.
+    pub fn new(store: &SharedSpace<Address>, bank_size: u64) -> Result<BankedSpace<Address>, MemoryError> {
+        if bank_size == 0 || bank_size > store.size() {
+            return Err(MemoryError::OutOfBounds);
+        }
+        let select = BankSelect { selected: Rc::new(Cell::new(0)), banks: (store.size() / bank_size) as usize };
+        Ok(BankedSpace { store: store.clone(), bank_size, select })
+    }
+    pub fn select(&self) -> BankSelect {
+        self.select.clone()
+    }
+    /// A register for the machine to switch banks with, mapped wherever the board decodes it.
+    /// `Overflow` if there are more banks than its byte can select.
+    pub fn register(&self) -> Result<BankRegister, MemoryError> {
+        if self.select.banks() > 0x100 {
+            return Err(MemoryError::Overflow);
+        }
+        Ok(BankRegister(self.select.clone()))
+    }
+    //The store's address for a `len` byte access at `address` in the window
+    fn store_address(&self, address: Address, len: usize) -> Result<Address, MemoryError> {
+        match address.as_u64().checked_add(len as u64) {
+            Some(end) if end <= self.bank_size => {
+                let base = self.select.selected() as u64 * self.bank_size;
+                Address::from_u64(base + address.as_u64()).ok_or(MemoryError::OutOfBounds)
+            },
+            _ => Err(MemoryError::OutOfBounds),
+        }
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for BankedSpace<Address> {
+    fn size(&self) -> u64 {
+        self.bank_size
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        self.store.read_byte(self.store_address(address, 1)?)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let address = self.store_address(address, 1)?;
+        self.store.read_byte_mut(address)
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.store.read_bytes(self.store_address(address, out.len())?, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        let address = self.store_address(address, out.len())?;
+        self.store.read_bytes_mut(address, out)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        let address = self.store_address(address, bytes.len())?;
+        self.store.write_bytes(address, bytes)
+    }
+}
+/// A one byte bank register for up to 256 banks. Reading it gives the selected bank and writing
+/// it selects one, wrapping past the last bank the way a mapper ignores the latch bits it
+/// doesn't decode.
+pub struct BankRegister(BankSelect);
+impl<Address: AddressType> AddressSpace<Address> for BankRegister {
+    fn size(&self) -> u64 {
+        1
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        if !self.address_in_space(address) {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Ok(self.0.selected() as u8)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        match bytes {
+            [value] if self.address_in_space(address) => self.0.select(*value as usize % self.0.banks()),
+            _ => Err(MemoryError::OutOfBounds),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn bank_switching() {
+        //Four 0x10 byte banks seen through two windows, with their registers at 0xFFF0 and 0xFFF1
+        let store = SharedSpace::new(Box::new(DenseStaticMemory::new(0x40u16)));
+        let low = BankedSpace::new(&store, 0x10).unwrap();
+        let high = BankedSpace::new(&store, 0x10).unwrap();
+        let (low_select, high_select) = (low.select(), high.select());
+        let mut space = SparseAddressSpace::<u16>::full();
+        space.add_space(0xFFF0, Box::new(low.register().unwrap())).unwrap();
+        space.add_space(0xFFF1, Box::new(high.register().unwrap())).unwrap();
+        space.add_space(0x8000, Box::new(low)).unwrap();
+        space.add_space(0xA000, Box::new(high)).unwrap();
+        for bank in 0..4u8 {
+            space.write_byte(0xFFF0, bank).unwrap();
+            space.write_bytes(0x800E, &[bank, bank]).unwrap();
+        }
+        space.write_byte(0xFFF1, 2).unwrap();
+        assert_eq!((space.read_byte(0x800F), space.read_byte(0xA00F)), (Ok(3), Ok(2)));
+        assert_eq!(space.write_bytes(0x800F, &[0, 0]), Err(MemoryError::InvalidAccess));
+        //Bank 5 of 4 wraps around to bank 1
+        space.write_byte(0xFFF1, 5).unwrap();
+        assert_eq!((space.read_byte(0xFFF1), space.read_byte(0xA00E)), (Ok(1), Ok(1)));
+
+        //Saved and restored through TOML
+        let saved = toml::to_string(&[("low", low_select.save()), ("high", high_select.save())].iter().cloned().collect::<BTreeMap<_, _>>()).unwrap();
+        high_select.select(0).unwrap();
+        low_select.select(0).unwrap();
+        assert_eq!(space.read_byte(0xA00E), Ok(0));
+        assert_eq!(high_select.select(4), Err(MemoryError::OutOfBounds));
+        let state: BTreeMap<String, BankState> = toml::from_str(&saved).unwrap();
+        low_select.restore(&state["low"]).unwrap();
+        high_select.restore(&state["high"]).unwrap();
+        assert_eq!(high_select.restore(&BankState { selected: 4 }), Err(MemoryError::OutOfBounds));
+        assert_eq!((space.read_byte(0x800E), space.read_byte(0xA00E)), (Ok(3), Ok(1)));
+        assert!(BankedSpace::new(&store, 0x41).is_err());
+    }
+    #[test]
+    fn register_width() {
+        //A byte selects all of 256 banks but not 257
+        let store = SharedSpace::<u16>::new(Box::new(DenseStaticMemory::new(0x101u16)));
+        let window = BankedSpace::new(&store, 1).unwrap();
+        assert_eq!(window.register().err(), Some(MemoryError::Overflow));
+        let window = BankedSpace::<u16>::new(&SharedSpace::new(Box::new(DenseStaticMemory::new(0x100u16))), 1).unwrap();
+        let mut register = window.register().unwrap();
+        register.write_byte(0u16, 0xFF).unwrap();
+        assert_eq!((window.select().selected(), register.read_byte(0u16)), (0xFF, Ok(0xFF)));
+    }
+}
@@ -0,0 +1,456 @@
+//! Memory maps described in TOML and built into a `SparseAddressSpace`, one `[[region]]` table
+//! per region:
+//!
+//! ```toml
+//! [[region]]
+//! name = "ram"
+//! kind = "ram"
+//! base = 0x0000
+//! size = 0x4000
+//!
+//! [[region]]
+//! name = "ram-mirror"
+//! kind = "mirror"
+//! of = "ram"
+//! base = 0x4000
+//! size = 0x4000
+//!
+//! [[region]]
+//! name = "basic"
+//! kind = "rom"
+//! file = "basic.bin"   # relative to the map's directory, size defaults to the file's
+//! base = 0xC000
+//! access = "rx"
+//!
+//! [[region]]
+//! name = "console"
+//! kind = "device"
+//! device = "uart"      # looked up in the devices given to `build`
+//! base = 0xB000
+//! size = 4
+//!
+//! [[region]]
+//! name = "settings"
+//! kind = "flash"
+//! file = "settings.bin" # made if it's missing and written as the machine changes it
+//! base = 0x8000
+//! size = 0x8000
+//! page_size = 0x1000    # and program_cycles and erase_cycles, see `FlashSettings`
+//! unlock = [0x5555, 0x2AAA] # the command addresses, from the region's base
+//! persistence = "on-drop" # written when the space is dropped, "write-through" by default
+//! ```
+//!
+//! `kind = "eeprom"` takes a `file` and `persistence` the same way but is written like RAM.
+//! `kind = "zeros"` maps read-only zeros. `access` defaults to "rwx". A region without `r` or
+//! `w` refuses those accesses even from a debugger. Cores also check `x`, and `p` keeps the
+//! region to privileged code.
+use crate::microvm::image::Image;
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+use crate::microvm::memory::mirror::{Mirror, SharedSpace};
+use crate::microvm::memory::nonvolatile::{FileBacked, FlashSettings, Persistence};
+pub use crate::microvm::memory::permissions::Permissions;
+use crate::microvm::memory::rom::ROM;
+use crate::microvm::memory::sparse::SparseAddressSpace;
+use crate::microvm::memory::zero::ZerosSpace;
+use crate::microvm::memory::MemoryError;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum RegionKind {
+    Ram,
+    Rom { file: PathBuf },
+    Zeros,
+    Eeprom { file: PathBuf, persistence: Option<Persistence> },
+    Flash {
+        file: PathBuf,
+        persistence: Option<Persistence>,
+        page_size: Option<u64>,
+        unlock: Option<[u64; 2]>,
+        program_cycles: Option<u64>,
+        erase_cycles: Option<u64>,
+    },
+    /// The same memory as the region named `of`, repeated if this region is larger.
+    Mirror { of: String },
+    /// A region made by the caller of `build`.
+    Device { device: String },
+}
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub base: u64,
+    /// Only regions with a file and mirrors can leave it out, taking the file's or mirrored
+    /// region's size.
+    pub size: Option<u64>,
+    #[serde(flatten)]
+    pub kind: RegionKind,
+    access: Option<String>,
+}
+impl Region {
+    pub fn permissions(&self) -> Option<Permissions> {
+        self.access.as_deref().map_or(Some(Permissions::ALL), Permissions::parse)
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MapError {
+    Parse(String),
+    DuplicateName(String),
+    MissingSize(String),
+    InvalidAccess(String),
+    /// The region doesn't fit in the address space.
+    OutOfRange(String),
+    /// Two regions share addresses.
+    Overlap(String, String),
+    /// A mirror of a region that doesn't exist or is itself a mirror.
+    UnknownRegion { region: String, of: String },
+    /// A mirror whose size isn't a multiple of the mirrored region's.
+    MirrorSize(String),
+    UnknownDevice { region: String, device: String },
+    /// The device made for the region has a different size.
+    DeviceSize(String),
+    /// A flash region's unlock address isn't inside it.
+    FlashUnlock(String),
+    File { region: String, error: String },
+}
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Parse(e) => write!(f, "{}", e),
+            MapError::DuplicateName(name) => write!(f, "there's more than one region called `{}`", name),
+            MapError::MissingSize(name) => write!(f, "region `{}` needs a size", name),
+            MapError::InvalidAccess(name) => write!(f, "region `{}` has an access other than letters from \"rwxp\"", name),
+            MapError::OutOfRange(name) => write!(f, "region `{}` doesn't fit in the address space", name),
+            MapError::Overlap(a, b) => write!(f, "regions `{}` and `{}` overlap", a, b),
+            MapError::UnknownRegion { region, of } => write!(f, "region `{}` mirrors `{}`, which isn't a RAM, ROM, zeros, EEPROM, flash or device region", region, of),
+            MapError::MirrorSize(name) => write!(f, "mirror `{}` isn't a multiple of the mirrored region's size", name),
+            MapError::UnknownDevice { region, device } => write!(f, "region `{}` uses the unknown device `{}`", region, device),
+            MapError::DeviceSize(name) => write!(f, "the device for region `{}` isn't the region's size", name),
+            MapError::FlashUnlock(name) => write!(f, "flash `{}` has an unlock address past its end, set `unlock`", name),
+            MapError::File { region, error } => write!(f, "region `{}`: {}", region, error),
+        }
+    }
+}
+/// Makes the region for a device name and size, `None` if it doesn't know the device.
+pub type Devices<'a, Address> = dyn FnMut(&str, u64) -> Option<Box<dyn AddressSpace<Address>>> + 'a;
+/// Makes the RAM for a region's base and size.
+pub type Ram<'a, Address> = dyn FnMut(u64, u64) -> Box<dyn AddressSpace<Address>> + 'a;
+struct Restricted<Address: AddressType> {
+    space: Box<dyn AddressSpace<Address>>,
+    permissions: Permissions,
+}
+impl<Address: AddressType> AddressSpace<Address> for Restricted<Address> {
+    fn size(&self) -> u64 {
+        self.space.size()
+    }
+    fn max_address(&self) -> Option<u64> {
+        self.space.max_address()
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        if !self.permissions.read {
+            return Err(MemoryError::InvalidAccess);
+        }
+        self.space.read_byte(address)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        if !self.permissions.read {
+            return Err(MemoryError::InvalidAccess);
+        }
+        self.space.read_byte_mut(address)
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        if !self.permissions.read {
+            return Err(MemoryError::InvalidAccess);
+        }
+        self.space.read_bytes(address, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        if !self.permissions.read {
+            return Err(MemoryError::InvalidAccess);
+        }
+        self.space.read_bytes_mut(address, out)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        if !self.permissions.write {
+            return Err(MemoryError::ReadOnly);
+        }
+        self.space.write_bytes(address, bytes)
+    }
+    fn tick(&mut self, cycles: u64) {
+        self.space.tick(cycles)
+    }
+}
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct MemoryMap {
+    #[serde(default, rename = "region")]
+    pub regions: Vec<Region>,
+}
+impl MemoryMap {
+    pub fn parse(text: &str) -> Result<MemoryMap, MapError> {
+        toml::from_str(text).map_err(|e| MapError::Parse(e.to_string()))
+    }
+    fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+    //The size of a region, reading a ROM's file if it has to
+    fn size(&self, region: &Region, root: &Path) -> Result<u64, MapError> {
+        match (region.size, &region.kind) {
+            (Some(size), _) => Ok(size),
+            (None, RegionKind::Rom { file } | RegionKind::Eeprom { file, .. } | RegionKind::Flash { file, .. }) => fs::metadata(root.join(file))
+                .map(|metadata| metadata.len())
+                .map_err(|e| MapError::File { region: region.name.clone(), error: format!("{}: {}", file.display(), e) }),
+            (None, RegionKind::Mirror { of }) => match self.region(of) {
+                Some(target) if !matches!(target.kind, RegionKind::Mirror { .. }) => self.size(target, root),
+                _ => Err(MapError::UnknownRegion { region: region.name.clone(), of: of.clone() }),
+            },
+            (None, _) => Err(MapError::MissingSize(region.name.clone())),
+        }
+    }
+    /// Checks the names, sizes and access of the regions and that none of them overlap,
+    /// returning each region's size. Files are found relative to `root`.
+    pub fn validate(&self, root: &Path) -> Result<Vec<u64>, MapError> {
+        let mut sizes = Vec::with_capacity(self.regions.len());
+        for (i, region) in self.regions.iter().enumerate() {
+            if self.regions[..i].iter().any(|other| other.name == region.name) {
+                return Err(MapError::DuplicateName(region.name.clone()));
+            }
+            region.permissions().ok_or_else(|| MapError::InvalidAccess(region.name.clone()))?;
+            let size = self.size(region, root)?;
+            if let RegionKind::Flash { unlock, .. } = region.kind {
+                if unlock.unwrap_or(FlashSettings::default().unlock).iter().any(|&offset| offset >= size) {
+                    return Err(MapError::FlashUnlock(region.name.clone()));
+                }
+            }
+            sizes.push(size);
+        }
+        let mut ranges: Vec<(u64, u64, &str)> = self.regions.iter().zip(sizes.iter())
+            .map(|(region, &size)| (region.base, region.base.saturating_add(size), region.name.as_str()))
+            .collect();
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                return Err(MapError::Overlap(pair[0].2.to_string(), pair[1].2.to_string()));
+            }
+        }
+        Ok(sizes)
+    }
+    /// Builds an address space covering every address, holding the regions.
+    pub fn build<Address: AddressType + 'static>(
+        &self,
+        root: &Path,
+        devices: &mut Devices<Address>,
+    ) -> Result<SparseAddressSpace<Address>, MapError> {
+        self.build_with_ram(root, devices, &mut |_, size| Box::new(DenseStaticMemory::new(size)))
+    }
+    /// `build` with RAM regions made by `ram`, such as RAM that isn't zeroed.
+    pub fn build_with_ram<Address: AddressType + 'static>(
+        &self,
+        root: &Path,
+        devices: &mut Devices<Address>,
+        ram: &mut Ram<Address>,
+    ) -> Result<SparseAddressSpace<Address>, MapError> {
+        let sizes = self.validate(root)?;
+        let address = |region: &Region, value: u64| Address::from_u64(value).ok_or_else(|| MapError::OutOfRange(region.name.clone()));
+        let mut shared: Vec<Option<SharedSpace<Address>>> = Vec::with_capacity(self.regions.len());
+        for (region, &len) in self.regions.iter().zip(sizes.iter()) {
+            let space: Box<dyn AddressSpace<Address>> = match &region.kind {
+                RegionKind::Ram => ram(region.base, len),
+                RegionKind::Rom { file } => {
+                    let error = |error: String| MapError::File { region: region.name.clone(), error };
+                    let bytes = fs::read(root.join(file)).map_err(|e| error(format!("{}: {}", file.display(), e)))?;
+                    let rom = ROM::from_image(&Image::from_raw(region.base, &bytes), region.base, len)
+                        .map_err(|_| error(format!("{} is larger than the region", file.display())))?;
+                    Box::new(rom)
+                },
+                RegionKind::Zeros => Box::new(ZerosSpace::new(len)),
+                RegionKind::Eeprom { file, persistence } | RegionKind::Flash { file, persistence, .. } => {
+                    let memory = FileBacked::open(&root.join(file), len, persistence.unwrap_or(Persistence::WriteThrough))
+                        .map_err(|e| MapError::File { region: region.name.clone(), error: format!("{}: {}", file.display(), e) })?;
+                    match region.kind {
+                        RegionKind::Flash { page_size, unlock, program_cycles, erase_cycles, .. } => {
+                            let default = FlashSettings::default();
+                            Box::new(memory.with_flash(FlashSettings {
+                                page_size: page_size.unwrap_or(default.page_size),
+                                unlock: unlock.unwrap_or(default.unlock),
+                                program_cycles: program_cycles.unwrap_or(default.program_cycles),
+                                erase_cycles: erase_cycles.unwrap_or(default.erase_cycles),
+                                ..default
+                            }))
+                        },
+                        _ => Box::new(memory),
+                    }
+                },
+                RegionKind::Mirror { .. } => {
+                    shared.push(None);
+                    continue;
+                },
+                RegionKind::Device { device } => {
+                    let space = devices(device, len)
+                        .ok_or_else(|| MapError::UnknownDevice { region: region.name.clone(), device: device.clone() })?;
+                    if space.size() != len {
+                        return Err(MapError::DeviceSize(region.name.clone()));
+                    }
+                    space
+                },
+            };
+            let permissions = region.permissions().unwrap_or(Permissions::ALL);
+            let space = if permissions.read && permissions.write {
+                space
+            } else {
+                Box::new(Restricted { space, permissions })
+            };
+            shared.push(Some(SharedSpace::new(space)));
+        }
+        let mut out = SparseAddressSpace::full();
+        for (i, region) in self.regions.iter().enumerate() {
+            let space: Box<dyn AddressSpace<Address>> = match (&region.kind, &shared[i]) {
+                (RegionKind::Mirror { of }, _) => {
+                    let j = self.regions.iter().position(|other| &other.name == of).filter(|&j| shared[j].is_some())
+                        .ok_or_else(|| MapError::UnknownRegion { region: region.name.clone(), of: of.clone() })?;
+                    if sizes[j] == 0 || sizes[i] % sizes[j] != 0 {
+                        return Err(MapError::MirrorSize(region.name.clone()));
+                    }
+                    let target = shared[j].as_ref().unwrap();
+                    Box::new(Mirror::new(target, sizes[i], target.size()).map_err(|_| MapError::MirrorSize(region.name.clone()))?)
+                },
+                (_, Some(space)) => Box::new(space.clone()),
+                (_, None) => unreachable!(),
+            };
+            let permissions = region.permissions().unwrap_or(Permissions::ALL);
+            out.add_space_with_permissions(address(region, region.base)?, space, permissions)
+                .map_err(|_| MapError::OutOfRange(region.name.clone()))?;
+        }
+        Ok(out)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(text: &str) -> Result<SparseAddressSpace<u16>, MapError> {
+        MemoryMap::parse(text)?.build(Path::new("."), &mut |_, _| None)
+    }
+
+    #[test]
+    fn builds_regions() {
+        let root = std::env::temp_dir();
+        let file = format!("micro16-map-{}.bin", std::process::id());
+        let eeprom = format!("micro16-map-{}-eeprom.bin", std::process::id());
+        let cache = format!("micro16-map-{}-cache.bin", std::process::id());
+        fs::write(root.join(&file), [0xEA, 0x4C]).unwrap();
+        let text = format!(r#"
+            [[region]]
+            name = "ram"
+            kind = "ram"
+            base = 0
+            size = 0x100
+
+            [[region]]
+            name = "ram-mirror"
+            kind = "mirror"
+            of = "ram"
+            base = 0x100
+            size = 0x200
+
+            [[region]]
+            name = "rom"
+            kind = "rom"
+            file = "{}"
+            base = 0xF000
+            access = "rx"
+
+            [[region]]
+            name = "port"
+            kind = "device"
+            device = "latch"
+            base = 0xE000
+            size = 2
+
+            [[region]]
+            name = "settings"
+            kind = "eeprom"
+            file = "{}"
+            base = 0xD000
+            size = 4
+
+            [[region]]
+            name = "cache"
+            kind = "eeprom"
+            file = "{}"
+            base = 0xD100
+            size = 2
+            persistence = "on-drop"
+        "#, file, eeprom, cache);
+        let map = MemoryMap::parse(&text).unwrap();
+        let mut space: SparseAddressSpace<u16> = map.build(&root, &mut |name, size| match name {
+            "latch" => Some(Box::new(DenseStaticMemory::new(size as u16))),
+            _ => None,
+        }).unwrap();
+        fs::remove_file(root.join(&file)).unwrap();
+        assert_eq!(map.regions[2].permissions(), Some(Permissions { read: true, write: false, execute: true, privileged: false }));
+        space.write_byte(0x10, 0xAA).unwrap();
+        assert_eq!(space.read_byte(0x110), Ok(0xAA));
+        space.write_byte(0x2FF, 0x55).unwrap();
+        assert_eq!(space.read_byte(0xFF), Ok(0x55));
+        assert_eq!(space.read_byte(0xF001), Ok(0x4C));
+        assert_eq!(space.write_byte(0xF000, 0), Err(MemoryError::ReadOnly));
+        space.write_byte(0xE000, 7).unwrap();
+        assert_eq!(space.read_byte(0xE000), Ok(7));
+        assert_eq!(space.read_byte(0xE002), Err(MemoryError::InvalidAccess));
+        space.write_byte(0xD001, 0).unwrap();
+        assert_eq!(fs::read(root.join(&eeprom)).unwrap(), [0xFF, 0, 0xFF, 0xFF]);
+        fs::remove_file(root.join(&eeprom)).unwrap();
+        space.write_byte(0xD100, 3).unwrap();
+        assert_eq!(fs::read(root.join(&cache)).unwrap(), [0xFF, 0xFF]);
+        drop(space);
+        assert_eq!(fs::read(root.join(&cache)).unwrap(), [3, 0xFF]);
+        fs::remove_file(root.join(&cache)).unwrap();
+    }
+    #[test]
+    fn flash_unlock() {
+        use crate::microvm::memory::nonvolatile::{FLASH_PROGRAM, FLASH_UNLOCK1, FLASH_UNLOCK2};
+        let file = std::env::temp_dir().join(format!("micro16-map-{}-flash.bin", std::process::id()));
+        let flash = |unlock: &str| format!("[[region]]\nname = \"f\"\nkind = \"flash\"\nfile = {:?}\nbase = 0x8000\nsize = 0x100\n{}", file, unlock);
+        //The default unlock addresses don't fit, so nothing could ever program it
+        assert_eq!(build(&flash("")).err(), Some(MapError::FlashUnlock("f".to_string())));
+        assert_eq!(build(&flash("unlock = [0x55, 0x100]\n")).err().unwrap().to_string(),
+                   "flash `f` has an unlock address past its end, set `unlock`");
+        assert!(!file.exists());
+        let mut space = build(&flash("unlock = [0x55, 0x2A]\n")).unwrap();
+        for &(address, value) in [(0x8055, FLASH_UNLOCK1), (0x802A, FLASH_UNLOCK2), (0x8055, FLASH_PROGRAM), (0x8010, 0x3C)].iter() {
+            space.write_byte(address, value).unwrap();
+        }
+        drop(space);
+        let bytes = fs::read(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!((bytes.len(), bytes[0x10], bytes[0x11]), (0x100, 0x3C, 0xFF));
+    }
+    #[test]
+    fn reports_errors() {
+        let region = |name: &str, base: u64, size: u64| format!("[[region]]\nname = \"{}\"\nkind = \"ram\"\nbase = {}\nsize = {}\n", name, base, size);
+        let overlap = region("low", 0, 0x100) + &region("high", 0x200, 0x100) + &region("middle", 0xFF, 0x10);
+        assert_eq!(build(&overlap).err(), Some(MapError::Overlap("low".to_string(), "middle".to_string())));
+        let adjacent = region("low", 0, 0x100) + &region("high", 0x100, 0x100);
+        assert!(build(&adjacent).is_ok());
+        assert_eq!(build(&(region("a", 0, 1) + &region("a", 1, 1))).err(), Some(MapError::DuplicateName("a".to_string())));
+        assert_eq!(build(&region("big", 0xFF00, 0x200)).err(), Some(MapError::OutOfRange("big".to_string())));
+        let mirror = "[[region]]\nname = \"m\"\nkind = \"mirror\"\nof = \"nothing\"\nbase = 0\n";
+        assert_eq!(build(mirror).err(), Some(MapError::UnknownRegion { region: "m".to_string(), of: "nothing".to_string() }));
+        let device = "[[region]]\nname = \"d\"\nkind = \"device\"\ndevice = \"uart\"\nbase = 0\nsize = 4\n";
+        assert_eq!(build(device).err().unwrap().to_string(), "region `d` uses the unknown device `uart`");
+        let access = region("a", 0, 1).replace("kind", "access = \"rq\"\nkind");
+        assert_eq!(build(&access).err(), Some(MapError::InvalidAccess("a".to_string())));
+        assert!(matches!(build("[[region]]\nname = \"x\"\nkind = \"flash\"\nbase = 0\n"), Err(MapError::Parse(_))));
+        let persistence = "[[region]]\nname = \"x\"\nkind = \"eeprom\"\nfile = \"x.bin\"\nbase = 0\npersistence = \"never\"\n";
+        assert!(matches!(build(persistence), Err(MapError::Parse(_))));
+        let file = std::env::temp_dir().join(format!("micro16-map-{}-big.bin", std::process::id()));
+        fs::write(&file, [0; 4]).unwrap();
+        let rom = format!("[[region]]\nname = \"r\"\nkind = \"rom\"\nfile = {:?}\nbase = 0x10\nsize = 2\n", file);
+        let result = build(&rom);
+        fs::remove_file(&file).unwrap();
+        assert_eq!(result.err(), Some(MapError::File { region: "r".to_string(), error: format!("{} is larger than the region", file.display()) }));
+    }
+}
@@ -0,0 +1,150 @@
+//! One backing store seen at several addresses, either mapped again as it is or repeated
+//! through a larger window like a partially decoded address bus.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::permissions::Access;
+use crate::microvm::memory::MemoryError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A space that more than one region can hold. Every handle ticks the space, so map one of them
+/// and reach the space through `Mirror`s everywhere else.
+pub struct SharedSpace<Address: AddressType>(Rc<RefCell<Box<dyn AddressSpace<Address>>>>);
+impl<Address: AddressType> SharedSpace<Address> {
+    pub fn new(space: Box<dyn AddressSpace<Address>>) -> SharedSpace<Address> {
+        SharedSpace(Rc::new(RefCell::new(space)))
+    }
+}
+impl<Address: AddressType> Clone for SharedSpace<Address> {
+    fn clone(&self) -> SharedSpace<Address> {
+        SharedSpace(self.0.clone())
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for SharedSpace<Address> {
+    fn size(&self) -> u64 {
+        self.0.borrow().size()
+    }
+    fn max_address(&self) -> Option<u64> {
+        self.0.borrow().max_address()
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        self.0.borrow().read_byte(address)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        self.0.borrow_mut().read_byte_mut(address)
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.0.borrow().read_bytes(address, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.0.borrow_mut().read_bytes_mut(address, out)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        self.0.borrow_mut().write_bytes(address, bytes)
+    }
+    fn read_bytes_checked(&mut self, address: Address, out: &mut [u8], access: Access) -> Result<(), MemoryError> {
+        self.0.borrow_mut().read_bytes_checked(address, out, access)
+    }
+    fn write_bytes_checked(&mut self, address: Address, bytes: &[u8], access: Access) -> Result<(), MemoryError> {
+        self.0.borrow_mut().write_bytes_checked(address, bytes, access)
+    }
+    fn tick(&mut self, cycles: u64) {
+        self.0.borrow_mut().tick(cycles)
+    }
+}
+/// The first `period` bytes of a shared space repeated through `size` bytes. It never ticks the
+/// space, that's left to wherever the space itself is mapped.
+pub struct Mirror<Address: AddressType> {
+    target: SharedSpace<Address>,
+    size: u64,
+    period: u64,
+}
+impl<Address: AddressType> Mirror<Address> {
+    /// The period can't be 0 or more than the target's size.
+    pub fn new(target: &SharedSpace<Address>, size: u64, period: u64) -> Result<Mirror<Address>, MemoryError> {
+        if period == 0 || period > target.size() {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Ok(Mirror { target: target.clone(), size, period })
+    }
+    fn wrap(&self, address: Address) -> Result<Address, MemoryError> {
+        if address.as_u64() >= self.size {
+            return Err(MemoryError::OutOfBounds);
+        }
+        Address::from_u64(address.as_u64() % self.period).ok_or(MemoryError::OutOfBounds)
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for Mirror<Address> {
+    fn size(&self) -> u64 {
+        self.size
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        self.target.read_byte(self.wrap(address)?)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let address = self.wrap(address)?;
+        self.target.read_byte_mut(address)
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        //Nothing is written unless all of it is in the mirror
+        let start = address.as_u64();
+        if start as u128 + bytes.len() as u128 > self.size as u128 {
+            return Err(MemoryError::OutOfBounds);
+        }
+        //Forwarded a period at a time, so each part is contiguous in the target
+        let mut done = 0;
+        while done < bytes.len() {
+            let offset = (start + done as u64) % self.period;
+            let len = (self.period - offset).min((bytes.len() - done) as u64) as usize;
+            let target = Address::from_u64(offset).ok_or(MemoryError::OutOfBounds)?;
+            self.target.write_bytes(target, &bytes[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::device::{DeviceSpace, InterruptLines, Timer, TIMER_ENABLE};
+    use crate::microvm::memory::address_space::{DenseStaticMemory, MemoryView, MemoryViewMut};
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+
+    #[test]
+    fn mirrors_and_views() {
+        //A 6502 board with 2K of RAM, zero page and stack included, decoded every 2K up to 0x2000
+        let mut space = SparseAddressSpace::<u16>::full();
+        let ram = SharedSpace::new(Box::new(DenseStaticMemory::new(0x800u16)));
+        space.add_space(0, Box::new(ram.clone())).unwrap();
+        space.add_space(0x800, Box::new(Mirror::new(&ram, 0x1800, 0x800).unwrap())).unwrap();
+        space.write_byte(0x01FF, 0xAA).unwrap();
+        assert_eq!((space.read_byte(0x09FF), space.read_byte(0x19FF)), (Ok(0xAA), Ok(0xAA)));
+        space.write_bytes(0x1FFF, &[1]).unwrap();
+        assert_eq!(space.read_byte(0x07FF), Ok(1));
+        //Wrapping around the period, and nothing written when the mirror ends partway
+        space.write_bytes(0x0FFE, &[6, 7, 8]).unwrap();
+        assert_eq!((space.read_byte(0x07FE), space.read_byte(0x0000)), (Ok(6), Ok(8)));
+        let mut mirror = Mirror::new(&ram, 0x1800, 0x800).unwrap();
+        assert_eq!(mirror.write_bytes(0x17FF, &[9, 9]), Err(MemoryError::OutOfBounds));
+        assert_eq!(space.read_byte(0x07FF), Ok(7));
+        //Only 16 of a device's bytes decoded through a 256 byte window
+        let lines = InterruptLines::new();
+        let timer = SharedSpace::new(Box::new(DeviceSpace::new(Box::new(Timer::new(lines.line(0))))));
+        space.add_space(0x4000, Box::new(timer.clone())).unwrap();
+        space.add_space(0x4010, Box::new(Mirror::new(&timer, 0xF0, 0x10).unwrap())).unwrap();
+        space.write_byte(0x40F8, TIMER_ENABLE).unwrap();
+        space.tick(5);
+        assert_eq!(space.read_byte(0x4020), Ok(5), "the mirror doesn't tick the timer again");
+        assert_eq!(Mirror::new(&timer, 0x100, 0x11).err(), Some(MemoryError::OutOfBounds));
+
+        let view = MemoryView::new(&space, 0x1F0, 0x20).unwrap();
+        assert_eq!(view.size(), 0x20);
+        assert_eq!(view.read_byte(0xF), Ok(0xAA));
+        assert_eq!(view.read_byte(0x20), Err(MemoryError::OutOfBounds));
+        let mut view = MemoryViewMut::new(&mut space, 0x7FE, 4).unwrap();
+        view.write_bytes(0, &[2, 3, 4, 5]).unwrap();
+        assert_eq!(view.write_bytes(2, &[0, 0, 0]), Err(MemoryError::OutOfBounds));
+        assert_eq!(space.read_byte(0x1000), Ok(4));
+        assert_eq!(MemoryView::new(&SparseAddressSpace::new(0x100u16).unwrap(), 0xF0, 0x11).err(), Some(MemoryError::OutOfBounds));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::microvm::mmu::AccessType;
+use std::io;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum MemoryError {
@@ -7,9 +9,34 @@ pub enum MemoryError {
     Overflow,
     Underflow,
     Overlap,
+    Misaligned,
+    /// Nothing is mapped at the address.
+    AccessFault { address: u64, kind: AccessType },
+    /// The region at the address doesn't allow the access.
+    PermissionFault { address: u64, kind: AccessType },
+    /// The host couldn't keep what was written, such as a file refusing it.
+    Io(io::ErrorKind),
+}
+impl MemoryError {
+    /// The error as a fault of a core's access at `address`, where a plain access failed.
+    pub fn fault(self, address: u64, kind: AccessType) -> MemoryError {
+        match self {
+            MemoryError::OutOfBounds | MemoryError::InvalidAccess => MemoryError::AccessFault { address, kind },
+            MemoryError::ReadOnly => MemoryError::PermissionFault { address, kind },
+            error => error,
+        }
+    }
 }
 pub mod address;
 pub mod address_space;
 pub mod sparse;
 pub mod zero;
-pub mod rom;
\ No newline at end of file
+pub mod rom;
+pub mod map;
+pub mod mirror;
+pub mod bank;
+pub mod overlay;
+pub mod permissions;
+pub mod profile;
+pub mod strict;
+pub mod nonvolatile;
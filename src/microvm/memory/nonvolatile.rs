@@ -0,0 +1,345 @@
+//! Memory kept in a file on the host, such as a board's EEPROM or flash, so what the machine
+//! stores is still there next run.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::MemoryError;
+use crate::microvm::mmu::AccessType;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// When writes reach the file.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Persistence {
+    /// Each write goes to the file as it's made.
+    WriteThrough,
+    /// The file is written by `flush` and when the region is dropped.
+    OnDrop,
+}
+pub const FLASH_UNLOCK1: u8 = 0xAA;
+pub const FLASH_UNLOCK2: u8 = 0x55;
+pub const FLASH_PROGRAM: u8 = 0xA0;
+pub const FLASH_ERASE: u8 = 0x80;
+pub const FLASH_ERASE_PAGE: u8 = 0x30;
+pub const FLASH_ERASE_CHIP: u8 = 0x10;
+pub const FLASH_RESET: u8 = 0xF0;
+/// Flash commands as on SST39SF parts. Each starts with `FLASH_UNLOCK1` written to `unlock[0]`
+/// and `FLASH_UNLOCK2` to `unlock[1]`, then:
+///
+/// | Write to   | Value              | Then                                             |
+/// |------------|--------------------|--------------------------------------------------|
+/// | unlock[0]  | `FLASH_PROGRAM`    | the byte to program at its address               |
+/// | unlock[0]  | `FLASH_ERASE`      | both unlock writes again and one of these:       |
+/// | a page     | `FLASH_ERASE_PAGE` | that page is erased to 0xFF                      |
+/// | unlock[0]  | `FLASH_ERASE_CHIP` | everything is erased to 0xFF                     |
+///
+/// Any other write abandons the command, as `FLASH_RESET` does, and is refused with a
+/// `PermissionFault`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FlashSettings {
+    pub page_size: u64,
+    pub unlock: [u64; 2],
+    pub program_cycles: u64,
+    /// For a page, a chip erase takes this for each page.
+    pub erase_cycles: u64,
+}
+impl Default for FlashSettings {
+    fn default() -> FlashSettings {
+        FlashSettings { page_size: 0x1000, unlock: [0x5555, 0x2AAA], program_cycles: 0, erase_cycles: 0 }
+    }
+}
+//How far into a command the writes have got
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Command {
+    Idle,
+    Unlocked1,
+    Unlocked2,
+    Program,
+    Erase,
+    EraseUnlocked1,
+    EraseUnlocked2,
+}
+struct Flash {
+    settings: FlashSettings,
+    command: Command,
+    busy: u64,
+    status: u8,
+}
+/// Bytes read from and written back to a file, which is made if it doesn't exist and padded
+/// with 0xFF up to the region's size. A write the file refuses fails with `MemoryError::Io` but
+/// stays in memory and is tried again by later writes and `flush`.
+///
+/// As flash, writes are commands, see `FlashSettings`. Programming only clears bits, so a byte
+/// has to be erased before it can take any value. While a command is running the machine reads
+/// a status, whose bit 6 toggles with each read and whose bit 7 is the inverse of bit 7 of the
+/// byte being programmed, or 0 during an erase. Writes are ignored until it's done. Loaders put
+/// bytes in flash with `program`.
+pub struct FileBacked {
+    data: Vec<u8>,
+    file: File,
+    persistence: Persistence,
+    //The bytes changed since the file was last written
+    dirty: Option<(usize, usize)>,
+    flash: Option<Flash>,
+}
+impl FileBacked {
+    pub fn open(path: &Path, size: u64, persistence: Persistence) -> io::Result<FileBacked> {
+        let size = usize::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too big for this host"))?;
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut data = Vec::with_capacity(size);
+        file.read_to_end(&mut data)?;
+        if data.len() > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "larger than the region"));
+        }
+        let padded = data.len()..size;
+        data.resize(size, 0xFF);
+        let mut memory = FileBacked { data, file, persistence, dirty: None, flash: None };
+        memory.mark(padded.start, padded.end);
+        memory.flush()?;
+        Ok(memory)
+    }
+    /// Makes the memory flash.
+    pub fn with_flash(mut self, settings: FlashSettings) -> FileBacked {
+        self.flash = Some(Flash { settings, command: Command::Idle, busy: 0, status: 0 });
+        self
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+    /// Puts `bytes` at `offset` as they are, even in flash, the way a programmer would.
+    pub fn program(&mut self, offset: u64, bytes: &[u8]) -> Result<(), MemoryError> {
+        let (start, end) = self.range(offset, bytes.len())?;
+        self.data[start..end].copy_from_slice(bytes);
+        self.changed(start, end)
+    }
+    /// Whether a flash command is still running.
+    pub fn is_busy(&self) -> bool {
+        self.flash.as_ref().is_some_and(|flash| flash.busy > 0)
+    }
+    /// Writes the changed bytes to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some((start, end)) = self.dirty {
+            self.file.seek(SeekFrom::Start(start as u64))?;
+            self.file.write_all(&self.data[start..end])?;
+            self.file.flush()?;
+            self.dirty = None;
+        }
+        Ok(())
+    }
+    fn mark(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        self.dirty = Some(match self.dirty {
+            Some((first, last)) => (first.min(start), last.max(end)),
+            None => (start, end),
+        });
+    }
+    //Marks the bytes dirty and writes them through if the file is kept up to date
+    fn changed(&mut self, start: usize, end: usize) -> Result<(), MemoryError> {
+        self.mark(start, end);
+        if self.persistence == Persistence::WriteThrough {
+            self.flush().map_err(|e| MemoryError::Io(e.kind()))?;
+        }
+        Ok(())
+    }
+    //Runs a machine write to a flash
+    fn command(&mut self, offset: usize, value: u8) -> Result<(), MemoryError> {
+        let flash = self.flash.as_mut().unwrap();
+        if flash.busy > 0 {
+            return Ok(());
+        }
+        let settings = flash.settings;
+        let page = settings.page_size.max(1) as usize;
+        let at = |unlock: usize| offset as u64 == settings.unlock[unlock];
+        //The bytes a finished command changes and what they become
+        let (command, changed) = match (flash.command, value) {
+            (Command::Idle, FLASH_UNLOCK1) if at(0) => (Command::Unlocked1, None),
+            (Command::Unlocked1, FLASH_UNLOCK2) if at(1) => (Command::Unlocked2, None),
+            (Command::Unlocked2, FLASH_PROGRAM) if at(0) => (Command::Program, None),
+            (Command::Unlocked2, FLASH_ERASE) if at(0) => (Command::Erase, None),
+            (Command::Erase, FLASH_UNLOCK1) if at(0) => (Command::EraseUnlocked1, None),
+            (Command::EraseUnlocked1, FLASH_UNLOCK2) if at(1) => (Command::EraseUnlocked2, None),
+            (Command::Program, value) => {
+                flash.busy = settings.program_cycles;
+                flash.status = !value & 0x80;
+                (Command::Idle, Some((offset, offset + 1, self.data[offset] & value)))
+            },
+            (Command::EraseUnlocked2, FLASH_ERASE_PAGE) => {
+                let start = offset / page * page;
+                flash.busy = settings.erase_cycles;
+                flash.status = 0;
+                (Command::Idle, Some((start, start.saturating_add(page).min(self.data.len()), 0xFF)))
+            },
+            (Command::EraseUnlocked2, FLASH_ERASE_CHIP) if at(0) => {
+                flash.busy = settings.erase_cycles.saturating_mul(self.data.len().div_ceil(page) as u64);
+                flash.status = 0;
+                (Command::Idle, Some((0, self.data.len(), 0xFF)))
+            },
+            (_, FLASH_RESET) => (Command::Idle, None),
+            _ => {
+                flash.command = Command::Idle;
+                return Err(MemoryError::PermissionFault { address: offset as u64, kind: AccessType::Store });
+            },
+        };
+        flash.command = command;
+        match changed {
+            Some((start, end, byte)) => {
+                self.data[start..end].fill(byte);
+                self.changed(start, end)
+            },
+            None => Ok(()),
+        }
+    }
+    //A machine read, which is the status while a command runs
+    fn read_mut(&mut self, offset: usize) -> u8 {
+        match &mut self.flash {
+            Some(flash) if flash.busy > 0 => {
+                flash.status ^= 0x40;
+                flash.status
+            },
+            _ => self.data[offset],
+        }
+    }
+    fn range(&self, address: impl AddressType, len: usize) -> Result<(usize, usize), MemoryError> {
+        let start = usize::try_from(address.as_u64()).map_err(|_| MemoryError::OutOfBounds)?;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok((start, end)),
+            _ => Err(MemoryError::OutOfBounds),
+        }
+    }
+}
+impl Drop for FileBacked {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for FileBacked {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        let (start, _) = self.range(address, 1)?;
+        Ok(self.data[start])
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let (start, _) = self.range(address, 1)?;
+        Ok(self.read_mut(start))
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        let (start, end) = self.range(address, out.len())?;
+        out.copy_from_slice(&self.data[start..end]);
+        Ok(())
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        let (start, _) = self.range(address, out.len())?;
+        for (offset, byte) in (start..).zip(out.iter_mut()) {
+            *byte = self.read_mut(offset);
+        }
+        Ok(())
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        let (start, end) = self.range(address, bytes.len())?;
+        if self.flash.is_some() {
+            for (i, &byte) in bytes.iter().enumerate() {
+                self.command(start + i, byte)?;
+            }
+            Ok(())
+        } else {
+            self.data[start..end].copy_from_slice(bytes);
+            self.changed(start, end)
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        if let Some(flash) = &mut self.flash {
+            flash.busy = flash.busy.saturating_sub(cycles);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("micro16-nonvolatile-{}-{}.bin", std::process::id(), name))
+    }
+
+    #[test]
+    fn persists_eeprom() {
+        let path = path("eeprom");
+        fs::write(&path, [1, 2]).unwrap();
+        let mut eeprom = FileBacked::open(&path, 4, Persistence::WriteThrough).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [1, 2, 0xFF, 0xFF]);
+        AddressSpace::<u16>::write_bytes(&mut eeprom, 1, &[7, 8]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [1, 7, 8, 0xFF]);
+        assert_eq!(AddressSpace::<u16>::write_bytes(&mut eeprom, 3, &[0, 0]), Err(MemoryError::OutOfBounds));
+        drop(eeprom);
+
+        let mut eeprom = FileBacked::open(&path, 4, Persistence::OnDrop).unwrap();
+        AddressSpace::<u16>::write_byte(&mut eeprom, 0, 9).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [1, 7, 8, 0xFF]);
+        drop(eeprom);
+        assert_eq!(fs::read(&path).unwrap(), [9, 7, 8, 0xFF]);
+        assert_eq!(FileBacked::open(&path, 2, Persistence::OnDrop).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        //A write the file refuses fails but is kept
+        let mut eeprom = FileBacked::open(&path, 4, Persistence::WriteThrough).unwrap();
+        eeprom.file = File::open(&path).unwrap();
+        assert!(matches!(AddressSpace::<u16>::write_byte(&mut eeprom, 2, 5), Err(MemoryError::Io(_))));
+        assert_eq!(eeprom.as_slice(), [9, 7, 5, 0xFF]);
+        drop(eeprom);
+        fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn programs_and_erases_flash() {
+        let path = path("flash");
+        let settings = FlashSettings { page_size: 0x100, unlock: [0x555, 0x2AA], program_cycles: 2, erase_cycles: 10 };
+        let mut flash = FileBacked::open(&path, 0x800, Persistence::WriteThrough).unwrap().with_flash(settings);
+        assert_eq!(AddressSpace::<u16>::write_byte(&mut flash, 0x123, 0x00), Err(MemoryError::PermissionFault { address: 0x123, kind: AccessType::Store }));
+        let mut write = |address: u16, value: u8| AddressSpace::<u16>::write_byte(&mut flash, address, value).unwrap();
+        let unlock = [(0x555, FLASH_UNLOCK1), (0x2AA, FLASH_UNLOCK2)];
+        for &(address, value) in unlock.iter().chain(&[(0x555, FLASH_PROGRAM), (0x123, 0x3C)]) {
+            write(address, value);
+        }
+        assert_eq!(flash.as_slice()[0x123], 0x3C, "the write outside a command did nothing");
+        assert!(flash.is_busy());
+        let read = |flash: &mut FileBacked| AddressSpace::<u16>::read_byte_mut(flash, 0x123).unwrap();
+        assert_eq!((read(&mut flash), read(&mut flash)), (0xC0, 0x80));
+        let mut status = [0; 2];
+        AddressSpace::<u16>::read_bytes_mut(&mut flash, 0x7FE, &mut status).unwrap();
+        assert_eq!(status, [0xC0, 0x80]);
+        assert_eq!(AddressSpace::<u16>::read_bytes_mut(&mut flash, 0x7FF, &mut status), Err(MemoryError::OutOfBounds));
+        AddressSpace::<u16>::tick(&mut flash, 2);
+        assert_eq!(read(&mut flash), 0x3C);
+
+        //Programming only clears bits
+        for &(address, value) in unlock.iter().chain(&[(0x555, FLASH_PROGRAM), (0x123, 0xC3)]) {
+            AddressSpace::<u16>::write_byte(&mut flash, address, value).unwrap();
+        }
+        AddressSpace::<u16>::tick(&mut flash, 2);
+        assert_eq!(read(&mut flash), 0x00);
+        assert_eq!(fs::read(&path).unwrap()[0x120..0x124], [0xFF, 0xFF, 0xFF, 0x00]);
+
+        let erase = [(0x555, FLASH_ERASE), (0x555, FLASH_UNLOCK1), (0x2AA, FLASH_UNLOCK2), (0x150, FLASH_ERASE_PAGE)];
+        for &(address, value) in unlock.iter().chain(&erase) {
+            AddressSpace::<u16>::write_byte(&mut flash, address, value).unwrap();
+        }
+        assert_eq!(read(&mut flash) & 0x80, 0);
+        AddressSpace::<u16>::tick(&mut flash, 10);
+        assert!(!flash.is_busy());
+        assert_eq!(read(&mut flash), 0xFF);
+        drop(flash);
+        assert!(fs::read(&path).unwrap().iter().all(|&byte| byte == 0xFF));
+
+        //Loaders program flash directly
+        let mut flash = FileBacked::open(&path, 0x800, Persistence::WriteThrough).unwrap().with_flash(settings);
+        flash.program(0x7FE, &[1, 2]).unwrap();
+        assert_eq!(flash.program(0x7FF, &[1, 2]), Err(MemoryError::OutOfBounds));
+        assert_eq!(fs::read(&path).unwrap()[0x7FE..], [1, 2]);
+        fs::remove_file(path).unwrap();
+    }
+}
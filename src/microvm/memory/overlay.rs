@@ -0,0 +1,139 @@
+//! Copy-on-write patches over a space that's left as it is, such as firmware in a `ROM`.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::MemoryError;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// Bytes that differ between the base and the overlay, starting at `address`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Patch {
+    pub address: u64,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+/// Writes land in the overlay and reads see them ahead of the base, which is only ever read, so
+/// a read-only base takes writes too. Forks share the base and copy only the patches. The base
+/// isn't ticked.
+pub struct Overlay<Address: AddressType> {
+    base: Rc<dyn AddressSpace<Address>>,
+    patches: BTreeMap<u64, u8>,
+}
+impl<Address: AddressType> Overlay<Address> {
+    pub fn new(base: Box<dyn AddressSpace<Address>>) -> Overlay<Address> {
+        Overlay { base: Rc::from(base), patches: BTreeMap::new() }
+    }
+    /// An overlay over the same base starting with these patches, which go their own way after.
+    pub fn fork(&self) -> Overlay<Address> {
+        Overlay { base: self.base.clone(), patches: self.patches.clone() }
+    }
+    /// The written bytes as runs of consecutive addresses, including ones written with the value
+    /// the base already had.
+    pub fn patches(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (&address, &byte) in self.patches.iter() {
+            match runs.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() as u64 == address => bytes.push(byte),
+                _ => runs.push((address, vec![byte])),
+            }
+        }
+        runs
+    }
+    /// The runs of bytes that read differently from the base.
+    pub fn diff(&self) -> Vec<Patch> {
+        let mut runs: Vec<Patch> = Vec::new();
+        for (&address, &byte) in self.patches.iter() {
+            let original = Address::from_u64(address).and_then(|address| self.base.read_byte(address).ok());
+            if original == Some(byte) {
+                continue;
+            }
+            let original = original.unwrap_or(0);
+            match runs.last_mut() {
+                Some(run) if run.address + run.patched.len() as u64 == address => {
+                    run.original.push(original);
+                    run.patched.push(byte);
+                },
+                _ => runs.push(Patch { address, original: vec![original], patched: vec![byte] }),
+            }
+        }
+        runs
+    }
+    /// Goes back to the base's bytes for `len` addresses from `address`.
+    pub fn discard(&mut self, address: u64, len: u64) {
+        let end = address.saturating_add(len);
+        let discarded: Vec<u64> = self.patches.range(address..end).map(|(&address, _)| address).collect();
+        for address in discarded {
+            self.patches.remove(&address);
+        }
+    }
+    pub fn discard_all(&mut self) {
+        self.patches.clear();
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for Overlay<Address> {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+    fn max_address(&self) -> Option<u64> {
+        self.base.max_address()
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        match self.patches.get(&address.as_u64()) {
+            Some(&byte) => Ok(byte),
+            None => self.base.read_byte(address),
+        }
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.base.read_bytes(address, out)?;
+        let start = address.as_u64();
+        for (&patched, &byte) in self.patches.range(start..start.saturating_add(out.len() as u64)) {
+            out[(patched - start) as usize] = byte;
+        }
+        Ok(())
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        let start = address.as_u64();
+        //u128 so the end of a full 64 bit base fits
+        let end = start as u128 + bytes.len() as u128;
+        if end > self.max_address().map_or(0, |max| max as u128 + 1) {
+            return Err(MemoryError::OutOfBounds);
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.patches.insert(start + i as u64, byte);
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::rom::ROM;
+
+    #[test]
+    fn patches_a_rom() {
+        let mut rom = ROM::new(0x100u16);
+        rom.get_mut().as_mut_slice()[..4].copy_from_slice(&[0xEA, 0xEA, 0x4C, 0x00]);
+        let mut firmware = Overlay::<u16>::new(Box::new(rom));
+        firmware.write_bytes(1, &[0x60, 0x4C]).unwrap();
+        firmware.write_byte(0xFF, 0x80).unwrap();
+        let mut bytes = [0; 4];
+        firmware.read_bytes(0, &mut bytes).unwrap();
+        assert_eq!(bytes, [0xEA, 0x60, 0x4C, 0x00]);
+        assert_eq!(firmware.patches(), vec![(1, vec![0x60, 0x4C]), (0xFF, vec![0x80])]);
+        //Writing 0x4C over 0x4C isn't a difference
+        assert_eq!(firmware.diff(), vec![
+            Patch { address: 1, original: vec![0xEA], patched: vec![0x60] },
+            Patch { address: 0xFF, original: vec![0], patched: vec![0x80] },
+        ]);
+        assert_eq!(firmware.write_bytes(0xFF, &[0, 0]), Err(MemoryError::OutOfBounds));
+
+        let mut fork = firmware.fork();
+        fork.write_byte(0, 0x00).unwrap();
+        firmware.discard(0, 0x10);
+        assert_eq!((firmware.read_byte(0), firmware.read_byte(1), firmware.read_byte(0xFF)), (Ok(0xEA), Ok(0xEA), Ok(0x80)));
+        assert_eq!((fork.read_byte(0), fork.read_byte(1)), (Ok(0x00), Ok(0x60)));
+        fork.discard_all();
+        assert!(fork.patches().is_empty());
+        assert_eq!(fork.read_byte(2), Ok(0x4C));
+    }
+}
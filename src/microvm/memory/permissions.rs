@@ -0,0 +1,43 @@
+//! What a region lets cores do, checked on the accesses cores make for their programs.
+use crate::microvm::mmu::AccessType;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Only privileged code, such as RISC-V M and S-mode, gets the other permissions.
+    pub privileged: bool,
+}
+impl Permissions {
+    pub const ALL: Permissions = Permissions { read: true, write: true, execute: true, privileged: false };
+    /// Parses letters from "rwxp", `None` for anything else.
+    pub fn parse(text: &str) -> Option<Permissions> {
+        let mut permissions = Permissions { read: false, write: false, execute: false, privileged: false };
+        for c in text.chars() {
+            let flag = match c {
+                'r' => &mut permissions.read,
+                'w' => &mut permissions.write,
+                'x' => &mut permissions.execute,
+                'p' => &mut permissions.privileged,
+                _ => return None,
+            };
+            *flag = true;
+        }
+        Some(permissions)
+    }
+    pub fn allows(&self, access: Access) -> bool {
+        let kind = match access.kind {
+            AccessType::Fetch => self.execute,
+            AccessType::Load => self.read,
+            AccessType::Store => self.write,
+        };
+        kind && (access.privileged || !self.privileged)
+    }
+}
+/// An access a core makes for its program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Access {
+    pub kind: AccessType,
+    pub privileged: bool,
+}
@@ -0,0 +1,183 @@
+//! Counting the accesses a region sees, for finding hot code and data. `StrictRam` finds reads
+//! of RAM that was never written.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::permissions::Access;
+use crate::microvm::memory::MemoryError;
+use crate::microvm::mmu::AccessType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+//From coldest to hottest
+const HEAT: &[u8] = b".:-=+*#%@";
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    pub reads: u64,
+    pub writes: u64,
+    pub fetches: u64,
+}
+impl Counts {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes + self.fetches
+    }
+}
+/// The counts of a `Profiled` region, kept after the region is mapped. Addresses are where the
+/// region is mapped and each count covers a bucket of `1 << bucket_bits` addresses. An access
+/// counts once, in the bucket of its first byte.
+#[derive(Clone)]
+pub struct Profile {
+    buckets: Rc<RefCell<BTreeMap<u64, Counts>>>,
+    base: u64,
+    bucket_bits: u32,
+}
+impl Profile {
+    fn record(&self, address: u64, kind: AccessType) {
+        //Only saturates if the region is mapped somewhere other than `base`
+        let address = self.base.saturating_add(address);
+        let mut buckets = self.buckets.borrow_mut();
+        let counts = buckets.entry(address >> self.bucket_bits << self.bucket_bits).or_default();
+        match kind {
+            AccessType::Fetch => counts.fetches += 1,
+            AccessType::Load => counts.reads += 1,
+            AccessType::Store => counts.writes += 1,
+        }
+    }
+    /// Each bucket that was accessed, by address.
+    pub fn counts(&self) -> Vec<(u64, Counts)> {
+        self.buckets.borrow().iter().map(|(&address, &counts)| (address, counts)).collect()
+    }
+    /// The `n` buckets with the most accesses, hottest first.
+    pub fn hottest(&self, n: usize) -> Vec<(u64, Counts)> {
+        let mut counts = self.counts();
+        counts.sort_by_key(|&(address, counts)| (std::cmp::Reverse(counts.total()), address));
+        counts.truncate(n);
+        counts
+    }
+    pub fn csv(&self) -> String {
+        let mut out = String::from("address,reads,writes,fetches\n");
+        for (address, counts) in self.counts() {
+            out.push_str(&format!("{:#x},{},{},{}\n", address, counts.reads, counts.writes, counts.fetches));
+        }
+        out
+    }
+    /// A row of `columns` buckets to a line, from `.` for the fewest accesses to `@` for the
+    /// most, with a space for none. Rows without accesses are left out.
+    pub fn heatmap(&self, columns: usize) -> String {
+        let counts = self.counts();
+        let hottest = counts.iter().map(|(_, counts)| counts.total()).max().unwrap_or(0);
+        let row_size = (columns as u64) << self.bucket_bits;
+        let mut rows: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        for (address, counts) in counts {
+            let row = rows.entry(address / row_size * row_size).or_insert_with(|| vec![b' '; columns]);
+            let heat = (counts.total() * HEAT.len() as u64).div_ceil(hottest) - 1;
+            row[((address % row_size) >> self.bucket_bits) as usize] = HEAT[heat as usize];
+        }
+        rows.iter().map(|(address, row)| format!("{:08x} {}\n", address, String::from_utf8_lossy(row))).collect()
+    }
+}
+/// A region counting the machine's accesses to it, which are reads, writes and, through
+/// `read_bytes_checked`, fetches. A debugger's reads aren't counted.
+pub struct Profiled<Space> {
+    space: Space,
+    profile: Profile,
+}
+impl<Space> Profiled<Space> {
+    /// Profiles a region that's mapped at `base`.
+    pub fn new(space: Space, base: u64, bucket_bits: u32) -> Profiled<Space> {
+        Profiled { space, profile: Profile { buckets: Rc::default(), base, bucket_bits } }
+    }
+    pub fn profile(&self) -> Profile {
+        self.profile.clone()
+    }
+    pub fn get(&self) -> &Space {
+        &self.space
+    }
+    pub fn get_mut(&mut self) -> &mut Space {
+        &mut self.space
+    }
+    pub fn into_inner(self) -> Space {
+        self.space
+    }
+}
+impl<Address: AddressType, Space: AddressSpace<Address>> AddressSpace<Address> for Profiled<Space> {
+    fn size(&self) -> u64 {
+        self.space.size()
+    }
+    fn max_address(&self) -> Option<u64> {
+        self.space.max_address()
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        self.space.read_byte(address)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let byte = self.space.read_byte_mut(address)?;
+        self.profile.record(address.as_u64(), AccessType::Load);
+        Ok(byte)
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.space.read_bytes(address, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.space.read_bytes_mut(address, out)?;
+        self.profile.record(address.as_u64(), AccessType::Load);
+        Ok(())
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        self.space.write_bytes(address, bytes)?;
+        self.profile.record(address.as_u64(), AccessType::Store);
+        Ok(())
+    }
+    fn read_bytes_checked(&mut self, address: Address, out: &mut [u8], access: Access) -> Result<(), MemoryError> {
+        self.space.read_bytes_checked(address, out, access)?;
+        self.profile.record(address.as_u64(), access.kind);
+        Ok(())
+    }
+    fn write_bytes_checked(&mut self, address: Address, bytes: &[u8], access: Access) -> Result<(), MemoryError> {
+        self.space.write_bytes_checked(address, bytes, access)?;
+        self.profile.record(address.as_u64(), AccessType::Store);
+        Ok(())
+    }
+    fn tick(&mut self, cycles: u64) {
+        self.space.tick(cycles)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+
+    #[test]
+    fn counts_accesses() {
+        let ram = Profiled::new(DenseStaticMemory::new(0x100u16), 0x8000, 4);
+        let profile = ram.profile();
+        let mut space = SparseAddressSpace::<u16>::full();
+        space.add_space(0x8000, Box::new(ram)).unwrap();
+        space.write_bytes(0x8000, &[0xEA; 4]).unwrap();
+        let mut bytes = [0; 2];
+        for _ in 0..3 {
+            space.read_bytes_checked(0x8000, &mut bytes, Access { kind: AccessType::Fetch, privileged: true }).unwrap();
+        }
+        space.read_byte_mut(0x8012).unwrap();
+        space.read_byte(0x8013).unwrap();
+        assert_eq!(profile.counts(), vec![
+            (0x8000, Counts { reads: 0, writes: 1, fetches: 3 }),
+            (0x8010, Counts { reads: 1, writes: 0, fetches: 0 }),
+        ]);
+        assert_eq!(profile.hottest(1), vec![(0x8000, Counts { reads: 0, writes: 1, fetches: 3 })]);
+        assert_eq!(profile.csv(), "address,reads,writes,fetches\n0x8000,0,1,3\n0x8010,1,0,0\n");
+        space.write_bytes(0x80F0, &[0; 8]).unwrap();
+        assert_eq!(profile.heatmap(8), "00008000 @-      \n00008080        -\n");
+    }    #[test]
+    fn counts_the_top_of_a_full_space() {
+        let ram = Profiled::new(DenseStaticMemory::new(0x10u64), u64::MAX - 0xF, 4);
+        let profile = ram.profile();
+        let mut space = SparseAddressSpace::<u64>::full();
+        space.add_space(u64::MAX - 0xF, Box::new(ram)).unwrap();
+        space.write_bytes(u64::MAX - 3, &[1; 4]).unwrap();
+        space.read_byte_mut(u64::MAX).unwrap();
+        assert_eq!(profile.counts(), vec![(u64::MAX - 0xF, Counts { reads: 1, writes: 1, fetches: 0 })]);
+    }
+}
@@ -1,17 +1,29 @@
 use crate::microvm::memory::address_space::{DenseStaticMemory, AddressSpace};
 use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::MemoryError;
+use crate::microvm::image::Image;
+use std::convert::TryFrom;
 
 pub struct ROM {
     memory: DenseStaticMemory
 }
 impl<Address: AddressType> AddressSpace<Address> for ROM {
-    fn size(&self) -> Address {
+    fn size(&self) -> u64 {
         self.memory.size()
     }
 
     fn read_byte(&self, address: Address) -> Result<u8, super::MemoryError> {
         self.memory.read_byte(address)
     }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.memory.read_bytes(address, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.memory.read_bytes(address, out)
+    }
+    fn memory(&self) -> Option<&[u8]> {
+        Some(self.memory.as_slice())
+    }
 }
 impl ROM {
     pub fn new(size: impl AddressType) -> ROM {
@@ -19,7 +31,36 @@ impl ROM {
             memory: DenseStaticMemory::new(size)
         }
     }
+    /// A ROM of `size` bytes mapped at `base` holding the image, zeros where the image has no
+    /// bytes. The image has to fit inside it.
+    pub fn from_image(image: &Image, base: u64, size: impl AddressType) -> Result<ROM, MemoryError> {
+        let mut rom = ROM::new(size);
+        let data = rom.memory.as_mut_slice();
+        for (address, bytes) in image.chunks.iter() {
+            let start = address.checked_sub(base).and_then(|start| usize::try_from(start).ok()).ok_or(MemoryError::OutOfBounds)?;
+            let end = start.checked_add(bytes.len()).ok_or(MemoryError::OutOfBounds)?;
+            data.get_mut(start..end).ok_or(MemoryError::OutOfBounds)?.copy_from_slice(bytes);
+        }
+        Ok(rom)
+    }
     pub fn get_mut(&mut self) -> &mut DenseStaticMemory {
         &mut self.memory
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::image::ihex;
+
+    #[test]
+    fn from_image() {
+        let image = ihex::parse(":02F00000EA4CD8\n:00000001FF\n").unwrap();
+        let rom = ROM::from_image(&image, 0xF000, 0x1000u16).unwrap();
+        assert_eq!(AddressSpace::<u16>::read_byte(&rom, 1), Ok(0x4C));
+        assert_eq!(AddressSpace::<u16>::read_byte(&rom, 2), Ok(0));
+        assert_eq!(ROM::from_image(&image, 0xF001, 0x1000u16).err(), Some(MemoryError::OutOfBounds));
+        assert_eq!(ROM::from_image(&image, 0xE000, 0x1000u16).err(), Some(MemoryError::OutOfBounds));
+        let top = Image::from_raw(u64::MAX - 1, &[1, 2, 3]);
+        assert_eq!(ROM::from_image(&top, 0, 0x1000u16).err(), Some(MemoryError::OutOfBounds));
+    }
+}
@@ -1,11 +1,99 @@
-use std::ops::{Range, Deref, DerefMut};
+use std::ops::{Range, RangeInclusive, Deref, DerefMut};
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 use crate::microvm::memory::MemoryError;
 use crate::microvm::memory::address::*;
-use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::address_space::{offset_address, AddressSpace};
+use crate::microvm::device::{Device, DeviceSpace};
+use crate::microvm::memory::permissions::{Access, Permissions};
+//A mapped space and where it's mapped
+type Region<Address> = OffsetAddressSpace<Address, dyn AddressSpace<Address>, Box<dyn AddressSpace<Address>>>;
 pub struct SparseAddressSpace<Address: AddressType> {
-    spaces: Vec<OffsetAddressSpace<Address, dyn AddressSpace<Address>, Box<dyn AddressSpace<Address>>>>,
-    size: Address,
+    //In the order they were added, which is how the pages refer to them
+    spaces: Vec<Region<Address>>,
+    //What each space lets cores do, by index into `spaces`
+    permissions: Vec<Permissions>,
+    //Indices into `spaces` sorted by offset
+    order: Vec<usize>,
+    pages: PageTable,
+    //Whether `find` uses the pages, only turned off to measure what they save
+    paged: bool,
+    //The highest address, so a space can cover every address
+    last: Address,
+}
+const PAGE_BITS: u32 = 8;
+const CHUNK_BITS: u32 = 12;
+//Pages from 4G up aren't in the table and are searched for
+const TABLE_PAGES: u64 = 1 << 24;
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Page {
+    Unmapped,
+    //Every address in the page is in the space
+    Whole(u32),
+    //The space is the only one in the page but doesn't cover all of it
+    Part(u32),
+    //More than one space, or past the table
+    Mixed,
+}
+//A chunk's worth of pages, kept as one entry while they're all the same
+#[derive(Clone)]
+enum Chunk {
+    Uniform(Page),
+    Pages(Box<[Page]>),
+}
+//The spaces in each page, two levels deep so a 32 bit space only pays for the chunks it uses
+//and a region covering whole chunks costs one entry per chunk
+#[derive(Default)]
+struct PageTable {
+    chunks: Vec<Chunk>,
+}
+impl PageTable {
+    fn get(&self, address: u64) -> Page {
+        let page = address >> PAGE_BITS;
+        match self.chunks.get((page >> CHUNK_BITS) as usize) {
+            Some(Chunk::Uniform(entry)) => *entry,
+            Some(Chunk::Pages(pages)) => pages[(page & ((1 << CHUNK_BITS) - 1)) as usize],
+            None if page >= TABLE_PAGES => Page::Mixed,
+            None => Page::Unmapped,
+        }
+    }
+    //Adds space `index`, which covers `first..=last`
+    fn add(&mut self, index: usize, first: u64, last: u64) {
+        let page_size = 1u64 << PAGE_BITS;
+        let last_page = (last >> PAGE_BITS).min(TABLE_PAGES - 1);
+        let mut page = first >> PAGE_BITS;
+        while page <= last_page {
+            let chunk = (page >> CHUNK_BITS) as usize;
+            let chunk_first = (chunk as u64) << CHUNK_BITS;
+            let chunk_last = chunk_first + (1 << CHUNK_BITS) - 1;
+            let end = chunk_last.min(last_page);
+            if self.chunks.len() <= chunk {
+                self.chunks.resize(chunk + 1, Chunk::Uniform(Page::Unmapped));
+            }
+            let whole = first <= chunk_first << PAGE_BITS && (chunk_last << PAGE_BITS) + (page_size - 1) <= last;
+            match self.chunks[chunk] {
+                Chunk::Uniform(Page::Unmapped) if whole => {
+                    self.chunks[chunk] = Chunk::Uniform(Page::Whole(index as u32));
+                    page = end + 1;
+                    continue;
+                },
+                Chunk::Uniform(entry) => self.chunks[chunk] = Chunk::Pages(vec![entry; 1 << CHUNK_BITS].into_boxed_slice()),
+                Chunk::Pages(_) => (),
+            }
+            if let Chunk::Pages(pages) = &mut self.chunks[chunk] {
+                for page in page..=end {
+                    let entry = &mut pages[(page - chunk_first) as usize];
+                    let start = page << PAGE_BITS;
+                    *entry = match *entry {
+                        Page::Unmapped if first <= start && start + (page_size - 1) <= last => Page::Whole(index as u32),
+                        Page::Unmapped => Page::Part(index as u32),
+                        _ => Page::Mixed,
+                    };
+                }
+            }
+            page = end + 1;
+        }
+    }
 }
 pub struct OffsetAddressSpace<Address, Space, SpaceStorage> where
     Address: AddressType,
@@ -21,7 +109,7 @@ pub struct OffsetAddressSpaceMut<Address, Space, SpaceStorage> where
     offset: Address,
     space: SpaceStorage
 }
-impl<'a, Address, Space, SpaceStorage> OffsetAddressSpaceMut<Address, Space, SpaceStorage> where
+impl<Address, Space, SpaceStorage> OffsetAddressSpaceMut<Address, Space, SpaceStorage> where
     Address: AddressType,
     Space: AddressSpace<Address> + ?Sized,
     SpaceStorage: Deref<Target=Space> + DerefMut {
@@ -33,11 +121,18 @@ impl<Address, Space, SpaceStorage> OffsetAddressSpace<Address, Space, SpaceStora
     Address: AddressType,
     Space: AddressSpace<Address> + ?Sized,
     SpaceStorage: Deref<Target=Space> {
-    pub fn address_range(&self) -> Range<Address> {
-        Range { start: self.offset, end: self.space.size()+self.offset }
+    pub fn offset(&self) -> Address {
+        self.offset
+    }
+    /// The addresses the space covers, `None` if it's empty.
+    pub fn address_range(&self) -> Option<RangeInclusive<Address>> {
+        let last = self.space.max_address()?;
+        Some(self.offset..=Address::from_u64(self.offset.as_u64().checked_add(last)?)?)
     }
     pub fn sub_offset(&self, range: Range<Address>) -> Result<Range<Address>, MemoryError> {
-        Ok(Range { start: range.start.checked_sub(&self.offset).ok_or(MemoryError::Underflow)?, end: range.end-self.offset })
+        let start = range.start.checked_sub(&self.offset).ok_or(MemoryError::Underflow)?;
+        let end = range.end.checked_sub(&self.offset).ok_or(MemoryError::Underflow)?;
+        Ok(Range { start, end })
     }
     pub fn relative_range(&self, range: Range<Address>) -> Result<Range<Address>, MemoryError> {
         match self.sub_offset(range) {
@@ -52,55 +147,91 @@ impl<Address, Space, SpaceStorage> OffsetAddressSpace<Address, Space, SpaceStora
         }
     }
     pub fn does_overlap<OSpaceStorage: Deref<Target=Space>>(&self, other: &OffsetAddressSpace<Address, Space, OSpaceStorage>) -> bool {
-        let r1 = self.address_range();
-        let r2 = other.address_range();
-        r1.contains(&r2.start) || r1.contains(&r2.end) || r2.contains(&r1.start) || r2.contains(&r1.end)
+        match (self.address_range(), other.address_range()) {
+            (Some(r1), Some(r2)) => r1.start() <= r2.end() && r2.start() <= r1.end(),
+            _ => false,
+        }
     }
 }
 impl<Address, Space, SpaceStorage> AddressSpace<Address> for OffsetAddressSpaceMut<Address, Space, SpaceStorage> where
     Address: AddressType,
     Space: AddressSpace<Address> + ?Sized,
     SpaceStorage: Deref<Target=Space> + DerefMut {
-    fn size(&self) -> Address {
+    fn size(&self) -> u64 {
         self.space.deref().size()
     }
+    fn max_address(&self) -> Option<u64> {
+        self.space.deref().max_address()
+    }
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
-        self.space.read_byte(self.offset.checked_add(&address).ok_or(MemoryError::Overflow)?)
+        self.space.read_byte(address.checked_sub(&self.offset).ok_or(MemoryError::OutOfBounds)?)
     }
     fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
         self.space.deref_mut().write_bytes(address.checked_sub(&self.offset).ok_or(MemoryError::OutOfBounds)?, bytes)
     }
     fn address_in_space(&self, address: Address) -> bool {
-        self.as_ref().address_range().contains(&address)
+        self.as_ref().address_in_space(address)
     }
 }
 impl<Address, Space, SpaceStorage> AddressSpace<Address> for OffsetAddressSpace<Address, Space, SpaceStorage> where
     Address: AddressType,
     Space: AddressSpace<Address> + ?Sized,
     SpaceStorage: Deref<Target=Space> {
-    fn size(&self) -> Address {
+    fn size(&self) -> u64 {
         self.space.deref().size()
     }
+    fn max_address(&self) -> Option<u64> {
+        self.space.deref().max_address()
+    }
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
-        self.space.read_byte(self.offset.checked_add(&address).ok_or(MemoryError::Overflow)?)
+        self.space.read_byte(address.checked_sub(&self.offset).ok_or(MemoryError::OutOfBounds)?)
     }
     fn address_in_space(&self, address: Address) -> bool {
-        self.address_range().contains(&address)
+        address.checked_sub(&self.offset).is_some_and(|relative| self.space.address_in_space(relative))
     }
 }
 impl<'a, Address: AddressType> SparseAddressSpace< Address> {
-    pub fn new(size: Address) -> SparseAddressSpace< Address> {
+    /// A space of the addresses below `size`, `Underflow` if `size` is 0.
+    pub fn new(size: Address) -> Result<SparseAddressSpace<Address>, MemoryError> {
+        Ok(SparseAddressSpace {
+            spaces: Vec::with_capacity(4),
+            permissions: Vec::with_capacity(4),
+            order: Vec::with_capacity(4),
+            pages: PageTable::default(),
+            paged: true,
+            last: size.checked_sub(&Address::one()).ok_or(MemoryError::Underflow)?
+        })
+    }
+    /// A space covering every address.
+    pub fn full() -> SparseAddressSpace<Address> {
         SparseAddressSpace {
             spaces: Vec::with_capacity(4),
-            size
+            permissions: Vec::with_capacity(4),
+            order: Vec::with_capacity(4),
+            pages: PageTable::default(),
+            paged: true,
+            last: Address::max_value()
         }
     }
+    /// A space covering every address that looks every access up by binary search instead of
+    /// through the page table, for the benchmarks to compare against.
+    #[doc(hidden)]
+    pub fn full_without_pages() -> SparseAddressSpace<Address> {
+        SparseAddressSpace { paged: false, ..Self::full() }
+    }
     pub fn add_space(&mut self, offset: Address, new_space: Box<dyn AddressSpace<Address>>) -> Result<(), MemoryError>  {
-        if offset+new_space.as_ref().size() > self.size {
-            return Err(MemoryError::Overflow)
+        self.add_space_with_permissions(offset, new_space, Permissions::ALL)
+    }
+    /// Maps a space that only allows cores the accesses in `permissions`.
+    pub fn add_space_with_permissions(&mut self, offset: Address, new_space: Box<dyn AddressSpace<Address>>,
+                                      permissions: Permissions) -> Result<(), MemoryError> {
+        if let Some(max) = new_space.max_address() {
+            if offset.as_u64().checked_add(max).ok_or(MemoryError::Overflow)? > self.last.as_u64() {
+                return Err(MemoryError::Overflow)
+            }
         }
         let new_offset_space = OffsetAddressSpace {
-            offset: offset,
+            offset,
             space: new_space
         };
         for space in self.spaces.iter() {
@@ -109,11 +240,63 @@ impl<'a, Address: AddressType> SparseAddressSpace< Address> {
             }
         }
         let position = self.find_space_position(offset).err().ok_or(MemoryError::Overlap)?;
-        self.spaces.insert(position, new_offset_space);
+        if let Some(range) = new_offset_space.address_range() {
+            self.pages.add(self.spaces.len(), range.start().as_u64(), range.end().as_u64());
+        }
+        self.order.insert(position, self.spaces.len());
+        self.spaces.push(new_offset_space);
+        self.permissions.push(permissions);
+        Ok(())
+    }
+    /// Maps a memory-mapped device at `offset`.
+    pub fn add_device(&mut self, offset: Address, device: Box<dyn Device>) -> Result<(), MemoryError> {
+        self.add_space(offset, Box::new(DeviceSpace::new(device)))
+    }
+    //The part of a `len` byte access at `address` that starts `done` bytes in: the space it's in,
+    //where it starts in that space and how many bytes of the access it holds
+    fn part(&self, address: Address, done: usize, len: usize) -> Result<(usize, Address, usize), MemoryError> {
+        let address = offset_address(address, done).map_err(|_| MemoryError::InvalidAccess)?;
+        let i = self.find(address).ok_or(MemoryError::InvalidAccess)?;
+        let space = &self.spaces[i];
+        let start = address - space.offset;
+        //Bytes from `start` to the end of the space, less one so a full 64 bit space fits
+        let left = space.space.max_address().ok_or(MemoryError::InvalidAccess)? - start.as_u64();
+        Ok((i, start, left.min((len - done - 1) as u64) as usize + 1))
+    }
+    /// The permissions of the region holding an address, `None` if nothing is mapped there.
+    pub fn permissions(&self, address: Address) -> Option<Permissions> {
+        self.find(address).map(|i| self.permissions[i])
+    }
+    //Faults at the first byte of a `len` byte access that isn't mapped or isn't allowed
+    fn check(&self, address: Address, len: usize, access: Access) -> Result<(), MemoryError> {
+        let mut done = 0;
+        while done < len {
+            let fault_address = address.as_u64().wrapping_add(done as u64);
+            let (i, _, part) = self.part(address, done, len)
+                .map_err(|_| MemoryError::AccessFault { address: fault_address, kind: access.kind })?;
+            if !self.permissions[i].allows(access) {
+                return Err(MemoryError::PermissionFault { address: fault_address, kind: access.kind });
+            }
+            done += part;
+        }
         Ok(())
     }
+    //The index of the space holding an address, looked up in the pages when it can be
+    fn find(&self, address: Address) -> Option<usize> {
+        if !self.paged {
+            return self.find_space_position(address).ok().map(|position| self.order[position]);
+        }
+        match self.pages.get(address.as_u64()) {
+            Page::Unmapped => None,
+            Page::Whole(i) => Some(i as usize),
+            Page::Part(i) => Some(i as usize).filter(|&i| self.spaces[i].address_in_space(address)),
+            Page::Mixed => self.find_space_position(address).ok().map(|position| self.order[position]),
+        }
+    }
+    //The position in `order` of the space holding an address, or where a space there would go
     fn find_space_position(&self, containing_address: Address) -> Result<usize, usize> {
-        self.spaces.binary_search_by(|space| {
+        self.order.binary_search_by(|&i| {
+            let space = &self.spaces[i];
             if space.offset > containing_address {
                 Ordering::Greater
             } else if space.address_in_space(containing_address) {
@@ -124,43 +307,252 @@ impl<'a, Address: AddressType> SparseAddressSpace< Address> {
         })
     }
     pub fn find_space(&'a self, containing_address: Address) -> Option<OffsetAddressSpace<Address, dyn AddressSpace<Address>+'a, &'a dyn AddressSpace<Address>>> {
-        let i = self.find_space_position(containing_address).ok()?;
-        let oa = self.spaces.get(i)?;
-        let out = OffsetAddressSpace { offset: oa.offset, space: oa.space.deref() };
-        return Some(out);
-
+        let oa = &self.spaces[self.find(containing_address)?];
+        Some(OffsetAddressSpace { offset: oa.offset, space: oa.space.deref() })
     }
-
-    pub fn find_space_mut(&'a mut self, containing_address: Address) -> Option<OffsetAddressSpace<Address, dyn AddressSpace<Address>+'a, &'a mut dyn AddressSpace<Address>>> {
-        let i = self.find_space_position(containing_address).ok()?;
-        let oa = self.spaces.get_mut(i)?;
-        let space = oa.space.deref_mut();
-        //FIXME:
-        // work around for https://github.com/rust-lang/rust/issues/53613
-        let fixxed_space: &mut dyn AddressSpace<Address> = unsafe {
-            std::mem::transmute(space)
-        };
-
-        Some(OffsetAddressSpace { offset: oa.offset, space: fixxed_space })
-
+    pub fn find_space_mut(&mut self, containing_address: Address) -> Option<OffsetAddressSpace<Address, dyn AddressSpace<Address>, &mut (dyn AddressSpace<Address> + 'static)>> {
+        let i = self.find(containing_address)?;
+        let oa = &mut self.spaces[i];
+        Some(OffsetAddressSpace { offset: oa.offset, space: oa.space.deref_mut() })
+    }
+}
+//A region's error for an access from `start` in the region, which is mapped at `offset`, with
+//its faults moved to the space's addresses
+fn region_fault(error: MemoryError, offset: u64, start: u64, access: Access) -> MemoryError {
+    match error {
+        MemoryError::AccessFault { address, kind } => MemoryError::AccessFault { address: offset + address, kind },
+        MemoryError::PermissionFault { address, kind } => MemoryError::PermissionFault { address: offset + address, kind },
+        error => error.fault(offset + start, access.kind),
     }
 }
+//The `len` bytes from `start` when the space is plain memory holding them, to copy without
+//going through the space
+fn direct<Address: AddressType>(space: &dyn AddressSpace<Address>, start: Address, len: usize) -> Option<&[u8]> {
+    let start = usize::try_from(start.as_u64()).ok()?;
+    space.memory()?.get(start..start.checked_add(len)?)
+}
+fn direct_mut<Address: AddressType>(space: &mut dyn AddressSpace<Address>, start: Address, len: usize) -> Option<&mut [u8]> {
+    let start = usize::try_from(start.as_u64()).ok()?;
+    space.memory_mut()?.get_mut(start..start.checked_add(len)?)
+}
 impl< Address: AddressType> AddressSpace<Address> for SparseAddressSpace< Address> {
-    fn size(&self) -> Address {
-        self.size.clone()
+    fn size(&self) -> u64 {
+        self.last.as_u64().saturating_add(1)
+    }
+    fn max_address(&self) -> Option<u64> {
+        Some(self.last.as_u64())
+    }
+    fn address_in_space(&self, address: Address) -> bool {
+        address <= self.last
     }
 
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
-        self.find_space(address).ok_or(MemoryError::InvalidAccess)?.read_byte(address)
+        let space = &self.spaces[self.find(address).ok_or(MemoryError::InvalidAccess)?];
+        let start = address - space.offset;
+        match direct(space.space.deref(), start, 1) {
+            Some(bytes) => Ok(bytes[0]),
+            None => space.space.read_byte(start),
+        }
     }
-    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
-        let space =  self.find_space_mut(address).ok_or(MemoryError::InvalidAccess)?;
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let i = self.find(address).ok_or(MemoryError::InvalidAccess)?;
+        let space = &mut self.spaces[i];
         let start = address - space.offset;
-        let end = start+Address::from_usize(bytes.len()).ok_or(MemoryError::Overflow)?;
-        if end > space.offset.checked_add(&space.space.size()).ok_or(MemoryError::Overflow)? {
-            Err(MemoryError::InvalidAccess)
-        } else {
-            space.space.write_bytes(start, bytes)
+        match direct(space.space.deref(), start, 1) {
+            Some(bytes) => Ok(bytes[0]),
+            None => space.space.read_byte_mut(start),
+        }
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        let mut done = 0;
+        while done < out.len() {
+            let (i, start, len) = self.part(address, done, out.len())?;
+            let (space, out) = (self.spaces[i].space.deref(), &mut out[done..done + len]);
+            match direct(space, start, len) {
+                Some(bytes) => out.copy_from_slice(bytes),
+                None => space.read_bytes(start, out)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        let mut done = 0;
+        while done < out.len() {
+            let (i, start, len) = self.part(address, done, out.len())?;
+            let (space, out) = (self.spaces[i].space.deref_mut(), &mut out[done..done + len]);
+            match direct(space, start, len) {
+                Some(bytes) => out.copy_from_slice(bytes),
+                None => space.read_bytes_mut(start, out)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        //Nothing is written unless every byte is mapped
+        let mut done = 0;
+        while done < bytes.len() {
+            done += self.part(address, done, bytes.len())?.2;
         }
+        done = 0;
+        while done < bytes.len() {
+            let (i, start, len) = self.part(address, done, bytes.len())?;
+            let (space, bytes) = (self.spaces[i].space.deref_mut(), &bytes[done..done + len]);
+            match direct_mut(space, start, len) {
+                Some(memory) => memory.copy_from_slice(bytes),
+                None => space.write_bytes(start, bytes)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+    fn read_bytes_checked(&mut self, address: Address, out: &mut [u8], access: Access) -> Result<(), MemoryError> {
+        self.check(address, out.len(), access)?;
+        let mut done = 0;
+        while done < out.len() {
+            let (i, start, len) = self.part(address, done, out.len())?;
+            let space = &mut self.spaces[i];
+            space.space.read_bytes_checked(start, &mut out[done..done + len], access)
+                .map_err(|e| region_fault(e, space.offset.as_u64(), start.as_u64(), access))?;
+            done += len;
+        }
+        Ok(())
+    }
+    fn write_bytes_checked(&mut self, address: Address, bytes: &[u8], access: Access) -> Result<(), MemoryError> {
+        self.check(address, bytes.len(), access)?;
+        let mut done = 0;
+        while done < bytes.len() {
+            let (i, start, len) = self.part(address, done, bytes.len())?;
+            let space = &mut self.spaces[i];
+            space.space.write_bytes_checked(start, &bytes[done..done + len], access)
+                .map_err(|e| region_fault(e, space.offset.as_u64(), start.as_u64(), access))?;
+            done += len;
+        }
+        Ok(())
+    }
+    fn tick(&mut self, cycles: u64) {
+        for space in self.spaces.iter_mut() {
+            space.space.tick(cycles);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::bits::Endianness;
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+    use crate::microvm::memory::overlay::Overlay;
+    use crate::microvm::memory::rom::ROM;
+    use crate::microvm::memory::zero::ZerosSpace;
+
+    #[test]
+    fn wide_accesses() {
+        let mut space = SparseAddressSpace::new(0x100u16).unwrap();
+        space.add_space(0x10, Box::new(DenseStaticMemory::new(0x10u16))).unwrap();
+        space.add_space(0x20, Box::new(DenseStaticMemory::new(0x10u16))).unwrap();
+        space.add_space(0x30, Box::new(ROM::new(0x10u16))).unwrap();
+        space.write_u32(0x1C, 0x11223344, Endianness::Big).unwrap();
+        assert_eq!(space.read_u16(0x1C, Endianness::Little), Ok(0x2211));
+        //Straddles the two RAM regions
+        space.write_bytes(0x1E, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(space.read_u32(0x20, Endianness::Little), Ok(0x0403));
+        let mut bytes = [0; 6];
+        space.read_bytes(0x1C, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x11, 0x22, 1, 2, 3, 4]);
+        space.write_u64(0x28, 0x0102030405060708, Endianness::Little).unwrap();
+        assert_eq!(space.read_u64(0x28, Endianness::Big), Ok(0x0807060504030201));
+        assert_eq!(space.read_u32(0x1E, Endianness::Little), Err(MemoryError::Misaligned));
+        assert_eq!(space.write_u16(0x21, 0, Endianness::Little), Err(MemoryError::Misaligned));
+        assert_eq!(space.read_bytes(0x0E, &mut bytes), Err(MemoryError::InvalidAccess));
+        //Nothing is written when part of the access isn't mapped
+        assert_eq!(space.write_bytes(0x0F, &[9, 9]), Err(MemoryError::InvalidAccess));
+        assert_eq!(space.read_byte(0x10), Ok(0));
+        assert_eq!(space.write_bytes(0x2F, &[9, 9]), Err(MemoryError::ReadOnly));
+    }
+    #[test]
+    fn page_lookups() {
+        use crate::microvm::device::{InterruptLines, Timer};
+        let mut space = SparseAddressSpace::<u16>::full();
+        space.add_space(0x40, Box::new(DenseStaticMemory::new(0x140u16))).unwrap();
+        space.add_device(0x180, Box::new(Timer::new(InterruptLines::new().line(0)))).unwrap();
+        assert_eq!((space.pages.get(0x0), space.pages.get(0x100), space.pages.get(0x200)), (Page::Part(0), Page::Mixed, Page::Unmapped));
+        space.write_byte(0x17F, 1).unwrap();
+        assert_eq!((space.read_byte(0x3F), space.read_byte(0x17F)), (Err(MemoryError::InvalidAccess), Ok(1)));
+        assert_eq!((space.read_byte(0x18C), space.read_byte(0x190)), (Ok(0), Err(MemoryError::InvalidAccess)));
+        assert_eq!(space.find_space_mut(0x100).map(|space| space.offset()), Some(0x40));
+        //Across the table's first and second level
+        let mut space = SparseAddressSpace::<u32>::full();
+        space.add_space(0xF_FF80, Box::new(DenseStaticMemory::new(0x200u32))).unwrap();
+        assert_eq!((space.pages.get(0xF_FF00), space.pages.get(0x10_0000), space.pages.get(0x10_0100)), (Page::Part(0), Page::Whole(0), Page::Part(0)));
+        space.write_bytes(0xF_FFFE, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(space.read_u16(0x10_0000, Endianness::Little), Ok(0x0403));
+        //Nearly 4G sharing its first page, with whole chunks kept as one entry each
+        let mut space = SparseAddressSpace::<u64>::full();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x80u64))).unwrap();
+        space.add_space(0x80, Box::new(ZerosSpace::new((1 << 32) - 0x100))).unwrap();
+        let expanded = space.pages.chunks.iter().filter(|chunk| matches!(chunk, Chunk::Pages(_))).count();
+        assert_eq!(expanded, 2);
+        assert_eq!((space.pages.get(0), space.pages.get(0x8000_0000), space.pages.get(0xFFFF_FF00)),
+                   (Page::Mixed, Page::Whole(1), Page::Part(1)));
+        space.write_byte(0x7F, 1).unwrap();
+        assert_eq!((space.read_byte(0x7F), space.read_byte(0xFFFF_FF7F)), (Ok(1), Ok(0)));
+        assert_eq!(space.read_byte(0xFFFF_FF80), Err(MemoryError::InvalidAccess));
+    }
+    #[test]
+    fn permission_faults() {
+        use crate::microvm::mmu::AccessType::{Fetch, Load, Store};
+        let mut space = SparseAddressSpace::new(0x100u16).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x40u16))).unwrap();
+        let read_only = Permissions::parse("rx").unwrap();
+        space.add_space_with_permissions(0x40, Box::new(DenseStaticMemory::new(0x40u16)), read_only).unwrap();
+        space.add_space_with_permissions(0x80, Box::new(DenseStaticMemory::new(0x40u16)), Permissions::parse("rwp").unwrap()).unwrap();
+        let (user, privileged) = (|kind| Access { kind, privileged: false }, |kind| Access { kind, privileged: true });
+        let mut bytes = [0; 4];
+        space.write_bytes_checked(0x3E, &[1, 2], user(Store)).unwrap();
+        assert_eq!(space.write_bytes_checked(0x3E, &[1, 2, 3], user(Store)), Err(MemoryError::PermissionFault { address: 0x40, kind: Store }));
+        assert_eq!(space.read_byte(0x40), Ok(0));
+        space.read_bytes_checked(0x3E, &mut bytes, user(Fetch)).unwrap();
+        assert_eq!(space.read_bytes_checked(0x7E, &mut bytes, user(Load)), Err(MemoryError::PermissionFault { address: 0x80, kind: Load }));
+        space.read_bytes_checked(0x80, &mut bytes, privileged(Load)).unwrap();
+        assert_eq!(space.read_bytes_checked(0x80, &mut bytes, privileged(Fetch)), Err(MemoryError::PermissionFault { address: 0x80, kind: Fetch }));
+        assert_eq!(space.read_bytes_checked(0xBE, &mut bytes, privileged(Load)), Err(MemoryError::AccessFault { address: 0xC0, kind: Load }));
+        //A debugger's accesses aren't checked
+        space.write_byte(0x40, 5).unwrap();
+        assert_eq!((space.permissions(0x41), space.permissions(0xC0)), (Some(read_only), None));
+    }
+    #[test]
+    fn full_spaces() {
+        let mut space = SparseAddressSpace::<u64>::full();
+        assert_eq!(space.size(), u64::MAX);
+        space.add_space(u64::MAX - 0xFFF, Box::new(DenseStaticMemory::new(0x1000u64))).unwrap();
+        space.add_space(0x8000_0000, Box::new(DenseStaticMemory::new(0x1000u64))).unwrap();
+        space.write_u64(u64::MAX - 7, 0x0102030405060708, Endianness::Little).unwrap();
+        assert_eq!(space.read_byte(u64::MAX), Ok(1));
+        assert_eq!(space.write_bytes(u64::MAX, &[0, 0]), Err(MemoryError::InvalidAccess));
+        assert_eq!(space.add_space(u64::MAX, Box::new(DenseStaticMemory::new(1u64))).err(), Some(MemoryError::Overlap));
+        assert_eq!(space.add_space(0x8000_0FFF, Box::new(DenseStaticMemory::new(2u64))).err(), Some(MemoryError::Overlap));
+        assert!(space.add_space(0x8000_1000, Box::new(DenseStaticMemory::new(2u64))).is_ok());
+
+        //Wrapped, the space still reaches the last address
+        assert_eq!((space.max_address(), space.address_in_space(u64::MAX)), (Some(u64::MAX), true));
+        let mut overlay = Overlay::new(Box::new(space));
+        assert!(overlay.address_in_space(u64::MAX));
+        overlay.write_byte(u64::MAX, 9).unwrap();
+        assert_eq!(overlay.read_byte(u64::MAX), Ok(9));
+        assert_eq!(overlay.write_bytes(u64::MAX, &[0, 0]), Err(MemoryError::OutOfBounds));
+
+        let mut space = SparseAddressSpace::<u32>::full();
+        assert_eq!(space.size(), 1 << 32);
+        space.add_space(0xFFFF_0000, Box::new(DenseStaticMemory::new(0x10000u64))).unwrap();
+        assert_eq!(space.read_u32(0xFFFF_FFFC, Endianness::Little), Ok(0));
+        let mut space = SparseAddressSpace::new(0x100u16).unwrap();
+        assert_eq!(space.add_space(0xF0, Box::new(DenseStaticMemory::new(0x11u64))).err(), Some(MemoryError::Overflow));
+        assert_eq!(SparseAddressSpace::new(0u16).err(), Some(MemoryError::Underflow));
+
+        let region: Region<u16> = OffsetAddressSpace { offset: 0x10, space: Box::new(DenseStaticMemory::new(0x10u16)) };
+        assert_eq!(region.relative_range(0x12..0x14), Ok(2..4));
+        assert_eq!(region.relative_range(Range { start: 0x12, end: 0x8 }), Err(MemoryError::OutOfBounds));
+        assert_eq!(region.sub_offset(0x8..0x12), Err(MemoryError::Underflow));
     }
 }
@@ -0,0 +1,115 @@
+//! RAM that knows which of its bytes have been written, for catching firmware that reads RAM
+//! before setting it.
+use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::{AddressSpace, DenseStaticMemory, Fill};
+use crate::microvm::memory::MemoryError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The reads of never-written bytes in strict RAM, shared by the RAM and whoever reports them.
+#[derive(Clone, Debug, Default)]
+pub struct UninitializedReads(Rc<RefCell<Vec<u64>>>);
+impl UninitializedReads {
+    pub fn new() -> UninitializedReads {
+        UninitializedReads::default()
+    }
+    /// The addresses read since the last call, as runs of address and length.
+    pub fn take(&self) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for address in self.0.borrow_mut().drain(..) {
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == address => *len += 1,
+                _ => runs.push((address, 1)),
+            }
+        }
+        runs
+    }
+}
+/// RAM whose machine reads of bytes that were never written are reported, once for each byte,
+/// with the addresses where it's mapped. A debugger's reads aren't.
+pub struct StrictRam {
+    memory: DenseStaticMemory,
+    //A bit for each byte
+    written: Vec<u64>,
+    base: u64,
+    reads: UninitializedReads,
+}
+impl StrictRam {
+    /// `size` bytes of RAM mapped at `base` and holding `fill` until written.
+    pub fn new(size: u64, fill: Fill, base: u64, reads: &UninitializedReads) -> StrictRam {
+        let memory = DenseStaticMemory::filled(size, fill);
+        StrictRam { memory, written: vec![0; size.div_ceil(64) as usize], base, reads: reads.clone() }
+    }
+    pub fn is_written(&self, offset: u64) -> bool {
+        self.written.get((offset / 64) as usize).is_some_and(|bits| bits >> (offset % 64) & 1 != 0)
+    }
+    fn mark(&mut self, offset: u64, len: usize) {
+        for offset in offset..offset + len as u64 {
+            self.written[(offset / 64) as usize] |= 1 << (offset % 64);
+        }
+    }
+    //Reports the bytes of a read that were never written
+    fn check(&mut self, offset: u64, len: usize) {
+        for offset in offset..offset + len as u64 {
+            if !self.is_written(offset) {
+                self.reads.0.borrow_mut().push(self.base + offset);
+                self.mark(offset, 1);
+            }
+        }
+    }
+}
+impl<Address: AddressType> AddressSpace<Address> for StrictRam {
+    fn size(&self) -> u64 {
+        self.memory.size()
+    }
+    fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
+        self.memory.read_byte(address)
+    }
+    fn read_byte_mut(&mut self, address: Address) -> Result<u8, MemoryError> {
+        let byte = self.memory.read_byte(address)?;
+        self.check(address.as_u64(), 1);
+        Ok(byte)
+    }
+    fn read_bytes(&self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.memory.read_bytes(address, out)
+    }
+    fn read_bytes_mut(&mut self, address: Address, out: &mut [u8]) -> Result<(), MemoryError> {
+        self.memory.read_bytes(address, out)?;
+        self.check(address.as_u64(), out.len());
+        Ok(())
+    }
+    fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), MemoryError> {
+        self.memory.write_bytes(address, bytes)?;
+        self.mark(address.as_u64(), bytes.len());
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+
+    #[test]
+    fn fills_and_uninitialized_reads() {
+        let bytes = |fill| (0..4).map(|offset| Fill::byte(fill, offset)).collect::<Vec<u8>>();
+        assert_eq!(bytes(Fill::Checkerboard), vec![0x55, 0xAA, 0x55, 0xAA]);
+        assert_eq!(bytes(Fill::Ones), vec![0xFF; 4]);
+        assert_eq!(bytes(Fill::Random(7)), bytes(Fill::parse("random:7").unwrap()));
+        assert_ne!(bytes(Fill::Random(7)), bytes(Fill::Random(8)));
+        assert_eq!((Fill::parse("random"), Fill::parse("0xff")), (Some(Fill::Random(0)), None));
+
+        let reads = UninitializedReads::new();
+        let mut space = SparseAddressSpace::<u16>::full();
+        space.add_space(0x200, Box::new(StrictRam::new(0x100, Fill::Ones, 0x200, &reads))).unwrap();
+        space.write_bytes(0x210, &[1, 2]).unwrap();
+        assert_eq!(space.read_u32(0x210, crate::microvm::bits::Endianness::Little), Ok(0xFFFF_0201));
+        assert!(reads.take().is_empty(), "a debugger's reads aren't reported");
+        let mut out = [0; 4];
+        space.read_bytes_mut(0x20F, &mut out).unwrap();
+        space.read_byte_mut(0x2FF).unwrap();
+        assert_eq!(out, [0xFF, 1, 2, 0xFF]);
+        assert_eq!(reads.take(), vec![(0x20F, 1), (0x212, 1), (0x2FF, 1)]);
+        space.read_bytes_mut(0x20F, &mut out).unwrap();
+        assert!(reads.take().is_empty(), "each byte is reported once");
+    }
+}
@@ -3,11 +3,16 @@ use crate::microvm::memory::address::AddressType;
 use crate::microvm::memory::MemoryError;
 
 pub struct ZerosSpace {
-    size: usize
+    size: u64
+}
+impl ZerosSpace {
+    pub fn new(size: u64) -> ZerosSpace {
+        ZerosSpace { size }
+    }
 }
 impl<Address: AddressType> AddressSpace<Address> for ZerosSpace {
-    fn size(&self) -> Address {
-        Address::from_usize(self.size).expect("size too big")
+    fn size(&self) -> u64 {
+        self.size
     }
 
     fn read_byte(&self, address: Address) -> Result<u8, MemoryError> {
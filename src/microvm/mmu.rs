@@ -1,7 +1,308 @@
+//! RISC-V virtual memory: `satp` driven Sv32, Sv39 and Sv48 page walks over a physical space.
 use super::memory;
+use crate::microvm::bits::Endianness;
 use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use std::collections::HashMap;
 
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+//Wide enough for an Sv48 virtual page number, Sv32 and Sv39 numbers are masked the same way
+const VPN_MASK: u64 = (1 << 36) - 1;
+const TLB_CAPACITY: usize = 256;
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PagingMode {
+    Bare,
+    Sv32,
+    Sv39,
+    Sv48,
+}
+impl PagingMode {
+    pub fn levels(self) -> u32 {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv32 => 2,
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+    fn vpn_bits(self) -> u32 {
+        if self == PagingMode::Sv32 { 10 } else { 9 }
+    }
+    fn pte_size(self) -> usize {
+        if self == PagingMode::Sv32 { 4 } else { 8 }
+    }
+    fn ppn_mask(self) -> u64 {
+        if self == PagingMode::Sv32 { (1 << 22) - 1 } else { (1 << 44) - 1 }
+    }
+    fn virtual_bits(self) -> u32 {
+        PAGE_SHIFT + self.levels() * self.vpn_bits()
+    }
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+/// Translation state gathered from `satp`, `mstatus` and the effective privilege level.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Paging {
+    pub mode: PagingMode,
+    //Physical page number of the root page table
+    pub root: u64,
+    pub asid: u64,
+    //The access is made from U-mode
+    pub user: bool,
+    pub sum: bool,
+    pub mxr: bool,
+}
+impl Paging {
+    pub fn bare() -> Paging {
+        Paging { mode: PagingMode::Bare, root: 0, asid: 0, user: false, sum: false, mxr: false }
+    }
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TranslationFault {
+    Page,
+    Access,
+}
+//Cached leaf, superpages are cached per 4KiB page
+#[derive(Copy, Clone, Debug)]
+struct TlbEntry {
+    asid: u64,
+    global: bool,
+    ppn: u64,
+    flags: u64,
+}
 pub struct MMU<Address: AddressType> {
-    space: memory::sparse::SparseAddressSpace<Address>
-}
\ No newline at end of file
+    space: memory::sparse::SparseAddressSpace<Address>,
+    tlb: HashMap<u64, TlbEntry>,
+}
+impl<Address: AddressType> MMU<Address> {
+    pub fn new(space: memory::sparse::SparseAddressSpace<Address>) -> MMU<Address> {
+        MMU {
+            space,
+            tlb: HashMap::new(),
+        }
+    }
+    pub fn space(&self) -> &memory::sparse::SparseAddressSpace<Address> {
+        &self.space
+    }
+    pub fn space_mut(&mut self) -> &mut memory::sparse::SparseAddressSpace<Address> {
+        &mut self.space
+    }
+    pub fn into_space(self) -> memory::sparse::SparseAddressSpace<Address> {
+        self.space
+    }
+    /// Translates a virtual address to a physical one, walking the page tables on a TLB miss and
+    /// setting the accessed and dirty bits of the leaf entry.
+    pub fn translate(&mut self, address: u64, access: AccessType, paging: &Paging) -> Result<u64, TranslationFault> {
+        self.translate_checked(address, access, paging, |_, _, _| true)
+    }
+    /// `translate` with every page table access first passed to `permitted`, which gets the
+    /// physical address, size and kind of the access. A refused access is an access fault.
+    pub fn translate_checked(&mut self, address: u64, access: AccessType, paging: &Paging,
+                             permitted: impl Fn(u64, usize, AccessType) -> bool) -> Result<u64, TranslationFault> {
+        if paging.mode == PagingMode::Bare {
+            return Ok(address);
+        }
+        //Sv39 and Sv48 addresses must be sign extended from their top bit
+        if paging.mode != PagingMode::Sv32 {
+            let upper = (address as i64) >> (paging.mode.virtual_bits() - 1);
+            if upper != 0 && upper != -1 {
+                return Err(TranslationFault::Page);
+            }
+        }
+        let vpn = (address >> PAGE_SHIFT) & VPN_MASK;
+        let cached = self.tlb.get(&vpn).copied()
+            .filter(|entry| entry.global || entry.asid == paging.asid)
+            .filter(|entry| access != AccessType::Store || entry.flags & PTE_D != 0);
+        let entry = match cached {
+            Some(entry) => {
+                Self::check_permissions(entry.flags, access, paging)?;
+                entry
+            },
+            None => {
+                let (mut entry, pte_address) = self.walk(vpn, paging, &permitted)?;
+                Self::check_permissions(entry.flags, access, paging)?;
+                let updated = entry.flags | PTE_A | if access == AccessType::Store { PTE_D } else { 0 };
+                if updated != entry.flags {
+                    if !permitted(pte_address, paging.mode.pte_size(), AccessType::Store) {
+                        return Err(TranslationFault::Access);
+                    }
+                    self.update_pte(pte_address, updated)?;
+                    entry.flags = updated;
+                }
+                if self.tlb.len() >= TLB_CAPACITY {
+                    self.tlb.clear();
+                }
+                self.tlb.insert(vpn, entry);
+                entry
+            },
+        };
+        Ok((entry.ppn << PAGE_SHIFT) | (address & (PAGE_SIZE - 1)))
+    }
+    /// SFENCE.VMA, `None` for either argument matches every address or address space.
+    pub fn flush(&mut self, address: Option<u64>, asid: Option<u64>) {
+        let matches = |entry: &TlbEntry| asid.is_none_or(|asid| !entry.global && entry.asid == asid);
+        match address {
+            Some(address) => {
+                let vpn = (address >> PAGE_SHIFT) & VPN_MASK;
+                if self.tlb.get(&vpn).is_some_and(matches) {
+                    self.tlb.remove(&vpn);
+                }
+            },
+            None => self.tlb.retain(|_, entry| !matches(entry)),
+        }
+    }
+    fn read_pte(&self, address: u64, size: usize) -> Result<u64, TranslationFault> {
+        let physical = Address::from_u64(address).ok_or(TranslationFault::Access)?;
+        let pte = if size == 4 {
+            self.space.read_u32(physical, Endianness::Little).map(u64::from)
+        } else {
+            self.space.read_u64(physical, Endianness::Little)
+        };
+        pte.map_err(|_| TranslationFault::Access)
+    }
+    //Only the low byte holding the flags ever changes
+    fn update_pte(&mut self, address: u64, flags: u64) -> Result<(), TranslationFault> {
+        let physical = Address::from_u64(address).ok_or(TranslationFault::Access)?;
+        self.space.write_byte(physical, flags as u8).map_err(|_| TranslationFault::Access)
+    }
+    //Returns the leaf entry and the physical address of its PTE
+    fn walk(&self, vpn: u64, paging: &Paging, permitted: &impl Fn(u64, usize, AccessType) -> bool) -> Result<(TlbEntry, u64), TranslationFault> {
+        let mode = paging.mode;
+        let vpn_bits = mode.vpn_bits();
+        let mut table = paging.root << PAGE_SHIFT;
+        for level in (0..mode.levels()).rev() {
+            let index = (vpn >> (level * vpn_bits)) & ((1 << vpn_bits) - 1);
+            let pte_address = table + index * mode.pte_size() as u64;
+            if !permitted(pte_address, mode.pte_size(), AccessType::Load) {
+                return Err(TranslationFault::Access);
+            }
+            let pte = self.read_pte(pte_address, mode.pte_size())?;
+            //Bits 63:54 are reserved or belong to the unimplemented Svpbmt and Svnapot
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+                return Err(TranslationFault::Page);
+            }
+            let ppn = (pte >> 10) & mode.ppn_mask();
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+            let superpage_mask = (1 << (level * vpn_bits)) - 1;
+            if ppn & superpage_mask != 0 {
+                return Err(TranslationFault::Page);
+            }
+            let entry = TlbEntry {
+                asid: paging.asid,
+                global: pte & PTE_G != 0,
+                ppn: ppn | (vpn & superpage_mask),
+                flags: pte & 0xFF,
+            };
+            return Ok((entry, pte_address));
+        }
+        Err(TranslationFault::Page)
+    }
+    fn check_permissions(flags: u64, access: AccessType, paging: &Paging) -> Result<(), TranslationFault> {
+        let user_page = flags & PTE_U != 0;
+        //S-mode may only read and write user pages with SUM set and never execute them
+        let privilege_ok = if paging.user {
+            user_page
+        } else {
+            !user_page || (paging.sum && access != AccessType::Fetch)
+        };
+        let allowed = match access {
+            AccessType::Fetch => flags & PTE_X != 0,
+            AccessType::Load => flags & PTE_R != 0 || (paging.mxr && flags & PTE_X != 0),
+            AccessType::Store => flags & PTE_W != 0,
+        };
+        if privilege_ok && allowed {
+            Ok(())
+        } else {
+            Err(TranslationFault::Page)
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+
+    fn pte(ppn: u64, flags: u64) -> u64 {
+        (ppn << 10) | flags | PTE_V
+    }
+    fn mmu_with_tables(entries: &[(u64, u64)], pte_size: usize) -> MMU<u16> {
+        let mut space = SparseAddressSpace::new(0xFFFF).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x8000u16))).unwrap();
+        let mut mmu = MMU::new(space);
+        for &(address, value) in entries {
+            mmu.space_mut().write_bytes(address as u16, &value.to_le_bytes()[..pte_size]).unwrap();
+        }
+        mmu
+    }
+    fn sv39(user: bool) -> Paging {
+        Paging { mode: PagingMode::Sv39, root: 1, asid: 0, user, sum: false, mxr: false }
+    }
+
+    #[test]
+    fn sv39_walk_sets_accessed_and_dirty() {
+        //0x4000_2000 -> root[1] -> table at 0x2000, [0] -> table at 0x3000, [2] -> page 5
+        let mut mmu = mmu_with_tables(&[
+            (0x1000 + 8, pte(2, 0)),
+            (0x2000, pte(3, 0)),
+            (0x3000 + 2 * 8, pte(5, PTE_R | PTE_W)),
+        ], 8);
+        let paging = sv39(false);
+        assert_eq!(mmu.translate(0x4000_2123, AccessType::Load, &paging), Ok(0x5123));
+        assert_eq!(mmu.space().read_byte(0x3010).unwrap() as u64 & (PTE_A | PTE_D), PTE_A);
+        assert_eq!(mmu.translate(0x4000_2008, AccessType::Store, &paging), Ok(0x5008));
+        assert_eq!(mmu.space().read_byte(0x3010).unwrap() as u64 & (PTE_A | PTE_D), PTE_A | PTE_D);
+        assert_eq!(mmu.translate(0x4000_2000, AccessType::Fetch, &paging), Err(TranslationFault::Page));
+        assert_eq!(mmu.translate(0x4000_3000, AccessType::Load, &paging), Err(TranslationFault::Page));
+        //Not sign extended from bit 38
+        assert_eq!(mmu.translate(0x80_4000_2000, AccessType::Load, &paging), Err(TranslationFault::Page));
+    }
+    #[test]
+    fn superpages_and_user_permissions() {
+        //A 2MiB megapage at 0x20_0000 mapped onto physical 0 and marked as a user page
+        let mut mmu = mmu_with_tables(&[
+            (0x1000, pte(2, 0)),
+            (0x2000 + 8, pte(0, PTE_R | PTE_X | PTE_U)),
+            (0x2000 + 16, pte(1, PTE_R)),
+        ], 8);
+        assert_eq!(mmu.translate(0x20_1234, AccessType::Fetch, &sv39(true)), Ok(0x1234));
+        assert_eq!(mmu.translate(0x20_1234, AccessType::Load, &sv39(false)), Err(TranslationFault::Page));
+        let sum = Paging { sum: true, ..sv39(false) };
+        assert_eq!(mmu.translate(0x20_1234, AccessType::Load, &sum), Ok(0x1234));
+        assert_eq!(mmu.translate(0x20_1234, AccessType::Fetch, &sum), Err(TranslationFault::Page));
+        //Misaligned megapage
+        assert_eq!(mmu.translate(0x40_0000, AccessType::Load, &sv39(false)), Err(TranslationFault::Page));
+    }
+    #[test]
+    fn sv32_and_tlb_flush() {
+        //0x0040_1000 -> root[1] -> table at 0x2000, [1] -> page 6
+        let mut mmu = mmu_with_tables(&[
+            (0x1000 + 4, pte(2, 0)),
+            (0x2000 + 4, pte(6, PTE_R | PTE_A)),
+        ], 4);
+        let paging = Paging { mode: PagingMode::Sv32, ..sv39(false) };
+        assert_eq!(mmu.translate(0x0040_1ABC, AccessType::Load, &paging), Ok(0x6ABC));
+        //Remap the page, the stale translation stays cached until an SFENCE.VMA
+        mmu.space_mut().write_bytes(0x2004, &(pte(7, PTE_R | PTE_A) as u32).to_le_bytes()).unwrap();
+        assert_eq!(mmu.translate(0x0040_1ABC, AccessType::Load, &paging), Ok(0x6ABC));
+        mmu.flush(Some(0x0040_1000), None);
+        assert_eq!(mmu.translate(0x0040_1ABC, AccessType::Load, &paging), Ok(0x7ABC));
+    }
+}
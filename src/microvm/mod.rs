@@ -1,4 +1,8 @@
 pub mod memory;
 pub mod vm;
 pub mod mmu;
-pub mod bits;
\ No newline at end of file
+pub mod bits;
+pub mod loader;
+pub mod image;
+pub mod debug;
+pub mod device;
\ No newline at end of file
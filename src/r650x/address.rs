@@ -12,7 +12,9 @@ pub enum AddressMode {
     AbsoluteY,
     Indirect,
     IndexedIndirect, //($NN, X)
-    IndirectIndexed //($NN) Y
+    IndirectIndexed, //($NN) Y
+    ZeroPageIndirect, //($NN), 65C02
+    AbsoluteIndexedIndirect //($NNNN, X), 65C02
 }
 
 impl AddressMode {
@@ -31,6 +33,8 @@ impl AddressMode {
             AddressMode::Indirect => 2,
             AddressMode::IndexedIndirect => 1,
             AddressMode::IndirectIndexed => 1,
+            AddressMode::ZeroPageIndirect => 1,
+            AddressMode::AbsoluteIndexedIndirect => 2,
         }
     }
 }
\ No newline at end of file
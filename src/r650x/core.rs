@@ -1,112 +1,797 @@
 use super::regs::Regs;
-use crate::r650x::alu::ALU;
+use crate::r650x::address::AddressMode;
+use crate::r650x::decoder::Variant;
+use crate::r650x::timing;
+use crate::r650x::instructions::Instruction;
 use crate::r650x::pipeline::Pipeline;
 use crate::microvm::memory::sparse::SparseAddressSpace;
 use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::permissions::Access;
 use crate::microvm::memory::MemoryError;
-use crate::r650x::flags::{PSRFlag, FlagRegister};
+use crate::microvm::mmu::AccessType;
+use crate::microvm::bits::Endianness;
+use crate::r650x::flags::{PSRFlag, FlagRegister, PSR};
+use crate::microvm::debug::{Debuggable, StepResult};
+use crate::microvm::device::InterruptLines;
+use std::convert::TryFrom;
+
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+//The bits PHP and BRK push on top of the flags
+const BREAK: u8 = 0x10;
+const UNUSED: u8 = 0x20;
+
+/// Why an instruction couldn't run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Fault {
+    UnrecognizedInstruction(u8),
+    Memory(MemoryError),
+}
+impl From<MemoryError> for Fault {
+    fn from(e: MemoryError) -> Fault {
+        Fault::Memory(e)
+    }
+}
+//Where an instruction's operand comes from
+#[derive(Copy, Clone)]
+enum Operand {
+    Implied,
+    Accumulator,
+    Immediate(u8),
+    Address(u16),
+}
 
 pub struct Core {
     pipeline: Pipeline,
     regs: Regs,
-    space: SparseAddressSpace<u16>
+    space: SparseAddressSpace<u16>,
+    variant: Variant,
+    cycles: u64,
+    interrupts: Option<InterruptLines>,
 }
 
 impl Core {
-    fn cycle(&mut self) {
+    /// A `variant` core in the state after a reset, with pc from the reset vector, or 0 if the
+    /// vector isn't mapped.
+    pub fn new(space: SparseAddressSpace<u16>, variant: Variant) -> Core {
+        let pc = space.read_u16(RESET_VECTOR, Endianness::Little).unwrap_or(0);
+        let regs = Regs { pc, sp: 0xFD, accumulator: 0, x: 0, y: 0, psr: PSR::new(0x34) };
+        Core { pipeline: Pipeline::new(), regs, space, variant, cycles: 0, interrupts: None }
+    }
+    pub fn space(&self) -> &SparseAddressSpace<u16> {
+        &self.space
+    }
+    pub fn space_mut(&mut self) -> &mut SparseAddressSpace<u16> {
+        &mut self.space
+    }
+    /// Connects device interrupt lines, sampled before each step. Any raised line pulls IRQ
+    /// low, which is taken through the IRQ vector while the interrupt disable flag is clear.
+    pub fn connect_interrupts(&mut self, lines: InterruptLines) {
+        self.interrupts = Some(lines);
+    }
+    /// The cycles run since reset.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    /// Takes a pending IRQ or fetches and executes a single instruction, then ticks the devices
+    /// for the cycles it took. On a fault the registers and cycle count are left as they were
+    /// before it.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        let (regs, cycles) = (self.regs, self.cycles);
+        match self.execute() {
+            Ok(()) => {
+                self.space.tick(self.cycles - cycles);
+                Ok(())
+            },
+            Err(e) => {
+                self.regs = regs;
+                self.cycles = cycles;
+                Err(e)
+            },
+        }
+    }
+    fn execute(&mut self) -> Result<(), Fault> {
+        use Instruction::*;
+        let irq = self.interrupts.as_ref().is_some_and(|lines| lines.pending() != 0);
+        if irq && !self.flag(PSRFlag::InterruptDisable) {
+            return self.interrupt().map_err(Fault::from);
+        }
+        let opcode = self.fetch()?;
+        self.pipeline.latch_instruction(opcode);
+        let decoded = self.pipeline.decode(self.variant).map_err(|_| Fault::UnrecognizedInstruction(opcode))?;
+        let (operand, crossed) = self.operand(decoded.address_mode())?;
+        let instruction = decoded.instruction();
+        self.cycles += timing::cycles(instruction, decoded.address_mode(), self.variant);
+        //Reads take a cycle to fix up the high byte when the index carries into it
+        let fix_up = matches!(instruction, LDA | LDX | LDY | AND | EOR | ORA | BIT | ADC | SBC | CMP)
+            || (self.variant == Variant::Cmos && matches!(instruction, ASL | LSR | ROL | ROR));
+        if crossed && fix_up {
+            self.cycles += 1;
+        }
+        if self.variant == Variant::Cmos && matches!(instruction, ADC | SBC) && self.flag(PSRFlag::Decimal) {
+            self.cycles += 1;
+        }
+        let reads = matches!(instruction, LDA | LDX | LDY | AND | EOR | ORA | BIT | ADC | SBC | CMP | CPX | CPY
+            | INC | DEC | ASL | LSR | ROL | ROR | BCC | BCS | BEQ | BMI | BNE | BPL | BVC | BVS | BRA | TRB | TSB);
+        if reads {
+            let m = self.read(operand)?;
+            self.pipeline.latch_memory_value(m);
+        }
+        match instruction {
+            LDA => self.regs.accumulator = self.load_register(),
+            LDX => self.regs.x = self.load_register(),
+            LDY => self.regs.y = self.load_register(),
+            STA => self.write(operand, self.regs.accumulator)?,
+            STX => self.write(operand, self.regs.x)?,
+            STY => self.write(operand, self.regs.y)?,
+
+            TAX => self.regs.x = self.transfer(self.regs.accumulator),
+            TAY => self.regs.y = self.transfer(self.regs.accumulator),
+            TXA => self.regs.accumulator = self.transfer(self.regs.x),
+            TYA => self.regs.accumulator = self.transfer(self.regs.y),
+
+            TSX => self.regs.x = self.transfer(self.regs.sp),
+            TXS => self.regs.sp = self.regs.x,
+            PHA => self.stack_push(self.regs.accumulator)?,
+            PHP => self.stack_push(self.regs.psr.value() | BREAK | UNUSED)?,
+            PLA => {
+                let value = self.stack_pull()?;
+                self.regs.accumulator = self.transfer(value);
+            },
+            PLP => self.pull_psr()?,
+
+            AND => self.i_and(),
+            EOR => self.i_eor(),
+            ORA => self.i_ora(),
+            //BIT #imm only sets Z
+            BIT if decoded.address_mode() == AddressMode::Immediate => self.test_bits(self.m()),
+            BIT => self.i_bit(),
+
+            ADC => self.i_adc(),
+            SBC => self.i_sbc(),
+            CMP => self.compare(self.regs.accumulator),
+            CPX => self.compare(self.regs.x),
+            CPY => self.compare(self.regs.y),
+
+            INC => self.modify(self.m().wrapping_add(1)),
+            INX => self.regs.x = self.transfer(self.regs.x.wrapping_add(1)),
+            INY => self.regs.y = self.transfer(self.regs.y.wrapping_add(1)),
+            DEC => self.modify(self.m().wrapping_sub(1)),
+            DEX => self.regs.x = self.transfer(self.regs.x.wrapping_sub(1)),
+            DEY => self.regs.y = self.transfer(self.regs.y.wrapping_sub(1)),
+
+            ASL => self.i_asl(),
+            LSR => self.i_lsr(),
+            ROL => self.i_rol(),
+            ROR => self.i_ror(),
 
+            JMP => self.regs.pc = self.address(operand),
+            JSR => {
+                //The return address is the last byte of the JSR
+                self.push_pc(self.regs.pc.wrapping_sub(1))?;
+                self.regs.pc = self.address(operand);
+            },
+            RTS => self.regs.pc = self.pull_pc()?.wrapping_add(1),
+
+            BCC => self.branch(!self.flag(PSRFlag::Carry)),
+            BCS => self.branch(self.flag(PSRFlag::Carry)),
+            BEQ => self.branch(self.flag(PSRFlag::Zero)),
+            BMI => self.branch(self.flag(PSRFlag::Negative)),
+            BNE => self.branch(!self.flag(PSRFlag::Zero)),
+            BPL => self.branch(!self.flag(PSRFlag::Negative)),
+            BVC => self.branch(!self.flag(PSRFlag::Overflow)),
+            BVS => self.branch(self.flag(PSRFlag::Overflow)),
+
+            CLC => self.regs.psr.clear(PSRFlag::Carry),
+            CLD => self.regs.psr.clear(PSRFlag::Decimal),
+            CLI => self.regs.psr.clear(PSRFlag::InterruptDisable),
+            CLV => self.regs.psr.clear(PSRFlag::Overflow),
+            SEC => self.regs.psr.set(PSRFlag::Carry),
+            SED => self.regs.psr.set(PSRFlag::Decimal),
+            SEI => self.regs.psr.set(PSRFlag::InterruptDisable),
+
+            BRK => {
+                //BRK skips a padding byte
+                self.push_pc(self.regs.pc.wrapping_add(1))?;
+                self.stack_push(self.regs.psr.value() | BREAK | UNUSED)?;
+                self.enter_handler()?;
+            },
+            NOP => (),
+            RTI => {
+                self.pull_psr()?;
+                self.regs.pc = self.pull_pc()?;
+            },
+
+            BRA => self.branch(true),
+            PHX => self.stack_push(self.regs.x)?,
+            PHY => self.stack_push(self.regs.y)?,
+            PLX => {
+                let value = self.stack_pull()?;
+                self.regs.x = self.transfer(value);
+            },
+            PLY => {
+                let value = self.stack_pull()?;
+                self.regs.y = self.transfer(value);
+            },
+            STZ => self.write(operand, 0)?,
+            TRB => {
+                let m = self.m();
+                self.test_bits(m);
+                self.pipeline.latch_memory_value(m & !self.regs.accumulator);
+            },
+            TSB => {
+                let m = self.m();
+                self.test_bits(m);
+                self.pipeline.latch_memory_value(m | self.regs.accumulator);
+            },
+        }
+        if matches!(instruction, INC | DEC | ASL | LSR | ROL | ROR | TRB | TSB) {
+            self.write(operand, self.m())?;
+        }
+        Ok(())
+    }
+    //An IRQ pushes the pc of the next instruction and the flags without the break bit
+    fn interrupt(&mut self) -> Result<(), MemoryError> {
+        self.push_pc(self.regs.pc)?;
+        self.stack_push(self.regs.psr.value() & !BREAK | UNUSED)?;
+        self.enter_handler()?;
+        self.cycles += 7;
+        Ok(())
+    }
+    //What BRK and IRQ share once the return state is pushed
+    fn enter_handler(&mut self) -> Result<(), MemoryError> {
+        self.regs.psr.set(PSRFlag::InterruptDisable);
+        if self.variant == Variant::Cmos {
+            self.regs.psr.clear(PSRFlag::Decimal);
+        }
+        self.regs.pc = self.load_u16(IRQ_VECTOR, IRQ_VECTOR.wrapping_add(1))?;
+        Ok(())
+    }
+    //Reads the operand bytes after the opcode and resolves them to an operand, and whether
+    //indexing crossed a page
+    fn operand(&mut self, mode: AddressMode) -> Result<(Operand, bool), MemoryError> {
+        let (x, y) = (self.regs.x, self.regs.y);
+        let indexed = |base: u16, index: u8| {
+            let address = base.wrapping_add(index as u16);
+            (Operand::Address(address), (base ^ address) & 0xFF00 != 0)
+        };
+        let operand = match mode {
+            AddressMode::Implied => Operand::Implied,
+            AddressMode::Accumulator => Operand::Accumulator,
+            AddressMode::Immediate | AddressMode::Relative => Operand::Immediate(self.fetch()?),
+            AddressMode::ZeroPage => Operand::Address(self.fetch()? as u16),
+            AddressMode::ZeroPageX => Operand::Address(self.fetch()?.wrapping_add(x) as u16),
+            AddressMode::ZeroPageY => Operand::Address(self.fetch()?.wrapping_add(y) as u16),
+            AddressMode::Absolute => Operand::Address(self.fetch_u16()?),
+            AddressMode::AbsoluteX => return Ok(indexed(self.fetch_u16()?, x)),
+            AddressMode::AbsoluteY => return Ok(indexed(self.fetch_u16()?, y)),
+            AddressMode::Indirect => {
+                //The NMOS parts don't carry into the high byte of the pointer
+                let pointer = self.fetch_u16()?;
+                let high = match self.variant {
+                    Variant::Nmos => (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF),
+                    Variant::Cmos => pointer.wrapping_add(1),
+                };
+                Operand::Address(self.load_u16(pointer, high)?)
+            },
+            AddressMode::AbsoluteIndexedIndirect => {
+                let pointer = self.fetch_u16()?.wrapping_add(x as u16);
+                Operand::Address(self.load_u16(pointer, pointer.wrapping_add(1))?)
+            },
+            AddressMode::ZeroPageIndirect => {
+                let pointer = self.fetch()?;
+                Operand::Address(self.load_u16(pointer as u16, pointer.wrapping_add(1) as u16)?)
+            },
+            AddressMode::IndexedIndirect => {
+                let pointer = self.fetch()?.wrapping_add(x);
+                Operand::Address(self.load_u16(pointer as u16, pointer.wrapping_add(1) as u16)?)
+            },
+            AddressMode::IndirectIndexed => {
+                let pointer = self.fetch()?;
+                let base = self.load_u16(pointer as u16, pointer.wrapping_add(1) as u16)?;
+                return Ok(indexed(base, y));
+            },
+        };
+        Ok((operand, false))
+    }
+    fn read(&mut self, operand: Operand) -> Result<u8, MemoryError> {
+        match operand {
+            Operand::Implied => Ok(0),
+            Operand::Accumulator => Ok(self.regs.accumulator),
+            Operand::Immediate(value) => Ok(value),
+            Operand::Address(address) => self.load(address),
+        }
+    }
+    fn write(&mut self, operand: Operand, value: u8) -> Result<(), MemoryError> {
+        match operand {
+            Operand::Accumulator => self.regs.accumulator = value,
+            Operand::Address(address) => self.store(address, value)?,
+            Operand::Implied | Operand::Immediate(_) => (),
+        }
+        Ok(())
+    }
+    fn address(&self, operand: Operand) -> u16 {
+        match operand {
+            Operand::Address(address) => address,
+            _ => self.regs.pc,
+        }
+    }
+    fn access(kind: AccessType) -> Access {
+        //The 6502 has no privilege levels
+        Access { kind, privileged: true }
+    }
+    fn fetch(&mut self) -> Result<u8, MemoryError> {
+        let mut byte = [0];
+        self.space.read_bytes_checked(self.regs.pc, &mut byte, Core::access(AccessType::Fetch))?;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        Ok(byte[0])
+    }
+    fn fetch_u16(&mut self) -> Result<u16, MemoryError> {
+        let low = self.fetch()?;
+        Ok(u16::from_le_bytes([low, self.fetch()?]))
+    }
+    fn load(&mut self, address: u16) -> Result<u8, MemoryError> {
+        let mut byte = [0];
+        self.space.read_bytes_checked(address, &mut byte, Core::access(AccessType::Load))?;
+        Ok(byte[0])
+    }
+    //A little endian word with its bytes at `low` and `high`, which may wrap within a page
+    fn load_u16(&mut self, low: u16, high: u16) -> Result<u16, MemoryError> {
+        let low = self.load(low)?;
+        Ok(u16::from_le_bytes([low, self.load(high)?]))
+    }
+    fn store(&mut self, address: u16, value: u8) -> Result<(), MemoryError> {
+        self.space.write_bytes_checked(address, &[value], Core::access(AccessType::Store))
     }
     fn stack_push(&mut self, byte: u8) -> Result<(), MemoryError> {
-        self.space.write_byte(self.sp_address(), byte)?;
-        self.regs.sp -= 1;
+        self.store(self.sp_address(), byte)?;
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
         Ok(())
     }
+    fn stack_pull(&mut self) -> Result<u8, MemoryError> {
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        self.load(self.sp_address())
+    }
     fn sp_address(&self) -> u16 {
         0x100 + (self.regs.sp as u16)
     }
-    fn pc_high(&self) -> u8 {
-        ((self.regs.pc & 0x00FF) >> 8) as u8
+    fn push_pc(&mut self, pc: u16) -> Result<(), MemoryError> {
+        let [low, high] = pc.to_le_bytes();
+        self.stack_push(high)?;
+        self.stack_push(low)
     }
-    fn pc_low(&self) -> u8 {
-        (self.regs.pc & 0xFF) as u8
+    fn pull_pc(&mut self) -> Result<u16, MemoryError> {
+        let low = self.stack_pull()?;
+        Ok(u16::from_le_bytes([low, self.stack_pull()?]))
     }
-    fn push_pc(&mut self) -> Result<(), MemoryError>{
-        self.stack_push(self.pc_high())?;
-        self.stack_push(self.pc_low())
+    //The break bit only exists on the stack
+    fn pull_psr(&mut self) -> Result<(), MemoryError> {
+        let value = self.stack_pull()?;
+        self.regs.psr = PSR::new((value & !BREAK) | UNUSED);
+        Ok(())
     }
-    fn do_instruction(&mut self, instruction: u8) {
-
+    fn flag(&self, flag: PSRFlag) -> bool {
+        self.regs.psr.get_bit(flag.into())
     }
-    fn fetch(&mut self) -> Result<u8, MemoryError> {
-        let b =self.space.read_byte(self.regs.pc)?;
-        self.regs.pc += 1;
-        Ok(b)
+    fn set_flag(&mut self, flag: PSRFlag, value: bool) {
+        if value {
+            self.regs.psr.set(flag)
+        } else {
+            self.regs.psr.clear(flag)
+        }
     }
     fn check_for_flags(&mut self, reg: u8) {
-        if (reg as i8) < 0 {
-            self.regs.psr.set(PSRFlag::Negative)
-        }
-        if reg == 0 {
-            self.regs.psr.set(PSRFlag::Zero)
-        }
+        self.set_flag(PSRFlag::Negative, (reg as i8) < 0);
+        self.set_flag(PSRFlag::Zero, reg == 0);
     }
     fn m(&self) -> u8 {
         self.pipeline.m()
     }
-    //Flags: CZVN
-    fn i_adc(&mut self) {
-        let carry = self.regs.psr.get(PSRFlag::Carry) as u8;
-        let accumulator = self.regs.accumulator;
+    fn load_register(&mut self) -> u8 {
+        self.transfer(self.m())
+    }
+    //A value moved into a register, setting N and Z
+    fn transfer(&mut self, value: u8) -> u8 {
+        self.check_for_flags(value);
+        value
+    }
+    //The result of a read-modify-write, written back after the instruction
+    fn modify(&mut self, value: u8) {
+        self.pipeline.latch_memory_value(value);
+        self.check_for_flags(value);
+    }
+    //A taken branch takes a cycle, and another if it lands on a different page
+    fn branch(&mut self, taken: bool) {
+        if taken {
+            let next = self.regs.pc;
+            self.regs.pc = next.wrapping_add(self.m() as i8 as u16); //Relative
+            self.cycles += 1 + ((next ^ self.regs.pc) & 0xFF00 != 0) as u64;
+        }
+    }
+    fn compare(&mut self, reg: u8) {
         let m = self.m();
-        //Unsigned check for carry
-        let inter_result = accumulator.overflowing_add(m);
-        let result = (inter_result.0).overflowing_add(carry);
-        let did_carry = (result.1) || (inter_result.1);
-
-        //Signed check for overflow
-        let inter_resulti = (accumulator as i8).overflowing_add(m as i8);
-        let resulti = (inter_result.0).overflowing_add(carry as i8);
-        let did_overflow = (resulti.1) || (inter_resulti.1);
-
-        //Done so store the results
+        self.set_flag(PSRFlag::Carry, reg >= m);
+        self.check_for_flags(reg.wrapping_sub(m));
+    }
+    //Binary addition of `m` and the carry to the accumulator. Flags: CZVN
+    fn add(&mut self, m: u8) {
+        let accumulator = self.regs.accumulator;
+        let sum = accumulator as u16 + m as u16 + self.flag(PSRFlag::Carry) as u16;
+        let result = sum as u8;
+        self.set_flag(PSRFlag::Carry, sum > 0xFF);
+        //Signed overflow when both inputs have a sign the result doesn't
+        self.set_flag(PSRFlag::Overflow, (accumulator ^ result) & (m ^ result) & 0x80 != 0);
         self.regs.accumulator = result;
-        if did_overflow {
-            self.regs.psr.set(PSRFlag::Overflow)
+        self.check_for_flags(result);
+    }
+    fn i_adc(&mut self) {
+        let (accumulator, m) = (self.regs.accumulator, self.m());
+        let carry = self.flag(PSRFlag::Carry) as u8;
+        self.add(m);
+        if self.flag(PSRFlag::Decimal) {
+            //NMOS decimal mode: Z is from the binary sum, N and V from the sum before the high
+            //digit is adjusted
+            let mut low = (accumulator & 0xF) + (m & 0xF) + carry;
+            if low > 9 {
+                low += 6;
+            }
+            let mut high = (accumulator >> 4) as u16 + (m >> 4) as u16 + (low > 0xF) as u16;
+            let unadjusted = ((high << 4) as u8) | (low & 0xF);
+            self.set_flag(PSRFlag::Negative, (unadjusted as i8) < 0);
+            self.set_flag(PSRFlag::Overflow, (accumulator ^ unadjusted) & !(accumulator ^ m) & 0x80 != 0);
+            if high > 9 {
+                high += 6;
+            }
+            self.set_flag(PSRFlag::Carry, high > 0xF);
+            self.regs.accumulator = ((high << 4) as u8) | (low & 0xF);
+            if self.variant == Variant::Cmos {
+                //The 65C02 sets N and Z from the decimal result
+                self.check_for_flags(self.regs.accumulator);
+            }
         }
-        if did_carry {
-            self.regs.psr.set(PSRFlag::Carry)
+    }
+    fn i_sbc(&mut self) {
+        let (accumulator, m) = (self.regs.accumulator, self.m());
+        let borrow = !self.flag(PSRFlag::Carry) as i16;
+        //The flags are the binary subtraction's, even in decimal mode
+        self.add(!m);
+        if self.flag(PSRFlag::Decimal) && self.variant == Variant::Cmos {
+            //The 65C02 adjusts the whole binary difference, and N and Z follow the result
+            let low = (accumulator & 0xF) as i16 - (m & 0xF) as i16 - borrow;
+            let mut difference = accumulator as i16 - m as i16 - borrow;
+            if difference < 0 {
+                difference -= 0x60;
+            }
+            if low < 0 {
+                difference -= 0x06;
+            }
+            self.regs.accumulator = self.transfer(difference as u8);
+        } else if self.flag(PSRFlag::Decimal) {
+            let mut low = (accumulator & 0xF) as i16 - (m & 0xF) as i16 - borrow;
+            let mut high = (accumulator >> 4) as i16 - (m >> 4) as i16;
+            if low < 0 {
+                low -= 6;
+                high -= 1;
+            }
+            if high < 0 {
+                high -= 6;
+            }
+            self.regs.accumulator = ((high << 4) as u8) | (low as u8 & 0xF);
         }
-        self.check_for_flags(result);
     }
     fn i_and(&mut self) {
-        let result = self.regs.accumulator & self.pipeline.m();
-        self.regs.accumulator = result;
-        self.check_for_flags(result);
+        let result = self.regs.accumulator & self.m();
+        self.regs.accumulator = self.transfer(result);
+    }
+    fn i_eor(&mut self) {
+        let result = self.regs.accumulator ^ self.m();
+        self.regs.accumulator = self.transfer(result);
+    }
+    fn i_ora(&mut self) {
+        let result = self.regs.accumulator | self.m();
+        self.regs.accumulator = self.transfer(result);
+    }
+    //Z from the bits `m` has in common with the accumulator, for BIT #imm, TRB and TSB
+    fn test_bits(&mut self, m: u8) {
+        self.set_flag(PSRFlag::Zero, self.regs.accumulator & m == 0);
+    }
+    fn i_bit(&mut self) {
+        let m = self.m();
+        self.set_flag(PSRFlag::Zero, self.regs.accumulator & m == 0);
+        self.set_flag(PSRFlag::Negative, m & 0x80 != 0);
+        self.set_flag(PSRFlag::Overflow, m & 0x40 != 0);
     }
     fn i_asl(&mut self) {
-        let result = self.pipeline.m().overflowing_shl(1);
-        if result.1 {
-            self.regs.psr.set(PSRFlag::Carry);
+        let m = self.m();
+        self.set_flag(PSRFlag::Carry, m & 0x80 != 0);
+        self.modify(m << 1);
+    }
+    fn i_lsr(&mut self) {
+        let m = self.m();
+        self.set_flag(PSRFlag::Carry, m & 1 != 0);
+        self.modify(m >> 1);
+    }
+    fn i_rol(&mut self) {
+        let (m, carry) = (self.m(), self.flag(PSRFlag::Carry) as u8);
+        self.set_flag(PSRFlag::Carry, m & 0x80 != 0);
+        self.modify((m << 1) | carry);
+    }
+    fn i_ror(&mut self) {
+        let (m, carry) = (self.m(), self.flag(PSRFlag::Carry) as u8);
+        self.set_flag(PSRFlag::Carry, m & 1 != 0);
+        self.modify((m >> 1) | (carry << 7));
+    }
+}
+impl Debuggable for Core {
+    fn register_bits(&self) -> u32 {
+        16
+    }
+    fn pc(&self) -> u64 {
+        self.regs.pc.into()
+    }
+    fn set_pc(&mut self, pc: u64) {
+        self.regs.pc = pc as u16;
+    }
+    fn registers(&self) -> Vec<(&'static str, u64)> {
+        let regs = &self.regs;
+        vec![("a", regs.accumulator), ("x", regs.x), ("y", regs.y), ("sp", regs.sp), ("p", regs.psr.value())]
+            .into_iter().map(|(name, value)| (name, value.into())).collect()
+    }
+    fn set_register(&mut self, name: &str, value: u64) -> Option<()> {
+        let value = u8::try_from(value).ok()?;
+        match name {
+            "a" => self.regs.accumulator = value,
+            "x" => self.regs.x = value,
+            "y" => self.regs.y = value,
+            "sp" => self.regs.sp = value,
+            "p" => self.regs.psr = PSR::new(value),
+            _ => return None,
         }
-        self.pipeline.latch_memory_value(result.0);
-        self.check_for_flags(result.0);
+        Some(())
+    }
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        self.space.read_byte(u16::try_from(address).ok()?).ok()
     }
-    fn i_bcc(&mut self) {
-        if self.regs.psr.get(PSRFlag::Carry) == false {
-            self.regs.pc = ((self.regs.pc as i16) + ((self.pipeline.m() as i8) as i16)) as u16; //Relative
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()> {
+        self.space.write_byte(u16::try_from(address).ok()?, value).ok()
+    }
+    fn step(&mut self) -> StepResult {
+        match Core::step(self) {
+            Ok(()) => StepResult::Running,
+            Err(e) => StepResult::Fault(format!("{:?}", e)),
         }
     }
-    fn i_bcs(&mut self) {
-        if self.regs.psr.get(PSRFlag::Carry) == true {
-            self.regs.pc = ((self.regs.pc as i16) + ((self.pipeline.m() as i8) as i16)) as u16; //Relative
+    fn cycles(&self) -> Option<u64> {
+        Some(self.cycles)
+    }
+    fn disassemble(&self, address: u64) -> Option<(u64, String)> {
+        let bytes: Vec<u8> = (address..address + 3).map_while(|address| self.read_byte(address)).collect();
+        let (len, text) = super::disassembler::disassemble(&bytes, u16::try_from(address).ok()?, self.variant)?;
+        Some((len as u64, text))
+    }
+    fn is_call(&self, address: u64) -> bool {
+        //JSR absolute
+        self.read_byte(address) == Some(0x20)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+
+    #[test]
+    fn reset_and_debug_view() {
+        let mut space = SparseAddressSpace::new(u16::MAX).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(u16::MAX))).unwrap();
+        space.write_bytes(RESET_VECTOR, &[0x00, 0x06]).unwrap();
+        space.write_bytes(0x0600, &[0x20, 0x00, 0x07]).unwrap();
+        let mut core = Core::new(space, Variant::Nmos);
+        assert_eq!(core.pc(), 0x0600);
+        assert!(core.is_call(0x0600));
+        assert_eq!(core.disassemble(0x0600), Some((3, "JSR $0700".to_string())));
+        assert_eq!(core.set_register("x", 0x1FF), None);
+        core.set_register("x", 0x42).unwrap();
+        assert_eq!(core.registers()[1], ("x", 0x42));
+        assert_eq!(Debuggable::step(&mut core), StepResult::Running);
+        assert_eq!(core.pc(), 0x0700);
+        assert_eq!(core.registers()[3], ("sp", 0xFB));
+    }
+    //A core with RAM everywhere and `program` at 0x0600, where it starts
+    fn core(program: &[u8]) -> Core {
+        let mut space = SparseAddressSpace::full();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x10000u64))).unwrap();
+        space.write_bytes(RESET_VECTOR, &[0x00, 0x06]).unwrap();
+        space.write_bytes(0x0600, program).unwrap();
+        Core::new(space, Variant::Nmos)
+    }
+    fn cmos(program: &[u8]) -> Core {
+        Core { variant: Variant::Cmos, ..core(program) }
+    }
+    fn run_to(core: &mut Core, pc: u16) {
+        for _ in 0..1000 {
+            if core.regs.pc == pc {
+                return;
+            }
+            core.step().unwrap();
         }
+        panic!("pc never reached {:#x}", pc);
+    }
+    fn flags(core: &Core) -> [bool; 4] {
+        [PSRFlag::Carry, PSRFlag::Zero, PSRFlag::Overflow, PSRFlag::Negative].map(|flag| core.flag(flag))
     }
-    fn i_beq(&mut self) {
-        if self.regs.psr.get(PSRFlag::Zero) == true {
-            self.regs.pc = ((self.regs.pc as i16) + ((self.pipeline.m() as i8) as i16)) as u16; //Relative
+
+    #[test]
+    fn runs_a_loop() {
+        //LDX #5; LDA #0; CLC; ADC #3; DEX; BNE -6; STA $0200; TAY; LDA ($10),Y
+        let mut core = core(&[0xA2, 0x05, 0xA9, 0x00, 0x18, 0x69, 0x03, 0xCA, 0xD0, 0xFA, 0x8D, 0x00, 0x02,
+                              0xA8, 0xB1, 0x10]);
+        core.space.write_bytes(0x10, &[0xF1, 0x01]).unwrap();
+        run_to(&mut core, 0x060D);
+        assert_eq!(core.space.read_byte(0x0200), Ok(15));
+        assert_eq!(flags(&core), [false, true, false, false]);
+        run_to(&mut core, 0x0610);
+        //Loading 15 back through the pointer clears Z again
+        assert_eq!((core.regs.accumulator, core.regs.y), (15, 15));
+        assert_eq!(flags(&core), [false, false, false, false]);
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        //CLC; LDA #$50; ADC #$50; SEC; LDA #$80; ADC #$FF; CMP #$81; SED; CLC; LDA #$19;
+        //ADC #$28; SEC; SBC #$48
+        let mut core = core(&[0x18, 0xA9, 0x50, 0x69, 0x50, 0x38, 0xA9, 0x80, 0x69, 0xFF, 0xC9, 0x81, 0xF8,
+                              0x18, 0xA9, 0x19, 0x69, 0x28, 0x38, 0xE9, 0x48]);
+        run_to(&mut core, 0x0605);
+        assert_eq!(core.regs.accumulator, 0xA0);
+        assert_eq!(flags(&core), [false, false, true, true]);
+        run_to(&mut core, 0x060A);
+        assert_eq!(core.regs.accumulator, 0x80);
+        assert_eq!(flags(&core), [true, false, false, true]);
+        run_to(&mut core, 0x060C);
+        assert_eq!(flags(&core), [false, false, false, true]);
+        run_to(&mut core, 0x0612);
+        assert_eq!(core.regs.accumulator, 0x47);
+        run_to(&mut core, 0x0615);
+        assert_eq!(core.regs.accumulator, 0x99);
+        assert!(!core.flag(PSRFlag::Carry));
+    }
+
+    #[test]
+    fn subroutines_and_interrupts() {
+        //JSR $0610; BRK; NOP; ...; $0610: INX; RTS; $0620: INY; RTI
+        let mut core = core(&[0x20, 0x10, 0x06, 0x00, 0xEA, 0xEA]);
+        core.space.write_bytes(0x0610, &[0xE8, 0x60]).unwrap();
+        core.space.write_bytes(0x0620, &[0xC8, 0x40]).unwrap();
+        core.space.write_bytes(IRQ_VECTOR, &[0x20, 0x06]).unwrap();
+        run_to(&mut core, 0x0603);
+        assert_eq!((core.regs.x, core.regs.sp), (1, 0xFD));
+        core.step().unwrap();
+        assert_eq!(core.regs.pc, 0x0620);
+        assert!(core.flag(PSRFlag::InterruptDisable));
+        //The pushed flags have the break bit
+        assert_eq!(core.space.read_byte(0x01FB), Ok(0x34 | BREAK | UNUSED));
+        run_to(&mut core, 0x0605);
+        assert_eq!((core.regs.y, core.regs.sp), (1, 0xFD));
+    }
+
+    #[test]
+    fn irq() {
+        //CLI; NOP; NOP; ...; $0620: INY; RTI
+        let mut core = core(&[0x58, 0xEA, 0xEA]);
+        core.space.write_bytes(0x0620, &[0xC8, 0x40]).unwrap();
+        core.space.write_bytes(IRQ_VECTOR, &[0x20, 0x06]).unwrap();
+        let lines = InterruptLines::new();
+        let line = lines.line(3);
+        core.connect_interrupts(lines);
+        //Masked until CLI
+        line.raise();
+        core.step().unwrap();
+        assert_eq!(core.regs.pc, 0x0601);
+        let cycles = core.cycles();
+        core.step().unwrap();
+        assert_eq!((core.regs.pc, core.cycles() - cycles), (0x0620, 7));
+        assert!(core.flag(PSRFlag::InterruptDisable));
+        //The pushed flags don't have the break bit, and the handler's I keeps the line out
+        assert_eq!(core.space.read_byte(0x01FB), Ok(UNUSED));
+        core.step().unwrap();
+        assert_eq!(core.regs.y, 1);
+        line.lower();
+        core.step().unwrap();
+        assert_eq!(core.regs.pc, 0x0601);
+        assert!(!core.flag(PSRFlag::InterruptDisable));
+    }
+    #[test]
+    fn cmos_instructions() {
+        //LDX #3; PHX; PLY; STZ $0200; LDA #$0F; STA $0201; LDA #$3C; TSB $0201; TRB $0201; INC A;
+        //LDA ($10); BIT #$80; BRA +2; NOP; NOP; JMP ($0620,X)
+        let mut cpu = cmos(&[0xA2, 0x03, 0xDA, 0x7A, 0x9C, 0x00, 0x02, 0xA9, 0x0F, 0x8D, 0x01, 0x02, 0xA9, 0x3C,
+                              0x0C, 0x01, 0x02, 0x1C, 0x01, 0x02, 0x1A, 0xB2, 0x10, 0x89, 0x80, 0x80, 0x02, 0xEA, 0xEA,
+                              0x7C, 0x20, 0x06]);
+        cpu.space.write_bytes(0x0200, &[0xFF]).unwrap();
+        cpu.space.write_bytes(0x10, &[0x01, 0x02]).unwrap();
+        cpu.space.write_bytes(0x0623, &[0x30, 0x06]).unwrap();
+        run_to(&mut cpu, 0x0611);
+        assert_eq!(cpu.space.read_byte(0x0201), Ok(0x3F));
+        assert!(!cpu.flag(PSRFlag::Zero));
+        run_to(&mut cpu, 0x0615);
+        assert_eq!((cpu.regs.accumulator, cpu.space.read_byte(0x0201)), (0x3D, Ok(0x03)));
+        run_to(&mut cpu, 0x0619);
+        assert_eq!(cpu.regs.accumulator, 0x03);
+        //BIT #imm leaves N and V alone
+        assert_eq!(flags(&cpu), [false, true, false, false]);
+        run_to(&mut cpu, 0x0630);
+        assert_eq!((cpu.regs.x, cpu.regs.y, cpu.regs.sp), (3, 3, 0xFD));
+        assert_eq!(cpu.space.read_byte(0x0200), Ok(0));
+        //The same opcodes are undefined on the NMOS parts
+        let mut nmos = core(&[0xDA]);
+        assert_eq!(nmos.step(), Err(Fault::UnrecognizedInstruction(0xDA)));
+    }
+
+    #[test]
+    fn cmos_fixes() {
+        //JMP ($02FF) takes its high byte from $0300 rather than $0200
+        for (mut cpu, target) in [(core(&[0x6C, 0xFF, 0x02]), 0x0800), (cmos(&[0x6C, 0xFF, 0x02]), 0x0700)] {
+            cpu.space.write_bytes(0x02FF, &[0x00, 0x07]).unwrap();
+            cpu.space.write_bytes(0x0200, &[0x08]).unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.regs.pc, target);
         }
+        //SED; SEC; LDA #$99; ADC #$00; LDA #$00; SBC #$01; BRK
+        let mut core = cmos(&[0xF8, 0x38, 0xA9, 0x99, 0x69, 0x00, 0xA9, 0x00, 0xE9, 0x01, 0x00]);
+        core.space.write_bytes(IRQ_VECTOR, &[0x20, 0x06]).unwrap();
+        run_to(&mut core, 0x0606);
+        //N and Z are the decimal result's
+        assert_eq!(core.regs.accumulator, 0x00);
+        assert_eq!(flags(&core), [true, true, false, false]);
+        run_to(&mut core, 0x060A);
+        assert_eq!(core.regs.accumulator, 0x99);
+        assert_eq!(flags(&core), [false, false, false, true]);
+        core.step().unwrap();
+        assert_eq!(core.regs.pc, 0x0620);
+        assert!(!core.flag(PSRFlag::Decimal));
     }
-    fn i_tax(&mut self) {
-        self.regs.x = self.regs.accumulator
+
+    #[test]
+    fn cycle_counts() {
+        //LDX #$FF; LDA $0201,X; LDA $0200,X; STA $0200,X; LDY #1; DEY; BEQ +0; BNE +0; BEQ -$80
+        let mut core = core(&[0xA2, 0xFF, 0xBD, 0x01, 0x02, 0xBD, 0x00, 0x02, 0x9D, 0x00, 0x02, 0xA0, 0x01, 0x88,
+                              0xF0, 0x00, 0xD0, 0x00, 0xF0, 0x80]);
+        //The first load and the last branch cross a page, the store always takes the extra cycle
+        let expected = [2, 5, 4, 5, 2, 2, 3, 2, 4];
+        for (i, &cycles) in expected.iter().enumerate() {
+            let before = core.cycles();
+            core.step().unwrap();
+            assert_eq!(core.cycles() - before, cycles, "instruction {}", i);
+        }
+        assert_eq!(core.regs.pc, 0x0594);
+        //A fault doesn't count
+        core.set_pc(0x0605);
+        core.write_byte(0x0605, 0x02).unwrap();
+        assert!(core.step().is_err());
+        assert_eq!(Debuggable::cycles(&core), Some(29));
+        //SED; ADC #0 takes a cycle more on the 65C02
+        let mut core = cmos(&[0xF8, 0x69, 0x00]);
+        run_to(&mut core, 0x0603);
+        assert_eq!(core.cycles(), 5);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn faults_leave_the_registers() {
+        let mut space = SparseAddressSpace::full();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x1000u64))).unwrap();
+        //LDA #1; LDA $2000; .byte 2
+        space.write_bytes(0x0600, &[0xA9, 0x01, 0xAD, 0x00, 0x20, 0x02]).unwrap();
+        let mut core = Core::new(space, Variant::Nmos);
+        core.set_pc(0x0600);
+        core.step().unwrap();
+        assert_eq!(core.step(), Err(Fault::Memory(MemoryError::AccessFault { address: 0x2000, kind: AccessType::Load })));
+        assert_eq!((core.regs.pc, core.regs.accumulator), (0x0602, 1));
+        core.set_pc(0x0605);
+        assert_eq!(core.step(), Err(Fault::UnrecognizedInstruction(2)));
+        //LDA with its address cut off at the end of RAM
+        core.write_byte(0x0FFF, 0xAD).unwrap();
+        core.set_pc(0x0FFF);
+        assert_eq!(core.step(), Err(Fault::Memory(MemoryError::AccessFault { address: 0x1000, kind: AccessType::Fetch })));
+        assert_eq!(core.regs.pc, 0x0FFF);
+    }
+}
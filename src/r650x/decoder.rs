@@ -9,7 +9,66 @@ pub struct DecodedInstruction {
     instruction: super::instructions::Instruction,
     address_mode: AddressMode
 }
-pub fn decode(b: u8) -> Result<DecodedInstruction, DecoderError> {
+impl DecodedInstruction {
+    pub fn instruction(self) -> super::instructions::Instruction {
+        self.instruction
+    }
+    pub fn address_mode(self) -> AddressMode {
+        self.address_mode
+    }
+}
+/// The members of the family, which decode some opcodes differently.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Variant {
+    /// The original NMOS 6502
+    Nmos,
+    /// The WDC 65C02, with the CMOS instructions and address modes
+    Cmos,
+}
+pub fn decode(b: u8, variant: Variant) -> Result<DecodedInstruction, DecoderError> {
+    match variant {
+        Variant::Cmos => cmos(b).map_or_else(|| nmos(b), Ok),
+        Variant::Nmos => nmos(b),
+    }
+}
+//The opcodes the 65C02 added, in slots the NMOS parts leave undefined. The bit instructions
+//(RMB, SMB, BBR, BBS) and WAI/STP aren't decoded.
+fn cmos(b: u8) -> Option<DecodedInstruction> {
+    use super::instructions::Instruction::*;
+    use super::address::AddressMode::*;
+    let (instruction, address_mode) = match b {
+        0x80 => (BRA, Relative),
+        0xDA => (PHX, Implied),
+        0xFA => (PLX, Implied),
+        0x5A => (PHY, Implied),
+        0x7A => (PLY, Implied),
+        0x64 => (STZ, ZeroPage),
+        0x74 => (STZ, ZeroPageX),
+        0x9C => (STZ, Absolute),
+        0x9E => (STZ, AbsoluteX),
+        0x04 => (TSB, ZeroPage),
+        0x0C => (TSB, Absolute),
+        0x14 => (TRB, ZeroPage),
+        0x1C => (TRB, Absolute),
+        0x12 => (ORA, ZeroPageIndirect),
+        0x32 => (AND, ZeroPageIndirect),
+        0x52 => (EOR, ZeroPageIndirect),
+        0x72 => (ADC, ZeroPageIndirect),
+        0x92 => (STA, ZeroPageIndirect),
+        0xB2 => (LDA, ZeroPageIndirect),
+        0xD2 => (CMP, ZeroPageIndirect),
+        0xF2 => (SBC, ZeroPageIndirect),
+        0x1A => (INC, Accumulator),
+        0x3A => (DEC, Accumulator),
+        0x89 => (BIT, Immediate),
+        0x34 => (BIT, ZeroPageX),
+        0x3C => (BIT, AbsoluteX),
+        0x7C => (JMP, AbsoluteIndexedIndirect),
+        _ => return None,
+    };
+    Some(DecodedInstruction { instruction, address_mode })
+}
+fn nmos(b: u8) -> Result<DecodedInstruction, DecoderError> {
     use super::instructions::Instruction::*;
     use super::address::AddressMode::*;
     let high = (b>>4) & 0xF;
@@ -160,8 +219,8 @@ pub fn decode(b: u8) -> Result<DecodedInstruction, DecoderError> {
                 0xB => ni(LDA, AbsoluteY),
                 0xC => ni(CMP, Immediate),
                 0xD => ni(CMP, AbsoluteY),
-                0xE => ni(CMP, Immediate),
-                0xF => ni(CMP, AbsoluteY),
+                0xE => ni(SBC, Immediate),
+                0xF => ni(SBC, AbsoluteY),
                 _ => panic!("high to big")
             }
         }
@@ -249,7 +308,7 @@ mod tests {
     fn test1() {
         let mut counter = 0;
         for i in 0u8..255 {
-            if super::decode(i).is_ok() {
+            if super::decode(i, super::Variant::Nmos).is_ok() {
                 counter+=1;
             }
         }
@@ -0,0 +1,69 @@
+use super::address::AddressMode;
+use super::decoder::{Variant, decode};
+
+/// Disassembles the instruction at the start of `bytes`, which is at `address`, as `variant`
+/// decodes it. Returns its length and text, or `None` if the opcode is unknown or `bytes` is
+/// too short.
+pub fn disassemble(bytes: &[u8], address: u16, variant: Variant) -> Option<(usize, String)> {
+    let decoded = decode(*bytes.first()?, variant).ok()?;
+    let mode = decoded.address_mode();
+    let len = 1 + mode.extra_bytes();
+    let operand = bytes.get(1..len)?;
+    let byte = operand.first().copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, operand.get(1).copied().unwrap_or(0)]);
+    let operand = match mode {
+        AddressMode::Implied => String::new(),
+        AddressMode::Accumulator => "A".to_string(),
+        AddressMode::Immediate => format!("#${:02X}", byte),
+        AddressMode::ZeroPage => format!("${:02X}", byte),
+        AddressMode::ZeroPageX => format!("${:02X},X", byte),
+        AddressMode::ZeroPageY => format!("${:02X},Y", byte),
+        //Relative to the next instruction
+        AddressMode::Relative => format!("${:04X}", address.wrapping_add(2).wrapping_add(byte as i8 as u16)),
+        AddressMode::Absolute => format!("${:04X}", word),
+        AddressMode::AbsoluteX => format!("${:04X},X", word),
+        AddressMode::AbsoluteY => format!("${:04X},Y", word),
+        AddressMode::Indirect => format!("(${:04X})", word),
+        AddressMode::IndexedIndirect => format!("(${:02X},X)", byte),
+        AddressMode::IndirectIndexed => format!("(${:02X}),Y", byte),
+        AddressMode::ZeroPageIndirect => format!("(${:02X})", byte),
+        AddressMode::AbsoluteIndexedIndirect => format!("(${:04X},X)", word),
+    };
+    let mnemonic = format!("{:?}", decoded.instruction());
+    Some((len, if operand.is_empty() { mnemonic } else { format!("{} {}", mnemonic, operand) }))
+}
+#[cfg(test)]
+mod tests {
+    use super::disassemble;
+    use crate::r650x::decoder::Variant;
+
+    #[test]
+    fn address_modes() {
+        let text = |bytes: &[u8]| disassemble(bytes, 0x0600, Variant::Nmos).map(|(_, text)| text);
+        assert_eq!(disassemble(&[0xE8], 0, Variant::Nmos), Some((1, "INX".to_string())));
+        assert_eq!(text(&[0x0A]).unwrap(), "ASL A");
+        assert_eq!(text(&[0xA9, 0x0F]).unwrap(), "LDA #$0F");
+        assert_eq!(text(&[0xE9, 0x01]).unwrap(), "SBC #$01");
+        assert_eq!(text(&[0xB6, 0x10]).unwrap(), "LDX $10,Y");
+        assert_eq!(text(&[0xD0, 0xFE]).unwrap(), "BNE $0600");
+        assert_eq!(text(&[0x20, 0x34, 0x12]).unwrap(), "JSR $1234");
+        assert_eq!(text(&[0x6C, 0xFC, 0xFF]).unwrap(), "JMP ($FFFC)");
+        assert_eq!(text(&[0x91, 0x20]).unwrap(), "STA ($20),Y");
+        assert_eq!(text(&[0x8D, 0x00]), None, "the operand is cut off");
+        assert_eq!(text(&[0x02]), None);
+        assert_eq!(text(&[0x80, 0x00]), None, "BRA is 65C02 only");
+    }
+
+    #[test]
+    fn cmos_address_modes() {
+        let text = |bytes: &[u8]| disassemble(bytes, 0x0600, Variant::Cmos).map(|(_, text)| text);
+        assert_eq!(text(&[0x80, 0xFE]).unwrap(), "BRA $0600");
+        assert_eq!(text(&[0xB2, 0x20]).unwrap(), "LDA ($20)");
+        assert_eq!(text(&[0x7C, 0x00, 0x20]).unwrap(), "JMP ($2000,X)");
+        assert_eq!(text(&[0x1A]).unwrap(), "INC A");
+        assert_eq!(text(&[0x89, 0x80]).unwrap(), "BIT #$80");
+        assert_eq!(text(&[0x9E, 0x00, 0x02]).unwrap(), "STZ $0200,X");
+        assert_eq!(text(&[0x6C, 0xFC, 0xFF]).unwrap(), "JMP ($FFFC)");
+        assert_eq!(text(&[0x02]), None);
+    }
+}
@@ -64,6 +64,11 @@ pub trait FlagRegister<T>: Sized where
         self.get_bit(which.into())
     }
 }
+impl PSR {
+    pub fn new(value: u8) -> PSR {
+        PSR(value)
+    }
+}
 impl FlagRegister<u8> for PSR {
     type FlagType = PSRFlag;
     fn value(&self) -> u8 {
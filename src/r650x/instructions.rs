@@ -1,4 +1,4 @@
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Instruction {
     LDA,
     LDX,
@@ -65,6 +65,16 @@ pub enum Instruction {
 
     BRK,
     NOP,
-    RTI
+    RTI,
+
+    //65C02
+    BRA,
+    PHX,
+    PHY,
+    PLX,
+    PLY,
+    STZ,
+    TRB,
+    TSB
 
 }
\ No newline at end of file
@@ -10,4 +10,7 @@ pub mod counter;
 pub mod port;
 pub mod decoder;
 pub mod instructions;
-pub mod pipeline;
\ No newline at end of file
+pub mod pipeline;
+pub mod semihost;
+pub mod disassembler;
+pub mod timing;
\ No newline at end of file
@@ -1,4 +1,4 @@
-use crate::r650x::decoder::{DecodedInstruction, DecoderError, decode};
+use crate::r650x::decoder::{DecodedInstruction, DecoderError, Variant, decode};
 
 pub struct Pipeline {
     raw_instruction: u8,
@@ -6,6 +6,10 @@ pub struct Pipeline {
     memory_value: u8,
 }
 impl Pipeline {
+    pub fn new() -> Pipeline {
+        let nop = decode(0xEA, Variant::Nmos).unwrap_or_else(|_| unreachable!("NOP decodes"));
+        Pipeline { raw_instruction: 0xEA, decoded_instruction: nop, memory_value: 0 }
+    }
     pub fn latch_instruction(&mut self, raw_instruction: u8) {
         self.raw_instruction = raw_instruction
     }
@@ -15,8 +19,13 @@ impl Pipeline {
     pub fn m(&self) -> u8 {
         self.memory_value
     }
-    pub fn decode(&mut self) -> Result<DecodedInstruction, DecoderError> {
-        self.decoded_instruction = decode(self.raw_instruction)?;
+    pub fn decode(&mut self, variant: Variant) -> Result<DecodedInstruction, DecoderError> {
+        self.decoded_instruction = decode(self.raw_instruction, variant)?;
         Ok(self.decoded_instruction)
     }
-}
\ No newline at end of file
+}
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline::new()
+    }
+}
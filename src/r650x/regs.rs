@@ -1,3 +1,4 @@
+#[derive(Copy, Clone)]
 pub struct Regs {
     pub pc: u16,
     pub sp: u8,
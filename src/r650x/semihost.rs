@@ -0,0 +1,94 @@
+//! Host I/O for 6502 test programs through a two byte trap at a configurable address. A byte
+//! stored to the first goes to the output, a store to the second ends the program with that
+//! byte as its exit code.
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::sparse::SparseAddressSpace;
+use crate::microvm::memory::MemoryError;
+use std::cell::Cell;
+use std::io::Write;
+use std::rc::Rc;
+
+pub const PUTCHAR: u16 = 0;
+pub const EXIT: u16 = 1;
+
+/// The exit code once the program has stored to the exit trap, shared with the port.
+#[derive(Clone, Debug, Default)]
+pub struct ExitStatus(Rc<Cell<Option<u8>>>);
+impl ExitStatus {
+    pub fn get(&self) -> Option<u8> {
+        self.0.get()
+    }
+}
+pub struct TrapPort {
+    output: Box<dyn Write>,
+    exit: ExitStatus,
+}
+impl TrapPort {
+    pub fn new(output: Box<dyn Write>) -> (TrapPort, ExitStatus) {
+        let exit = ExitStatus::default();
+        (TrapPort { output, exit: exit.clone() }, exit)
+    }
+    /// Maps a port writing to `output` at `address` and `address + 1`.
+    pub fn install(space: &mut SparseAddressSpace<u16>, address: u16, output: Box<dyn Write>) -> Result<ExitStatus, MemoryError> {
+        let (port, exit) = TrapPort::new(output);
+        space.add_space(address, Box::new(port))?;
+        Ok(exit)
+    }
+}
+impl AddressSpace<u16> for TrapPort {
+    fn size(&self) -> u64 {
+        2
+    }
+    fn read_byte(&self, address: u16) -> Result<u8, MemoryError> {
+        match address {
+            PUTCHAR => Ok(0),
+            EXIT => Ok(self.exit.get().unwrap_or(0)),
+            _ => Err(MemoryError::OutOfBounds),
+        }
+    }
+    fn write_bytes(&mut self, address: u16, bytes: &[u8]) -> Result<(), MemoryError> {
+        for (address, &byte) in (address..).zip(bytes) {
+            match address {
+                PUTCHAR => {
+                    self.output.write_all(&[byte]).and_then(|()| self.output.flush())
+                        .map_err(|_| MemoryError::InvalidAccess)?;
+                },
+                EXIT => self.exit.0.set(Some(byte)),
+                _ => return Err(MemoryError::OutOfBounds),
+            }
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn putchar_and_exit() {
+        let mut space = SparseAddressSpace::new(u16::MAX).unwrap();
+        let output = Output::default();
+        let exit = TrapPort::install(&mut space, 0xF000, Box::new(output.clone())).unwrap();
+        space.write_bytes(0xF000, b"h").unwrap();
+        space.write_byte(0xF000, b'i').unwrap();
+        assert_eq!(exit.get(), None);
+        space.write_byte(0xF001, 3).unwrap();
+        assert_eq!(&output.0.borrow()[..], b"hi");
+        assert_eq!(exit.get(), Some(3));
+        assert_eq!(space.read_byte(0xF001), Ok(3));
+        assert_eq!(TrapPort::install(&mut space, 0xF001, Box::new(output)).err(), Some(MemoryError::Overlap));
+    }
+}
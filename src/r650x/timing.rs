@@ -0,0 +1,57 @@
+use super::address::AddressMode;
+use super::decoder::Variant;
+use super::instructions::Instruction;
+
+/// The cycles an instruction takes before any penalties: a taken branch and crossing a page,
+/// indexing across a page on a read, and the 65C02's extra cycle for decimal arithmetic.
+pub fn cycles(instruction: Instruction, mode: AddressMode, variant: Variant) -> u64 {
+    use Instruction::*;
+    use AddressMode::*;
+    let modifies = matches!(instruction, ASL | LSR | ROL | ROR | INC | DEC | TRB | TSB);
+    let stores = matches!(instruction, STA | STX | STY | STZ);
+    match (instruction, mode) {
+        (BRK, _) => 7,
+        (RTI, _) | (RTS, _) | (JSR, _) => 6,
+        (PHA, _) | (PHP, _) | (PHX, _) | (PHY, _) => 3,
+        (PLA, _) | (PLP, _) | (PLX, _) | (PLY, _) => 4,
+        (JMP, Absolute) => 3,
+        (JMP, Indirect) if variant == Variant::Nmos => 5,
+        (JMP, _) => 6,
+        (_, Implied) | (_, Accumulator) | (_, Immediate) | (_, Relative) => 2,
+        (_, ZeroPage) if modifies => 5,
+        (_, ZeroPageX) | (_, Absolute) if modifies => 6,
+        //The 65C02 only takes the extra cycle for the shifts when the index crosses a page
+        (ASL, AbsoluteX) | (LSR, AbsoluteX) | (ROL, AbsoluteX) | (ROR, AbsoluteX) if variant == Variant::Cmos => 6,
+        (_, AbsoluteX) if modifies => 7,
+        (_, ZeroPage) => 3,
+        (_, ZeroPageX) | (_, ZeroPageY) | (_, Absolute) => 4,
+        //Stores always take the cycle reads only take when the index crosses a page
+        (_, AbsoluteX) | (_, AbsoluteY) => if stores { 5 } else { 4 },
+        (_, IndirectIndexed) => if stores { 6 } else { 5 },
+        (_, ZeroPageIndirect) => 5,
+        (_, IndexedIndirect) | (_, Indirect) | (_, AbsoluteIndexedIndirect) => 6,
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::cycles;
+    use crate::r650x::decoder::{Variant, decode};
+
+    #[test]
+    fn opcode_cycles() {
+        let of = |opcode, variant| {
+            let decoded = decode(opcode, variant).ok().unwrap();
+            cycles(decoded.instruction(), decoded.address_mode(), variant)
+        };
+        for (opcode, expected) in [(0x00, 7), (0xEA, 2), (0xA9, 2), (0xA5, 3), (0xB5, 4), (0xAD, 4), (0xBD, 4), (0x9D, 5),
+                                   (0xA1, 6), (0xB1, 5), (0x91, 6), (0x06, 5), (0x16, 6), (0x0E, 6), (0x1E, 7), (0xFE, 7),
+                                   (0x4C, 3), (0x6C, 5), (0x20, 6), (0x60, 6), (0x40, 6), (0x48, 3), (0x68, 4), (0xD0, 2)] {
+            assert_eq!(of(opcode, Variant::Nmos), expected, "{:#04x}", opcode);
+        }
+        for (opcode, expected) in [(0x1E, 6), (0xFE, 7), (0x6C, 6), (0x7C, 6), (0xB2, 5), (0x92, 5), (0x04, 5), (0x1C, 6),
+                                   (0x64, 3), (0x74, 4), (0x9C, 4), (0x9E, 5), (0x80, 2), (0xDA, 3), (0x7A, 4), (0x1A, 2),
+                                   (0x89, 2), (0x3C, 4)] {
+            assert_eq!(of(opcode, Variant::Cmos), expected, "{:#04x}", opcode);
+        }
+    }
+}
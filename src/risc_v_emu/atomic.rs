@@ -0,0 +1,102 @@
+//! The A extension, load-reserved/store-conditional and the atomic memory operations.
+use super::core::{Core, CoreSettings};
+use super::decoder::RawInstruction;
+use super::exception::Exception;
+use crate::microvm::mmu::AccessType;
+
+const LR: u8 = 0b00010;
+const SC: u8 = 0b00011;
+
+impl<Settings: CoreSettings> Core<Settings> {
+    /// Executes the AMO opcode. With a single hart every AMO is trivially atomic, so the
+    /// aq and rl bits are ignored.
+    pub(crate) fn execute_atomic(&mut self, instruction: RawInstruction) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let size = match instruction.funct3().value() {
+            0b010 => 4,
+            0b011 if self.is_rv64() => 8,
+            _ => return Err(illegal),
+        };
+        //Word results are sign extended on RV64
+        let extend = |value: u64| if size == 4 { value as i32 as i64 as u64 } else { value };
+        let unsigned = |value: u64| if size == 4 { value as u32 as u64 } else { value };
+        let (rd, rs1, rs2) = (instruction.rd(), instruction.rs1(), instruction.rs2());
+        let address = self.x(rs1);
+        let funct5 = instruction.funct7().value() >> 2;
+        match funct5 {
+            LR => {
+                if rs2 != 0 {
+                    return Err(illegal);
+                }
+                let value = self.load(address, size)?;
+                self.reservation = Some(address);
+                self.set_x(rd, extend(value));
+            },
+            SC => {
+                if !address.is_multiple_of(size as u64) {
+                    return Err(Exception::StoreAddressMisaligned(address));
+                }
+                let reserved = self.reservation.take() == Some(address);
+                if reserved {
+                    self.store(address, size, self.x(rs2))?;
+                }
+                self.set_x(rd, !reserved as u64);
+            },
+            _ => {
+                if !address.is_multiple_of(size as u64) {
+                    return Err(Exception::StoreAddressMisaligned(address));
+                }
+                //AMOs read and write memory, so they are translated as stores and every fault
+                //is a store/AMO fault
+                let physical = self.translate(address, size, AccessType::Store)?;
+                let old = self.load_physical(physical, size, AccessType::Load).map_err(|_| Exception::StoreAccessFault(address))?;
+                let (old, operand) = (extend(old), extend(self.x(rs2)));
+                let new = match funct5 {
+                    0b00001 => operand,
+                    0b00000 => old.wrapping_add(operand),
+                    0b00100 => old ^ operand,
+                    0b01100 => old & operand,
+                    0b01000 => old | operand,
+                    0b10000 => (old as i64).min(operand as i64) as u64,
+                    0b10100 => (old as i64).max(operand as i64) as u64,
+                    0b11000 => unsigned(old).min(unsigned(operand)),
+                    0b11100 => unsigned(old).max(unsigned(operand)),
+                    _ => return Err(illegal),
+                };
+                self.store_physical(physical, size, new).map_err(|_| Exception::StoreAccessFault(address))?;
+                self.set_x(rd, old);
+            },
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::risc_v_emu::core::tests::{core_with_program, Rv32, Rv64};
+    use crate::risc_v_emu::exception::Exception;
+
+    #[test]
+    fn reservations_and_amos() {
+        let program = [
+            0x100525af, //lr.w a1, (a0)
+            0x18c5262f, //sc.w a2, a2, (a0)
+            0x18c526af, //sc.w a3, a2, (a0)
+            0xe0b5272f, //amomaxu.w a4, a1, (a0)
+            0xa0b527af, //amomax.w a5, a1, (a0)
+        ];
+        let mut core = core_with_program::<Rv64>("ia", &program);
+        core.set_x(10, 0x100);
+        core.set_x(12, 0xFFFF_FFFF_8000_0000);
+        core.store(0x100, 4, 5).unwrap();
+        for _ in 0..5 {
+            core.step().unwrap();
+        }
+        assert_eq!((core.x(11), core.x(12), core.x(13)), (5, 0, 1), "the second sc fails");
+        assert_eq!((core.x(14), core.x(15)), (0xFFFF_FFFF_8000_0000, 0xFFFF_FFFF_8000_0000));
+        assert_eq!(core.load(0x100, 4), Ok(5), "amomaxu compares unsigned, amomax signed");
+
+        let mut core = core_with_program::<Rv32>("ia", &program[3..]);
+        core.set_x(10, 0x102);
+        assert_eq!(core.step(), Err(Exception::StoreAddressMisaligned(0x102)));
+    }
+}
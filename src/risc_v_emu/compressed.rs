@@ -0,0 +1,251 @@
+//! The C extension, expansion of 16 bit instructions to their 32 bit equivalents.
+use super::instructions::BaseOpcodes;
+
+/// Whether the low bits of a parcel mark it as a 16 bit instruction.
+pub fn is_compressed(parcel: u16) -> bool {
+    parcel & 0b11 != 0b11
+}
+//Bits `high..=low` of the parcel, shifted down to bit 0
+fn bits(parcel: u16, high: u32, low: u32) -> u32 {
+    (parcel as u32 >> low) & ((1 << (high - low + 1)) - 1)
+}
+fn opcode(opcode: BaseOpcodes) -> u32 {
+    ((opcode as u32) << 2) | 0b11
+}
+//rd', rs1' and rs2' name x8 to x15
+fn prime(reg: u32) -> u32 {
+    reg + 8
+}
+fn sign_extend(value: u32, len: u32) -> u32 {
+    (((value << (32 - len)) as i32) >> (32 - len)) as u32
+}
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, op: BaseOpcodes) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode(op)
+}
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, op: BaseOpcodes) -> u32 {
+    (imm << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode(op)
+}
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, op: BaseOpcodes) -> u32 {
+    ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1F) << 7) | opcode(op)
+}
+fn b_type(imm: u32, rs1: u32, funct3: u32) -> u32 {
+    (((imm >> 12) & 1) << 31) | (((imm >> 5) & 0x3F) << 25) | (rs1 << 15) | (funct3 << 12) |
+        (((imm >> 1) & 0xF) << 8) | (((imm >> 11) & 1) << 7) | opcode(BaseOpcodes::Branch)
+}
+fn j_type(imm: u32, rd: u32) -> u32 {
+    (((imm >> 20) & 1) << 31) | (((imm >> 1) & 0x3FF) << 21) | (((imm >> 11) & 1) << 20) |
+        (((imm >> 12) & 0xFF) << 12) | (rd << 7) | opcode(BaseOpcodes::Jal)
+}
+//Offset of C.J and C.JAL
+fn cj_offset(parcel: u16) -> u32 {
+    let offset = (bits(parcel, 12, 12) << 11) | (bits(parcel, 11, 11) << 4) | (bits(parcel, 10, 9) << 8) |
+        (bits(parcel, 8, 8) << 10) | (bits(parcel, 7, 7) << 6) | (bits(parcel, 6, 6) << 7) |
+        (bits(parcel, 5, 3) << 1) | (bits(parcel, 2, 2) << 5);
+    sign_extend(offset, 12)
+}
+//Offset of C.BEQZ and C.BNEZ
+fn cb_offset(parcel: u16) -> u32 {
+    let offset = (bits(parcel, 12, 12) << 8) | (bits(parcel, 11, 10) << 3) | (bits(parcel, 6, 5) << 6) |
+        (bits(parcel, 4, 3) << 1) | (bits(parcel, 2, 2) << 5);
+    sign_extend(offset, 9)
+}
+//Six bit immediate of C.ADDI, C.LI, C.ANDI and the shifts
+fn ci_immediate(parcel: u16) -> u32 {
+    sign_extend((bits(parcel, 12, 12) << 5) | bits(parcel, 6, 2), 6)
+}
+//Scaled offsets of the word and double word register based loads and stores
+fn word_offset(parcel: u16) -> u32 {
+    (bits(parcel, 12, 10) << 3) | (bits(parcel, 6, 6) << 2) | (bits(parcel, 5, 5) << 6)
+}
+fn double_offset(parcel: u16) -> u32 {
+    (bits(parcel, 12, 10) << 3) | (bits(parcel, 6, 5) << 6)
+}
+
+/// Expands a 16 bit instruction, returning `None` for reserved and illegal encodings. Whether
+/// the expanded instruction's extension (F or D) is present is left to its execution.
+pub fn expand(parcel: u16, rv64: bool) -> Option<u32> {
+    use BaseOpcodes::*;
+    let funct3 = bits(parcel, 15, 13);
+    let rd = bits(parcel, 11, 7);
+    let rs2 = bits(parcel, 6, 2);
+    let (rd_prime, rs2_prime) = (prime(bits(parcel, 4, 2)), prime(bits(parcel, 4, 2)));
+    let rs1_prime = prime(bits(parcel, 9, 7));
+    Some(match (bits(parcel, 1, 0), funct3) {
+        (0b00, 0b000) => {
+            let imm = (bits(parcel, 12, 11) << 4) | (bits(parcel, 10, 7) << 6) | (bits(parcel, 6, 6) << 2) |
+                (bits(parcel, 5, 5) << 3);
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, 0b000, rd_prime, OpImm)
+        },
+        (0b00, 0b001) => i_type(double_offset(parcel), rs1_prime, 0b011, rd_prime, LoadFP),
+        (0b00, 0b010) => i_type(word_offset(parcel), rs1_prime, 0b010, rd_prime, Load),
+        (0b00, 0b011) if rv64 => i_type(double_offset(parcel), rs1_prime, 0b011, rd_prime, Load),
+        (0b00, 0b011) => i_type(word_offset(parcel), rs1_prime, 0b010, rd_prime, LoadFP),
+        (0b00, 0b101) => s_type(double_offset(parcel), rs2_prime, rs1_prime, 0b011, StoreFP),
+        (0b00, 0b110) => s_type(word_offset(parcel), rs2_prime, rs1_prime, 0b010, Store),
+        (0b00, 0b111) if rv64 => s_type(double_offset(parcel), rs2_prime, rs1_prime, 0b011, Store),
+        (0b00, 0b111) => s_type(word_offset(parcel), rs2_prime, rs1_prime, 0b010, StoreFP),
+        (0b01, 0b000) => i_type(ci_immediate(parcel) & 0xFFF, rd, 0b000, rd, OpImm),
+        (0b01, 0b001) if rv64 => {
+            if rd == 0 {
+                return None;
+            }
+            i_type(ci_immediate(parcel) & 0xFFF, rd, 0b000, rd, OpImm32)
+        },
+        (0b01, 0b001) => j_type(cj_offset(parcel), 1),
+        (0b01, 0b010) => i_type(ci_immediate(parcel) & 0xFFF, 0, 0b000, rd, OpImm),
+        (0b01, 0b011) if rd == 2 => {
+            let imm = (bits(parcel, 12, 12) << 9) | (bits(parcel, 6, 6) << 4) | (bits(parcel, 5, 5) << 6) |
+                (bits(parcel, 4, 3) << 7) | (bits(parcel, 2, 2) << 5);
+            if imm == 0 {
+                return None;
+            }
+            i_type(sign_extend(imm, 10) & 0xFFF, 2, 0b000, 2, OpImm)
+        },
+        (0b01, 0b011) => {
+            let imm = ci_immediate(parcel);
+            if imm == 0 {
+                return None;
+            }
+            (imm << 12) | (rd << 7) | opcode(Lui)
+        },
+        (0b01, 0b100) => {
+            let shamt = (bits(parcel, 12, 12) << 5) | bits(parcel, 6, 2);
+            match bits(parcel, 11, 10) {
+                0b00 | 0b01 if shamt >= 32 && !rv64 => return None,
+                0b00 => i_type(shamt, rs1_prime, 0b101, rs1_prime, OpImm),
+                0b01 => i_type(shamt | 0b0100_0000_0000, rs1_prime, 0b101, rs1_prime, OpImm),
+                0b10 => i_type(ci_immediate(parcel) & 0xFFF, rs1_prime, 0b111, rs1_prime, OpImm),
+                _ => {
+                    let (funct7, funct3, op) = match (bits(parcel, 12, 12), bits(parcel, 6, 5)) {
+                        (0, 0b00) => (0b010_0000, 0b000, Op),
+                        (0, 0b01) => (0, 0b100, Op),
+                        (0, 0b10) => (0, 0b110, Op),
+                        (0, _) => (0, 0b111, Op),
+                        (_, 0b00) if rv64 => (0b010_0000, 0b000, Op32),
+                        (_, 0b01) if rv64 => (0, 0b000, Op32),
+                        _ => return None,
+                    };
+                    r_type(funct7, rs2_prime, rs1_prime, funct3, rs1_prime, op)
+                },
+            }
+        },
+        (0b01, 0b101) => j_type(cj_offset(parcel), 0),
+        (0b01, 0b110) => b_type(cb_offset(parcel), rs1_prime, 0b000),
+        (0b01, 0b111) => b_type(cb_offset(parcel), rs1_prime, 0b001),
+        (0b10, 0b000) => {
+            let shamt = (bits(parcel, 12, 12) << 5) | bits(parcel, 6, 2);
+            if shamt >= 32 && !rv64 {
+                return None;
+            }
+            i_type(shamt, rd, 0b001, rd, OpImm)
+        },
+        (0b10, 0b001) => {
+            let imm = (bits(parcel, 12, 12) << 5) | (bits(parcel, 6, 5) << 3) | (bits(parcel, 4, 2) << 6);
+            i_type(imm, 2, 0b011, rd, LoadFP)
+        },
+        (0b10, 0b010) | (0b10, 0b011) => {
+            let word = (bits(parcel, 12, 12) << 5) | (bits(parcel, 6, 4) << 2) | (bits(parcel, 3, 2) << 6);
+            let double = (bits(parcel, 12, 12) << 5) | (bits(parcel, 6, 5) << 3) | (bits(parcel, 4, 2) << 6);
+            match funct3 {
+                _ if rd == 0 && (funct3 == 0b010 || rv64) => return None,
+                0b010 => i_type(word, 2, 0b010, rd, Load),
+                _ if rv64 => i_type(double, 2, 0b011, rd, Load),
+                _ => i_type(word, 2, 0b010, rd, LoadFP),
+            }
+        },
+        (0b10, 0b100) => match (bits(parcel, 12, 12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i_type(0, rd, 0b000, 0, Jalr),
+            (0, _, _) => r_type(0, rs2, 0, 0b000, rd, Op),
+            (_, 0, 0) => 0x0010_0073,
+            (_, _, 0) => i_type(0, rd, 0b000, 1, Jalr),
+            _ => r_type(0, rs2, rd, 0b000, rd, Op),
+        },
+        (0b10, 0b101) => {
+            let imm = (bits(parcel, 12, 10) << 3) | (bits(parcel, 9, 7) << 6);
+            s_type(imm, rs2, 2, 0b011, StoreFP)
+        },
+        (0b10, 0b110) | (0b10, 0b111) => {
+            let word = (bits(parcel, 12, 9) << 2) | (bits(parcel, 8, 7) << 6);
+            let double = (bits(parcel, 12, 10) << 3) | (bits(parcel, 9, 7) << 6);
+            match funct3 {
+                0b110 => s_type(word, rs2, 2, 0b010, Store),
+                _ if rv64 => s_type(double, rs2, 2, 0b011, Store),
+                _ => s_type(word, rs2, 2, 0b010, StoreFP),
+            }
+        },
+        _ => return None,
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Pairs checked against an assembler, the compressed form and its 32 bit expansion
+    #[test]
+    fn expands_like_the_assembler() {
+        let rv32 = [
+            (0x0040, 0x00410413), //c.addi4spn s0, sp, 4
+            (0x1ffc, 0x3fc10793), //c.addi4spn a5, sp, 1020
+            (0x41c4, 0x0045a483), //c.lw s1, 4(a1)
+            (0xc1c4, 0x0095a223), //c.sw s1, 4(a1)
+            (0x2580, 0x0085b407), //c.fld fs0, 8(a1)
+            (0x61c0, 0x0045a407), //c.flw fs0, 4(a1)
+            (0xa588, 0x00a5b427), //c.fsd fa0, 8(a1)
+            (0xe588, 0x00a5a427), //c.fsw fa0, 8(a1)
+            (0x157d, 0xfff50513), //c.addi a0, -1
+            (0x2009, 0x002000ef), //c.jal 2
+            (0x5579, 0xffe00513), //c.li a0, -2
+            (0x7179, 0xfd010113), //c.addi16sp sp, -48
+            (0x75fd, 0xfffff5b7), //c.lui a1, 0xfffff
+            (0x6585, 0x000015b7), //c.lui a1, 1
+            (0x838d, 0x0037d793), //c.srli a5, 3
+            (0x8405, 0x40145413), //c.srai s0, 1
+            (0x887d, 0x01f47413), //c.andi s0, 31
+            (0x8c05, 0x40940433), //c.sub s0, s1
+            (0x8fb9, 0x00e7c7b3), //c.xor a5, a4
+            (0x8fd9, 0x00e7e7b3), //c.or a5, a4
+            (0x8c65, 0x00947433), //c.and s0, s1
+            (0xbff5, 0xffdff06f), //c.j -4
+            (0xaffd, 0x7fe0006f), //c.j 2046
+            (0xdc75, 0xfe040ee3), //c.beqz s0, -4
+            (0xcc7d, 0x0e040f63), //c.beqz s0, 254
+            (0xe399, 0x00079363), //c.bnez a5, 6
+            (0x050a, 0x00251513), //c.slli a0, 2
+            (0x2562, 0x01813507), //c.fldsp fa0, 24(sp)
+            (0x4512, 0x00412503), //c.lwsp a0, 4(sp)
+            (0x6562, 0x01812507), //c.flwsp fa0, 24(sp)
+            (0x8582, 0x00058067), //c.jr a1
+            (0x852e, 0x00b00533), //c.mv a0, a1
+            (0x9002, 0x00100073), //c.ebreak
+            (0x9582, 0x000580e7), //c.jalr a1
+            (0x952e, 0x00b50533), //c.add a0, a1
+            (0xa086, 0x04113027), //c.fsdsp ft1, 64(sp)
+            (0xc22a, 0x00a12223), //c.swsp a0, 4(sp)
+            (0xec2a, 0x00a12c27), //c.fswsp fa0, 24(sp)
+        ];
+        for &(compressed, expanded) in rv32.iter() {
+            assert_eq!(expand(compressed, false), Some(expanded), "{:#06x}", compressed);
+        }
+        let rv64 = [
+            (0x2405, 0x0014041b), //c.addiw s0, 1
+            (0x6988, 0x0105b503), //c.ld a0, 16(a1)
+            (0xe988, 0x00a5b823), //c.sd a0, 16(a1)
+            (0x9c05, 0x4094043b), //c.subw s0, s1
+            (0x9c25, 0x0094043b), //c.addw s0, s1
+            (0x1522, 0x02851513), //c.slli a0, 40
+            (0x9385, 0x0217d793), //c.srli a5, 33
+            (0x6582, 0x00013583), //c.ldsp a1, 0(sp)
+            (0xe82a, 0x00a13823), //c.sdsp a0, 16(sp)
+        ];
+        for &(compressed, expanded) in rv64.iter() {
+            assert_eq!(expand(compressed, true), Some(expanded), "{:#06x}", compressed);
+        }
+        assert_eq!(expand(0x0000, false), None, "the all zero parcel is illegal");
+        assert_eq!(expand(0x9c05, false), None, "c.subw is RV64 only");
+        assert_eq!(expand(0x1522, false), None, "RV32 shifts take at most 31");
+    }
+}
@@ -0,0 +1,131 @@
+//! Runs riscv-tests and riscv-arch-test programs, which report their result by writing to
+//! the `tohost` symbol and leave their results between `begin_signature` and `end_signature`.
+use super::core::{Core, CoreSettings};
+use crate::microvm::loader::{ElfImage, LoaderError};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Pass,
+    /// The number of the failing test case, riscv-tests keeps it in `gp`.
+    Fail(u64),
+    /// `tohost` was never written within the step limit.
+    Timeout,
+}
+impl Outcome {
+    /// Decodes a `tohost` value, 1 is a pass and any other value is the failing test number
+    /// shifted left by one with the low bit set.
+    pub fn from_tohost(value: u64) -> Outcome {
+        if value == 1 {
+            Outcome::Pass
+        } else {
+            Outcome::Fail(value >> 1)
+        }
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HarnessError {
+    Loader(LoaderError),
+    MissingSymbol(&'static str),
+    /// The signature region could not be read back from memory.
+    Signature,
+}
+impl fmt::Display for HarnessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HarnessError::Loader(e) => write!(f, "{}", e),
+            HarnessError::MissingSymbol(name) => write!(f, "the test has no `{}` symbol", name),
+            HarnessError::Signature => write!(f, "the signature region is not readable"),
+        }
+    }
+}
+impl From<LoaderError> for HarnessError {
+    fn from(e: LoaderError) -> Self {
+        HarnessError::Loader(e)
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestRun {
+    pub outcome: Outcome,
+    pub steps: u64,
+    /// The signature words, if the test defines a signature region.
+    pub signature: Option<Vec<u32>>,
+}
+
+/// Loads a test executable into `core` and steps it, taking traps, until it writes `tohost`
+/// or `max_steps` instructions have run.
+pub fn run<Settings: CoreSettings>(core: &mut Core<Settings>, elf: &[u8], max_steps: u64) -> Result<TestRun, HarnessError> {
+    let image = core.load_elf(elf)?;
+    let tohost = image.symbol("tohost").ok_or(HarnessError::MissingSymbol("tohost"))?.value;
+    let mut outcome = Outcome::Timeout;
+    let mut steps = 0;
+    while steps < max_steps {
+        core.step_with_traps();
+        steps += 1;
+        //tohost is a 64 bit location but RV32 tests only ever write its low word
+        match core.read_physical(tohost, 8) {
+            Some(0) | None => (),
+            Some(value) => {
+                outcome = Outcome::from_tohost(value);
+                break;
+            },
+        }
+    }
+    Ok(TestRun {
+        outcome,
+        steps,
+        signature: signature(core, &image)?,
+    })
+}
+/// Reads the words between `begin_signature` and `end_signature`, `None` if the image has no
+/// signature region.
+pub fn signature<Settings: CoreSettings>(core: &Core<Settings>, image: &ElfImage) -> Result<Option<Vec<u32>>, HarnessError> {
+    let (begin, end) = match (image.symbol("begin_signature"), image.symbol("end_signature")) {
+        (Some(begin), Some(end)) => (begin.value, end.value),
+        (None, None) => return Ok(None),
+        (None, _) => return Err(HarnessError::MissingSymbol("begin_signature")),
+        (_, None) => return Err(HarnessError::MissingSymbol("end_signature")),
+    };
+    (begin..end).step_by(4)
+        .map(|address| core.read_physical(address, 4).map(|word| word as u32))
+        .collect::<Option<Vec<u32>>>()
+        .map(Some)
+        .ok_or(HarnessError::Signature)
+}
+/// Formats a signature the way the architectural test framework's reference files are laid
+/// out, one zero padded hex word per line.
+pub fn format_signature(signature: &[u32]) -> String {
+    signature.iter().map(|word| format!("{:08x}\n", word)).collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::loader::tests::elf32;
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+    use crate::risc_v_emu::core::tests::Rv32;
+    use crate::risc_v_emu::extensions::Extensions;
+
+    fn run_program(program: &[u32]) -> TestRun {
+        let text: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        let elf = elf32(243, 0x100, &text, 0x200, &[0; 8], 0);
+        let mut core = Core::<Rv32>::new(SparseAddressSpace::new(0x2000u16).unwrap(), Extensions::base());
+        run(&mut core, &elf, 100).unwrap()
+    }
+    #[test]
+    fn decodes_tohost() {
+        let fail = run_program(&[
+            0x00b00193, //li gp, 11
+            0x00119193, //slli gp, gp, 1
+            0x0011e193, //ori gp, gp, 1
+            0x20302023, //sw gp, 0x200(zero)
+        ]);
+        assert_eq!((fail.outcome, fail.steps, fail.signature), (Outcome::Fail(11), 4, None));
+        let pass = run_program(&[
+            0x00100193, //li gp, 1
+            0x20302023, //sw gp, 0x200(zero)
+        ]);
+        assert_eq!(pass.outcome, Outcome::Pass);
+        assert_eq!(run_program(&[0x0000006f]).outcome, Outcome::Timeout); //j .
+        assert_eq!(format_signature(&[0xdeadbeef, 1]), "deadbeef\n00000001\n");
+    }
+}
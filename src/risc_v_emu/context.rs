@@ -1,10 +1,28 @@
 use super::regs;
 use super::csr;
+use super::float::{FloatRegs, Fcsr};
 use super::types::DataType;
-use crate::microvm::memory::address::AddressType;
 
-pub struct Context<RegType: DataType, Address: AddressType> {
-    regs: regs::Regs<RegType>,
-    csr: csr::CSR,
-    a: Address
-}
\ No newline at end of file
+pub struct Context<RegType: DataType> {
+    pub regs: regs::Regs<RegType>,
+    pub fregs: FloatRegs,
+    pub fcsr: Fcsr,
+    pub csr: csr::CSR,
+    pub pc: RegType,
+}
+impl<RegType: DataType> Context<RegType> {
+    pub fn new() -> Context<RegType> {
+        Context {
+            regs: regs::Regs::new(),
+            fregs: FloatRegs::new(),
+            fcsr: Fcsr::default(),
+            csr: csr::CSR::new(),
+            pc: RegType::from_u64(0),
+        }
+    }
+}
+impl<RegType: DataType> Default for Context<RegType> {
+    fn default() -> Self {
+        Context::new()
+    }
+}
@@ -1,6 +1,20 @@
 use super::context;
 use crate::risc_v_emu::types::DataType;
 use crate::microvm::memory::address::AddressType;
+use crate::microvm::memory::address_space::AddressSpace;
+use crate::microvm::memory::permissions::Access;
+use crate::microvm::memory::sparse::SparseAddressSpace;
+use crate::microvm::memory::MemoryError;
+use crate::microvm::device::InterruptLines;
+use crate::microvm::loader::{self, ElfImage, LoaderError, Machine};
+use crate::microvm::mmu::{AccessType, Paging, PagingMode, TranslationFault, MMU};
+use crate::risc_v_emu::compressed;
+use crate::risc_v_emu::decoder::RawInstruction;
+use crate::risc_v_emu::exception::Exception;
+use crate::risc_v_emu::extensions::{Extension, Extensions};
+use crate::risc_v_emu::instructions::BaseOpcodes;
+use crate::risc_v_emu::csr::{self, accessibility::PrivilegeLevel};
+use num::traits::FromPrimitive;
 
 pub trait CoreSettings: Sized {
     type XLen: DataType;
@@ -8,5 +22,550 @@ pub trait CoreSettings: Sized {
     type Address: AddressType;
 }
 pub struct Core<Settings: CoreSettings> {
-    context: context::Context<Settings::RegType, Settings::Address>,
-}
\ No newline at end of file
+    pub(crate) context: context::Context<Settings::RegType>,
+    mmu: MMU<Settings::Address>,
+    extensions: Extensions,
+    //Address reserved by the last LR
+    pub(crate) reservation: Option<u64>,
+    //Device interrupt lines, any raised line is a machine external interrupt
+    pub(crate) interrupts: Option<InterruptLines>,
+}
+//Sign extends the low 32 bits, the result of every RV64 *W instruction
+fn sign_extend_word(value: u64) -> u64 {
+    value as i32 as i64 as u64
+}
+impl<Settings: CoreSettings> Core<Settings> {
+    pub fn new(space: SparseAddressSpace<Settings::Address>, extensions: Extensions) -> Core<Settings> {
+        let mut context = context::Context::new();
+        if extensions.has(Extension::F) {
+            context.csr.mstatus |= csr::FS_INITIAL;
+        }
+        Core {
+            context,
+            mmu: MMU::new(space),
+            extensions,
+            reservation: None,
+            interrupts: None,
+        }
+    }
+    pub fn context(&self) -> &context::Context<Settings::RegType> {
+        &self.context
+    }
+    pub fn context_mut(&mut self) -> &mut context::Context<Settings::RegType> {
+        &mut self.context
+    }
+    pub fn space(&self) -> &SparseAddressSpace<Settings::Address> {
+        self.mmu.space()
+    }
+    pub fn space_mut(&mut self) -> &mut SparseAddressSpace<Settings::Address> {
+        self.mmu.space_mut()
+    }
+    pub fn mmu(&self) -> &MMU<Settings::Address> {
+        &self.mmu
+    }
+    pub fn mmu_mut(&mut self) -> &mut MMU<Settings::Address> {
+        &mut self.mmu
+    }
+    /// Maps an ELF executable into the address space and sets the PC to its entry point.
+    pub fn load_elf(&mut self, elf: &[u8]) -> Result<ElfImage, LoaderError> {
+        let machine = if self.is_rv64() { Machine::RiscV64 } else { Machine::RiscV32 };
+        let image = loader::load(elf, machine, self.space_mut())?;
+        self.set_pc(image.entry);
+        Ok(image)
+    }
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+    pub fn xlen() -> u32 {
+        Settings::RegType::bit_len()
+    }
+    pub fn pc(&self) -> u64 {
+        self.context.pc.to_u64()
+    }
+    pub fn set_pc(&mut self, pc: u64) {
+        self.context.pc = Settings::RegType::from_u64(pc)
+    }
+    //Register value zero extended from XLEN
+    pub fn x(&self, reg: usize) -> u64 {
+        self.context.regs.get(reg).to_u64()
+    }
+    //Register value sign extended from XLEN
+    pub fn x_signed(&self, reg: usize) -> i64 {
+        self.context.regs.get(reg).to_i64()
+    }
+    pub fn set_x(&mut self, reg: usize, value: u64) {
+        self.context.regs.set(reg, Settings::RegType::from_u64(value))
+    }
+    //Truncates an address computation to XLEN
+    pub(crate) fn wrap(&self, value: u64) -> u64 {
+        Settings::RegType::from_u64(value).to_u64()
+    }
+    pub(crate) fn is_rv64(&self) -> bool {
+        Self::xlen() == 64
+    }
+    pub fn supports_privilege(&self, privilege: PrivilegeLevel) -> bool {
+        match privilege {
+            PrivilegeLevel::Machine => true,
+            PrivilegeLevel::Supervisor => self.extensions.has(Extension::S),
+            PrivilegeLevel::User => self.extensions.has(Extension::U),
+            PrivilegeLevel::Hypervisor => false,
+        }
+    }
+    pub(crate) fn instruction_alignment(&self) -> u64 {
+        if self.extensions.has(Extension::C) { 2 } else { 4 }
+    }
+    //Loads and stores use MPP as their privilege when MPRV is set
+    fn effective_privilege(&self, access: AccessType) -> PrivilegeLevel {
+        let csr = &self.context.csr;
+        if access != AccessType::Fetch && csr.mstatus & csr::MSTATUS_MPRV != 0 {
+            PrivilegeLevel::from_bits((csr.mstatus >> csr::MSTATUS_MPP_SHIFT) as u8)
+        } else {
+            csr.privilege()
+        }
+    }
+    //Translation state for an access
+    fn paging(&self, access: AccessType) -> Paging {
+        let csr = &self.context.csr;
+        let privilege = self.effective_privilege(access);
+        let mode = if privilege == PrivilegeLevel::Machine { PagingMode::Bare } else { self.satp_mode() };
+        let (root, asid) = if self.is_rv64() {
+            (csr.satp & ((1 << 44) - 1), (csr.satp >> 44) & 0xFFFF)
+        } else {
+            (csr.satp & ((1 << 22) - 1), (csr.satp >> 22) & 0x1FF)
+        };
+        Paging {
+            mode,
+            root,
+            asid,
+            user: privilege == PrivilegeLevel::User,
+            sum: csr.mstatus & csr::MSTATUS_SUM != 0,
+            mxr: csr.mstatus & csr::MSTATUS_MXR != 0,
+        }
+    }
+    pub(crate) fn satp_mode(&self) -> PagingMode {
+        let satp = self.context.csr.satp;
+        match (self.is_rv64(), satp >> (Self::xlen() - 4)) {
+            (false, mode) if mode & 0b1000 != 0 => PagingMode::Sv32,
+            (true, 8) => PagingMode::Sv39,
+            (true, 9) => PagingMode::Sv48,
+            _ => PagingMode::Bare,
+        }
+    }
+    //Translates a `size` byte access and checks the physical address against PMP. Page table
+    //accesses are checked as S-mode accesses
+    pub(crate) fn translate(&mut self, address: u64, size: usize, access: AccessType) -> Result<u64, Exception> {
+        let paging = self.paging(access);
+        let pmp = &self.context.csr.pmp;
+        let walk_permitted = |physical, size, access| pmp.check(physical, size, access, PrivilegeLevel::Supervisor);
+        let physical = self.mmu.translate_checked(address, access, &paging, walk_permitted);
+        let privilege = self.effective_privilege(access);
+        let physical = physical.and_then(|physical| {
+            if self.context.csr.pmp.check(physical, size, access, privilege) {
+                Ok(physical)
+            } else {
+                Err(TranslationFault::Access)
+            }
+        });
+        physical.map_err(|fault| match (fault, access) {
+            (TranslationFault::Page, AccessType::Fetch) => Exception::InstructionPageFault(address),
+            (TranslationFault::Page, AccessType::Load) => Exception::LoadPageFault(address),
+            (TranslationFault::Page, AccessType::Store) => Exception::StorePageFault(address),
+            (TranslationFault::Access, access) => Self::access_fault(access, address),
+        })
+    }
+    //The exception for a failed access at the virtual `address`
+    fn access_fault(access: AccessType, address: u64) -> Exception {
+        match access {
+            AccessType::Fetch => Exception::InstructionAccessFault(address),
+            AccessType::Load => Exception::LoadAccessFault(address),
+            AccessType::Store => Exception::StoreAccessFault(address),
+        }
+    }
+    //A program's access, privileged outside U-mode
+    fn access(&self, kind: AccessType) -> Access {
+        Access { kind, privileged: self.effective_privilege(kind) != PrivilegeLevel::User }
+    }
+    fn physical_address(address: u64) -> Option<Settings::Address> {
+        Settings::Address::from_u64(address)
+    }
+    pub(crate) fn read_physical(&self, physical: u64, size: usize) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        self.mmu.space().read_bytes(Self::physical_address(physical)?, &mut bytes[..size]).ok()?;
+        Some(u64::from_le_bytes(bytes))
+    }
+    //A fetch or load by the program, checked against the region's permissions. Devices may act
+    //on it
+    pub(crate) fn load_physical(&mut self, physical: u64, size: usize, kind: AccessType) -> Result<u64, MemoryError> {
+        let access = self.access(kind);
+        let address = Self::physical_address(physical).ok_or(MemoryError::AccessFault { address: physical, kind })?;
+        let mut bytes = [0u8; 8];
+        self.mmu.space_mut().read_bytes_checked(address, &mut bytes[..size], access)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+    pub(crate) fn store_physical(&mut self, physical: u64, size: usize, value: u64) -> Result<(), MemoryError> {
+        let access = self.access(AccessType::Store);
+        let address = Self::physical_address(physical).ok_or(MemoryError::AccessFault { address: physical, kind: access.kind })?;
+        self.mmu.space_mut().write_bytes_checked(address, &value.to_le_bytes()[..size], access)
+    }
+    pub(crate) fn write_physical(&mut self, physical: u64, size: usize, value: u64) -> Option<()> {
+        let physical = Self::physical_address(physical)?;
+        self.mmu.space_mut().write_bytes(physical, &value.to_le_bytes()[..size]).ok()
+    }
+    //Fetches the instruction at the PC along with the compressed parcel it was expanded from
+    fn fetch(&mut self) -> Result<(RawInstruction, Option<u16>), Exception> {
+        let pc = self.pc();
+        let mut parcels = [0u16; 2];
+        //With C a 32 bit instruction can straddle two pages, so each half is translated separately
+        for half in 0..2 {
+            let address = self.wrap(pc.wrapping_add(half * 2));
+            let physical = self.translate(address, 2, AccessType::Fetch)?;
+            let parcel = self.load_physical(physical, 2, AccessType::Fetch).map_err(|_| Exception::InstructionAccessFault(address))?;
+            parcels[half as usize] = parcel as u16;
+            if half == 0 && self.extensions.has(Extension::C) && compressed::is_compressed(parcels[0]) {
+                let expanded = compressed::expand(parcels[0], self.is_rv64());
+                let expanded = expanded.ok_or(Exception::IllegalInstruction(parcels[0] as u32))?;
+                return Ok((RawInstruction(expanded), Some(parcels[0])));
+            }
+        }
+        Ok((RawInstruction(parcels[0] as u32 | (parcels[1] as u32) << 16), None))
+    }
+    /// Little endian load of `size` bytes, zero extended.
+    pub(crate) fn load(&mut self, address: u64, size: usize) -> Result<u64, Exception> {
+        if !address.is_multiple_of(size as u64) {
+            return Err(Exception::LoadAddressMisaligned(address));
+        }
+        let physical = self.translate(address, size, AccessType::Load)?;
+        self.load_physical(physical, size, AccessType::Load).map_err(|_| Exception::LoadAccessFault(address))
+    }
+    /// Little endian store of the low `size` bytes of `value`.
+    pub(crate) fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), Exception> {
+        if !address.is_multiple_of(size as u64) {
+            return Err(Exception::StoreAddressMisaligned(address));
+        }
+        let physical = self.translate(address, size, AccessType::Store)?;
+        self.store_physical(physical, size, value).map_err(|_| Exception::StoreAccessFault(address))
+    }
+    fn jump_target(&self, target: u64) -> Result<u64, Exception> {
+        let target = self.wrap(target);
+        if !target.is_multiple_of(self.instruction_alignment()) {
+            Err(Exception::InstructionAddressMisaligned(target))
+        } else {
+            Ok(target)
+        }
+    }
+    /// Fetches and executes a single instruction. On an exception the PC is left pointing at
+    /// the faulting instruction.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        self.context.csr.cycle = self.context.csr.cycle.wrapping_add(1);
+        self.mmu.space_mut().tick(1);
+        let (instruction, parcel) = self.fetch()?;
+        let length = if parcel.is_some() { 2 } else { 4 };
+        //mtval reports the instruction as it was encoded, not its expansion
+        let next_pc = self.execute(instruction, length).map_err(|e| match (e, parcel) {
+            (Exception::IllegalInstruction(_), Some(parcel)) => Exception::IllegalInstruction(parcel as u32),
+            (e, _) => e,
+        })?;
+        self.set_pc(next_pc);
+        self.context.csr.instret = self.context.csr.instret.wrapping_add(1);
+        Ok(())
+    }
+    //Executes a `length` byte instruction and returns the next PC
+    fn execute(&mut self, instruction: RawInstruction, length: u64) -> Result<u64, Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let pc = self.pc();
+        let mut next_pc = self.wrap(pc.wrapping_add(length));
+        let (rd, rs1, rs2) = (instruction.rd(), instruction.rs1(), instruction.rs2());
+        let funct3 = instruction.funct3().value();
+        let funct7 = instruction.funct7().value();
+        match instruction.opcode().ok_or(illegal)? {
+            BaseOpcodes::Lui => self.set_x(rd, instruction.imm_u() as u64),
+            BaseOpcodes::Auipc => self.set_x(rd, pc.wrapping_add(instruction.imm_u() as u64)),
+            BaseOpcodes::Jal => {
+                let target = self.jump_target(pc.wrapping_add(instruction.imm_j() as u64))?;
+                self.set_x(rd, next_pc);
+                next_pc = target;
+            },
+            BaseOpcodes::Jalr => {
+                if funct3 != 0 {
+                    return Err(illegal);
+                }
+                let target = self.jump_target(self.x(rs1).wrapping_add(instruction.imm_i() as u64) & !1)?;
+                self.set_x(rd, next_pc);
+                next_pc = target;
+            },
+            BaseOpcodes::Branch => {
+                let (a, b) = (self.x(rs1), self.x(rs2));
+                let (a_signed, b_signed) = (self.x_signed(rs1), self.x_signed(rs2));
+                let taken = match funct3 {
+                    0b000 => a == b,
+                    0b001 => a != b,
+                    0b100 => a_signed < b_signed,
+                    0b101 => a_signed >= b_signed,
+                    0b110 => a < b,
+                    0b111 => a >= b,
+                    _ => return Err(illegal),
+                };
+                if taken {
+                    next_pc = self.jump_target(pc.wrapping_add(instruction.imm_b() as u64))?;
+                }
+            },
+            BaseOpcodes::Load => {
+                let address = self.wrap(self.x(rs1).wrapping_add(instruction.imm_i() as u64));
+                let value = match funct3 {
+                    0b000 => self.load(address, 1)? as i8 as u64,
+                    0b001 => self.load(address, 2)? as i16 as u64,
+                    0b010 => sign_extend_word(self.load(address, 4)?),
+                    0b011 if self.is_rv64() => self.load(address, 8)?,
+                    0b100 => self.load(address, 1)?,
+                    0b101 => self.load(address, 2)?,
+                    0b110 if self.is_rv64() => self.load(address, 4)?,
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            },
+            BaseOpcodes::Store => {
+                let address = self.wrap(self.x(rs1).wrapping_add(instruction.imm_s() as u64));
+                let size = match funct3 {
+                    0b000 => 1,
+                    0b001 => 2,
+                    0b010 => 4,
+                    0b011 if self.is_rv64() => 8,
+                    _ => return Err(illegal),
+                };
+                self.store(address, size, self.x(rs2))?;
+            },
+            BaseOpcodes::OpImm => {
+                let value = self.op(funct3, instruction.imm_i() as u64, true, instruction)?;
+                self.set_x(rd, value);
+            },
+            BaseOpcodes::Op => {
+                let value = match funct7 {
+                    0b000_0000 | 0b010_0000 => self.op(funct3, self.x(rs2), false, instruction)?,
+                    0b000_0001 if self.extensions.has(Extension::M) => self.mul_div(instruction),
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            },
+            BaseOpcodes::OpImm32 if self.is_rv64() => {
+                let value = self.op_32(funct3, instruction.imm_i() as u64, true, instruction)?;
+                self.set_x(rd, value);
+            },
+            BaseOpcodes::Op32 if self.is_rv64() => {
+                let value = match funct7 {
+                    0b000_0000 | 0b010_0000 => self.op_32(funct3, self.x(rs2), false, instruction)?,
+                    0b000_0001 if self.extensions.has(Extension::M) => self.mul_div_32(instruction)?,
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            },
+            //Single hart with no caches so both FENCE and FENCE.I are no-ops
+            BaseOpcodes::MiscMem => {
+                if funct3 > 1 {
+                    return Err(illegal);
+                }
+            },
+            BaseOpcodes::System => {
+                match (funct3, instruction.imm_i(), rs1, rd) {
+                    (0, 0, 0, 0) => return Err(Exception::EnvironmentCall(self.context.csr.privilege())),
+                    (0, 1, 0, 0) => return Err(Exception::Breakpoint(pc)),
+                    (0, 0x102, 0, 0) => next_pc = self.sret().ok_or(illegal)?,
+                    (0, 0x302, 0, 0) => next_pc = self.mret().ok_or(illegal)?,
+                    //WFI may return immediately, interrupts are checked before every step anyway
+                    (0, 0x105, 0, 0) => {
+                        if self.context.csr.privilege() < PrivilegeLevel::Machine && self.context.csr.mstatus & csr::MSTATUS_TW != 0 {
+                            return Err(illegal);
+                        }
+                    },
+                    (0, _, _, 0) if funct7 == 0b000_1001 => self.sfence_vma(rs1, rs2).ok_or(illegal)?,
+                    (0, _, _, _) | (0b100, _, _, _) => return Err(illegal),
+                    _ => self.execute_csr(instruction)?,
+                }
+            },
+            BaseOpcodes::Amo if self.extensions.has(Extension::A) => self.execute_atomic(instruction)?,
+            BaseOpcodes::LoadFP | BaseOpcodes::StoreFP | BaseOpcodes::MAdd | BaseOpcodes::MSub |
+            BaseOpcodes::NMSub | BaseOpcodes::NMAdd | BaseOpcodes::OpFP if self.extensions.has(Extension::F) => {
+                self.execute_float(instruction)?;
+            },
+            _ => return Err(illegal),
+        }
+        Ok(next_pc)
+    }
+    //Shared ALU for OP and OP-IMM, `b` is either rs2 or the sign extended immediate
+    fn op(&self, funct3: u8, b: u64, immediate: bool, instruction: RawInstruction) -> Result<u64, Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let a = self.x(instruction.rs1());
+        let a_signed = self.x_signed(instruction.rs1());
+        let b = self.wrap(b);
+        let b_signed = Settings::RegType::from_u64(b).to_i64();
+        let shamt = b & (Self::xlen() - 1) as u64;
+        //funct7 selects SUB and SRA, OP-IMM shifts keep it above a 5 (RV32) or 6 (RV64) bit shamt
+        let (upper, alternate) = if immediate {
+            let shamt_bits = if self.is_rv64() { 6 } else { 5 };
+            (instruction.0 >> (20 + shamt_bits), 0b010_0000 >> (shamt_bits - 5))
+        } else {
+            (instruction.funct7().value() as u32, 0b010_0000)
+        };
+        let is_alternate = |allowed: bool| {
+            if upper == 0 {
+                Ok(false)
+            } else if allowed && upper == alternate {
+                Ok(true)
+            } else {
+                Err(illegal)
+            }
+        };
+        if !immediate && funct3 != 0b000 && funct3 != 0b101 {
+            is_alternate(false)?;
+        }
+        Ok(match funct3 {
+            0b000 if !immediate && is_alternate(true)? => a.wrapping_sub(b),
+            0b000 => a.wrapping_add(b),
+            0b001 => {
+                is_alternate(false)?;
+                a << shamt
+            },
+            0b010 => (a_signed < b_signed) as u64,
+            0b011 => (a < b) as u64,
+            0b100 => a ^ b,
+            0b101 if is_alternate(true)? => (a_signed >> shamt) as u64,
+            0b101 => a >> shamt,
+            0b110 => a | b,
+            _ => a & b,
+        })
+    }
+    //RV64 only OP-32 and OP-IMM-32 which operate on the low word and sign extend the result
+    fn op_32(&self, funct3: u8, b: u64, immediate: bool, instruction: RawInstruction) -> Result<u64, Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let a = self.x(instruction.rs1()) as u32;
+        let b = b as u32;
+        let funct7 = instruction.funct7().value();
+        let shift = b & 0b1_1111;
+        let value = match (funct3, funct7) {
+            (0b000, _) if immediate => a.wrapping_add(b),
+            (0b000, 0b000_0000) => a.wrapping_add(b),
+            (0b000, 0b010_0000) => a.wrapping_sub(b),
+            (0b001, 0b000_0000) => a << shift,
+            (0b101, 0b000_0000) => a >> shift,
+            (0b101, 0b010_0000) => ((a as i32) >> shift) as u32,
+            _ => return Err(illegal),
+        };
+        Ok(sign_extend_word(value as u64))
+    }
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::risc_v_emu::types::{DoubleWord, QuadWord};
+    use crate::microvm::memory::address_space::DenseStaticMemory;
+
+    pub struct Rv32;
+    impl CoreSettings for Rv32 {
+        type XLen = DoubleWord;
+        type RegType = DoubleWord;
+        type Address = u16;
+    }
+    pub struct Rv64;
+    impl CoreSettings for Rv64 {
+        type XLen = QuadWord;
+        type RegType = QuadWord;
+        type Address = u16;
+    }
+    pub fn core_with_program<Settings: CoreSettings<Address=u16>>(extensions: &str, program: &[u32]) -> Core<Settings> {
+        let mut space = SparseAddressSpace::new(0x2000u16).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x2000u16))).unwrap();
+        let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        space.write_bytes(0, &bytes).unwrap();
+        Core::new(space, Extensions::from_letters(extensions).unwrap())
+    }
+    //PMP entry 0 as a NAPOT region covering everything, so S-mode and U-mode can run
+    pub fn allow_all_pmp<Settings: CoreSettings>(core: &mut Core<Settings>) {
+        use crate::risc_v_emu::csr::{accessibility::CsrAddress, PMPADDR0, PMPCFG0};
+        core.write_csr(CsrAddress::new(PMPADDR0), u64::MAX).unwrap();
+        core.write_csr(CsrAddress::new(PMPCFG0), 0x1F).unwrap();
+    }
+
+    #[test]
+    fn sums_a_loop() {
+        let program = [
+            0x00a00513, //li a0, 10
+            0x00000593, //li a1, 0
+            0x00a585b3, //loop: add a1, a1, a0
+            0xfff50513, //addi a0, a0, -1
+            0xfe051ce3, //bnez a0, loop
+            0x10b02023, //sw a1, 256(zero)
+            0x00100073, //ebreak
+        ];
+        let mut core = core_with_program::<Rv32>("i", &program);
+        while core.step().is_ok() {}
+        assert_eq!(core.x(11), 55);
+        assert_eq!(core.load(0x100, 4), Ok(55));
+        assert_eq!(core.step(), Err(Exception::Breakpoint(0x18)));
+    }
+    #[test]
+    fn runs_an_elf_executable() {
+        use crate::microvm::loader::tests::elf32;
+        let text: Vec<u8> = [
+            0x20002503u32, //lw a0, 0x200(zero)
+            0x20a02223, //sw a0, 0x204(zero)
+        ].iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        let elf = elf32(243, 0x100, &text, 0x200, &[42, 0, 0, 0], 4);
+        let mut core = Core::<Rv32>::new(SparseAddressSpace::new(0x2000u16).unwrap(), Extensions::base());
+        let image = core.load_elf(&elf).unwrap();
+        assert_eq!((core.pc(), image.symbol("tohost").unwrap().value), (0x100, 0x200));
+        core.step().unwrap();
+        core.step().unwrap();
+        assert_eq!(core.load(0x204, 4), Ok(42));
+        assert_eq!(core.step(), Err(Exception::InstructionAccessFault(0x108)));
+        assert_eq!(Core::<Rv64>::new(SparseAddressSpace::new(0x2000u16).unwrap(), Extensions::base()).load_elf(&elf).err(),
+                   Some(LoaderError::WrongClass { expected: loader::ElfClass::Elf64, found: loader::ElfClass::Elf32 }));
+    }
+    #[test]
+    fn region_permissions() {
+        use crate::microvm::memory::permissions::Permissions;
+        let program = [
+            0x00001537u32, //lui a0, 1
+            0x00052583, //lw a1, 0(a0)
+            0x00b52023, //sw a1, 0(a0)
+        ];
+        let mut space = SparseAddressSpace::new(0x2000u16).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x1000u16))).unwrap();
+        space.add_space_with_permissions(0x1000, Box::new(DenseStaticMemory::new(0x1000u16)), Permissions::parse("r").unwrap()).unwrap();
+        let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        space.write_bytes(0, &bytes).unwrap();
+        let mut core = Core::<Rv32>::new(space, Extensions::base());
+        core.step().unwrap();
+        core.step().unwrap();
+        assert_eq!(core.step(), Err(Exception::StoreAccessFault(0x1000)));
+        core.set_pc(0x1000);
+        assert_eq!(core.step(), Err(Exception::InstructionAccessFault(0x1000)));
+    }
+    #[test]
+    fn xlen_dependent_behaviour() {
+        let program = [
+            0xfff00513, //li a0, -1
+            0x00155593, //srli a1, a0, 1
+            0x0015051b, //addiw a0, a0, 1
+        ];
+        let mut core = core_with_program::<Rv32>("i", &program);
+        core.step().unwrap();
+        core.step().unwrap();
+        assert_eq!(core.x(11), 0x7FFF_FFFF);
+        assert_eq!(core.step(), Err(Exception::IllegalInstruction(0x0015051b)));
+
+        let mut core = core_with_program::<Rv64>("i", &program);
+        for _ in 0..3 {
+            core.step().unwrap();
+        }
+        assert_eq!(core.x(11), 0x7FFF_FFFF_FFFF_FFFF);
+        assert_eq!(core.x(10), 0);
+    }
+    #[test]
+    fn misaligned_load_faults() {
+        let program = [
+            0x00102503, //lw a0, 1(zero)
+        ];
+        let mut core = core_with_program::<Rv32>("i", &program);
+        assert_eq!(core.step(), Err(Exception::LoadAddressMisaligned(1)));
+        assert_eq!(core.pc(), 0);
+    }
+}
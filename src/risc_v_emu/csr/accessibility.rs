@@ -1,17 +1,63 @@
 #[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum PrivilegeLevel {
     User = 0,
     Supervisor = 1,
     Hypervisor = 2,
     Machine = 3,
 }
+impl PrivilegeLevel {
+    pub fn from_bits(bits: u8) -> PrivilegeLevel {
+        match bits & 0b11 {
+            0 => PrivilegeLevel::User,
+            1 => PrivilegeLevel::Supervisor,
+            2 => PrivilegeLevel::Hypervisor,
+            _ => PrivilegeLevel::Machine,
+        }
+    }
+}
 #[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Use {
     Standard = 11,
     NonStandard,
 }
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Accessibility {
     ReadOnly,
     ReadWrite,
     ReadWriteShadow
-}
\ No newline at end of file
+}
+/// 12 bit CSR address. Bits 11:10 mark read-only registers, bits 9:8 hold the lowest privilege
+/// level that may access it and a few sub-ranges are reserved for custom registers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CsrAddress(pub u16);
+impl CsrAddress {
+    pub const MASK: u16 = 0xFFF;
+    pub fn new(address: u16) -> CsrAddress {
+        CsrAddress(address & Self::MASK)
+    }
+    pub fn value(self) -> u16 {
+        self.0
+    }
+    pub fn accessibility(self) -> Accessibility {
+        if self.0 >> 10 == 0b11 {
+            Accessibility::ReadOnly
+        } else {
+            Accessibility::ReadWrite
+        }
+    }
+    pub fn is_read_only(self) -> bool {
+        self.accessibility() == Accessibility::ReadOnly
+    }
+    pub fn privilege(self) -> PrivilegeLevel {
+        PrivilegeLevel::from_bits((self.0 >> 8) as u8)
+    }
+    //Custom ranges from the privileged spec's address mapping: all of 0x800-0x8FF and the
+    //top quarter (xC0-xFF) of 0x5xx-0x7xx and 0x9xx-0xFxx
+    pub fn usage(self) -> Use {
+        let top = self.0 >> 8;
+        let custom = top == 0x8 || (top >= 0x5 && self.0 & 0xC0 == 0xC0);
+        if custom { Use::NonStandard } else { Use::Standard }
+    }
+}
@@ -0,0 +1,351 @@
+use super::*;
+use crate::risc_v_emu::core::{Core, CoreSettings};
+use crate::risc_v_emu::decoder::RawInstruction;
+use crate::risc_v_emu::exception::{Exception, Interrupt};
+use crate::risc_v_emu::extensions::Extension;
+
+impl<Settings: CoreSettings> Core<Settings> {
+    fn misa(&self) -> u64 {
+        let mxl = if self.is_rv64() { 2 } else { 1 };
+        (mxl << (Self::xlen() - 2)) | self.extensions().bits() as u64
+    }
+    //Registers that are always present, `None` if the address isn't implemented
+    fn read_builtin_csr(&self, address: CsrAddress) -> Option<u64> {
+        let float = self.extensions().has(Extension::F);
+        let supervisor = self.supports_privilege(PrivilegeLevel::Supervisor);
+        let rv32 = !self.is_rv64();
+        let csr = &self.context.csr;
+        Some(match address.value() {
+            FFLAGS if float => self.context.fcsr.fflags() as u64,
+            FRM if float => self.context.fcsr.frm() as u64,
+            FCSR if float => self.context.fcsr.fcsr() as u64,
+            CYCLE | MCYCLE => self.wrap(csr.cycle),
+            INSTRET | MINSTRET => self.wrap(csr.instret),
+            CYCLEH | MCYCLEH if rv32 => csr.cycle >> 32,
+            INSTRETH | MINSTRETH if rv32 => csr.instret >> 32,
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            MISA => self.misa(),
+            MSTATUS => self.mstatus(),
+            MTVEC => csr.mtvec,
+            MSCRATCH => csr.mscratch,
+            MEPC => csr.mepc,
+            MCAUSE => csr.mcause,
+            MTVAL => csr.mtval,
+            MIE => csr.mie,
+            MIP => csr.mip,
+            MEDELEG if supervisor => csr.medeleg,
+            MIDELEG if supervisor => csr.mideleg,
+            SSTATUS if supervisor => self.mstatus() & (SSTATUS_MASK | 1 << (Self::xlen() - 1)),
+            SIE if supervisor => csr.mie & csr.mideleg,
+            SIP if supervisor => csr.mip & csr.mideleg,
+            STVEC if supervisor => csr.stvec,
+            SSCRATCH if supervisor => csr.sscratch,
+            SEPC if supervisor => csr.sepc,
+            SCAUSE if supervisor => csr.scause,
+            STVAL if supervisor => csr.stval,
+            SATP if supervisor => csr.satp,
+            PMPCFG0..=PMPCFG15 => self.pmpcfg(address.value() - PMPCFG0)?,
+            PMPADDR0..=PMPADDR63 => {
+                let entry = (address.value() - PMPADDR0) as usize;
+                if entry < csr.pmp.entries() { csr.pmp.addr(entry) } else { 0 }
+            },
+            _ => return None,
+        })
+    }
+    //SD summarises FS being dirty in the top bit
+    fn mstatus(&self) -> u64 {
+        let mstatus = self.context.csr.mstatus;
+        if mstatus & MSTATUS_FS == FS_DIRTY {
+            mstatus | (1 << (Self::xlen() - 1))
+        } else {
+            mstatus
+        }
+    }
+    fn write_mstatus(&mut self, value: u64) {
+        let mut writable = MSTATUS_MIE | MSTATUS_MPIE;
+        if self.extensions().has(Extension::F) {
+            writable |= MSTATUS_FS;
+        }
+        if self.supports_privilege(PrivilegeLevel::User) {
+            writable |= MSTATUS_MPRV | MSTATUS_TW;
+        }
+        if self.supports_privilege(PrivilegeLevel::Supervisor) {
+            writable |= SSTATUS_MASK | MSTATUS_TVM | MSTATUS_TSR;
+        }
+        //MPP is WARL, unsupported privilege levels leave it unchanged
+        if self.supports_privilege(PrivilegeLevel::from_bits((value >> MSTATUS_MPP_SHIFT) as u8)) {
+            writable |= MSTATUS_MPP;
+        }
+        let csr = &mut self.context.csr;
+        csr.mstatus = (csr.mstatus & !writable) | (value & writable);
+    }
+    fn write_sstatus(&mut self, value: u64) {
+        let mut writable = SSTATUS_MASK;
+        if !self.extensions().has(Extension::F) {
+            writable &= !MSTATUS_FS;
+        }
+        let csr = &mut self.context.csr;
+        csr.mstatus = (csr.mstatus & !writable) | (value & writable);
+    }
+    //Interrupt bits that exist in mie and mip
+    fn interrupt_mask(&self) -> u64 {
+        let supervisor = self.supports_privilege(PrivilegeLevel::Supervisor);
+        Interrupt::PRIORITY.iter()
+            .filter(|interrupt| supervisor || !interrupt.is_supervisor())
+            .fold(0, |mask, interrupt| mask | interrupt.mask())
+    }
+    //Each pmpcfg register packs XLEN/8 entries, RV64 only has the even numbered ones.
+    //Entries past the implemented ones read as zero
+    fn pmpcfg(&self, index: u16) -> Option<u64> {
+        if self.is_rv64() && !index.is_multiple_of(2) {
+            return None;
+        }
+        let pmp = &self.context.csr.pmp;
+        let first = index as usize * 4;
+        Some((0..Self::xlen() as usize / 8)
+            .filter(|i| first + i < pmp.entries())
+            .fold(0, |value, i| value | (pmp.cfg(first + i) as u64) << (8 * i)))
+    }
+    fn write_pmpcfg(&mut self, index: u16, value: u64) {
+        let first = index as usize * 4;
+        let pmp = &mut self.context.csr.pmp;
+        for i in 0..Self::xlen() as usize / 8 {
+            if first + i < pmp.entries() {
+                pmp.set_cfg(first + i, (value >> (8 * i)) as u8);
+            }
+        }
+    }
+    //satp is WARL, writes selecting an unsupported mode are ignored entirely
+    fn write_satp(&mut self, value: u64) {
+        if self.is_rv64() && ![0, 8, 9].contains(&(value >> 60)) {
+            return;
+        }
+        self.context.csr.satp = value;
+    }
+    fn write_builtin_csr(&mut self, address: CsrAddress, value: u64) {
+        let rv64 = self.is_rv64();
+        let alignment = self.instruction_alignment();
+        let interrupts = self.interrupt_mask();
+        let supervisor_interrupts = interrupts & !(Interrupt::MachineSoftware.mask() |
+            Interrupt::MachineTimer.mask() | Interrupt::MachineExternal.mask());
+        let fcsr = &mut self.context.fcsr;
+        let csr = &mut self.context.csr;
+        match address.value() {
+            FFLAGS | FRM | FCSR => {
+                match address.value() {
+                    FFLAGS => fcsr.set_fflags(value as u8),
+                    FRM => fcsr.set_frm(value as u8),
+                    _ => fcsr.set_fcsr(value as u32),
+                }
+                csr.mstatus |= FS_DIRTY;
+            },
+            MCYCLE if rv64 => csr.cycle = value,
+            MINSTRET if rv64 => csr.instret = value,
+            //RV32 writes each half of the 64 bit counters separately
+            MCYCLE => csr.cycle = (csr.cycle & !0xFFFF_FFFF) | value,
+            MINSTRET => csr.instret = (csr.instret & !0xFFFF_FFFF) | value,
+            MCYCLEH => csr.cycle = (csr.cycle & 0xFFFF_FFFF) | (value << 32),
+            MINSTRETH => csr.instret = (csr.instret & 0xFFFF_FFFF) | (value << 32),
+            MSTATUS => self.write_mstatus(value),
+            //Only direct and vectored modes are supported, a reserved mode keeps the old one
+            MTVEC if value & 0b11 < 2 => csr.mtvec = value,
+            MTVEC => csr.mtvec = (value & !0b11) | (csr.mtvec & 0b11),
+            MSCRATCH => csr.mscratch = value,
+            MEPC => csr.mepc = value & !(alignment - 1),
+            MCAUSE => csr.mcause = value,
+            MTVAL => csr.mtval = value,
+            MIE => csr.mie = value & interrupts,
+            //The machine level bits of mip are driven by the interrupt lines, M-mode may post
+            //supervisor interrupts
+            MIP => csr.mip = (csr.mip & !supervisor_interrupts) | (value & supervisor_interrupts),
+            //Environment calls from M-mode can't be delegated
+            MEDELEG => csr.medeleg = value & 0xB3FF & !(1 << 11),
+            MIDELEG => csr.mideleg = value & supervisor_interrupts,
+            SSTATUS => self.write_sstatus(value),
+            SIE => csr.mie = (csr.mie & !csr.mideleg) | (value & csr.mideleg),
+            SIP => {
+                let writable = csr.mideleg & Interrupt::SupervisorSoftware.mask();
+                csr.mip = (csr.mip & !writable) | (value & writable)
+            },
+            STVEC if value & 0b11 < 2 => csr.stvec = value,
+            STVEC => csr.stvec = (value & !0b11) | (csr.stvec & 0b11),
+            SSCRATCH => csr.sscratch = value,
+            SEPC => csr.sepc = value & !(alignment - 1),
+            SCAUSE => csr.scause = value,
+            STVAL => csr.stval = value,
+            SATP => self.write_satp(value),
+            PMPCFG0..=PMPCFG15 => self.write_pmpcfg(address.value() - PMPCFG0, value),
+            //pmpaddr holds bits 55:2 of a physical address on RV64 and 33:2 on RV32
+            PMPADDR0..=PMPADDR63 => {
+                let entry = (address.value() - PMPADDR0) as usize;
+                if entry < csr.pmp.entries() {
+                    csr.pmp.set_addr(entry, value & if rv64 { (1 << 54) - 1 } else { 0xFFFF_FFFF });
+                }
+            },
+            //misa is WARL and the extensions are fixed at construction
+            _ => (),
+        }
+    }
+    /// Reads a CSR without privilege checks, `None` if it isn't implemented.
+    pub fn read_csr(&mut self, address: CsrAddress) -> Option<u64> {
+        let value = match self.context.csr.hook(address) {
+            Some(hook) => hook.read(),
+            None => self.read_builtin_csr(address)?,
+        };
+        Some(self.wrap(value))
+    }
+    /// Writes a CSR without privilege or read-only checks, `None` if it isn't implemented.
+    pub fn write_csr(&mut self, address: CsrAddress, value: u64) -> Option<()> {
+        let value = self.wrap(value);
+        match self.context.csr.hook(address) {
+            Some(hook) => hook.write(value),
+            None => {
+                self.read_builtin_csr(address)?;
+                self.write_builtin_csr(address, value);
+            },
+        }
+        Some(())
+    }
+    fn csr_implemented(&mut self, address: CsrAddress) -> bool {
+        self.context.csr.hook(address).is_some() || self.read_builtin_csr(address).is_some()
+    }
+    /// Executes CSRRW, CSRRS, CSRRC and their immediate forms.
+    pub(crate) fn execute_csr(&mut self, instruction: RawInstruction) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let (rd, rs1) = (instruction.rd(), instruction.rs1());
+        let funct3 = instruction.funct3().value();
+        let address = CsrAddress::new((instruction.0 >> 20) as u16);
+        let source = if funct3 & 0b100 != 0 { rs1 as u64 } else { self.x(rs1) };
+        //CSRRW to x0 has no read side effects and CSRRS/CSRRC with a zero source don't write
+        let (read, write) = match funct3 & 0b11 {
+            0b01 => (rd != 0, true),
+            0b10 | 0b11 => (true, rs1 != 0),
+            _ => return Err(illegal),
+        };
+        let csr = &self.context.csr;
+        let float_disabled = (FFLAGS..=FCSR).contains(&address.value()) && csr.mstatus & MSTATUS_FS == 0;
+        //TVM traps satp accesses from S-mode
+        let trapped_vm = address.value() == SATP && csr.privilege() == PrivilegeLevel::Supervisor &&
+            csr.mstatus & MSTATUS_TVM != 0;
+        if address.privilege() > csr.privilege() || (write && address.is_read_only()) ||
+            float_disabled || trapped_vm || !self.csr_implemented(address) {
+            return Err(illegal);
+        }
+        let old = if read { self.read_csr(address).ok_or(illegal)? } else { 0 };
+        if write {
+            let value = match funct3 & 0b11 {
+                0b01 => source,
+                0b10 => old | source,
+                _ => old & !source,
+            };
+            self.write_csr(address, value).ok_or(illegal)?;
+        }
+        self.set_x(rd, old);
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risc_v_emu::core::tests::{allow_all_pmp, core_with_program, Rv32};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Scratch {
+        value: Rc<RefCell<u64>>,
+        reads: Rc<RefCell<usize>>,
+    }
+    impl CsrHook for Scratch {
+        fn read(&mut self) -> u64 {
+            *self.reads.borrow_mut() += 1;
+            *self.value.borrow()
+        }
+        fn write(&mut self, value: u64) {
+            *self.value.borrow_mut() = value
+        }
+    }
+    #[test]
+    fn address_encoding() {
+        let mhartid = CsrAddress::new(MHARTID);
+        assert!(mhartid.is_read_only());
+        assert_eq!(mhartid.privilege(), PrivilegeLevel::Machine);
+        assert_eq!(CsrAddress::new(FCSR).privilege(), PrivilegeLevel::User);
+        assert!(!CsrAddress::new(FCSR).is_read_only());
+        assert_eq!(CsrAddress::new(MISA).usage(), accessibility::Use::Standard);
+        assert_eq!(CsrAddress::new(0x800).usage(), accessibility::Use::NonStandard);
+        assert_eq!(CsrAddress::new(0x7C0).usage(), accessibility::Use::NonStandard);
+        assert_eq!(CsrAddress::new(0xFC5).usage(), accessibility::Use::NonStandard);
+        assert_eq!(CsrAddress::new(0x3C0).usage(), accessibility::Use::Standard);
+    }
+    #[test]
+    fn float_csrs() {
+        let program = [
+            0x0021d573, //fsrmi a0, 3
+            0x001025f3, //frflags a1
+            0x003fe673, //csrrsi a2, fcsr, 31
+            0x003536f3, //csrrc a3, fcsr, a0
+            0x00302773, //frcsr a4
+        ];
+        let mut core = core_with_program::<Rv32>("if", &program);
+        for _ in 0..program.len() {
+            core.step().unwrap();
+        }
+        assert_eq!(core.x(10), 0);
+        assert_eq!(core.x(12), 3 << 5);
+        assert_eq!(core.x(13), (3 << 5) | 0x1F);
+        //a0 holds the old frm of zero so the csrrc clears nothing
+        assert_eq!(core.x(14), (3 << 5) | 0x1F);
+        assert_eq!(core.context().fcsr.frm(), 3);
+        let mut integer_only = core_with_program::<Rv32>("i", &program);
+        assert!(integer_only.step().is_err(), "fcsr needs the F extension");
+    }
+    #[test]
+    fn privilege_and_read_only() {
+        let program = [
+            0x30102573, //csrr a0, misa
+            0xc0002573, //rdcycle a0
+            0xf1402573, //csrr a0, mhartid
+            0xf1451073, //csrw mhartid, a0
+        ];
+        let mut core = core_with_program::<Rv32>("imafc", &program);
+        allow_all_pmp(&mut core);
+        core.step().unwrap();
+        assert_eq!(core.x(10), (1 << 30) | 0b1_0001_0010_0101);
+        core.context_mut().csr.set_privilege(PrivilegeLevel::User);
+        core.set_pc(0);
+        assert_eq!(core.step(), Err(Exception::IllegalInstruction(program[0])));
+        core.set_pc(4);
+        core.step().unwrap();
+        assert_eq!(core.x(10), 3, "cycles include the trapped attempt");
+        assert_eq!(core.context().csr.instret, 2);
+        core.context_mut().csr.set_privilege(PrivilegeLevel::Machine);
+        core.step().unwrap();
+        assert_eq!(core.step(), Err(Exception::IllegalInstruction(program[3])));
+    }
+    #[test]
+    fn custom_hooks() {
+        let program = [
+            0x80051073, //csrw 0x800, a0
+            0x800025f3, //csrr a1, 0x800
+            0x800075f3, //csrrci a1, 0x800, 0
+            0x7c0535f3, //csrrc a1, 0x7c0, a0
+        ];
+        let value = Rc::new(RefCell::new(0));
+        let reads = Rc::new(RefCell::new(0));
+        let mut core = core_with_program::<Rv32>("i", &program);
+        let hook = Scratch { value: value.clone(), reads: reads.clone() };
+        core.context_mut().csr.register(CsrAddress::new(0x800), Box::new(hook)).unwrap();
+        let duplicate = Scratch { value: value.clone(), reads: reads.clone() };
+        assert_eq!(core.context_mut().csr.register(CsrAddress::new(0x800), Box::new(duplicate)).err(),
+                   Some(CsrError::AlreadyRegistered(CsrAddress::new(0x800))));
+        core.set_x(10, 0x1234_5678);
+        core.step().unwrap();
+        assert_eq!(*reads.borrow(), 0, "csrw doesn't read");
+        core.step().unwrap();
+        core.step().unwrap();
+        assert_eq!(core.x(11), 0x1234_5678);
+        assert_eq!(*reads.borrow(), 2);
+        assert_eq!(*value.borrow(), 0x1234_5678);
+        assert_eq!(core.step(), Err(Exception::IllegalInstruction(program[3])), "0x7C0 isn't registered");
+    }
+}
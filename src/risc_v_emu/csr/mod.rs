@@ -1,5 +1,156 @@
-mod accessibility;
+//! Zicsr control and status registers.
+pub mod accessibility;
+pub mod execute;
 
-pub struct CSR {
+use accessibility::{CsrAddress, PrivilegeLevel};
+use super::pmp::Pmp;
+use std::collections::BTreeMap;
+
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+pub const CYCLE: u16 = 0xC00;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const INSTRETH: u16 = 0xC82;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+pub const SATP: u16 = 0x180;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPCFG15: u16 = 0x3AF;
+pub const PMPADDR0: u16 = 0x3B0;
+pub const PMPADDR63: u16 = 0x3EF;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+pub const PMP_ENTRIES: usize = 16;
 
-}
\ No newline at end of file
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u32 = 11;
+pub const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_FS_SHIFT: u32 = 13;
+pub const MSTATUS_FS: u64 = 0b11 << MSTATUS_FS_SHIFT;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+//The mstatus bits visible through sstatus, SD is added on read
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR;
+pub const FS_INITIAL: u64 = 1 << MSTATUS_FS_SHIFT;
+pub const FS_DIRTY: u64 = 0b11 << MSTATUS_FS_SHIFT;
+
+/// Read and write hooks for an embedder defined CSR. Privilege and read-only checks are done
+/// from the address before either hook is called.
+pub trait CsrHook {
+    fn read(&mut self) -> u64;
+    fn write(&mut self, value: u64);
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CsrError {
+    AlreadyRegistered(CsrAddress),
+}
+pub struct CSR {
+    privilege: PrivilegeLevel,
+    pub cycle: u64,
+    pub instret: u64,
+    pub mstatus: u64,
+    pub mtvec: u64,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub mie: u64,
+    pub mip: u64,
+    pub medeleg: u64,
+    pub mideleg: u64,
+    pub stvec: u64,
+    pub sscratch: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub satp: u64,
+    pub pmp: Pmp,
+    hooks: BTreeMap<CsrAddress, Box<dyn CsrHook>>,
+}
+impl CSR {
+    pub fn new() -> CSR {
+        CSR {
+            privilege: PrivilegeLevel::Machine,
+            cycle: 0,
+            instret: 0,
+            mstatus: (PrivilegeLevel::Machine as u64) << MSTATUS_MPP_SHIFT,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mie: 0,
+            mip: 0,
+            medeleg: 0,
+            mideleg: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            pmp: Pmp::new(PMP_ENTRIES),
+            hooks: BTreeMap::new(),
+        }
+    }
+    /// Privilege level the hart is currently running at.
+    pub fn privilege(&self) -> PrivilegeLevel {
+        self.privilege
+    }
+    pub fn set_privilege(&mut self, privilege: PrivilegeLevel) {
+        self.privilege = privilege
+    }
+    /// Registers a custom CSR. A hook at a standard address replaces the built-in register.
+    pub fn register(&mut self, address: CsrAddress, hook: Box<dyn CsrHook>) -> Result<(), CsrError> {
+        if self.hooks.contains_key(&address) {
+            return Err(CsrError::AlreadyRegistered(address));
+        }
+        self.hooks.insert(address, hook);
+        Ok(())
+    }
+    pub fn unregister(&mut self, address: CsrAddress) -> Option<Box<dyn CsrHook>> {
+        self.hooks.remove(&address)
+    }
+    pub(crate) fn hook(&mut self, address: CsrAddress) -> Option<&mut Box<dyn CsrHook>> {
+        self.hooks.get_mut(&address)
+    }
+}
+impl Default for CSR {
+    fn default() -> Self {
+        CSR::new()
+    }
+}
@@ -0,0 +1,140 @@
+//! The debugging view of a core, on its own or running a Linux program.
+use super::compressed;
+use super::core::{Core, CoreSettings};
+use super::csr::{accessibility::CsrAddress, MTVEC};
+use super::decoder::RawInstruction;
+use super::disassembler;
+use super::instructions::BaseOpcodes;
+use super::linux::{Process, Stop};
+use super::regs::ABI_NAMES;
+use crate::microvm::debug::{Debuggable, StepResult};
+
+fn register_number(name: &str) -> Option<usize> {
+    match name {
+        "fp" => Some(8),
+        _ => ABI_NAMES.iter().position(|&abi| abi == name)
+            .or_else(|| name.strip_prefix('x')?.parse().ok().filter(|&i| i < 32)),
+    }
+}
+//The instruction at `address` with compressed instructions expanded
+fn instruction<Settings: CoreSettings>(core: &Core<Settings>, address: u64) -> Option<u32> {
+    let parcel = core.read_physical(address, 2)? as u16;
+    if compressed::is_compressed(parcel) {
+        compressed::expand(parcel, core.is_rv64())
+    } else {
+        core.read_physical(address, 4).map(|bits| bits as u32)
+    }
+}
+/// Without a trap handler in mtvec an exception is a fault, otherwise the handler is entered.
+impl<Settings: CoreSettings> Debuggable for Core<Settings> {
+    fn register_bits(&self) -> u32 {
+        Self::xlen()
+    }
+    fn pc(&self) -> u64 {
+        Core::pc(self)
+    }
+    fn set_pc(&mut self, pc: u64) {
+        Core::set_pc(self, pc)
+    }
+    fn registers(&self) -> Vec<(&'static str, u64)> {
+        ABI_NAMES.iter().enumerate().map(|(i, &name)| (name, self.x(i))).collect()
+    }
+    fn set_register(&mut self, name: &str, value: u64) -> Option<()> {
+        let reg = register_number(name)?;
+        self.set_x(reg, value);
+        Some(())
+    }
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        self.read_physical(address, 1).map(|byte| byte as u8)
+    }
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()> {
+        self.write_physical(address, 1, value.into())
+    }
+    fn step(&mut self) -> StepResult {
+        if self.read_csr(CsrAddress::new(MTVEC)) == Some(0) {
+            if let Err(e) = Core::step(self) {
+                return StepResult::Fault(format!("{:?}", e));
+            }
+        } else {
+            self.step_with_traps();
+        }
+        StepResult::Running
+    }
+    fn disassemble(&self, address: u64) -> Option<(u64, String)> {
+        let parcel = self.read_physical(address, 2)?;
+        let len = disassembler::length(parcel as u16);
+        let bits = self.read_physical(address, len as usize)? as u32;
+        Some((len, disassembler::disassemble(bits, address, self.is_rv64())))
+    }
+    fn is_call(&self, address: u64) -> bool {
+        //ra and t0 are the link registers
+        instruction(self, address).is_some_and(|bits| {
+            let i = RawInstruction(bits);
+            matches!(i.opcode(), Some(BaseOpcodes::Jal) | Some(BaseOpcodes::Jalr)) && (i.rd() == 1 || i.rd() == 5)
+        })
+    }
+}
+impl<Settings: CoreSettings> Debuggable for Process<Settings> {
+    fn register_bits(&self) -> u32 {
+        self.core().register_bits()
+    }
+    fn pc(&self) -> u64 {
+        self.core().pc()
+    }
+    fn set_pc(&mut self, pc: u64) {
+        self.core_mut().set_pc(pc)
+    }
+    fn registers(&self) -> Vec<(&'static str, u64)> {
+        self.core().registers()
+    }
+    fn set_register(&mut self, name: &str, value: u64) -> Option<()> {
+        self.core_mut().set_register(name, value)
+    }
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        self.read_memory(address, 1).ok().map(|bytes| bytes[0])
+    }
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()> {
+        self.write_memory(address, &[value]).ok()
+    }
+    fn step(&mut self) -> StepResult {
+        match Process::step(self) {
+            Some(Stop::Exited(code)) => StepResult::Exited(code),
+            Some(Stop::Exception(e)) => StepResult::Fault(format!("{:?}", e)),
+            Some(Stop::StepLimit) | None => StepResult::Running,
+        }
+    }
+    fn disassemble(&self, address: u64) -> Option<(u64, String)> {
+        self.core().disassemble(address)
+    }
+    fn is_call(&self, address: u64) -> bool {
+        self.core().is_call(address)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risc_v_emu::core::tests::{core_with_program, Rv32};
+
+    #[test]
+    fn core_view() {
+        let program = [
+            0x008000ef, //jal ra, 8
+            0x00000513, //li a0, 0
+            0x00000000, //illegal
+        ];
+        let mut core = core_with_program::<Rv32>("imc", &program);
+        assert!(core.is_call(0));
+        assert!(!core.is_call(4));
+        assert_eq!(core.disassemble(4), Some((4, "addi    a0, zero, 0".to_string())));
+        core.set_register("fp", 7).unwrap();
+        core.set_register("x10", 3).unwrap();
+        assert_eq!(core.set_register("x32", 0), None);
+        assert_eq!((core.x(8), core.x(10)), (7, 3));
+        assert_eq!(Debuggable::step(&mut core), StepResult::Running);
+        assert_eq!(Debuggable::step(&mut core), StepResult::Fault("IllegalInstruction(0)".to_string()));
+        assert_eq!(Debuggable::pc(&core), 8);
+        core.write_byte(0x1000, 0xAB).unwrap();
+        assert_eq!(core.read_byte(0x1000), Some(0xAB));
+        assert_eq!(core.read_byte(0x2000), None);
+    }
+}
@@ -25,6 +25,70 @@ pub enum FunctSize {
 pub struct Funct3(u8);
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Funct7(u8);
+impl Funct3 {
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+impl Funct7 {
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+/// A 32 bit instruction with accessors for the fields shared by every format.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RawInstruction(pub u32);
+impl RawInstruction {
+    fn field(self, range: std::ops::Range<usize>) -> u32 {
+        bits::extract_bits(self.0 as u64, range) as u32
+    }
+    //Sign extends the low `len` bits of `value`
+    fn sign_extend(value: u32, len: u32) -> i64 {
+        (((value as u64) << (64 - len)) as i64) >> (64 - len)
+    }
+    pub fn opcode(self) -> Option<BaseOpcodes> {
+        BaseOpcodes::decode(self.field(0..7) as u8)
+    }
+    pub fn rd(self) -> usize {
+        self.field(7..12) as usize
+    }
+    pub fn rs1(self) -> usize {
+        self.field(15..20) as usize
+    }
+    pub fn rs2(self) -> usize {
+        self.field(20..25) as usize
+    }
+    pub fn rs3(self) -> usize {
+        self.field(27..32) as usize
+    }
+    pub fn funct3(self) -> Funct3 {
+        Funct3(self.field(12..15) as u8)
+    }
+    pub fn funct7(self) -> Funct7 {
+        Funct7(self.field(25..32) as u8)
+    }
+    //Floating point format of the R4 type fused multiply-add instructions
+    pub fn funct2(self) -> u8 {
+        self.field(25..27) as u8
+    }
+    pub fn imm_i(self) -> i64 {
+        Self::sign_extend(self.field(20..32), 12)
+    }
+    pub fn imm_s(self) -> i64 {
+        Self::sign_extend(self.field(7..12) | (self.field(25..32) << 5), 12)
+    }
+    pub fn imm_b(self) -> i64 {
+        Self::sign_extend((self.field(8..12) << 1) | (self.field(25..31) << 5)
+            | (self.field(7..8) << 11) | (self.field(31..32) << 12), 13)
+    }
+    pub fn imm_u(self) -> i64 {
+        Self::sign_extend(self.0 & 0xFFFF_F000, 32)
+    }
+    pub fn imm_j(self) -> i64 {
+        Self::sign_extend((self.field(21..31) << 1) | (self.field(20..21) << 11)
+            | (self.field(12..20) << 12) | (self.field(31..32) << 20), 21)
+    }
+}
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Funct37(Funct3, Funct7);
 #[derive(Copy, Clone)]
@@ -0,0 +1,267 @@
+//! Disassembly into the assembler's syntax, without pseudo-instructions so every field shows.
+//! Compressed instructions are shown in their expanded form.
+use super::compressed;
+use super::decoder::RawInstruction;
+use super::instructions::BaseOpcodes;
+use super::regs::ABI_NAMES;
+
+const CSR_NAMES: [(u32, &str); 30] = [
+    (0x001, "fflags"), (0x002, "frm"), (0x003, "fcsr"),
+    (0x100, "sstatus"), (0x104, "sie"), (0x105, "stvec"), (0x140, "sscratch"), (0x141, "sepc"),
+    (0x142, "scause"), (0x143, "stval"), (0x144, "sip"), (0x180, "satp"),
+    (0x300, "mstatus"), (0x301, "misa"), (0x302, "medeleg"), (0x303, "mideleg"), (0x304, "mie"),
+    (0x305, "mtvec"), (0x340, "mscratch"), (0x341, "mepc"), (0x342, "mcause"), (0x343, "mtval"),
+    (0x344, "mip"), (0x3A0, "pmpcfg0"), (0x3B0, "pmpaddr0"),
+    (0xC00, "cycle"), (0xC01, "time"), (0xC02, "instret"), (0xF14, "mhartid"), (0xB00, "mcycle"),
+];
+
+fn x(reg: usize) -> &'static str {
+    ABI_NAMES[reg]
+}
+fn f(reg: usize) -> String {
+    format!("f{}", reg)
+}
+fn csr(number: u32) -> String {
+    CSR_NAMES.iter().find(|&&(n, _)| n == number).map_or_else(|| format!("{:#x}", number), |&(_, name)| name.to_string())
+}
+fn line(mnemonic: &str, operands: String) -> String {
+    if operands.is_empty() { mnemonic.to_string() } else { format!("{:<7} {}", mnemonic, operands) }
+}
+/// The length of the instruction starting with `parcel`, 2 for compressed and otherwise 4.
+pub fn length(parcel: u16) -> u64 {
+    if compressed::is_compressed(parcel) { 2 } else { 4 }
+}
+/// Disassembles the instruction in `bits` at `address`, which is needed for branch targets.
+/// Only the low 16 bits are used when they hold a compressed instruction.
+pub fn disassemble(bits: u32, address: u64, rv64: bool) -> String {
+    let instruction = if compressed::is_compressed(bits as u16) {
+        match compressed::expand(bits as u16, rv64) {
+            Some(expanded) => expanded,
+            None => return format!(".half   {:#06x}", bits as u16),
+        }
+    } else {
+        bits
+    };
+    decode(RawInstruction(instruction), address, rv64).unwrap_or_else(|| format!(".word   {:#010x}", instruction))
+}
+fn decode(i: RawInstruction, address: u64, rv64: bool) -> Option<String> {
+    let (rd, rs1, rs2) = (i.rd(), i.rs1(), i.rs2());
+    let funct3 = i.funct3().value();
+    let funct7 = i.funct7().value();
+    let target = |offset: i64| format!("{:#x}", address.wrapping_add(offset as u64));
+    let r = |m: &str| line(m, format!("{}, {}, {}", x(rd), x(rs1), x(rs2)));
+    let imm = |m: &str, value: i64| line(m, format!("{}, {}, {}", x(rd), x(rs1), value));
+    let memory = |m: &str, reg: String, offset: i64| line(m, format!("{}, {}({})", reg, offset, x(rs1)));
+    Some(match i.opcode()? {
+        BaseOpcodes::Lui => line("lui", format!("{}, {:#x}", x(rd), (i.imm_u() >> 12) & 0xF_FFFF)),
+        BaseOpcodes::Auipc => line("auipc", format!("{}, {:#x}", x(rd), (i.imm_u() >> 12) & 0xF_FFFF)),
+        BaseOpcodes::Jal => line("jal", format!("{}, {}", x(rd), target(i.imm_j()))),
+        BaseOpcodes::Jalr if funct3 == 0 => line("jalr", format!("{}, {}({})", x(rd), i.imm_i(), x(rs1))),
+        BaseOpcodes::Branch => {
+            let m = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"][funct3 as usize];
+            if m.is_empty() {
+                return None;
+            }
+            line(m, format!("{}, {}, {}", x(rs1), x(rs2), target(i.imm_b())))
+        },
+        BaseOpcodes::Load => {
+            let m = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", ""][funct3 as usize];
+            if m.is_empty() || (!rv64 && (m == "ld" || m == "lwu")) {
+                return None;
+            }
+            memory(m, x(rd).to_string(), i.imm_i())
+        },
+        BaseOpcodes::Store => {
+            let m = ["sb", "sh", "sw", "sd"].get(funct3 as usize).filter(|&&m| rv64 || m != "sd")?;
+            memory(m, x(rs2).to_string(), i.imm_s())
+        },
+        BaseOpcodes::LoadFP => memory(["", "", "flw", "fld"].get(funct3 as usize).filter(|m| !m.is_empty())?, f(rd), i.imm_i()),
+        BaseOpcodes::StoreFP => memory(["", "", "fsw", "fsd"].get(funct3 as usize).filter(|m| !m.is_empty())?, f(rs2), i.imm_s()),
+        BaseOpcodes::OpImm => {
+            let shamt = (i.0 >> 20) & if rv64 { 0x3F } else { 0x1F };
+            match funct3 {
+                0 => imm("addi", i.imm_i()),
+                1 if i.0 >> 26 == 0 => imm("slli", shamt as i64),
+                2 => imm("slti", i.imm_i()),
+                3 => imm("sltiu", i.imm_i()),
+                4 => imm("xori", i.imm_i()),
+                5 if i.0 >> 26 == 0 => imm("srli", shamt as i64),
+                5 if i.0 >> 26 == 0b010000 => imm("srai", shamt as i64),
+                6 => imm("ori", i.imm_i()),
+                7 => imm("andi", i.imm_i()),
+                _ => return None,
+            }
+        },
+        BaseOpcodes::OpImm32 if rv64 => match (funct3, funct7) {
+            (0, _) => imm("addiw", i.imm_i()),
+            (1, 0) => imm("slliw", rs2 as i64),
+            (5, 0) => imm("srliw", rs2 as i64),
+            (5, 0b0100000) => imm("sraiw", rs2 as i64),
+            _ => return None,
+        },
+        BaseOpcodes::Op => r(match (funct7, funct3) {
+            (0, 0) => "add",
+            (0b0100000, 0) => "sub",
+            (0, 1) => "sll",
+            (0, 2) => "slt",
+            (0, 3) => "sltu",
+            (0, 4) => "xor",
+            (0, 5) => "srl",
+            (0b0100000, 5) => "sra",
+            (0, 6) => "or",
+            (0, 7) => "and",
+            (1, _) => ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"][funct3 as usize],
+            _ => return None,
+        }),
+        BaseOpcodes::Op32 if rv64 => r(match (funct7, funct3) {
+            (0, 0) => "addw",
+            (0b0100000, 0) => "subw",
+            (0, 1) => "sllw",
+            (0, 5) => "srlw",
+            (0b0100000, 5) => "sraw",
+            (1, 0) => "mulw",
+            (1, 4) => "divw",
+            (1, 5) => "divuw",
+            (1, 6) => "remw",
+            (1, 7) => "remuw",
+            _ => return None,
+        }),
+        BaseOpcodes::MiscMem => match funct3 {
+            0 => line("fence", String::new()),
+            1 => line("fence.i", String::new()),
+            _ => return None,
+        },
+        BaseOpcodes::System => match funct3 {
+            0 => match (i.0 >> 20, rs1, rd) {
+                (0, 0, 0) => line("ecall", String::new()),
+                (1, 0, 0) => line("ebreak", String::new()),
+                (0x102, 0, 0) => line("sret", String::new()),
+                (0x302, 0, 0) => line("mret", String::new()),
+                (0x105, 0, 0) => line("wfi", String::new()),
+                (_, _, 0) if funct7 == 0b000_1001 => line("sfence.vma", format!("{}, {}", x(rs1), x(rs2))),
+                _ => return None,
+            },
+            4 => return None,
+            _ => {
+                let m = ["", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci"][funct3 as usize];
+                let source = if funct3 >= 5 { rs1.to_string() } else { x(rs1).to_string() };
+                line(m, format!("{}, {}, {}", x(rd), csr(i.0 >> 20), source))
+            },
+        },
+        BaseOpcodes::Amo => {
+            let size = match funct3 {
+                2 => "w",
+                3 if rv64 => "d",
+                _ => return None,
+            };
+            let ordering = match funct7 & 3 {
+                0 => "",
+                1 => ".rl",
+                2 => ".aq",
+                _ => ".aqrl",
+            };
+            let operation = match funct7 >> 2 {
+                0b00010 if rs2 == 0 => return Some(line(&format!("lr.{}{}", size, ordering), format!("{}, ({})", x(rd), x(rs1)))),
+                0b00011 => "sc",
+                0b00001 => "amoswap",
+                0b00000 => "amoadd",
+                0b00100 => "amoxor",
+                0b01100 => "amoand",
+                0b01000 => "amoor",
+                0b10000 => "amomin",
+                0b10100 => "amomax",
+                0b11000 => "amominu",
+                0b11100 => "amomaxu",
+                _ => return None,
+            };
+            line(&format!("{}.{}{}", operation, size, ordering), format!("{}, {}, ({})", x(rd), x(rs2), x(rs1)))
+        },
+        BaseOpcodes::OpFP => return floating(i),
+        BaseOpcodes::MAdd | BaseOpcodes::MSub | BaseOpcodes::NMSub | BaseOpcodes::NMAdd => {
+            let format = ["s", "d"].get(i.funct2() as usize)?;
+            let m = match i.opcode()? {
+                BaseOpcodes::MAdd => "fmadd",
+                BaseOpcodes::MSub => "fmsub",
+                BaseOpcodes::NMSub => "fnmsub",
+                _ => "fnmadd",
+            };
+            line(&format!("{}.{}", m, format), format!("{}, {}, {}, {}", f(rd), f(rs1), f(rs2), f(i.rs3())))
+        },
+        _ => return None,
+    })
+}
+//OP-FP, the low two bits of funct7 select single or double precision
+fn floating(i: RawInstruction) -> Option<String> {
+    let (rd, rs1, rs2) = (i.rd(), i.rs1(), i.rs2());
+    let funct3 = i.funct3().value();
+    let funct7 = i.funct7().value();
+    let format = *["s", "d"].get((funct7 & 3) as usize)?;
+    let integer = |rs2: usize| ["w", "wu", "l", "lu"].get(rs2).copied();
+    let three = |m: &str| line(&format!("{}.{}", m, format), format!("{}, {}, {}", f(rd), f(rs1), f(rs2)));
+    let compare = |m: &str| line(&format!("{}.{}", m, format), format!("{}, {}, {}", x(rd), f(rs1), f(rs2)));
+    Some(match (funct7 >> 2, funct3) {
+        (0b00000, _) => three("fadd"),
+        (0b00001, _) => three("fsub"),
+        (0b00010, _) => three("fmul"),
+        (0b00011, _) => three("fdiv"),
+        (0b01011, _) if rs2 == 0 => line(&format!("fsqrt.{}", format), format!("{}, {}", f(rd), f(rs1))),
+        (0b00100, 0) => three("fsgnj"),
+        (0b00100, 1) => three("fsgnjn"),
+        (0b00100, 2) => three("fsgnjx"),
+        (0b00101, 0) => three("fmin"),
+        (0b00101, 1) => three("fmax"),
+        (0b10100, 2) => compare("feq"),
+        (0b10100, 1) => compare("flt"),
+        (0b10100, 0) => compare("fle"),
+        (0b11100, 1) if rs2 == 0 => line(&format!("fclass.{}", format), format!("{}, {}", x(rd), f(rs1))),
+        (0b11100, 0) if rs2 == 0 => line(&format!("fmv.x.{}", if format == "s" { "w" } else { "d" }), format!("{}, {}", x(rd), f(rs1))),
+        (0b11110, 0) if rs2 == 0 => line(&format!("fmv.{}.x", if format == "s" { "w" } else { "d" }), format!("{}, {}", f(rd), x(rs1))),
+        (0b11000, _) => line(&format!("fcvt.{}.{}", integer(rs2)?, format), format!("{}, {}", x(rd), f(rs1))),
+        (0b11010, _) => line(&format!("fcvt.{}.{}", format, integer(rs2)?), format!("{}, {}", f(rd), x(rs1))),
+        (0b01000, _) => {
+            let from = *["s", "d"].get(rs2)?;
+            line(&format!("fcvt.{}.{}", format, from), format!("{}, {}", f(rd), f(rs1)))
+        },
+        _ => return None,
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_assembler() {
+        //Encodings from llvm-mc, with the instruction at 0x1000
+        let cases: [(u32, bool, &str); 24] = [
+            (0x00a00513, false, "addi    a0, zero, 10"),
+            (0x40b50533, false, "sub     a0, a0, a1"),
+            (0x02b54533, false, "div     a0, a0, a1"),
+            (0x000015b7, false, "lui     a1, 0x1"),
+            (0xffc5a503, false, "lw      a0, -4(a1)"),
+            (0x00a13423, true, "sd      a0, 8(sp)"),
+            (0xfe051ce3, false, "bne     a0, zero, 0xff8"),
+            (0x008000ef, false, "jal     ra, 0x1008"),
+            (0x000080e7, false, "jalr    ra, 0(ra)"),
+            (0x41f55513, true, "srai    a0, a0, 31"),
+            (0x0015151b, true, "slliw   a0, a0, 1"),
+            (0x00000073, false, "ecall"),
+            (0x30200073, false, "mret"),
+            (0x30529073, false, "csrrw   zero, mtvec, t0"),
+            (0x3412d573, false, "csrrwi  a0, mepc, 5"),
+            (0x0005a52f, false, "amoadd.w a0, zero, (a1)"),
+            (0x1605b52f, true, "lr.d.aqrl a0, (a1)"),
+            (0x18b5352f, true, "sc.d    a0, a1, (a0)"),
+            (0x0005a507, false, "flw     f10, 0(a1)"),
+            (0x02b57553, false, "fadd.d  f10, f10, f11"),
+            (0xa0b52553, false, "feq.s   a0, f10, f11"),
+            (0xd0057553, false, "fcvt.s.w f10, a0"),
+            (0x4505, false, "addi    a0, zero, 1"),
+            (0xffffffff, false, ".word   0xffffffff"),
+        ];
+        for &(bits, rv64, expected) in cases.iter() {
+            assert_eq!(disassemble(bits, 0x1000, rv64), expected, "{:#010x}", bits);
+        }
+        assert_eq!(disassemble(0x00a13423, 0, false), ".word   0x00a13423", "sd is RV64 only");
+        assert_eq!((length(0x4505), length(0x0513)), (2, 4));
+    }
+}
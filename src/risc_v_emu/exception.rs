@@ -0,0 +1,97 @@
+use super::csr::accessibility::PrivilegeLevel;
+
+/// Synchronous exceptions, numbered by their `mcause` exception code. Variants carry the value
+/// reported in `mtval`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u32),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+    //Carries the privilege level the ecall was made from
+    EnvironmentCall(PrivilegeLevel),
+}
+impl Exception {
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+            Exception::EnvironmentCall(privilege) => 8 + *privilege as u64,
+        }
+    }
+    pub fn value(&self) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(a) | Exception::InstructionAccessFault(a) |
+            Exception::Breakpoint(a) | Exception::LoadAddressMisaligned(a) | Exception::LoadAccessFault(a) |
+            Exception::StoreAddressMisaligned(a) | Exception::StoreAccessFault(a) |
+            Exception::InstructionPageFault(a) | Exception::LoadPageFault(a) | Exception::StorePageFault(a) => a,
+            Exception::IllegalInstruction(instruction) => instruction as u64,
+            Exception::EnvironmentCall(_) => 0,
+        }
+    }
+}
+/// Interrupts, numbered by their bit in `mip` and `mie`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+impl Interrupt {
+    //Highest priority first
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal, Interrupt::MachineSoftware, Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal, Interrupt::SupervisorSoftware, Interrupt::SupervisorTimer,
+    ];
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+    pub fn mask(self) -> u64 {
+        1 << self.code()
+    }
+    pub fn is_supervisor(self) -> bool {
+        matches!(self, Interrupt::SupervisorSoftware | Interrupt::SupervisorTimer | Interrupt::SupervisorExternal)
+    }
+}
+/// A trap taken by the core, either a synchronous exception or an interrupt.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trap {
+    Exception(Exception),
+    Interrupt(Interrupt),
+}
+impl Trap {
+    pub fn code(&self) -> u64 {
+        match self {
+            Trap::Exception(exception) => exception.code(),
+            Trap::Interrupt(interrupt) => interrupt.code(),
+        }
+    }
+    pub fn value(&self) -> u64 {
+        match self {
+            Trap::Exception(exception) => exception.value(),
+            Trap::Interrupt(_) => 0,
+        }
+    }
+    pub fn is_interrupt(&self) -> bool {
+        matches!(self, Trap::Interrupt(_))
+    }
+}
@@ -0,0 +1,65 @@
+/// ISA extensions, numbered by their bit in `misa`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Extension {
+    A = 0,
+    C = 2,
+    D = 3,
+    F = 5,
+    I = 8,
+    M = 12,
+    S = 18,
+    U = 20,
+}
+impl Extension {
+    pub fn from_letter(letter: char) -> Option<Extension> {
+        Some(match letter.to_ascii_uppercase() {
+            'A' => Extension::A,
+            'C' => Extension::C,
+            'D' => Extension::D,
+            'F' => Extension::F,
+            'I' => Extension::I,
+            'M' => Extension::M,
+            'S' => Extension::S,
+            'U' => Extension::U,
+            _ => return None,
+        })
+    }
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Extensions(u32);
+impl Extensions {
+    pub fn base() -> Extensions {
+        Extensions(0).with(Extension::I)
+    }
+    pub fn with(self, extension: Extension) -> Extensions {
+        Extensions(self.0 | (1 << extension as u32))
+    }
+    pub fn has(self, extension: Extension) -> bool {
+        self.0 & (1 << extension as u32) != 0
+    }
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+    /// Parses the extension letters of an ISA string such as `imafd`, `G` expands to `IMAFD`.
+    pub fn from_letters(letters: &str) -> Option<Extensions> {
+        let mut out = Extensions::base();
+        for letter in letters.chars() {
+            if letter.eq_ignore_ascii_case(&'g') {
+                for &e in [Extension::M, Extension::A, Extension::F, Extension::D].iter() {
+                    out = out.with(e);
+                }
+            } else {
+                out = out.with(Extension::from_letter(letter)?);
+            }
+        }
+        if out.has(Extension::D) && !out.has(Extension::F) {
+            return None;
+        }
+        Some(out)
+    }
+}
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions::base()
+    }
+}
@@ -0,0 +1,255 @@
+use super::softfloat::{self, Format, SINGLE, DOUBLE};
+use super::{ExceptionFlags, RoundingMode};
+use crate::risc_v_emu::core::{Core, CoreSettings};
+use crate::risc_v_emu::csr;
+use crate::risc_v_emu::decoder::RawInstruction;
+use crate::risc_v_emu::exception::Exception;
+use crate::risc_v_emu::extensions::Extension;
+use crate::risc_v_emu::instructions::BaseOpcodes;
+
+impl<Settings: CoreSettings> Core<Settings> {
+    //fmt field encoding, only S and D are supported
+    fn float_format(&self, fmt: u8) -> Option<Format> {
+        match fmt {
+            0b00 if self.extensions().has(Extension::F) => Some(SINGLE),
+            0b01 if self.extensions().has(Extension::D) => Some(DOUBLE),
+            _ => None,
+        }
+    }
+    fn read_f(&self, fmt: Format, reg: usize) -> u64 {
+        if fmt == DOUBLE {
+            self.context.fregs.get_double(reg)
+        } else {
+            self.context.fregs.get_single(reg)
+        }
+    }
+    fn write_f(&mut self, fmt: Format, reg: usize, value: u64) {
+        if fmt == DOUBLE {
+            self.context.fregs.set_double(reg, value)
+        } else {
+            self.context.fregs.set_single(reg, value)
+        }
+    }
+    /// Executes LOAD-FP, STORE-FP, OP-FP and the fused multiply-add opcodes.
+    pub(crate) fn execute_float(&mut self, instruction: RawInstruction) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let (rd, rs1, rs2) = (instruction.rd(), instruction.rs1(), instruction.rs2());
+        let funct3 = instruction.funct3().value();
+        let rounding_mode = || self.context.fcsr.rounding_mode(funct3).ok_or(illegal);
+        if self.context.csr.mstatus & csr::MSTATUS_FS == 0 {
+            return Err(illegal);
+        }
+        let mut flags = ExceptionFlags::default();
+        match instruction.opcode().ok_or(illegal)? {
+            BaseOpcodes::LoadFP => {
+                let address = self.x(rs1).wrapping_add(instruction.imm_i() as u64);
+                let fmt = self.float_format(funct3.wrapping_sub(2)).ok_or(illegal)?;
+                let value = self.load(address, fmt.bit_len() as usize / 8)?;
+                self.write_f(fmt, rd, value);
+            },
+            BaseOpcodes::StoreFP => {
+                let address = self.x(rs1).wrapping_add(instruction.imm_s() as u64);
+                let fmt = self.float_format(funct3.wrapping_sub(2)).ok_or(illegal)?;
+                //Stores write the raw register bits, whether or not they are NaN-boxed
+                self.store(address, fmt.bit_len() as usize / 8, self.context.fregs.get_raw(rs2))?;
+            },
+            opcode @ BaseOpcodes::MAdd | opcode @ BaseOpcodes::MSub |
+            opcode @ BaseOpcodes::NMSub | opcode @ BaseOpcodes::NMAdd => {
+                let fmt = self.float_format(instruction.funct2()).ok_or(illegal)?;
+                let rm = rounding_mode()?;
+                let sign = fmt.sign_mask();
+                let (negate_product, negate_addend) = match opcode {
+                    BaseOpcodes::MAdd => (0, 0),
+                    BaseOpcodes::MSub => (0, sign),
+                    BaseOpcodes::NMSub => (sign, 0),
+                    _ => (sign, sign),
+                };
+                let a = self.read_f(fmt, rs1) ^ negate_product;
+                let b = self.read_f(fmt, rs2);
+                let c = self.read_f(fmt, instruction.rs3()) ^ negate_addend;
+                let value = softfloat::fused_mul_add(fmt, a, b, c, rm, &mut flags);
+                self.write_f(fmt, rd, value);
+            },
+            BaseOpcodes::OpFP => self.execute_op_fp(instruction, &mut flags)?,
+            _ => return Err(illegal),
+        }
+        self.context.fcsr.accrue(flags);
+        self.context.csr.mstatus |= csr::FS_DIRTY;
+        Ok(())
+    }
+    fn execute_op_fp(&mut self, instruction: RawInstruction, flags: &mut ExceptionFlags) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.0);
+        let (rd, rs1, rs2) = (instruction.rd(), instruction.rs1(), instruction.rs2());
+        let funct3 = instruction.funct3().value();
+        let funct7 = instruction.funct7().value();
+        let fmt = self.float_format(funct7 & 0b11).ok_or(illegal)?;
+        let rounding_mode = || self.context.fcsr.rounding_mode(funct3).ok_or(illegal);
+        let rv64 = Self::xlen() == 64;
+        let (a, b) = (self.read_f(fmt, rs1), self.read_f(fmt, rs2));
+        let sign = fmt.sign_mask();
+        type Arithmetic = fn(Format, u64, u64, RoundingMode, &mut ExceptionFlags) -> u64;
+        let arithmetic: Option<Arithmetic> = match funct7 >> 2 {
+            0b00000 => Some(softfloat::add),
+            0b00001 => Some(softfloat::sub),
+            0b00010 => Some(softfloat::mul),
+            0b00011 => Some(softfloat::div),
+            _ => None,
+        };
+        if let Some(operation) = arithmetic {
+            let value = operation(fmt, a, b, rounding_mode()?, flags);
+            self.write_f(fmt, rd, value);
+            return Ok(());
+        }
+        match funct7 >> 2 {
+            //FSQRT
+            0b01011 if rs2 == 0 => {
+                let value = softfloat::sqrt(fmt, a, rounding_mode()?, flags);
+                self.write_f(fmt, rd, value);
+            },
+            //FSGNJ, FSGNJN, FSGNJX
+            0b00100 => {
+                let sign_b = match funct3 {
+                    0b000 => b & sign,
+                    0b001 => !b & sign,
+                    0b010 => (a ^ b) & sign,
+                    _ => return Err(illegal),
+                };
+                self.write_f(fmt, rd, (a & !sign) | sign_b);
+            },
+            //FMIN, FMAX
+            0b00101 => {
+                let value = match funct3 {
+                    0b000 => softfloat::min(fmt, a, b, flags),
+                    0b001 => softfloat::max(fmt, a, b, flags),
+                    _ => return Err(illegal),
+                };
+                self.write_f(fmt, rd, value);
+            },
+            //FCVT.S.D, FCVT.D.S
+            0b01000 => {
+                let from = self.float_format(rs2 as u8).ok_or(illegal)?;
+                if from == fmt {
+                    return Err(illegal);
+                }
+                let value = softfloat::convert(from, fmt, self.read_f(from, rs1), rounding_mode()?, flags);
+                self.write_f(fmt, rd, value);
+            },
+            //FEQ, FLT, FLE
+            0b10100 => {
+                let value = match funct3 {
+                    0b010 => softfloat::eq(fmt, a, b, flags),
+                    0b001 => softfloat::lt(fmt, a, b, flags),
+                    0b000 => softfloat::le(fmt, a, b, flags),
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value as u64);
+            },
+            //FCVT.W, FCVT.WU, FCVT.L, FCVT.LU from a float
+            0b11000 => {
+                let (signed, width) = match rs2 {
+                    0 => (true, 32),
+                    1 => (false, 32),
+                    2 if rv64 => (true, 64),
+                    3 if rv64 => (false, 64),
+                    _ => return Err(illegal),
+                };
+                let value = softfloat::to_int(fmt, a, signed, width, rounding_mode()?, flags);
+                //32 bit results are sign extended even for the unsigned conversions
+                let value = if width == 32 { value as i32 as i64 as u64 } else { value };
+                self.set_x(rd, value);
+            },
+            //FCVT.S/D from an integer
+            0b11010 => {
+                let (signed, width) = match rs2 {
+                    0 => (true, 32),
+                    1 => (false, 32),
+                    2 if rv64 => (true, 64),
+                    3 if rv64 => (false, 64),
+                    _ => return Err(illegal),
+                };
+                let value = softfloat::from_int(fmt, self.x(rs1), signed, width, rounding_mode()?, flags);
+                self.write_f(fmt, rd, value);
+            },
+            //FMV.X.W, FMV.X.D, FCLASS
+            0b11100 if rs2 == 0 => {
+                let value = match funct3 {
+                    0b000 if fmt == SINGLE => self.context.fregs.get_raw(rs1) as i32 as i64 as u64,
+                    0b000 if rv64 => self.context.fregs.get_raw(rs1),
+                    0b001 => softfloat::classify(fmt, a),
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            },
+            //FMV.W.X, FMV.D.X
+            0b11110 if rs2 == 0 && funct3 == 0 && (fmt == SINGLE || rv64) => {
+                self.write_f(fmt, rd, self.x(rs1));
+            },
+            _ => return Err(illegal),
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::risc_v_emu::core::tests::{core_with_program, Rv32, Rv64};
+
+    #[test]
+    fn single_precision_arithmetic() {
+        let program = [
+            0x00300513, //li a0, 3
+            0xd0057053, //fcvt.s.w ft0, a0
+            0x00100513, //li a0, 1
+            0xd00570d3, //fcvt.s.w ft1, a0
+            0x18107153, //fdiv.s ft2, ft0, ft1 (rm = dyn)
+            0x180071d3, //fdiv.s ft3, ft0, ft0
+            0x1810f253, //fdiv.s ft4, ft1, ft1 (rm = dyn)
+            0x18101153, //fdiv.s ft2, ft0, ft1, rtz
+        ];
+        let mut core = core_with_program::<Rv32>("if", &program);
+        for _ in 0..program.len() {
+            core.step().unwrap();
+        }
+        assert_eq!(core.context().fregs.get_raw(2), 0xFFFF_FFFF_0000_0000 | 3.0f32.to_bits() as u64);
+        assert_eq!(core.context().fregs.get_single(3), 1.0f32.to_bits() as u64);
+        assert_eq!(core.context().fcsr.fflags(), 0);
+    }
+    #[test]
+    fn rounding_modes_and_flags() {
+        let program = [
+            0x00100513, //li a0, 1
+            0xd00570d3, //fcvt.s.w ft1, a0
+            0x00300513, //li a0, 3
+            0xd0057153, //fcvt.s.w ft2, a0
+            0x1820f1d3, //fdiv.s ft3, ft1, ft2 (rm = dyn)
+            0x1820a253, //fdiv.s ft4, ft1, ft2, rdn
+        ];
+        let mut core = core_with_program::<Rv32>("if", &program);
+        for _ in 0..program.len() {
+            core.step().unwrap();
+        }
+        let third = (1.0f32 / 3.0).to_bits() as u64;
+        assert_eq!(core.context().fregs.get_single(3), third);
+        assert_eq!(core.context().fregs.get_single(4), third - 1);
+        assert_eq!(core.context().fcsr.fflags(), 1);
+        core.context_mut().fcsr.set_frm(5);
+        core.set_pc(16);
+        assert!(core.step().is_err(), "reserved frm is illegal for dynamic rounding");
+    }
+    #[test]
+    fn nan_boxing_and_moves() {
+        let program = [
+            0xfff00513, //li a0, -1
+            0xf2050053, //fmv.d.x ft0, a0
+            0x200000d3, //fmv.s ft1, ft0
+            0xe00085d3, //fmv.x.w a1, ft1
+            0xe0009653, //fclass.s a2, ft1
+        ];
+        let mut core = core_with_program::<Rv64>("ifd", &program);
+        for _ in 0..program.len() {
+            core.step().unwrap();
+        }
+        //All ones is a correctly boxed single precision NaN
+        assert_eq!(core.x(11), u64::MAX);
+        assert_eq!(core.x(12), 1 << 9);
+    }
+}
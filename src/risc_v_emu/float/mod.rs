@@ -0,0 +1,136 @@
+//! F and D extension state: the floating point register file and `fcsr`.
+pub mod softfloat;
+pub mod execute;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RoundingMode {
+    NearestEven = 0,
+    TowardZero = 1,
+    Down = 2,
+    Up = 3,
+    NearestMaxMagnitude = 4,
+}
+//rm field value that selects the rounding mode in frm
+pub const DYNAMIC_ROUNDING_MODE: u8 = 0b111;
+impl RoundingMode {
+    pub fn from_bits(bits: u8) -> Option<RoundingMode> {
+        match bits {
+            0 => Some(RoundingMode::NearestEven),
+            1 => Some(RoundingMode::TowardZero),
+            2 => Some(RoundingMode::Down),
+            3 => Some(RoundingMode::Up),
+            4 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExceptionFlag {
+    Inexact = 0,
+    Underflow = 1,
+    Overflow = 2,
+    DivideByZero = 3,
+    Invalid = 4,
+}
+impl From<ExceptionFlag> for u8 {
+    fn from(flag: ExceptionFlag) -> u8 {
+        1 << (flag as u8)
+    }
+}
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct ExceptionFlags(u8);
+impl ExceptionFlags {
+    pub const MASK: u8 = 0b1_1111;
+    pub fn from_bits(bits: u8) -> ExceptionFlags {
+        ExceptionFlags(bits & Self::MASK)
+    }
+    pub fn value(self) -> u8 {
+        self.0
+    }
+    pub fn set(&mut self, flag: ExceptionFlag) {
+        self.0 |= u8::from(flag)
+    }
+    pub fn get(self, flag: ExceptionFlag) -> bool {
+        self.0 & u8::from(flag) != 0
+    }
+    pub fn accrue(&mut self, other: ExceptionFlags) {
+        self.0 |= other.0
+    }
+}
+/// The `fcsr` register. `fflags` and `frm` are views of its low 5 and upper 3 bits.
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct Fcsr {
+    flags: ExceptionFlags,
+    rounding_mode: u8,
+}
+impl Fcsr {
+    pub fn fflags(&self) -> u8 {
+        self.flags.value()
+    }
+    pub fn set_fflags(&mut self, value: u8) {
+        self.flags = ExceptionFlags::from_bits(value)
+    }
+    pub fn frm(&self) -> u8 {
+        self.rounding_mode
+    }
+    pub fn set_frm(&mut self, value: u8) {
+        self.rounding_mode = value & 0b111
+    }
+    pub fn fcsr(&self) -> u32 {
+        ((self.rounding_mode as u32) << 5) | self.flags.value() as u32
+    }
+    pub fn set_fcsr(&mut self, value: u32) {
+        self.set_fflags(value as u8);
+        self.set_frm((value >> 5) as u8);
+    }
+    pub fn accrue(&mut self, flags: ExceptionFlags) {
+        self.flags.accrue(flags)
+    }
+    //Resolves the rm field of an instruction, `None` if it selects a reserved mode
+    pub fn rounding_mode(&self, rm: u8) -> Option<RoundingMode> {
+        if rm == DYNAMIC_ROUNDING_MODE {
+            RoundingMode::from_bits(self.rounding_mode)
+        } else {
+            RoundingMode::from_bits(rm)
+        }
+    }
+}
+const NAN_BOX: u64 = 0xFFFF_FFFF_0000_0000;
+/// 32 entry floating point register file, FLEN is 64 so single precision values are NaN-boxed.
+pub struct FloatRegs {
+    regs: [u64; 32]
+}
+impl FloatRegs {
+    pub fn new() -> FloatRegs {
+        FloatRegs { regs: [0; 32] }
+    }
+    pub fn get_raw(&self, reg: usize) -> u64 {
+        self.regs[reg]
+    }
+    pub fn set_raw(&mut self, reg: usize, value: u64) {
+        self.regs[reg] = value
+    }
+    //Values that aren't properly NaN-boxed read as the canonical NaN
+    pub fn get_single(&self, reg: usize) -> u64 {
+        let value = self.regs[reg];
+        if value & NAN_BOX == NAN_BOX {
+            value & 0xFFFF_FFFF
+        } else {
+            softfloat::SINGLE.canonical_nan()
+        }
+    }
+    pub fn set_single(&mut self, reg: usize, value: u64) {
+        self.regs[reg] = NAN_BOX | (value & 0xFFFF_FFFF)
+    }
+    pub fn get_double(&self, reg: usize) -> u64 {
+        self.regs[reg]
+    }
+    pub fn set_double(&mut self, reg: usize, value: u64) {
+        self.regs[reg] = value
+    }
+}
+impl Default for FloatRegs {
+    fn default() -> Self {
+        FloatRegs::new()
+    }
+}
@@ -0,0 +1,649 @@
+//! Bit exact IEEE 754 binary32/binary64 arithmetic.
+//!
+//! Everything works on raw bit patterns held in a `u64` so the results don't depend on the host
+//! FPU or its rounding mode. NaN results are always the RISC-V canonical NaN and tininess is
+//! detected after rounding, as the RISC-V spec requires.
+use super::{RoundingMode, ExceptionFlags, ExceptionFlag};
+use std::cmp::Ordering;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Format {
+    exponent_bits: u32,
+    mantissa_bits: u32,
+}
+pub const SINGLE: Format = Format { exponent_bits: 8, mantissa_bits: 23 };
+pub const DOUBLE: Format = Format { exponent_bits: 11, mantissa_bits: 52 };
+
+impl Format {
+    pub fn bit_len(self) -> u32 {
+        1 + self.exponent_bits + self.mantissa_bits
+    }
+    fn bias(self) -> i32 {
+        (1 << (self.exponent_bits - 1)) - 1
+    }
+    fn max_biased_exponent(self) -> u64 {
+        (1 << self.exponent_bits) - 1
+    }
+    fn mantissa_mask(self) -> u64 {
+        (1 << self.mantissa_bits) - 1
+    }
+    pub fn sign_mask(self) -> u64 {
+        1 << (self.exponent_bits + self.mantissa_bits)
+    }
+    fn quiet_bit(self) -> u64 {
+        1 << (self.mantissa_bits - 1)
+    }
+    //Precision in bits including the hidden bit
+    fn precision(self) -> i32 {
+        self.mantissa_bits as i32 + 1
+    }
+    fn min_exponent(self) -> i32 {
+        1 - self.bias()
+    }
+    pub fn canonical_nan(self) -> u64 {
+        (self.max_biased_exponent() << self.mantissa_bits) | self.quiet_bit()
+    }
+    fn infinity(self, sign: bool) -> u64 {
+        self.pack_sign(sign) | (self.max_biased_exponent() << self.mantissa_bits)
+    }
+    fn max_finite(self, sign: bool) -> u64 {
+        self.pack_sign(sign) | ((self.max_biased_exponent() - 1) << self.mantissa_bits) | self.mantissa_mask()
+    }
+    fn zero(self, sign: bool) -> u64 {
+        self.pack_sign(sign)
+    }
+    fn pack_sign(self, sign: bool) -> u64 {
+        if sign { self.sign_mask() } else { 0 }
+    }
+    pub fn sign(self, bits: u64) -> bool {
+        bits & self.sign_mask() != 0
+    }
+    pub fn is_nan(self, bits: u64) -> bool {
+        matches!(unpack(self, bits).1, Kind::NaN { .. })
+    }
+    pub fn is_signaling_nan(self, bits: u64) -> bool {
+        unpack(self, bits).1 == Kind::NaN { signaling: true }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    Zero,
+    //value = sig * 2^exp, sig is never zero
+    Finite { exp: i32, sig: u64 },
+    Infinity,
+    NaN { signaling: bool },
+}
+
+fn unpack(fmt: Format, bits: u64) -> (bool, Kind) {
+    let sign = fmt.sign(bits);
+    let biased = (bits >> fmt.mantissa_bits) & fmt.max_biased_exponent();
+    let mantissa = bits & fmt.mantissa_mask();
+    let kind = if biased == 0 {
+        if mantissa == 0 {
+            Kind::Zero
+        } else {
+            Kind::Finite { exp: fmt.min_exponent() - fmt.mantissa_bits as i32, sig: mantissa }
+        }
+    } else if biased == fmt.max_biased_exponent() {
+        if mantissa == 0 {
+            Kind::Infinity
+        } else {
+            Kind::NaN { signaling: mantissa & fmt.quiet_bit() == 0 }
+        }
+    } else {
+        Kind::Finite {
+            exp: biased as i32 - fmt.bias() - fmt.mantissa_bits as i32,
+            sig: mantissa | (1 << fmt.mantissa_bits),
+        }
+    };
+    (sign, kind)
+}
+
+//Every intermediate significand is normalized so its MSB lands here, leaving enough room below
+//the rounding position for a guard and a sticky bit even after a carry.
+const NORMALIZED_MSB: i32 = 125;
+
+fn msb(sig: u128) -> i32 {
+    127 - sig.leading_zeros() as i32
+}
+fn normalize(exp: i32, sig: u128) -> (i32, u128) {
+    let shift = NORMALIZED_MSB - msb(sig);
+    if shift >= 0 {
+        (exp - shift, sig << shift)
+    } else {
+        (exp - shift, shift_right_jam(sig, -shift))
+    }
+}
+//Shifts right, ORing every bit shifted out into the LSB of the result.
+fn shift_right_jam(sig: u128, shift: i32) -> u128 {
+    if shift <= 0 {
+        sig
+    } else if shift >= 128 {
+        (sig != 0) as u128
+    } else {
+        (sig >> shift) | ((sig & ((1u128 << shift) - 1)) != 0) as u128
+    }
+}
+//Drops `shift` bits from `sig` rounding the result with `rm`. Returns the rounded value and
+//whether any bits were lost.
+fn shift_round(sig: u128, shift: i32, sign: bool, rm: RoundingMode) -> (u128, bool) {
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+    let (kept, rem_vs_half, inexact) = if shift > NORMALIZED_MSB + 1 {
+        (0, Ordering::Less, sig != 0)
+    } else {
+        let rem = sig & ((1u128 << shift) - 1);
+        (sig >> shift, rem.cmp(&(1u128 << (shift - 1))), rem != 0)
+    };
+    let round_up = match rm {
+        RoundingMode::NearestEven => rem_vs_half == Ordering::Greater || (rem_vs_half == Ordering::Equal && kept & 1 == 1),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => sign && inexact,
+        RoundingMode::Up => !sign && inexact,
+        RoundingMode::NearestMaxMagnitude => rem_vs_half != Ordering::Less,
+    };
+    (kept + round_up as u128, inexact)
+}
+
+//Rounds `(-1)^sign * sig * 2^exp` into `fmt`. `sig` must be non zero and any sticky bits must
+//already be folded into its LSB.
+fn round_pack(fmt: Format, sign: bool, exp: i32, sig: u128, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (exp, sig) = normalize(exp, sig);
+    let precision = fmt.precision();
+    let e = exp + NORMALIZED_MSB;
+    let emin = fmt.min_exponent();
+    let lsb_exp = e.max(emin) - (precision - 1);
+    let (mut kept, inexact) = shift_round(sig, lsb_exp - exp, sign, rm);
+    let mut lsb_exp = lsb_exp;
+    if inexact {
+        flags.set(ExceptionFlag::Inexact);
+        //Tininess is detected after rounding, as if the exponent range were unbounded
+        let tiny = if e < emin - 1 {
+            true
+        } else if e == emin - 1 {
+            shift_round(sig, lsb_exp - 1 - exp, sign, rm).0 < (1u128 << precision)
+        } else {
+            false
+        };
+        if tiny {
+            flags.set(ExceptionFlag::Underflow);
+        }
+    }
+    if kept == 1u128 << precision {
+        kept >>= 1;
+        lsb_exp += 1;
+    }
+    if kept == 0 {
+        return fmt.zero(sign);
+    }
+    let biased = if kept < 1u128 << (precision - 1) {
+        0
+    } else {
+        (lsb_exp + precision - 1 + fmt.bias()) as u64
+    };
+    if biased >= fmt.max_biased_exponent() {
+        flags.set(ExceptionFlag::Overflow);
+        flags.set(ExceptionFlag::Inexact);
+        let to_infinity = match rm {
+            RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+            RoundingMode::TowardZero => false,
+            RoundingMode::Down => sign,
+            RoundingMode::Up => !sign,
+        };
+        return if to_infinity { fmt.infinity(sign) } else { fmt.max_finite(sign) };
+    }
+    fmt.pack_sign(sign) | (biased << fmt.mantissa_bits) | (kept as u64 & fmt.mantissa_mask())
+}
+
+fn invalid(fmt: Format, flags: &mut ExceptionFlags) -> u64 {
+    flags.set(ExceptionFlag::Invalid);
+    fmt.canonical_nan()
+}
+//Any NaN operand produces the canonical NaN, signaling NaNs also raise the invalid flag.
+fn propagate_nan(fmt: Format, operands: &[Kind], flags: &mut ExceptionFlags) -> Option<u64> {
+    let mut any_nan = false;
+    for kind in operands {
+        if let Kind::NaN { signaling } = *kind {
+            any_nan = true;
+            if signaling {
+                flags.set(ExceptionFlag::Invalid);
+            }
+        }
+    }
+    if any_nan { Some(fmt.canonical_nan()) } else { None }
+}
+fn exact_zero_sign(rm: RoundingMode) -> bool {
+    rm == RoundingMode::Down
+}
+
+//Exact sum of two finite non zero values. Returns `None` when they cancel out exactly.
+fn add_finite(a: (bool, i32, u128), b: (bool, i32, u128)) -> Option<(bool, i32, u128)> {
+    let (sign_a, exp_a, sig_a) = a;
+    let (sign_b, exp_b, sig_b) = b;
+    let (exp_a, sig_a) = normalize(exp_a, sig_a);
+    let (exp_b, sig_b) = normalize(exp_b, sig_b);
+    let ((sign_big, exp_big, sig_big), (sign_small, exp_small, sig_small)) =
+        if (exp_a, sig_a) >= (exp_b, sig_b) {
+            ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b))
+        } else {
+            ((sign_b, exp_b, sig_b), (sign_a, exp_a, sig_a))
+        };
+    let sig_small = shift_right_jam(sig_small, exp_big - exp_small);
+    if sign_big == sign_small {
+        Some((sign_big, exp_big, sig_big + sig_small))
+    } else if sig_big == sig_small {
+        None
+    } else {
+        Some((sign_big, exp_big, sig_big - sig_small))
+    }
+}
+
+pub fn add(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign_a, kind_a) = unpack(fmt, a);
+    let (sign_b, kind_b) = unpack(fmt, b);
+    if let Some(nan) = propagate_nan(fmt, &[kind_a, kind_b], flags) {
+        return nan;
+    }
+    match (kind_a, kind_b) {
+        (Kind::Infinity, Kind::Infinity) if sign_a != sign_b => invalid(fmt, flags),
+        (Kind::Infinity, _) => a,
+        (_, Kind::Infinity) => b,
+        (Kind::Zero, Kind::Zero) => {
+            if sign_a == sign_b { a } else { fmt.zero(exact_zero_sign(rm)) }
+        },
+        (Kind::Zero, _) => b,
+        (_, Kind::Zero) => a,
+        (Kind::Finite { exp: exp_a, sig: sig_a }, Kind::Finite { exp: exp_b, sig: sig_b }) => {
+            match add_finite((sign_a, exp_a, sig_a as u128), (sign_b, exp_b, sig_b as u128)) {
+                Some((sign, exp, sig)) => round_pack(fmt, sign, exp, sig, rm, flags),
+                None => fmt.zero(exact_zero_sign(rm)),
+            }
+        },
+        _ => unreachable!("NaNs are handled above"),
+    }
+}
+pub fn sub(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    add(fmt, a, b ^ fmt.sign_mask(), rm, flags)
+}
+pub fn mul(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign_a, kind_a) = unpack(fmt, a);
+    let (sign_b, kind_b) = unpack(fmt, b);
+    if let Some(nan) = propagate_nan(fmt, &[kind_a, kind_b], flags) {
+        return nan;
+    }
+    let sign = sign_a != sign_b;
+    match (kind_a, kind_b) {
+        (Kind::Infinity, Kind::Zero) | (Kind::Zero, Kind::Infinity) => invalid(fmt, flags),
+        (Kind::Infinity, _) | (_, Kind::Infinity) => fmt.infinity(sign),
+        (Kind::Zero, _) | (_, Kind::Zero) => fmt.zero(sign),
+        (Kind::Finite { exp: exp_a, sig: sig_a }, Kind::Finite { exp: exp_b, sig: sig_b }) => {
+            round_pack(fmt, sign, exp_a + exp_b, sig_a as u128 * sig_b as u128, rm, flags)
+        },
+        _ => unreachable!("NaNs are handled above"),
+    }
+}
+pub fn div(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign_a, kind_a) = unpack(fmt, a);
+    let (sign_b, kind_b) = unpack(fmt, b);
+    if let Some(nan) = propagate_nan(fmt, &[kind_a, kind_b], flags) {
+        return nan;
+    }
+    let sign = sign_a != sign_b;
+    match (kind_a, kind_b) {
+        (Kind::Infinity, Kind::Infinity) | (Kind::Zero, Kind::Zero) => invalid(fmt, flags),
+        (Kind::Infinity, _) => fmt.infinity(sign),
+        (_, Kind::Infinity) | (Kind::Zero, _) => fmt.zero(sign),
+        (_, Kind::Zero) => {
+            flags.set(ExceptionFlag::DivideByZero);
+            fmt.infinity(sign)
+        },
+        (Kind::Finite { exp: exp_a, sig: sig_a }, Kind::Finite { exp: exp_b, sig: sig_b }) => {
+            let (exp_a, sig_a) = normalize(exp_a, sig_a as u128);
+            //A 64 bit divisor leaves a quotient with more than 60 bits of precision
+            let shift_b = 63 - msb(sig_b as u128);
+            let (exp_b, sig_b) = (exp_b - shift_b, (sig_b as u128) << shift_b);
+            let quotient = sig_a / sig_b;
+            let sticky = (sig_a % sig_b != 0) as u128;
+            round_pack(fmt, sign, exp_a - exp_b, quotient | sticky, rm, flags)
+        },
+        _ => unreachable!("NaNs are handled above"),
+    }
+}
+//Integer square root, returns the root and the remainder.
+fn isqrt(n: u128) -> (u128, u128) {
+    let mut rem = n;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, rem)
+}
+pub fn sqrt(fmt: Format, a: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign, kind) = unpack(fmt, a);
+    if let Some(nan) = propagate_nan(fmt, &[kind], flags) {
+        return nan;
+    }
+    match kind {
+        Kind::Zero => a,
+        _ if sign => invalid(fmt, flags),
+        Kind::Infinity => a,
+        Kind::Finite { exp, sig } => {
+            let (mut exp, mut sig) = normalize(exp, sig as u128);
+            if exp & 1 != 0 {
+                exp -= 1;
+                sig <<= 1;
+            }
+            let (root, rem) = isqrt(sig);
+            round_pack(fmt, false, exp / 2, root | (rem != 0) as u128, rm, flags)
+        },
+        _ => unreachable!("NaNs are handled above"),
+    }
+}
+//Computes `a*b + c` with a single rounding.
+pub fn fused_mul_add(fmt: Format, a: u64, b: u64, c: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign_a, kind_a) = unpack(fmt, a);
+    let (sign_b, kind_b) = unpack(fmt, b);
+    let (sign_c, kind_c) = unpack(fmt, c);
+    let product_invalid = matches!((kind_a, kind_b), (Kind::Infinity, Kind::Zero) | (Kind::Zero, Kind::Infinity));
+    //inf * 0 is invalid even when the addend is a quiet NaN
+    if let Some(nan) = propagate_nan(fmt, &[kind_a, kind_b, kind_c], flags) {
+        if product_invalid {
+            flags.set(ExceptionFlag::Invalid);
+        }
+        return nan;
+    }
+    if product_invalid {
+        return invalid(fmt, flags);
+    }
+    let sign_p = sign_a != sign_b;
+    let product = match (kind_a, kind_b) {
+        (Kind::Infinity, _) | (_, Kind::Infinity) => Kind::Infinity,
+        (Kind::Zero, _) | (_, Kind::Zero) => Kind::Zero,
+        _ => Kind::Finite { exp: 0, sig: 0 },
+    };
+    match (product, kind_c) {
+        (Kind::Infinity, Kind::Infinity) if sign_p != sign_c => invalid(fmt, flags),
+        (Kind::Infinity, _) => fmt.infinity(sign_p),
+        (_, Kind::Infinity) => c,
+        (Kind::Zero, Kind::Zero) => {
+            if sign_p == sign_c { fmt.zero(sign_p) } else { fmt.zero(exact_zero_sign(rm)) }
+        },
+        (Kind::Zero, _) => c,
+        _ => {
+            let (exp_p, sig_p) = match (kind_a, kind_b) {
+                (Kind::Finite { exp: exp_a, sig: sig_a }, Kind::Finite { exp: exp_b, sig: sig_b }) =>
+                    (exp_a + exp_b, sig_a as u128 * sig_b as u128),
+                _ => unreachable!("product is finite"),
+            };
+            match kind_c {
+                Kind::Zero => round_pack(fmt, sign_p, exp_p, sig_p, rm, flags),
+                Kind::Finite { exp: exp_c, sig: sig_c } => {
+                    match add_finite((sign_p, exp_p, sig_p), (sign_c, exp_c, sig_c as u128)) {
+                        Some((sign, exp, sig)) => round_pack(fmt, sign, exp, sig, rm, flags),
+                        None => fmt.zero(exact_zero_sign(rm)),
+                    }
+                },
+                _ => unreachable!("non finite addends are handled above"),
+            }
+        },
+    }
+}
+
+//Orders two non NaN values, treating both zeros as equal.
+fn compare_numbers(fmt: Format, a: u64, b: u64) -> Ordering {
+    let magnitude = |x: u64| x & !fmt.sign_mask();
+    let (mag_a, mag_b) = (magnitude(a), magnitude(b));
+    if mag_a == 0 && mag_b == 0 {
+        return Ordering::Equal;
+    }
+    match (fmt.sign(a), fmt.sign(b)) {
+        (false, false) => mag_a.cmp(&mag_b),
+        (true, true) => mag_b.cmp(&mag_a),
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+    }
+}
+//Quiet comparison, only signaling NaNs raise the invalid flag.
+pub fn eq(fmt: Format, a: u64, b: u64, flags: &mut ExceptionFlags) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        if fmt.is_signaling_nan(a) || fmt.is_signaling_nan(b) {
+            flags.set(ExceptionFlag::Invalid);
+        }
+        return false;
+    }
+    compare_numbers(fmt, a, b) == Ordering::Equal
+}
+//Signaling comparison, any NaN raises the invalid flag.
+pub fn lt(fmt: Format, a: u64, b: u64, flags: &mut ExceptionFlags) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        flags.set(ExceptionFlag::Invalid);
+        return false;
+    }
+    compare_numbers(fmt, a, b) == Ordering::Less
+}
+pub fn le(fmt: Format, a: u64, b: u64, flags: &mut ExceptionFlags) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        flags.set(ExceptionFlag::Invalid);
+        return false;
+    }
+    compare_numbers(fmt, a, b) != Ordering::Greater
+}
+fn min_max(fmt: Format, a: u64, b: u64, want: Ordering, flags: &mut ExceptionFlags) -> u64 {
+    if fmt.is_signaling_nan(a) || fmt.is_signaling_nan(b) {
+        flags.set(ExceptionFlag::Invalid);
+    }
+    match (fmt.is_nan(a), fmt.is_nan(b)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let order = match compare_numbers(fmt, a, b) {
+                //-0.0 is considered less than +0.0
+                Ordering::Equal => fmt.sign(b).cmp(&fmt.sign(a)),
+                order => order,
+            };
+            if order == want || order == Ordering::Equal { a } else { b }
+        },
+    }
+}
+pub fn min(fmt: Format, a: u64, b: u64, flags: &mut ExceptionFlags) -> u64 {
+    min_max(fmt, a, b, Ordering::Less, flags)
+}
+pub fn max(fmt: Format, a: u64, b: u64, flags: &mut ExceptionFlags) -> u64 {
+    min_max(fmt, a, b, Ordering::Greater, flags)
+}
+//The 10 bit mask written by FCLASS
+pub fn classify(fmt: Format, a: u64) -> u64 {
+    let (sign, kind) = unpack(fmt, a);
+    let subnormal = (a >> fmt.mantissa_bits) & fmt.max_biased_exponent() == 0;
+    let bit = match (sign, kind) {
+        (true, Kind::Infinity) => 0,
+        (true, Kind::Finite { .. }) if !subnormal => 1,
+        (true, Kind::Finite { .. }) => 2,
+        (true, Kind::Zero) => 3,
+        (false, Kind::Zero) => 4,
+        (false, Kind::Finite { .. }) if subnormal => 5,
+        (false, Kind::Finite { .. }) => 6,
+        (false, Kind::Infinity) => 7,
+        (_, Kind::NaN { signaling: true }) => 8,
+        (_, Kind::NaN { signaling: false }) => 9,
+    };
+    1 << bit
+}
+//Converts between formats, rounding if `to` is narrower.
+pub fn convert(from: Format, to: Format, a: u64, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign, kind) = unpack(from, a);
+    if let Some(nan) = propagate_nan(to, &[kind], flags) {
+        return nan;
+    }
+    match kind {
+        Kind::Zero => to.zero(sign),
+        Kind::Infinity => to.infinity(sign),
+        Kind::Finite { exp, sig } => round_pack(to, sign, exp, sig as u128, rm, flags),
+        _ => unreachable!("NaNs are handled above"),
+    }
+}
+//Converts to a `width` bit integer, saturating out of range values. The result is returned
+//zero extended to 64 bits.
+pub fn to_int(fmt: Format, a: u64, signed: bool, width: u32, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let (sign, kind) = unpack(fmt, a);
+    let width_mask = if width == 64 { u64::MAX } else { (1 << width) - 1 };
+    let (max, min) = if signed {
+        (width_mask >> 1, (width_mask >> 1) + 1)
+    } else {
+        (width_mask, 0)
+    };
+    let saturate = |negative: bool, flags: &mut ExceptionFlags| {
+        flags.set(ExceptionFlag::Invalid);
+        if negative { min } else { max }
+    };
+    let magnitude = match kind {
+        Kind::NaN { .. } => return saturate(false, flags),
+        Kind::Infinity => return saturate(sign, flags),
+        Kind::Zero => return 0,
+        Kind::Finite { exp, sig } => {
+            if exp > 64 {
+                return saturate(sign, flags);
+            }
+            let (rounded, inexact) = shift_round(sig as u128, -exp, sign, rm);
+            let limit = if sign { min as u128 } else { max as u128 };
+            if rounded > limit {
+                return saturate(sign, flags);
+            }
+            if inexact {
+                flags.set(ExceptionFlag::Inexact);
+            }
+            rounded as u64
+        },
+    };
+    if sign { magnitude.wrapping_neg() & width_mask } else { magnitude }
+}
+//Converts a `width` bit integer held in the low bits of `value`.
+pub fn from_int(fmt: Format, value: u64, signed: bool, width: u32, rm: RoundingMode, flags: &mut ExceptionFlags) -> u64 {
+    let value = if width == 64 { value } else { value & ((1 << width) - 1) };
+    let negative = signed && (value >> (width - 1)) & 1 == 1;
+    let magnitude = if negative {
+        (value | !((1u64 << (width - 1)) - 1)).wrapping_neg()
+    } else {
+        value
+    };
+    if magnitude == 0 {
+        fmt.zero(false)
+    } else {
+        round_pack(fmt, negative, 0, magnitude as u128, rm, flags)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags_of(f: impl FnOnce(&mut ExceptionFlags) -> u64) -> (u64, u8) {
+        let mut flags = ExceptionFlags::default();
+        let out = f(&mut flags);
+        (out, flags.value())
+    }
+    const NX: u8 = 1;
+    const UF: u8 = 2;
+    const OF: u8 = 4;
+    const DZ: u8 = 8;
+    const NV: u8 = 16;
+
+    #[test]
+    fn matches_host_round_to_nearest() {
+        let values = [1.0f64, -2.5, 0.1, 3.0e-308, 1.7976931348623157e308, 5e-324, 123456.789, -0.0, 7.0];
+        for &x in values.iter() {
+            for &y in values.iter() {
+                let rm = RoundingMode::NearestEven;
+                let mut flags = ExceptionFlags::default();
+                assert_eq!(add(DOUBLE, x.to_bits(), y.to_bits(), rm, &mut flags), (x + y).to_bits());
+                assert_eq!(mul(DOUBLE, x.to_bits(), y.to_bits(), rm, &mut flags), (x * y).to_bits());
+                if y != 0.0 {
+                    assert_eq!(div(DOUBLE, x.to_bits(), y.to_bits(), rm, &mut flags), (x / y).to_bits());
+                }
+                let (xs, ys) = (x as f32, y as f32);
+                assert_eq!(add(SINGLE, xs.to_bits() as u64, ys.to_bits() as u64, rm, &mut flags), (xs + ys).to_bits() as u64);
+            }
+            if x >= 0.0 {
+                let mut flags = ExceptionFlags::default();
+                assert_eq!(sqrt(DOUBLE, x.to_bits(), RoundingMode::NearestEven, &mut flags), x.sqrt().to_bits());
+            }
+        }
+    }
+    #[test]
+    fn directed_rounding() {
+        let one = 1.0f32.to_bits() as u64;
+        let tiny = 1.0e-10f32.to_bits() as u64;
+        let next_up = (1.0f32.to_bits() + 1) as u64;
+        let next_down = (1.0f32.to_bits() - 1) as u64;
+        assert_eq!(flags_of(|f| add(SINGLE, one, tiny, RoundingMode::NearestEven, f)), (one, NX));
+        assert_eq!(flags_of(|f| add(SINGLE, one, tiny, RoundingMode::Up, f)), (next_up, NX));
+        assert_eq!(flags_of(|f| sub(SINGLE, one, tiny, RoundingMode::Down, f)), (next_down, NX));
+        assert_eq!(flags_of(|f| sub(SINGLE, one, tiny, RoundingMode::TowardZero, f)), (next_down, NX));
+        //1 + 2^-24 is a tie, RNE rounds to even (1.0) and RMM away from zero
+        let half_ulp = (2.0f32).powi(-24).to_bits() as u64;
+        assert_eq!(add(SINGLE, one, half_ulp, RoundingMode::NearestEven, &mut ExceptionFlags::default()), one);
+        assert_eq!(add(SINGLE, one, half_ulp, RoundingMode::NearestMaxMagnitude, &mut ExceptionFlags::default()), next_up);
+    }
+    #[test]
+    fn exceptional_cases() {
+        let inf = f64::INFINITY.to_bits();
+        let max = f64::MAX.to_bits();
+        let min_normal = f64::MIN_POSITIVE.to_bits();
+        assert_eq!(flags_of(|f| sub(DOUBLE, inf, inf, RoundingMode::NearestEven, f)), (DOUBLE.canonical_nan(), NV));
+        assert_eq!(flags_of(|f| div(DOUBLE, 1.0f64.to_bits(), 0, RoundingMode::NearestEven, f)), (inf, DZ));
+        assert_eq!(flags_of(|f| mul(DOUBLE, max, max, RoundingMode::NearestEven, f)), (inf, OF | NX));
+        assert_eq!(flags_of(|f| mul(DOUBLE, max, max, RoundingMode::TowardZero, f)), (max, OF | NX));
+        assert_eq!(flags_of(|f| div(DOUBLE, min_normal, 3.0f64.to_bits(), RoundingMode::NearestEven, f)),
+                   ((f64::MIN_POSITIVE / 3.0).to_bits(), UF | NX));
+        assert_eq!(flags_of(|f| sqrt(DOUBLE, (-1.0f64).to_bits(), RoundingMode::NearestEven, f)), (DOUBLE.canonical_nan(), NV));
+        //x - x is -0 only when rounding down
+        assert_eq!(sub(DOUBLE, 1.0f64.to_bits(), 1.0f64.to_bits(), RoundingMode::Down, &mut ExceptionFlags::default()), (-0.0f64).to_bits());
+        //inf * 0 + qNaN still raises invalid
+        assert_eq!(flags_of(|f| fused_mul_add(DOUBLE, inf, 0, DOUBLE.canonical_nan(), RoundingMode::NearestEven, f)).1, NV);
+    }
+    #[test]
+    fn fused_multiply_add_rounds_once() {
+        let a = 1.0f64 + f64::EPSILON;
+        let c = -(1.0f64 + 2.0 * f64::EPSILON);
+        //a*a = 1 + 2eps + eps^2, only a single rounding keeps the eps^2 term
+        let out = fused_mul_add(DOUBLE, a.to_bits(), a.to_bits(), c.to_bits(), RoundingMode::NearestEven, &mut ExceptionFlags::default());
+        assert_eq!(f64::from_bits(out), f64::EPSILON * f64::EPSILON);
+    }
+    #[test]
+    fn integer_conversions() {
+        let rm = RoundingMode::NearestEven;
+        assert_eq!(flags_of(|f| to_int(SINGLE, 2.5f32.to_bits() as u64, true, 32, rm, f)), (2, NX));
+        assert_eq!(flags_of(|f| to_int(SINGLE, (-2.5f32).to_bits() as u64, true, 32, RoundingMode::Down, f)), ((-3i32) as u32 as u64, NX));
+        assert_eq!(flags_of(|f| to_int(DOUBLE, 1e20f64.to_bits(), true, 32, rm, f)), (i32::MAX as u64, NV));
+        assert_eq!(flags_of(|f| to_int(DOUBLE, (-1.0f64).to_bits(), false, 64, rm, f)), (0, NV));
+        assert_eq!(flags_of(|f| to_int(DOUBLE, (-0.25f64).to_bits(), false, 64, rm, f)), (0, NX));
+        assert_eq!(flags_of(|f| to_int(DOUBLE, DOUBLE.canonical_nan(), true, 64, rm, f)), (i64::MAX as u64, NV));
+        assert_eq!(from_int(DOUBLE, (-7i32) as u32 as u64, true, 32, rm, &mut ExceptionFlags::default()), (-7.0f64).to_bits());
+        assert_eq!(flags_of(|f| from_int(SINGLE, u64::MAX, false, 64, rm, f)), (18446744073709551616.0f32.to_bits() as u64, NX));
+    }
+    #[test]
+    fn classify_and_min_max() {
+        assert_eq!(classify(SINGLE, (-0.0f32).to_bits() as u64), 1 << 3);
+        assert_eq!(classify(SINGLE, 1), 1 << 5);
+        assert_eq!(classify(SINGLE, 0x7f80_0001), 1 << 8);
+        assert_eq!(classify(SINGLE, SINGLE.canonical_nan()), 1 << 9);
+        let (pz, nz) = (0.0f64.to_bits(), (-0.0f64).to_bits());
+        let mut flags = ExceptionFlags::default();
+        assert_eq!(min(DOUBLE, pz, nz, &mut flags), nz);
+        assert_eq!(max(DOUBLE, nz, pz, &mut flags), pz);
+        assert_eq!(min(DOUBLE, DOUBLE.canonical_nan(), 1.0f64.to_bits(), &mut flags), 1.0f64.to_bits());
+        assert_eq!(flags.value(), 0);
+    }
+}
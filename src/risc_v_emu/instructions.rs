@@ -9,6 +9,7 @@ pub enum InstructionFormat {
 
 const BaseOpcodeFlag: u8 = 0b11;
 #[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum BaseOpcodes {
     Load        = 0b00000,
     LoadFP      = 0b00001,
@@ -48,6 +49,49 @@ pub enum BaseOpcodes {
 
 
 
+}
+impl BaseOpcodes {
+    //Decodes the low 7 bits of a 32 bit instruction
+    pub fn decode(opcode: u8) -> Option<BaseOpcodes> {
+        use BaseOpcodes::*;
+        if opcode & BaseOpcodeFlag != BaseOpcodeFlag {
+            return None;
+        }
+        Some(match (opcode >> 2) & 0b11111 {
+            0b00000 => Load,
+            0b00001 => LoadFP,
+            0b00010 => Custom0,
+            0b00011 => MiscMem,
+            0b00100 => OpImm,
+            0b00101 => Auipc,
+            0b00110 => OpImm32,
+            0b00111 => B48A,
+            0b01000 => Store,
+            0b01001 => StoreFP,
+            0b01010 => Custom1,
+            0b01011 => Amo,
+            0b01100 => Op,
+            0b01101 => Lui,
+            0b01110 => Op32,
+            0b01111 => B64,
+            0b10000 => MAdd,
+            0b10001 => MSub,
+            0b10010 => NMSub,
+            0b10011 => NMAdd,
+            0b10100 => OpFP,
+            0b10101 => Reserved0,
+            0b10110 => Custom2,
+            0b10111 => B48B,
+            0b11000 => Branch,
+            0b11001 => Jalr,
+            0b11010 => Reserved1,
+            0b11011 => Jal,
+            0b11100 => System,
+            0b11101 => Reserved2,
+            0b11110 => Custom3,
+            _ => B80,
+        })
+    }
 }
 
 mod bit_lengths {
@@ -0,0 +1,177 @@
+//! File descriptors for emulated programs. Standard streams go to the host's (or whatever
+//! they're redirected to) and `openat` can only reach files under a sandbox directory.
+use super::errno;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub const O_ACCMODE: u64 = 0o3;
+pub const O_WRONLY: u64 = 0o1;
+pub const O_RDWR: u64 = 0o2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+pub enum Descriptor {
+    Stdin(Box<dyn Read>),
+    Stdout(Box<dyn Write>),
+    Stderr(Box<dyn Write>),
+    File(File),
+}
+impl Descriptor {
+    /// Reads into `buffer`, `EBADF` for the output streams.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, i64> {
+        match self {
+            Descriptor::Stdin(stdin) => stdin.read(buffer),
+            Descriptor::File(file) => file.read(buffer),
+            Descriptor::Stdout(_) | Descriptor::Stderr(_) => return Err(errno::EBADF),
+        }.map_err(|e| errno::from_io(&e))
+    }
+    /// Writes `bytes`, flushing the standard streams so output interleaves as the guest wrote it.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<usize, i64> {
+        match self {
+            Descriptor::Stdout(out) | Descriptor::Stderr(out) => out.write(bytes).and_then(|n| out.flush().map(|()| n)),
+            Descriptor::File(file) => file.write(bytes),
+            Descriptor::Stdin(_) => return Err(errno::EBADF),
+        }.map_err(|e| errno::from_io(&e))
+    }
+}
+/// Open descriptors indexed by fd, closed ones are `None` and get reused lowest first.
+pub struct FileTable {
+    root: Option<PathBuf>,
+    descriptors: Vec<Option<Descriptor>>,
+}
+impl FileTable {
+    /// Standard streams on fds 0 to 2, `root` is the sandbox directory, `None` disables `openat`.
+    pub fn new(root: Option<PathBuf>) -> FileTable {
+        FileTable {
+            root,
+            descriptors: vec![
+                Some(Descriptor::Stdin(Box::new(std::io::stdin()))),
+                Some(Descriptor::Stdout(Box::new(std::io::stdout()))),
+                Some(Descriptor::Stderr(Box::new(std::io::stderr()))),
+            ],
+        }
+    }
+    /// Replaces the descriptor at `fd`, used to redirect the standard streams.
+    pub fn set(&mut self, fd: usize, descriptor: Descriptor) {
+        if fd >= self.descriptors.len() {
+            self.descriptors.resize_with(fd + 1, || None);
+        }
+        self.descriptors[fd] = Some(descriptor);
+    }
+    pub fn get_mut(&mut self, fd: u64) -> Result<&mut Descriptor, i64> {
+        self.descriptors.get_mut(fd as usize).and_then(Option::as_mut).ok_or(errno::EBADF)
+    }
+    pub fn close(&mut self, fd: u64) -> Result<(), i64> {
+        let slot = self.descriptors.get_mut(fd as usize).ok_or(errno::EBADF)?;
+        slot.take().map(|_| ()).ok_or(errno::EBADF)
+    }
+    //Maps a guest path onto the sandbox. Absolute and relative paths both start at the root
+    //and `..` can't climb above it
+    fn resolve(&self, path: &str) -> Result<PathBuf, i64> {
+        let root = self.root.as_ref().ok_or(errno::EACCES)?;
+        let mut resolved = root.clone();
+        let mut depth = 0;
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    depth += 1;
+                },
+                Component::ParentDir if depth == 0 => return Err(errno::EACCES),
+                Component::ParentDir => {
+                    resolved.pop();
+                    depth -= 1;
+                },
+                _ => (),
+            }
+        }
+        //Symbolic links inside the sandbox must not lead out of it either. The file itself may
+        //not exist yet, so the deepest directory above it that does is checked, and a link as
+        //the last component is refused rather than followed
+        let root = root.canonicalize().map_err(|_| errno::EACCES)?;
+        if depth == 0 {
+            return Ok(resolved);
+        }
+        if resolved.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(errno::ELOOP);
+        }
+        let canonical = resolved.ancestors().skip(1).find_map(|ancestor| ancestor.canonicalize().ok()).ok_or(errno::EACCES)?;
+        if !canonical.starts_with(root) {
+            return Err(errno::EACCES);
+        }
+        Ok(resolved)
+    }
+    /// Opens a sandboxed file with Linux `open` flags, returning the new fd.
+    pub fn open(&mut self, path: &str, flags: u64) -> Result<u64, i64> {
+        let path = self.resolve(path)?;
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else if flags & O_CREAT != 0 {
+            options.create(true);
+        }
+        options.truncate(flags & O_TRUNC != 0).append(flags & O_APPEND != 0);
+        let file = options.open(&path).map_err(|e| errno::from_io(&e))?;
+        let fd = match self.descriptors.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None => {
+                self.descriptors.push(None);
+                self.descriptors.len() - 1
+            },
+        };
+        self.descriptors[fd] = Some(Descriptor::File(file));
+        Ok(fd as u64)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_in_the_sandbox() {
+        let root = std::env::temp_dir().join(format!("micro16-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        let mut files = FileTable::new(Some(root.clone()));
+        assert_eq!(files.resolve("/dir/../a.txt"), Ok(root.join("a.txt")));
+        assert_eq!(files.resolve("dir/b"), Ok(root.join("dir/b")));
+        assert_eq!(files.resolve("/"), Ok(root.clone()));
+        assert_eq!(files.resolve("../etc/passwd"), Err(errno::EACCES));
+        assert_eq!(files.open("missing", 0), Err(errno::ENOENT));
+        assert_eq!(files.open("new", O_WRONLY | O_CREAT), Ok(3));
+        assert_eq!(files.open("new", O_WRONLY | O_CREAT | O_EXCL), Err(errno::EEXIST));
+        files.close(1).unwrap();
+        assert_eq!(files.open("new", 0), Ok(1), "the lowest free fd is reused");
+        assert_eq!(files.close(1), Ok(()));
+        assert_eq!(files.close(1), Err(errno::EBADF));
+        assert_eq!(FileTable::new(None).open("new", 0), Err(errno::EACCES));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_in_the_sandbox() {
+        let root = std::env::temp_dir().join(format!("micro16-sandbox-links-{}", std::process::id()));
+        let outside = std::env::temp_dir().join(format!("micro16-sandbox-outside-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("file"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("dir"), root.join("inside")).unwrap();
+        let mut files = FileTable::new(Some(root.clone()));
+        assert_eq!(files.open("link/escaped", O_WRONLY | O_CREAT), Err(errno::EACCES));
+        assert_eq!(files.open("link/missing/escaped", O_WRONLY | O_CREAT), Err(errno::EACCES));
+        assert_eq!(files.open("dangling", O_WRONLY | O_CREAT), Err(errno::ELOOP));
+        assert_eq!(files.open("link", 0), Err(errno::ELOOP));
+        assert!(!outside.join("escaped").exists() && !outside.join("file").exists());
+        assert_eq!(files.open("inside/new", O_WRONLY | O_CREAT), Ok(3), "links within the sandbox are followed");
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+}
@@ -0,0 +1,428 @@
+//! User-mode emulation of statically linked Linux programs: the ELF is loaded with a Linux
+//! style initial stack and `ecall` is handled as a Linux RISC-V system call.
+pub mod files;
+pub mod syscalls;
+
+use super::core::{Core, CoreSettings};
+use super::csr::accessibility::{CsrAddress, PrivilegeLevel};
+use super::csr::{PMPADDR0, PMPCFG0};
+use super::exception::Exception;
+use crate::microvm::loader::{ElfImage, LoaderError};
+use crate::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+use crate::microvm::memory::MemoryError;
+use files::{Descriptor, FileTable};
+use num::traits::FromPrimitive;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Linux error numbers, system calls return them negated.
+pub mod errno {
+    pub const ENOENT: i64 = 2;
+    pub const EBADF: i64 = 9;
+    pub const ENOMEM: i64 = 12;
+    pub const EACCES: i64 = 13;
+    pub const EFAULT: i64 = 14;
+    pub const EEXIST: i64 = 17;
+    pub const ENODEV: i64 = 19;
+    pub const EISDIR: i64 = 21;
+    pub const EINVAL: i64 = 22;
+    pub const ENOTTY: i64 = 25;
+    pub const ESPIPE: i64 = 29;
+    pub const ENAMETOOLONG: i64 = 36;
+    pub const ENOSYS: i64 = 38;
+    pub const ELOOP: i64 = 40;
+
+    pub fn from_io(e: &std::io::Error) -> i64 {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::NotFound => ENOENT,
+            ErrorKind::PermissionDenied => EACCES,
+            ErrorKind::AlreadyExists => EEXIST,
+            ErrorKind::IsADirectory => EISDIR,
+            _ => EINVAL,
+        }
+    }
+}
+//Auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProcessConfig {
+    /// `argv`, including the program name in `argv[0]`.
+    pub args: Vec<String>,
+    /// `envp` entries in `NAME=value` form.
+    pub env: Vec<String>,
+    pub stack_size: u64,
+    /// Directory `openat` is confined to, `None` makes every `openat` fail.
+    pub sandbox: Option<PathBuf>,
+}
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        ProcessConfig {
+            args: vec!["a.out".to_string()],
+            env: Vec::new(),
+            stack_size: 64 * 1024,
+            sandbox: None,
+        }
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProcessError {
+    Loader(LoaderError),
+    Memory(MemoryError),
+    /// argv, envp and the auxiliary vector don't fit on the stack.
+    StackTooSmall,
+}
+impl From<LoaderError> for ProcessError {
+    fn from(e: LoaderError) -> Self {
+        ProcessError::Loader(e)
+    }
+}
+impl From<MemoryError> for ProcessError {
+    fn from(e: MemoryError) -> Self {
+        ProcessError::Memory(e)
+    }
+}
+/// Why a process stopped running.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Stop {
+    Exited(i32),
+    /// An exception other than `ecall`, which would have killed a Linux process with a signal.
+    Exception(Exception),
+    StepLimit,
+}
+
+pub struct Process<Settings: CoreSettings> {
+    core: Core<Settings>,
+    image: ElfImage,
+    files: FileTable,
+    //The program break, the first address past the heap, and where its mapped pages end
+    brk_start: u64,
+    brk: u64,
+    brk_mapped: u64,
+    //Anonymous mappings are handed out downwards from here
+    mmap_top: u64,
+    started: Instant,
+}
+//`None` past the top of a 64 bit space
+fn round_up(value: u64, to: u64) -> Option<u64> {
+    value.div_ceil(to).checked_mul(to)
+}
+impl<Settings: CoreSettings> Process<Settings> {
+    /// Loads `elf` into the core's address space and sets up the stack at the top of it. The
+    /// program runs in U-mode when the core has it, with PMP opened up for the whole space.
+    pub fn new(mut core: Core<Settings>, elf: &[u8], config: ProcessConfig) -> Result<Process<Settings>, ProcessError> {
+        let image = core.load_elf(elf)?;
+        let end = image.segments.iter().map(|segment| segment.address + segment.size).max().unwrap_or(0);
+        let brk_start = round_up(end, PAGE_SIZE).ok_or(MemoryError::Overflow)?;
+        let stack_top = core.space().size() & !0xF;
+        let stack_bottom = stack_top.checked_sub(config.stack_size).ok_or(ProcessError::StackTooSmall)? & !(PAGE_SIZE - 1);
+        if core.supports_privilege(PrivilegeLevel::User) {
+            let pmp = core.write_csr(CsrAddress::new(PMPADDR0), u64::MAX)
+                .and_then(|()| core.write_csr(CsrAddress::new(PMPCFG0), 0x1F));
+            if pmp.is_some() {
+                core.context_mut().csr.set_privilege(PrivilegeLevel::User);
+            }
+        }
+        let mut process = Process {
+            core,
+            image,
+            files: FileTable::new(config.sandbox.clone()),
+            brk_start,
+            brk: brk_start,
+            brk_mapped: brk_start,
+            mmap_top: stack_bottom - PAGE_SIZE,
+            started: Instant::now(),
+        };
+        process.map(stack_bottom, stack_top - stack_bottom)?;
+        let sp = process.initial_stack(stack_top, stack_bottom, &config)?;
+        process.core.set_x(2, sp);
+        Ok(process)
+    }
+    pub fn core(&self) -> &Core<Settings> {
+        &self.core
+    }
+    pub fn core_mut(&mut self) -> &mut Core<Settings> {
+        &mut self.core
+    }
+    pub fn image(&self) -> &ElfImage {
+        &self.image
+    }
+    /// Redirects fd 0, 1 or 2.
+    pub fn set_stdin(&mut self, stdin: Box<dyn Read>) {
+        self.files.set(0, Descriptor::Stdin(stdin));
+    }
+    pub fn set_stdout(&mut self, stdout: Box<dyn Write>) {
+        self.files.set(1, Descriptor::Stdout(stdout));
+    }
+    pub fn set_stderr(&mut self, stderr: Box<dyn Write>) {
+        self.files.set(2, Descriptor::Stderr(stderr));
+    }
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+    //Maps zeroed RAM
+    fn map(&mut self, address: u64, size: u64) -> Result<(), MemoryError> {
+        let offset = Settings::Address::from_u64(address).ok_or(MemoryError::Overflow)?;
+        self.core.space_mut().add_space(offset, Box::new(DenseStaticMemory::new(size)))
+    }
+    fn pointer_size() -> u64 {
+        Core::<Settings>::xlen() as u64 / 8
+    }
+    /// Reads guest memory for a system call, failing with `EFAULT`.
+    pub fn read_memory(&self, address: u64, len: u64) -> Result<Vec<u8>, i64> {
+        (0..len).map(|i| {
+            let address = address.checked_add(i).and_then(Settings::Address::from_u64).ok_or(errno::EFAULT)?;
+            self.core.space().read_byte(address).map_err(|_| errno::EFAULT)
+        }).collect()
+    }
+    pub fn write_memory(&mut self, address: u64, bytes: &[u8]) -> Result<(), i64> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let address = address.checked_add(i as u64).and_then(Settings::Address::from_u64).ok_or(errno::EFAULT)?;
+            self.core.space_mut().write_bytes(address, &[byte]).map_err(|_| errno::EFAULT)?;
+        }
+        Ok(())
+    }
+    //An XLEN sized value
+    fn read_pointer(&self, address: u64) -> Result<u64, i64> {
+        let mut bytes = [0u8; 8];
+        let size = Self::pointer_size() as usize;
+        bytes[..size].copy_from_slice(&self.read_memory(address, size as u64)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    fn write_pointer(&mut self, address: u64, value: u64) -> Result<(), i64> {
+        let size = Self::pointer_size() as usize;
+        self.write_memory(address, &value.to_le_bytes()[..size])
+    }
+    /// Reads a NUL terminated string of at most 4096 bytes.
+    pub fn read_string(&self, address: u64) -> Result<String, i64> {
+        let mut bytes = Vec::new();
+        for i in 0..4096 {
+            match self.read_memory(address.checked_add(i).ok_or(errno::EFAULT)?, 1)?[0] {
+                0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                byte => bytes.push(byte),
+            }
+        }
+        Err(errno::ENAMETOOLONG)
+    }
+    //Lays out, from the top down: the argument and environment strings, 16 random bytes, then
+    //from the returned 16 byte aligned sp up argc, argv, envp and the auxiliary vector
+    fn initial_stack(&mut self, top: u64, bottom: u64, config: &ProcessConfig) -> Result<u64, ProcessError> {
+        let overflow = |_| ProcessError::StackTooSmall;
+        let mut sp = top;
+        let mut push = |process: &mut Self, bytes: &[u8]| -> Result<u64, ProcessError> {
+            sp = sp.checked_sub(bytes.len() as u64).filter(|&sp| sp >= bottom).ok_or(ProcessError::StackTooSmall)?;
+            process.write_memory(sp, bytes).map_err(overflow)?;
+            Ok(sp)
+        };
+        let mut strings = |process: &mut Self, strings: &[String]| -> Result<Vec<u64>, ProcessError> {
+            strings.iter().map(|s| push(process, format!("{}\0", s).as_bytes())).collect()
+        };
+        let argv = strings(self, &config.args)?;
+        let envp = strings(self, &config.env)?;
+        let mut random = [0u8; 16];
+        for chunk in random.chunks_mut(8) {
+            chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+        }
+        let random = push(self, &random)?;
+
+        let image = &self.image;
+        let auxv = [
+            (AT_PHDR, image.program_headers.unwrap_or(0)),
+            (AT_PHENT, image.program_header_size),
+            (AT_PHNUM, if image.program_headers.is_some() { image.program_header_count } else { 0 }),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, image.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, self.core.extensions().bits() as u64),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u64];
+        words.extend(argv);
+        words.push(0);
+        words.extend(envp);
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|&(key, value)| vec![key, value]));
+        let sp = random.checked_sub(words.len() as u64 * Self::pointer_size()).filter(|&sp| sp >= bottom + 16)
+            .ok_or(ProcessError::StackTooSmall)? & !0xF;
+        for (i, &word) in words.iter().enumerate() {
+            self.write_pointer(sp + i as u64 * Self::pointer_size(), word).map_err(overflow)?;
+        }
+        Ok(sp)
+    }
+    /// Executes one instruction, handling `ecall` as a system call. Returns why the process
+    /// stopped, if it did.
+    pub fn step(&mut self) -> Option<Stop> {
+        match self.core.step() {
+            Ok(()) => None,
+            Err(Exception::EnvironmentCall(_)) => {
+                let stop = self.syscall();
+                let pc = self.core.pc();
+                self.core.set_pc(pc.wrapping_add(4));
+                stop
+            },
+            Err(e) => Some(Stop::Exception(e)),
+        }
+    }
+    /// Runs until the process exits or faults, or `max_steps` instructions have run.
+    pub fn run(&mut self, max_steps: Option<u64>) -> Stop {
+        let mut steps = 0;
+        while max_steps.is_none_or(|max| steps < max) {
+            if let Some(stop) = self.step() {
+                return stop;
+            }
+            steps += 1;
+        }
+        Stop::StepLimit
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microvm::loader::{tests::elf32, Machine};
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+    use crate::risc_v_emu::core::tests::Rv32;
+    use crate::risc_v_emu::extensions::Extensions;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    //Writes the two bytes at 0x2000 to stdout and exits with 7
+    const HELLO: [u32; 8] = [
+        0x00100513, //li a0, 1
+        0x000025b7, //lui a1, 2
+        0x00200613, //li a2, 2
+        0x04000893, //li a7, 64
+        0x00000073, //ecall
+        0x00700513, //li a0, 7
+        0x05d00893, //li a7, 93
+        0x00000073, //ecall
+    ];
+    fn process(config: ProcessConfig) -> Process<Rv32> {
+        let text: Vec<u8> = HELLO.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        let elf = elf32(Machine::RiscV32.e_machine(), 0x1000, &text, 0x2000, b"hi", 0);
+        let core = Core::new(SparseAddressSpace::new(u16::MAX).unwrap(), Extensions::from_letters("imu").unwrap());
+        Process::new(core, &elf, ProcessConfig { stack_size: 0x2000, ..config }).unwrap()
+    }
+    fn call(process: &mut Process<Rv32>, number: u64, args: &[u64]) -> i64 {
+        process.core.set_x(17, number);
+        for (i, &arg) in args.iter().enumerate() {
+            process.core.set_x(10 + i, arg);
+        }
+        assert_eq!(process.syscall(), None);
+        process.core.x_signed(10)
+    }
+
+    #[test]
+    fn writes_and_exits() {
+        let mut process = process(ProcessConfig::default());
+        let output = Output::default();
+        process.set_stdout(Box::new(output.clone()));
+        assert_eq!(process.core().context().csr.privilege(), PrivilegeLevel::User);
+        assert_eq!(process.run(Some(100)), Stop::Exited(7));
+        assert_eq!(&output.0.borrow()[..], b"hi");
+    }
+    #[test]
+    fn initial_stack() {
+        let config = ProcessConfig {
+            args: vec!["prog".to_string(), "x".to_string()],
+            env: vec!["A=1".to_string()],
+            ..ProcessConfig::default()
+        };
+        let process = process(config);
+        let sp = process.core().x(2);
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| process.read_pointer(sp + 4 * i).unwrap();
+        let string = |i: u64| process.read_string(word(i)).unwrap();
+        assert_eq!(word(0), 2);
+        assert_eq!((string(1), string(2), word(3)), ("prog".to_string(), "x".to_string(), 0));
+        assert_eq!((string(4), word(5)), ("A=1".to_string(), 0));
+        let auxv: Vec<(u64, u64)> = (0..14).map(|i| (word(6 + 2 * i), word(7 + 2 * i))).collect();
+        assert!(auxv.contains(&(AT_PAGESZ, PAGE_SIZE)));
+        assert!(auxv.contains(&(AT_ENTRY, 0x1000)));
+        assert_eq!(auxv.last(), Some(&(AT_NULL, 0)));
+        let random = auxv.iter().find(|&&(key, _)| key == AT_RANDOM).unwrap().1;
+        assert_eq!(process.read_memory(random, 16).map(|bytes| bytes.len()), Ok(16));
+    }
+    #[test]
+    fn brk_and_mmap() {
+        let mut process = process(ProcessConfig::default());
+        assert_eq!(call(&mut process, syscalls::BRK, &[0]), 0x3000);
+        assert_eq!(call(&mut process, syscalls::BRK, &[0x3100]), 0x3100);
+        assert_eq!(process.write_memory(0x30FF, &[1]), Ok(()));
+        assert_eq!(process.write_memory(0x4000, &[1]), Err(errno::EFAULT));
+        let mapped = call(&mut process, syscalls::MMAP, &[0, 0x1800, 3, 0x22, u32::MAX as u64, 0]) as u64;
+        assert_eq!(mapped % PAGE_SIZE, 0);
+        assert_eq!(process.read_memory(mapped, 0x2000).map(|bytes| bytes.len()), Ok(0x2000));
+        assert_eq!(call(&mut process, syscalls::MMAP, &[0, 0x1000, 3, 0x02, 3, 0]), -errno::ENODEV);
+        assert_eq!(call(&mut process, syscalls::BRK, &[mapped + 1]), 0x3100, "the heap can't grow into mappings");
+        assert_eq!(call(&mut process, 1234, &[]), -errno::ENOSYS);
+    }
+    #[test]
+    fn rounds_up_without_overflowing() {
+        assert_eq!(round_up(0x1001, PAGE_SIZE), Some(0x2000));
+        assert_eq!(round_up(u64::MAX - PAGE_SIZE + 1, PAGE_SIZE), Some(u64::MAX - PAGE_SIZE + 1));
+        assert_eq!(round_up(u64::MAX, PAGE_SIZE), None);
+        let process = process(ProcessConfig::default());
+        assert_eq!(process.read_string(u64::MAX), Err(errno::EFAULT));
+    }
+    #[test]
+    fn sandboxed_files() {
+        let root = std::env::temp_dir().join(format!("micro16-linux-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("data.txt"), b"contents").unwrap();
+        let mut process = process(ProcessConfig { sandbox: Some(root.clone()), ..ProcessConfig::default() });
+        call(&mut process, syscalls::BRK, &[0x4000]);
+        process.write_memory(0x3000, b"data.txt\0").unwrap();
+        let fd = call(&mut process, syscalls::OPENAT, &[-100i64 as u64, 0x3000, 0, 0]);
+        assert_eq!(fd, 3);
+        let fd = fd as u64;
+        assert_eq!(call(&mut process, syscalls::READ, &[fd, 0x3100, 4]), 4);
+        assert_eq!(process.read_memory(0x3100, 4), Ok(b"cont".to_vec()));
+        assert_eq!(call(&mut process, syscalls::LSEEK, &[fd, 1, 0]), 1);
+        assert_eq!(call(&mut process, syscalls::FSTAT, &[fd, 0x3200]), 0);
+        assert_eq!(process.read_memory(0x3200 + 48, 8), Ok(8u64.to_le_bytes().to_vec()));
+        assert_eq!(call(&mut process, syscalls::LSEEK, &[1, 0, 0]), -errno::ESPIPE);
+        assert_eq!(call(&mut process, syscalls::CLOSE, &[fd]), 0);
+        assert_eq!(call(&mut process, syscalls::READ, &[fd, 0x3100, 4]), -errno::EBADF);
+        process.write_memory(0x3000, b"../x\0").unwrap();
+        assert_eq!(call(&mut process, syscalls::OPENAT, &[-100i64 as u64, 0x3000, 0, 0]), -errno::EACCES);
+        process.write_memory(0x3000, b"missing\0").unwrap();
+        assert_eq!(call(&mut process, syscalls::OPENAT, &[-100i64 as u64, 0x3000, 0, 0]), -errno::ENOENT);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
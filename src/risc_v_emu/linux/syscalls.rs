@@ -0,0 +1,188 @@
+//! The system calls: the number is in `a7`, the arguments in `a0` to `a5` and the result, or
+//! a negated errno, goes back in `a0`. Anything not listed here fails with `ENOSYS`.
+use super::errno;
+use super::files::Descriptor;
+use super::{round_up, Process, Stop, PAGE_SIZE};
+use crate::risc_v_emu::core::CoreSettings;
+use std::io::{Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const IOCTL: u64 = 29;
+pub const OPENAT: u64 = 56;
+pub const CLOSE: u64 = 57;
+pub const LSEEK: u64 = 62;
+pub const READ: u64 = 63;
+pub const WRITE: u64 = 64;
+pub const READV: u64 = 65;
+pub const WRITEV: u64 = 66;
+pub const FSTAT: u64 = 80;
+pub const EXIT: u64 = 93;
+pub const EXIT_GROUP: u64 = 94;
+pub const SET_TID_ADDRESS: u64 = 96;
+pub const CLOCK_GETTIME: u64 = 113;
+pub const BRK: u64 = 214;
+pub const MUNMAP: u64 = 215;
+pub const MMAP: u64 = 222;
+pub const CLOCK_GETTIME64: u64 = 403;
+
+const AT_FDCWD: i64 = -100;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+//Largest single read or write, so a bogus length can't allocate unbounded host memory
+const MAX_TRANSFER: u64 = 1 << 20;
+
+impl<Settings: CoreSettings> Process<Settings> {
+    pub(super) fn syscall(&mut self) -> Option<Stop> {
+        let a = [10, 11, 12, 13, 14, 15].map(|reg| self.core.x(reg));
+        let result = match self.core.x(17) {
+            READ => self.read(a[0], a[1], a[2]),
+            WRITE => self.write(a[0], a[1], a[2]),
+            READV => self.vectored(a[0], a[1], a[2], Self::read),
+            WRITEV => self.vectored(a[0], a[1], a[2], Self::write),
+            OPENAT => self.openat(self.core.x_signed(10), a[1], a[2]),
+            CLOSE => self.files.close(a[0]).map(|()| 0),
+            LSEEK => self.lseek(a[0], self.core.x_signed(11), a[2]),
+            FSTAT => self.fstat(a[0], a[1]),
+            IOCTL => self.files.get_mut(a[0]).and(Err(errno::ENOTTY)),
+            EXIT | EXIT_GROUP => return Some(Stop::Exited(a[0] as i32)),
+            SET_TID_ADDRESS => Ok(1),
+            CLOCK_GETTIME => self.clock_gettime(a[0], a[1], Self::pointer_size()),
+            CLOCK_GETTIME64 => self.clock_gettime(a[0], a[1], 8),
+            BRK => Ok(self.set_brk(a[0])),
+            MMAP => self.mmap(a[0], a[1], a[3]),
+            MUNMAP => Ok(0),
+            _ => Err(errno::ENOSYS),
+        };
+        self.core.set_x(10, result.unwrap_or_else(|e| e.wrapping_neg() as u64));
+        None
+    }
+    fn read(&mut self, fd: u64, buffer: u64, count: u64) -> Result<u64, i64> {
+        let mut bytes = vec![0; count.min(MAX_TRANSFER) as usize];
+        let read = self.files.get_mut(fd)?.read(&mut bytes)?;
+        self.write_memory(buffer, &bytes[..read])?;
+        Ok(read as u64)
+    }
+    fn write(&mut self, fd: u64, buffer: u64, count: u64) -> Result<u64, i64> {
+        let bytes = self.read_memory(buffer, count.min(MAX_TRANSFER))?;
+        self.files.get_mut(fd)?.write(&bytes).map(|n| n as u64)
+    }
+    //readv and writev, an iovec is a base pointer followed by a length
+    fn vectored(&mut self, fd: u64, iov: u64, count: u64, transfer: fn(&mut Self, u64, u64, u64) -> Result<u64, i64>) -> Result<u64, i64> {
+        let mut total = 0;
+        for i in 0..count.min(1024) {
+            let entry = iov.checked_add(i * 2 * Self::pointer_size()).ok_or(errno::EFAULT)?;
+            let base = self.read_pointer(entry)?;
+            let len = self.read_pointer(entry.checked_add(Self::pointer_size()).ok_or(errno::EFAULT)?)?;
+            let done = transfer(self, fd, base, len)?;
+            total += done;
+            if done < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+    fn openat(&mut self, directory: i64, path: u64, flags: u64) -> Result<u64, i64> {
+        let path = self.read_string(path)?;
+        //Directory descriptors aren't supported, so relative paths need the working directory
+        if directory != AT_FDCWD && !path.starts_with('/') {
+            return Err(errno::EBADF);
+        }
+        self.files.open(&path, flags)
+    }
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> Result<u64, i64> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(errno::EINVAL),
+        };
+        match self.files.get_mut(fd)? {
+            Descriptor::File(file) => file.seek(position).map_err(|e| errno::from_io(&e)),
+            _ => Err(errno::ESPIPE),
+        }
+    }
+    //Fills the 128 byte asm-generic `struct stat`
+    fn fstat(&mut self, fd: u64, buffer: u64) -> Result<u64, i64> {
+        let (mode, size, block_size, modified) = match self.files.get_mut(fd)? {
+            Descriptor::File(file) => {
+                let metadata = file.metadata().map_err(|e| errno::from_io(&e))?;
+                let kind = if metadata.is_dir() { S_IFDIR | 0o755 } else { S_IFREG | 0o644 };
+                let modified = metadata.modified().ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                (kind, metadata.len(), 4096, modified)
+            },
+            _ => (S_IFCHR | 0o620, 0, 1024, Default::default()),
+        };
+        let mut stat = [0u8; 128];
+        let mut put = |offset: usize, bytes: &[u8]| stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(16, &mode.to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+        put(48, &size.to_le_bytes());
+        put(56, &(block_size as u32).to_le_bytes());
+        put(64, &size.div_ceil(512).to_le_bytes());
+        for time in [72, 88, 104].iter() {
+            put(*time, &modified.as_secs().to_le_bytes());
+            put(*time + 8, &u64::from(modified.subsec_nanos()).to_le_bytes());
+        }
+        self.write_memory(buffer, &stat)?;
+        Ok(0)
+    }
+    //A timespec of two `field` byte integers, seconds then nanoseconds
+    fn clock_gettime(&mut self, clock: u64, buffer: u64, field: u64) -> Result<u64, i64> {
+        let time = match clock {
+            CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            CLOCK_MONOTONIC => self.started.elapsed(),
+            _ => return Err(errno::EINVAL),
+        };
+        let field = field as usize;
+        self.write_memory(buffer, &time.as_secs().to_le_bytes()[..field])?;
+        let nanoseconds = buffer.checked_add(field as u64).ok_or(errno::EFAULT)?;
+        self.write_memory(nanoseconds, &u64::from(time.subsec_nanos()).to_le_bytes()[..field])?;
+        Ok(0)
+    }
+    //Moves the break, returning the new one, or the old one when it can't move. New pages are
+    //mapped as needed but never unmapped when the break shrinks
+    fn set_brk(&mut self, brk: u64) -> u64 {
+        if brk < self.brk_start {
+            return self.brk;
+        }
+        let mapped = match round_up(brk, PAGE_SIZE) {
+            Some(mapped) => mapped,
+            None => return self.brk,
+        };
+        if mapped > self.brk_mapped {
+            if mapped > self.mmap_top || self.map(self.brk_mapped, mapped - self.brk_mapped).is_err() {
+                return self.brk;
+            }
+            self.brk_mapped = mapped;
+        }
+        self.brk = brk;
+        brk
+    }
+    //Only anonymous mappings, placed downwards from below the stack unless MAP_FIXED
+    fn mmap(&mut self, address: u64, len: u64, flags: u64) -> Result<u64, i64> {
+        if flags & MAP_ANONYMOUS == 0 {
+            return Err(errno::ENODEV);
+        }
+        if len == 0 {
+            return Err(errno::EINVAL);
+        }
+        let size = round_up(len, PAGE_SIZE).ok_or(errno::ENOMEM)?;
+        if flags & MAP_FIXED != 0 {
+            if !address.is_multiple_of(PAGE_SIZE) {
+                return Err(errno::EINVAL);
+            }
+            return self.map(address, size).map(|()| address).map_err(|_| errno::ENOMEM);
+        }
+        let address = self.mmap_top.checked_sub(size).filter(|&address| address >= self.brk_mapped).ok_or(errno::ENOMEM)?;
+        self.map(address, size).map_err(|_| errno::ENOMEM)?;
+        self.mmap_top = address;
+        Ok(address)
+    }
+}
@@ -5,4 +5,17 @@ pub mod regs;
 pub mod csr;
 pub mod context;
 pub mod core;
-pub mod immediate;
\ No newline at end of file
+pub mod immediate;
+pub mod float;
+pub mod exception;
+pub mod extensions;
+pub mod trap;
+pub mod pmp;
+pub mod muldiv;
+pub mod atomic;
+pub mod compressed;
+pub mod conformance;
+pub mod linux;
+pub mod semihosting;
+pub mod disassembler;
+pub mod debug;
@@ -0,0 +1,72 @@
+//! The M extension, integer multiplication and division.
+use super::core::{Core, CoreSettings};
+use super::decoder::RawInstruction;
+use super::exception::Exception;
+
+impl<Settings: CoreSettings> Core<Settings> {
+    /// OP with funct7 1. Division by zero and signed overflow don't trap, they return the
+    /// results the spec fixes for them.
+    pub(crate) fn mul_div(&self, instruction: RawInstruction) -> u64 {
+        let (a, b) = (self.x(instruction.rs1()), self.x(instruction.rs2()));
+        let (a_signed, b_signed) = (self.x_signed(instruction.rs1()), self.x_signed(instruction.rs2()));
+        let xlen = Self::xlen();
+        match instruction.funct3().value() {
+            0b000 => a.wrapping_mul(b),
+            0b001 => ((a_signed as i128 * b_signed as i128) >> xlen) as u64,
+            0b010 => ((a_signed as i128 * b as i128) >> xlen) as u64,
+            0b011 => ((a as u128 * b as u128) >> xlen) as u64,
+            0b100 if b == 0 => u64::MAX,
+            0b100 => a_signed.wrapping_div(b_signed) as u64,
+            0b101 if b == 0 => u64::MAX,
+            0b101 => a / b,
+            0b110 if b == 0 => a,
+            0b110 => a_signed.wrapping_rem(b_signed) as u64,
+            _ if b == 0 => a,
+            _ => a % b,
+        }
+    }
+    //RV64 only OP-32 with funct7 1, the *W forms
+    pub(crate) fn mul_div_32(&self, instruction: RawInstruction) -> Result<u64, Exception> {
+        let a = self.x(instruction.rs1()) as u32;
+        let b = self.x(instruction.rs2()) as u32;
+        let value = match instruction.funct3().value() {
+            0b000 => a.wrapping_mul(b),
+            0b100 if b == 0 => u32::MAX,
+            0b100 => (a as i32).wrapping_div(b as i32) as u32,
+            0b101 if b == 0 => u32::MAX,
+            0b101 => a / b,
+            0b110 if b == 0 => a,
+            0b110 => (a as i32).wrapping_rem(b as i32) as u32,
+            0b111 if b == 0 => a,
+            0b111 => a % b,
+            _ => return Err(Exception::IllegalInstruction(instruction.0)),
+        };
+        Ok(value as i32 as i64 as u64)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::risc_v_emu::core::tests::{core_with_program, Rv32, Rv64};
+
+    #[test]
+    fn division_edge_cases() {
+        let program = [
+            0x02b54633, //div a2, a0, a1
+            0x02b566b3, //rem a3, a0, a1
+            0x02b53733, //mulhu a4, a0, a1
+        ];
+        let mut core = core_with_program::<Rv32>("im", &program);
+        core.set_x(10, 0x8000_0000);
+        core.set_x(11, u64::MAX);
+        for _ in 0..3 {
+            core.step().unwrap();
+        }
+        assert_eq!((core.x(12), core.x(13), core.x(14)), (0x8000_0000, 0, 0x7FFF_FFFF));
+
+        let mut core = core_with_program::<Rv64>("im", &program);
+        core.set_x(10, 7);
+        core.step().unwrap();
+        core.step().unwrap();
+        assert_eq!((core.x(12), core.x(13)), (u64::MAX, 7), "division by zero");
+    }
+}
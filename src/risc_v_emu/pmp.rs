@@ -0,0 +1,188 @@
+//! Physical memory protection: `pmpcfg` and `pmpaddr` with TOR, NA4 and NAPOT regions.
+use super::csr::accessibility::PrivilegeLevel;
+use crate::microvm::mmu::AccessType;
+
+const CFG_R: u8 = 1 << 0;
+const CFG_W: u8 = 1 << 1;
+const CFG_X: u8 = 1 << 2;
+const CFG_A_SHIFT: u8 = 3;
+const CFG_L: u8 = 1 << 7;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AddressMatching {
+    Off = 0,
+    TopOfRange = 1,
+    NaturallyAligned4 = 2,
+    NaturallyAlignedPowerOfTwo = 3,
+}
+impl AddressMatching {
+    pub fn from_cfg(cfg: u8) -> AddressMatching {
+        match (cfg >> CFG_A_SHIFT) & 0b11 {
+            0 => AddressMatching::Off,
+            1 => AddressMatching::TopOfRange,
+            2 => AddressMatching::NaturallyAligned4,
+            _ => AddressMatching::NaturallyAlignedPowerOfTwo,
+        }
+    }
+}
+/// PMP entries, `cfg` holds one `pmpcfg` byte per entry and `addr` the matching `pmpaddr`
+/// (bits 2 and up of the physical address).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pmp {
+    cfg: Vec<u8>,
+    addr: Vec<u64>,
+}
+impl Pmp {
+    /// `entries` is 0, 16 or 64.
+    pub fn new(entries: usize) -> Pmp {
+        Pmp {
+            cfg: vec![0; entries],
+            addr: vec![0; entries],
+        }
+    }
+    pub fn entries(&self) -> usize {
+        self.cfg.len()
+    }
+    pub fn cfg(&self, entry: usize) -> u8 {
+        self.cfg[entry]
+    }
+    pub fn addr(&self, entry: usize) -> u64 {
+        self.addr[entry]
+    }
+    fn locked(&self, entry: usize) -> bool {
+        self.cfg[entry] & CFG_L != 0
+    }
+    /// Writes a config byte, ignored for locked entries. R=0 W=1 is reserved and reads back
+    /// with W clear.
+    pub fn set_cfg(&mut self, entry: usize, cfg: u8) {
+        if self.locked(entry) {
+            return;
+        }
+        let cfg = cfg & !0b0110_0000;
+        self.cfg[entry] = if cfg & (CFG_R | CFG_W) == CFG_W { cfg & !CFG_W } else { cfg };
+    }
+    /// Writes an address register, ignored when the entry is locked or is the base of a locked
+    /// TOR entry.
+    pub fn set_addr(&mut self, entry: usize, addr: u64) {
+        let tor_base_locked = entry + 1 < self.entries() && self.locked(entry + 1) &&
+            AddressMatching::from_cfg(self.cfg[entry + 1]) == AddressMatching::TopOfRange;
+        if !self.locked(entry) && !tor_base_locked {
+            self.addr[entry] = addr;
+        }
+    }
+    //Byte range covered by an entry, end exclusive
+    fn range(&self, entry: usize) -> Option<(u128, u128)> {
+        let addr = self.addr[entry] as u128;
+        match AddressMatching::from_cfg(self.cfg[entry]) {
+            AddressMatching::Off => None,
+            AddressMatching::TopOfRange => {
+                let base = if entry == 0 { 0 } else { (self.addr[entry - 1] as u128) << 2 };
+                Some((base, addr << 2))
+            },
+            AddressMatching::NaturallyAligned4 => Some((addr << 2, (addr << 2) + 4)),
+            AddressMatching::NaturallyAlignedPowerOfTwo => {
+                let ones = self.addr[entry].trailing_ones();
+                let base = (addr & !((1 << ones) - 1)) << 2;
+                Some((base, base + (1 << (ones + 3))))
+            },
+        }
+    }
+    /// Whether a `size` byte access at a physical address is permitted. The lowest numbered
+    /// entry that overlaps the access decides, and it must cover the whole access.
+    pub fn check(&self, address: u64, size: usize, access: AccessType, privilege: PrivilegeLevel) -> bool {
+        let (start, end) = (address as u128, address as u128 + size as u128);
+        for entry in 0..self.entries() {
+            let (base, top) = match self.range(entry) {
+                Some(range) => range,
+                None => continue,
+            };
+            if end <= base || start >= top {
+                continue;
+            }
+            if start < base || end > top {
+                return false;
+            }
+            let cfg = self.cfg[entry];
+            if privilege == PrivilegeLevel::Machine && cfg & CFG_L == 0 {
+                return true;
+            }
+            let permission = match access {
+                AccessType::Fetch => CFG_X,
+                AccessType::Load => CFG_R,
+                AccessType::Store => CFG_W,
+            };
+            return cfg & permission != 0;
+        }
+        //Nothing matched, S-mode and U-mode fail as soon as any entry is implemented
+        privilege == PrivilegeLevel::Machine || self.entries() == 0
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAPOT: u8 = 3 << CFG_A_SHIFT;
+    const TOR: u8 = 1 << CFG_A_SHIFT;
+
+    #[test]
+    fn matching_modes_and_priority() {
+        let mut pmp = Pmp::new(16);
+        //0: NA4 read-only word at 0x100, 1: TOR [0x100, 0x200) RW, 2: NAPOT 0x1000-0x1FFF RWX
+        pmp.set_addr(0, 0x100 >> 2);
+        pmp.set_cfg(0, CFG_R | (2 << CFG_A_SHIFT));
+        pmp.set_addr(1, 0x200 >> 2);
+        pmp.set_cfg(1, CFG_R | CFG_W | TOR);
+        pmp.set_addr(2, (0x1000 >> 2) | 0x1FF);
+        pmp.set_cfg(2, CFG_R | CFG_W | CFG_X | NAPOT);
+        let user = PrivilegeLevel::User;
+        assert!(pmp.check(0x100, 4, AccessType::Load, user));
+        assert!(!pmp.check(0x100, 4, AccessType::Store, user), "entry 0 wins over entry 1");
+        assert!(pmp.check(0x104, 4, AccessType::Store, user));
+        assert!(!pmp.check(0x104, 4, AccessType::Fetch, user));
+        assert!(!pmp.check(0x1FC, 8, AccessType::Load, user), "partially covered accesses fail");
+        assert!(pmp.check(0x1FFC, 4, AccessType::Fetch, user));
+        assert!(!pmp.check(0x2000, 4, AccessType::Load, user), "no match fails below M-mode");
+        assert!(pmp.check(0x2000, 4, AccessType::Load, PrivilegeLevel::Machine));
+        assert!(pmp.check(0x100, 4, AccessType::Store, PrivilegeLevel::Machine));
+    }
+    #[test]
+    fn lock_bits() {
+        let mut pmp = Pmp::new(16);
+        pmp.set_addr(0, 0x100 >> 2);
+        pmp.set_addr(1, 0x200 >> 2);
+        pmp.set_cfg(1, CFG_R | TOR | CFG_L);
+        assert!(!pmp.check(0x180, 4, AccessType::Store, PrivilegeLevel::Machine), "locked entries bind M-mode");
+        pmp.set_cfg(1, CFG_R | CFG_W | TOR);
+        pmp.set_addr(1, 0x300 >> 2);
+        pmp.set_addr(0, 0);
+        assert_eq!((pmp.cfg(1), pmp.addr(0), pmp.addr(1)), (CFG_R | TOR | CFG_L, 0x100 >> 2, 0x200 >> 2));
+        pmp.set_cfg(2, CFG_W);
+        assert_eq!(pmp.cfg(2), 0, "R=0 W=1 is reserved");
+    }
+    #[test]
+    fn core_accesses_are_checked() {
+        use crate::risc_v_emu::core::tests::{core_with_program, Rv32};
+        use crate::risc_v_emu::csr::{accessibility::CsrAddress, PMPADDR0, PMPCFG0};
+        use crate::risc_v_emu::exception::Exception;
+
+        let program = [
+            0x10002583, //lw a1, 0x100(zero)
+            0x10a02023, //sw a0, 0x100(zero)
+        ];
+        let mut core = core_with_program::<Rv32>("iu", &program);
+        //Entry 0 makes the word at 0x100 read-only, entry 1 opens the rest of memory
+        core.write_csr(CsrAddress::new(PMPADDR0), 0x100 >> 2).unwrap();
+        core.write_csr(CsrAddress::new(PMPADDR0 + 1), u64::MAX).unwrap();
+        core.write_csr(CsrAddress::new(PMPCFG0), ((CFG_R | CFG_W | CFG_X | NAPOT) as u64) << 8 |
+            (CFG_R | (2 << CFG_A_SHIFT)) as u64).unwrap();
+        core.context_mut().csr.set_privilege(PrivilegeLevel::User);
+        core.step().unwrap();
+        assert_eq!(core.step(), Err(Exception::StoreAccessFault(0x100)));
+        core.context_mut().csr.set_privilege(PrivilegeLevel::Machine);
+        core.step().unwrap();
+        core.context_mut().csr.set_privilege(PrivilegeLevel::User);
+        core.write_csr(CsrAddress::new(PMPCFG0), 0).unwrap();
+        core.set_pc(0);
+        assert_eq!(core.step(), Err(Exception::InstructionAccessFault(0)));
+    }
+}
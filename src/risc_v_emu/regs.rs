@@ -3,4 +3,28 @@ pub struct Regs<RegType: DataType> {
 
     regs: [RegType; 32]
 
-}
\ No newline at end of file
+}
+impl<RegType: DataType> Regs<RegType> {
+    pub fn new() -> Regs<RegType> {
+        Regs { regs: [RegType::from_u64(0); 32] }
+    }
+    pub fn get(&self, reg: usize) -> RegType {
+        self.regs[reg]
+    }
+    //x0 is hardwired to zero so writes to it are dropped
+    pub fn set(&mut self, reg: usize, value: RegType) {
+        if reg != 0 {
+            self.regs[reg] = value;
+        }
+    }
+}
+impl<RegType: DataType> Default for Regs<RegType> {
+    fn default() -> Self {
+        Regs::new()
+    }
+}
+/// Register names from the calling convention, indexed by register number.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
@@ -0,0 +1,288 @@
+//! RISC-V semihosting, host I/O for bare-metal programs. A call is an `ebreak` between
+//! `slli x0, x0, 0x1f` and `srai x0, x0, 7`, with the operation in `a0` and its argument, usually
+//! a pointer to a block of XLEN sized parameters, in `a1`. The result goes back in `a0`.
+use super::core::{Core, CoreSettings};
+use super::linux::files::{self, FileTable};
+use std::path::PathBuf;
+use std::time::Instant;
+
+pub const SYS_OPEN: u64 = 0x01;
+pub const SYS_CLOSE: u64 = 0x02;
+pub const SYS_WRITEC: u64 = 0x03;
+pub const SYS_WRITE0: u64 = 0x04;
+pub const SYS_WRITE: u64 = 0x05;
+pub const SYS_READ: u64 = 0x06;
+pub const SYS_CLOCK: u64 = 0x10;
+pub const SYS_EXIT: u64 = 0x18;
+pub const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+const ENTRY_NOP: u32 = 0x01f01013;
+const EBREAK: u32 = 0x00100073;
+const EXIT_NOP: u32 = 0x40705013;
+/// The `SYS_EXIT` reason for a normal exit, anything else exits with 1.
+pub const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+//Strings written with SYS_WRITE0 are cut off here if the guest forgot the terminator
+const MAX_STRING: u64 = 4096;
+//SYS_OPEN names longer than this are refused, as PATH_MAX would
+const MAX_NAME: u64 = 4096;
+//SYS_READ and SYS_WRITE move at most this much at once and report the rest as not transferred
+const MAX_TRANSFER: u64 = 1 << 20;
+
+pub struct Semihost {
+    files: FileTable,
+    started: Instant,
+}
+impl Semihost {
+    /// `SYS_OPEN` can only reach files under `sandbox`, the console is always available as `:tt`.
+    pub fn new(sandbox: Option<PathBuf>) -> Semihost {
+        Semihost {
+            files: FileTable::new(sandbox),
+            started: Instant::now(),
+        }
+    }
+    /// The open handles, use it to redirect the console.
+    pub fn files_mut(&mut self) -> &mut FileTable {
+        &mut self.files
+    }
+    /// Whether the core is stopped at the `ebreak` of a semihosting call.
+    pub fn is_call<Settings: CoreSettings>(core: &Core<Settings>) -> bool {
+        let pc = core.pc();
+        let word = |address: u64| core.read_physical(address, 4);
+        word(pc) == Some(EBREAK.into()) && pc >= 4
+            && word(pc - 4) == Some(ENTRY_NOP.into()) && pc.checked_add(4).and_then(word) == Some(EXIT_NOP.into())
+    }
+    /// Performs the call the core is stopped at and moves past the `ebreak`. Returns the exit
+    /// code if the program exited.
+    pub fn call<Settings: CoreSettings>(&mut self, core: &mut Core<Settings>) -> Option<i32> {
+        let argument = core.x(11);
+        let mut call = Call { core, argument };
+        let result = match call.core.x(10) {
+            SYS_OPEN => self.open(&mut call),
+            SYS_CLOSE => call.parameter(0).map(|handle| match handle {
+                //The console stays open for the rest of the program
+                0..=2 => 0,
+                _ => self.files.close(handle).map_or(-1, |()| 0),
+            }),
+            SYS_WRITEC => call.read(argument, 1).map(|c| self.console(&c)),
+            SYS_WRITE0 => call.string(argument).map(|s| self.console(&s)),
+            SYS_WRITE => self.transfer(&mut call, false),
+            SYS_READ => self.transfer(&mut call, true),
+            SYS_CLOCK => Some(self.started.elapsed().as_millis() as i64 / 10),
+            SYS_EXIT if Core::<Settings>::xlen() == 32 => return Some(exit_code(argument, 0)),
+            SYS_EXIT | SYS_EXIT_EXTENDED => match (call.parameter(0), call.parameter(1)) {
+                (Some(reason), Some(subcode)) => return Some(exit_code(reason, subcode)),
+                _ => None,
+            },
+            _ => None,
+        };
+        call.core.set_x(10, result.unwrap_or(-1) as u64);
+        let pc = call.core.pc();
+        call.core.set_pc(pc + 4);
+        None
+    }
+    /// Performs a semihosting call if the core is at one, otherwise steps it taking traps.
+    pub fn step<Settings: CoreSettings>(&mut self, core: &mut Core<Settings>) -> Option<i32> {
+        if Self::is_call(core) {
+            self.call(core)
+        } else {
+            core.step_with_traps();
+            None
+        }
+    }
+    /// Runs until the program exits, returning its exit code, or `None` after `max_steps`.
+    pub fn run<Settings: CoreSettings>(&mut self, core: &mut Core<Settings>, max_steps: u64) -> Option<i32> {
+        (0..max_steps).find_map(|_| self.step(core))
+    }
+    fn console(&mut self, bytes: &[u8]) -> i64 {
+        if let Ok(out) = self.files.get_mut(1) {
+            let _ = out.write(bytes);
+        }
+        0
+    }
+    //Parameters are the name, a mode from 0 to 11 for "r", "rb", "r+", "r+b", "w", ..., "a+b"
+    //and the name's length
+    fn open<Settings: CoreSettings>(&mut self, call: &mut Call<Settings>) -> Option<i64> {
+        let (name, mode, len) = (call.parameter(0)?, call.parameter(1)?, call.parameter(2)?);
+        if len > MAX_NAME {
+            return None;
+        }
+        let name = String::from_utf8_lossy(&call.read(name, len)?).into_owned();
+        if name == ":tt" {
+            return Some(match mode {
+                0..=3 => 0,
+                4..=7 => 1,
+                _ => 2,
+            });
+        }
+        let access = match mode % 4 {
+            0 | 1 => 0,
+            _ => files::O_RDWR,
+        };
+        let flags = match mode / 4 {
+            0 => access,
+            1 => access.max(files::O_WRONLY) | files::O_CREAT | files::O_TRUNC,
+            2 => access.max(files::O_WRONLY) | files::O_CREAT | files::O_APPEND,
+            _ => return None,
+        };
+        self.files.open(&name, flags).ok().map(|handle| handle as i64)
+    }
+    //SYS_READ and SYS_WRITE take a handle, buffer and length and return the bytes not transferred
+    fn transfer<Settings: CoreSettings>(&mut self, call: &mut Call<Settings>, read: bool) -> Option<i64> {
+        let (handle, buffer, len) = (call.parameter(0)?, call.parameter(1)?, call.parameter(2)?);
+        let file = self.files.get_mut(handle).ok()?;
+        let done = if read {
+            let mut bytes = vec![0; len.min(MAX_TRANSFER) as usize];
+            let done = file.read(&mut bytes).ok()?;
+            call.write(buffer, &bytes[..done])?;
+            done
+        } else {
+            file.write(&call.read(buffer, len.min(MAX_TRANSFER))?).ok()?
+        };
+        Some((len - done as u64) as i64)
+    }
+}
+fn exit_code(reason: u64, subcode: u64) -> i32 {
+    if reason == ADP_STOPPED_APPLICATION_EXIT { subcode as i32 } else { 1 }
+}
+//Guest memory as the call sees it, physical since semihosting programs run bare-metal
+struct Call<'a, Settings: CoreSettings> {
+    core: &'a mut Core<Settings>,
+    argument: u64,
+}
+impl<'a, Settings: CoreSettings> Call<'a, Settings> {
+    fn parameter(&self, index: u64) -> Option<u64> {
+        let size = Core::<Settings>::xlen() as u64 / 8;
+        let address = index.checked_mul(size).and_then(|offset| self.argument.checked_add(offset))?;
+        self.core.read_physical(address, size as usize)
+    }
+    fn read(&self, address: u64, len: u64) -> Option<Vec<u8>> {
+        (address..address.checked_add(len)?).map(|address| self.core.read_physical(address, 1).map(|b| b as u8)).collect()
+    }
+    fn write(&mut self, address: u64, bytes: &[u8]) -> Option<()> {
+        bytes.iter().zip(address..).try_for_each(|(&byte, address)| self.core.write_physical(address, 1, byte.into()))
+    }
+    fn string(&self, address: u64) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for offset in 0..MAX_STRING {
+            match self.core.read_physical(address.checked_add(offset)?, 1)? as u8 {
+                0 => break,
+                byte => bytes.push(byte),
+            }
+        }
+        Some(bytes)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risc_v_emu::core::tests::{core_with_program, Rv32, Rv64};
+    use crate::risc_v_emu::linux::files::Descriptor;
+    use crate::microvm::memory::address_space::AddressSpace;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    fn semihost() -> (Semihost, Output) {
+        let mut semihost = Semihost::new(None);
+        let output = Output::default();
+        semihost.files_mut().set(1, Descriptor::Stdout(Box::new(output.clone())));
+        (semihost, output)
+    }
+
+    #[test]
+    fn prints_and_exits() {
+        let program = [
+            0x00400513, //li a0, SYS_WRITE0
+            0x000015b7, //lui a1, 1
+            ENTRY_NOP,
+            EBREAK,
+            EXIT_NOP,
+            0x01800513, //li a0, SYS_EXIT
+            0x000205b7, //lui a1, 0x20
+            0x02658593, //addi a1, a1, 0x26
+            ENTRY_NOP,
+            EBREAK,
+            EXIT_NOP,
+        ];
+        let mut core = core_with_program::<Rv32>("i", &program);
+        core.space_mut().write_bytes(0x1000, b"ok\n\0").unwrap();
+        let (mut semihost, output) = semihost();
+        assert_eq!(semihost.run(&mut core, 100), Some(0));
+        assert_eq!(&output.0.borrow()[..], b"ok\n");
+    }
+    #[test]
+    fn exit_reasons() {
+        let mut core = core_with_program::<Rv32>("i", &[ENTRY_NOP, EBREAK, EXIT_NOP]);
+        core.set_pc(4);
+        core.set_x(10, SYS_EXIT);
+        core.set_x(11, 0x20023);
+        assert_eq!(Semihost::new(None).call(&mut core), Some(1));
+        //RV64 passes the reason and the exit code in a parameter block
+        let mut core = core_with_program::<Rv64>("i", &[ENTRY_NOP, EBREAK, EXIT_NOP]);
+        core.space_mut().write_bytes(0x100, &[0x26, 0, 2, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        core.set_pc(4);
+        core.set_x(10, SYS_EXIT);
+        core.set_x(11, 0x100);
+        assert!(Semihost::is_call(&core));
+        assert_eq!(Semihost::new(None).step(&mut core), Some(3));
+    }
+    #[test]
+    fn addresses_past_the_end() {
+        let mut core = core_with_program::<Rv64>("i", &[ENTRY_NOP, EBREAK, EXIT_NOP]);
+        let mut call = |core: &mut Core<Rv64>, operation: u64, argument: u64| {
+            core.set_pc(4);
+            core.set_x(10, operation);
+            core.set_x(11, argument);
+            assert_eq!(Semihost::new(None).call(core), None);
+            core.x_signed(10)
+        };
+        assert_eq!(call(&mut core, SYS_WRITE0, u64::MAX), -1);
+        //A buffer that wraps around the end of the address space
+        let block: Vec<u8> = [1, u64::MAX - 1, 4].iter().flat_map(|p: &u64| p.to_le_bytes().to_vec()).collect();
+        core.space_mut().write_bytes(0x100, &block).unwrap();
+        assert_eq!(call(&mut core, SYS_WRITE, 0x100), -1);
+    }
+    #[test]
+    fn files_and_unknown_calls() {
+        let root = std::env::temp_dir().join(format!("micro16-semihost-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut core = core_with_program::<Rv32>("i", &[ENTRY_NOP, EBREAK, EXIT_NOP]);
+        let mut semihost = Semihost::new(Some(root.clone()));
+        let mut call = |core: &mut Core<Rv32>, operation: u64, parameters: &[u32]| {
+            let block: Vec<u8> = parameters.iter().flat_map(|p| p.to_le_bytes().to_vec()).collect();
+            core.space_mut().write_bytes(0x100, &block).unwrap();
+            core.set_pc(4);
+            core.set_x(10, operation);
+            core.set_x(11, 0x100);
+            assert_eq!(semihost.call(core), None);
+            assert_eq!(core.pc(), 8);
+            core.x_signed(10) as i32
+        };
+        core.space_mut().write_bytes(0x200, b"out.txt").unwrap();
+        core.space_mut().write_bytes(0x300, b"written").unwrap();
+        let handle = call(&mut core, SYS_OPEN, &[0x200, 4, 7]) as u32;
+        assert_eq!(handle, 3);
+        assert_eq!(call(&mut core, SYS_WRITE, &[handle, 0x300, 7]), 0);
+        assert_eq!(call(&mut core, SYS_CLOSE, &[handle]), 0);
+        assert_eq!(call(&mut core, SYS_CLOSE, &[handle]), -1);
+        let handle = call(&mut core, SYS_OPEN, &[0x200, 0, 7]) as u32;
+        assert_eq!(call(&mut core, SYS_READ, &[handle, 0x400, 10]), 3, "3 of the 10 bytes weren't read");
+        assert_eq!(core.space().read_byte(0x406), Ok(b'n'));
+        assert_eq!(call(&mut core, SYS_OPEN, &[0x300, 0, 7]), -1);
+        assert_eq!(call(&mut core, SYS_OPEN, &[0x200, 0, 4097]), -1, "the name is too long");
+        assert_eq!(call(&mut core, 0x99, &[]), -1);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"written");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
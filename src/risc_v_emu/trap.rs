@@ -0,0 +1,314 @@
+//! Trap entry, delegation and the trap return instructions.
+use super::core::{Core, CoreSettings};
+use super::csr::accessibility::PrivilegeLevel;
+use super::csr::{MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPP_SHIFT, MSTATUS_MPRV};
+use super::csr::{MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_TSR, MSTATUS_TVM};
+use super::exception::{Interrupt, Trap};
+use crate::microvm::device::InterruptLines;
+
+impl<Settings: CoreSettings> Core<Settings> {
+    /// Raises or lowers an interrupt line, reflected in `mip`.
+    pub fn set_interrupt_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        if pending {
+            self.context.csr.mip |= interrupt.mask();
+        } else {
+            self.context.csr.mip &= !interrupt.mask();
+        }
+    }
+    /// Connects device interrupt lines, sampled before each step with traps. Any raised line is
+    /// a pending machine external interrupt.
+    pub fn connect_interrupts(&mut self, lines: InterruptLines) {
+        self.interrupts = Some(lines);
+    }
+    /// The highest priority interrupt that is both pending and enabled. Interrupts for a more
+    /// privileged mode are always enabled, interrupts for the current mode are gated by
+    /// `mstatus.MIE` or `mstatus.SIE` and delegated interrupts are never taken in M-mode.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let csr = &self.context.csr;
+        let privilege = csr.privilege();
+        let machine_enabled = privilege < PrivilegeLevel::Machine || csr.mstatus & MSTATUS_MIE != 0;
+        let supervisor_enabled = privilege < PrivilegeLevel::Supervisor ||
+            (privilege == PrivilegeLevel::Supervisor && csr.mstatus & MSTATUS_SIE != 0);
+        let pending = csr.mip & csr.mie;
+        Interrupt::PRIORITY.iter().copied().find(|interrupt| {
+            let enabled = if csr.mideleg & interrupt.mask() != 0 { supervisor_enabled } else { machine_enabled };
+            enabled && pending & interrupt.mask() != 0
+        })
+    }
+    //Vectored mode only offsets interrupts, exceptions always go to the base
+    fn trap_vector(tvec: u64, trap: Trap) -> u64 {
+        let base = tvec & !0b11;
+        if tvec & 0b11 == 1 && trap.is_interrupt() {
+            base.wrapping_add(4 * trap.code())
+        } else {
+            base
+        }
+    }
+    /// Enters the trap handler with the current PC as the exception PC. Traps from S-mode or
+    /// U-mode go to S-mode when they are delegated through `medeleg` or `mideleg`.
+    pub fn take_trap(&mut self, trap: Trap) {
+        let pc = self.pc();
+        let xlen = Self::xlen();
+        let csr = &mut self.context.csr;
+        let cause = trap.code() | ((trap.is_interrupt() as u64) << (xlen - 1));
+        let delegation = if trap.is_interrupt() { csr.mideleg } else { csr.medeleg };
+        let target = if csr.privilege() <= PrivilegeLevel::Supervisor && delegation & (1 << trap.code()) != 0 {
+            csr.sepc = pc;
+            csr.scause = cause;
+            csr.stval = trap.value();
+            let spie = if csr.mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if csr.privilege() == PrivilegeLevel::Supervisor { MSTATUS_SPP } else { 0 };
+            csr.mstatus = (csr.mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp;
+            csr.set_privilege(PrivilegeLevel::Supervisor);
+            Self::trap_vector(csr.stvec, trap)
+        } else {
+            csr.mepc = pc;
+            csr.mcause = cause;
+            csr.mtval = trap.value();
+            let mpie = if csr.mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            let mpp = (csr.privilege() as u64) << MSTATUS_MPP_SHIFT;
+            csr.mstatus = (csr.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+            csr.set_privilege(PrivilegeLevel::Machine);
+            Self::trap_vector(csr.mtvec, trap)
+        };
+        self.set_pc(target);
+    }
+    fn lowest_privilege(&self) -> PrivilegeLevel {
+        if self.supports_privilege(PrivilegeLevel::User) {
+            PrivilegeLevel::User
+        } else {
+            PrivilegeLevel::Machine
+        }
+    }
+    //MRET, returns the new PC or `None` when not executed in M-mode
+    pub(crate) fn mret(&mut self) -> Option<u64> {
+        if self.context.csr.privilege() != PrivilegeLevel::Machine {
+            return None;
+        }
+        let lowest = self.lowest_privilege();
+        let csr = &mut self.context.csr;
+        let mpp = PrivilegeLevel::from_bits((csr.mstatus >> MSTATUS_MPP_SHIFT) as u8);
+        let mie = if csr.mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        csr.mstatus = (csr.mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | mie | MSTATUS_MPIE |
+            ((lowest as u64) << MSTATUS_MPP_SHIFT);
+        if mpp != PrivilegeLevel::Machine {
+            csr.mstatus &= !MSTATUS_MPRV;
+        }
+        csr.set_privilege(mpp);
+        Some(csr.mepc)
+    }
+    //SRET, `None` from U-mode or from S-mode with mstatus.TSR set
+    pub(crate) fn sret(&mut self) -> Option<u64> {
+        let csr = &mut self.context.csr;
+        match csr.privilege() {
+            PrivilegeLevel::Machine => (),
+            PrivilegeLevel::Supervisor if csr.mstatus & MSTATUS_TSR == 0 => (),
+            _ => return None,
+        }
+        let spp = if csr.mstatus & MSTATUS_SPP != 0 { PrivilegeLevel::Supervisor } else { PrivilegeLevel::User };
+        let sie = if csr.mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+        csr.mstatus = (csr.mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | sie | MSTATUS_SPIE;
+        csr.set_privilege(spp);
+        Some(csr.sepc)
+    }
+    //SFENCE.VMA, `None` from U-mode or from S-mode with mstatus.TVM set
+    pub(crate) fn sfence_vma(&mut self, rs1: usize, rs2: usize) -> Option<()> {
+        let csr = &self.context.csr;
+        match csr.privilege() {
+            PrivilegeLevel::Machine => (),
+            PrivilegeLevel::Supervisor if csr.mstatus & MSTATUS_TVM == 0 => (),
+            _ => return None,
+        }
+        let address = if rs1 != 0 { Some(self.x(rs1)) } else { None };
+        let asid = if rs2 != 0 { Some(self.x(rs2)) } else { None };
+        self.mmu_mut().flush(address, asid);
+        Some(())
+    }
+    /// Takes a pending interrupt or executes one instruction, entering the trap handler if it
+    /// raises an exception. Returns the trap that was taken, if any.
+    pub fn step_with_traps(&mut self) -> Option<Trap> {
+        if let Some(pending) = self.interrupts.as_ref().map(InterruptLines::pending) {
+            self.set_interrupt_pending(Interrupt::MachineExternal, pending != 0);
+        }
+        let trap = match self.pending_interrupt() {
+            Some(interrupt) => Trap::Interrupt(interrupt),
+            None => Trap::Exception(self.step().err()?),
+        };
+        self.take_trap(trap);
+        Some(trap)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risc_v_emu::core::tests::{allow_all_pmp, core_with_program, Rv32, Rv64};
+    use crate::risc_v_emu::csr::{accessibility::CsrAddress, MEDELEG, MSTATUS, SATP, STVEC};
+    use crate::risc_v_emu::exception::Exception;
+    use crate::microvm::device::{Timer, TIMER_ENABLE, TIMER_MATCHED};
+    use crate::microvm::memory::address_space::{AddressSpace, DenseStaticMemory};
+    use crate::microvm::memory::sparse::SparseAddressSpace;
+    use crate::risc_v_emu::extensions::Extensions;
+
+    const NOP: u32 = 0x00000013;
+
+    #[test]
+    fn direct_exception_handler() {
+        let program = [
+            0x02000293, //li t0, 0x20
+            0x30529073, //csrw mtvec, t0
+            0xffffffff, //illegal
+            0x02a00513, //li a0, 42
+            0x00100073, //ebreak
+            NOP, NOP, NOP,
+            0x34102373, //csrr t1, mepc
+            0x00430313, //addi t1, t1, 4
+            0x34131073, //csrw mepc, t1
+            0x342025f3, //csrr a1, mcause
+            0x34302673, //csrr a2, mtval
+            0x30200073, //mret
+        ];
+        let mut core = core_with_program::<Rv32>("i", &program);
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!(core.step_with_traps(), Some(Trap::Exception(Exception::IllegalInstruction(0xffffffff))));
+        assert_eq!(core.pc(), 0x20);
+        for _ in 0..7 {
+            assert_eq!(core.step_with_traps(), None);
+        }
+        assert_eq!((core.x(10), core.x(11), core.x(12)), (42, 2, 0xffffffff));
+        assert_eq!(core.step_with_traps(), Some(Trap::Exception(Exception::Breakpoint(0x10))));
+        //Without U-mode, MPP can only hold M
+        core.write_csr(CsrAddress::new(MSTATUS), 0).unwrap();
+        assert_eq!(core.read_csr(CsrAddress::new(MSTATUS)), Some(0b11 << MSTATUS_MPP_SHIFT));
+    }
+    #[test]
+    fn user_mode_ecall_and_vectored_interrupt() {
+        let program = [
+            0x04100293, //li t0, 0x41
+            0x30529073, //csrw mtvec, t0
+            0x08000293, //li t0, 0x80
+            0x30429073, //csrw mie, t0
+            0x30001073, //csrw mstatus, zero
+            0x03000293, //li t0, 0x30
+            0x34129073, //csrw mepc, t0
+            0x30200073, //mret
+            NOP, NOP, NOP, NOP,
+            0x00000073, //ecall
+            0x00150513, //addi a0, a0, 1
+            0xffdff06f, //j -4
+            NOP,
+            0x34102373, //csrr t1, mepc
+            0x00430313, //addi t1, t1, 4
+            0x34131073, //csrw mepc, t1
+            0x30200073, //mret
+            NOP, NOP, NOP,
+            0x00700593, //li a1, 7
+            0x10500073, //wfi
+        ];
+        let mut core = core_with_program::<Rv64>("iu", &program);
+        allow_all_pmp(&mut core);
+        for _ in 0..8 {
+            assert_eq!(core.step_with_traps(), None);
+        }
+        assert_eq!((core.pc(), core.context().csr.privilege()), (0x30, PrivilegeLevel::User));
+        assert_eq!(core.step_with_traps(), Some(Trap::Exception(Exception::EnvironmentCall(PrivilegeLevel::User))));
+        assert_eq!((core.pc(), core.context().csr.mcause), (0x40, 8));
+        assert_eq!(core.context().csr.mstatus & MSTATUS_MPP, 0);
+        for _ in 0..5 {
+            assert_eq!(core.step_with_traps(), None);
+        }
+        assert_eq!((core.x(10), core.context().csr.privilege()), (1, PrivilegeLevel::User));
+
+        core.set_interrupt_pending(Interrupt::MachineTimer, true);
+        assert_eq!(core.step_with_traps(), Some(Trap::Interrupt(Interrupt::MachineTimer)));
+        assert_eq!(core.pc(), 0x40 + 4 * 7);
+        assert_eq!(core.context().csr.mcause, (1 << 63) | 7);
+        assert_eq!(core.context().csr.mepc, 0x38);
+        //mstatus.MIE was cleared on entry so the still pending timer is masked in M-mode
+        assert_eq!(core.pending_interrupt(), None);
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!(core.x(11), 7);
+    }
+    #[test]
+    fn device_interrupt() {
+        let program = [
+            0x04000293, //li t0, 0x40
+            0x30529073, //csrw mtvec, t0
+            0x000012b7, //lui t0, 1
+            0x80028293, //addi t0, t0, -2048
+            0x30429073, //csrw mie, t0
+            0x30046073, //csrsi mstatus, 8
+            0x00150513, //addi a0, a0, 1
+            0xffdff06f, //j -4
+            NOP, NOP, NOP, NOP, NOP, NOP, NOP, NOP,
+            0x00002337, //lui t1, 2
+            0x00c34583, //lbu a1, 12(t1)
+            0x30200073, //mret
+        ];
+        let lines = InterruptLines::new();
+        let mut space = SparseAddressSpace::new(0x3000u16).unwrap();
+        space.add_space(0, Box::new(DenseStaticMemory::new(0x2000u16))).unwrap();
+        space.add_device(0x2000, Box::new(Timer::new(lines.line(0)))).unwrap();
+        let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        space.write_bytes(0, &bytes).unwrap();
+        space.write_bytes(0x2004, &5u32.to_le_bytes()).unwrap();
+        space.write_byte(0x2008, TIMER_ENABLE).unwrap();
+        let mut core = Core::<Rv32>::new(space, Extensions::from_letters("i").unwrap());
+        core.connect_interrupts(lines.clone());
+        for _ in 0..6 {
+            assert_eq!(core.step_with_traps(), None);
+        }
+        //The timer matched on the fifth cycle, but interrupts were only enabled by the sixth
+        assert_eq!(core.step_with_traps(), Some(Trap::Interrupt(Interrupt::MachineExternal)));
+        assert_eq!((core.pc(), core.context().csr.mcause), (0x40, (1 << 31) | 11));
+        for _ in 0..3 {
+            assert_eq!(core.step_with_traps(), None);
+        }
+        assert_eq!((core.x(11), lines.pending()), (TIMER_MATCHED as u64, 0));
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!((core.pc(), core.x(10)), (0x1c, 1));
+    }
+    #[test]
+    fn supervisor_paging_and_delegated_page_fault() {
+        let program = [
+            0x400005b7, //lui a1, 0x40000
+            0x10b5b023, //sd a1, 0x100(a1)
+            0x1005b603, //ld a2, 0x100(a1)
+            0x00003683, //ld a3, 0(zero)
+            NOP, NOP, NOP, NOP, NOP, NOP, NOP, NOP, NOP, NOP, NOP, NOP,
+            0x14202773, //csrr a4, scause
+            0x10200073, //sret
+        ];
+        let mut core = core_with_program::<Rv64>("isu", &program);
+        //Sv39 root table at 0x1000 with a 1GiB RWX gigapage mapping 0x4000_0000 onto 0
+        core.store(0x1008, 8, 0xF).unwrap();
+        allow_all_pmp(&mut core);
+        core.write_csr(CsrAddress::new(SATP), (8 << 60) | 1).unwrap();
+        core.write_csr(CsrAddress::new(MEDELEG), 1 << 13).unwrap();
+        core.write_csr(CsrAddress::new(STVEC), 0x4000_0040).unwrap();
+        core.context_mut().csr.set_privilege(PrivilegeLevel::Supervisor);
+        core.set_pc(0x4000_0000);
+        for _ in 0..3 {
+            assert_eq!(core.step_with_traps(), None);
+        }
+        assert_eq!(core.x(12), 0x4000_0000);
+        assert_eq!(core.space().read_byte(0x100).unwrap(), 0);
+        assert_eq!(core.space().read_byte(0x103).unwrap(), 0x40);
+        //Accessed and dirty were set by the walk
+        assert_eq!(core.space().read_byte(0x1008).unwrap(), 0xCF);
+
+        assert_eq!(core.step_with_traps(), Some(Trap::Exception(Exception::LoadPageFault(0))));
+        assert_eq!((core.pc(), core.context().csr.privilege()), (0x4000_0040, PrivilegeLevel::Supervisor));
+        assert_eq!((core.context().csr.sepc, core.context().csr.stval), (0x4000_000C, 0));
+        assert_eq!(core.context().csr.mepc, 0, "delegated traps leave the M-mode registers alone");
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!(core.x(14), 13);
+        assert_eq!(core.step_with_traps(), None);
+        assert_eq!((core.pc(), core.context().csr.privilege()), (0x4000_000C, PrivilegeLevel::Supervisor));
+
+        core.context_mut().csr.mstatus |= MSTATUS_TSR;
+        core.set_pc(0x4000_0044);
+        assert_eq!(core.step(), Err(Exception::IllegalInstruction(0x10200073)));
+    }
+}
@@ -9,12 +9,21 @@ pub trait DataType: Sized + Copy {
     fn unsigned(self) -> Self::Unsigned;
     fn store_signed(&mut self, i: Self::Signed);
     fn store_unsigned(&mut self, i: Self::Unsigned);
+    fn bit_len() -> u32 {
+        (std::mem::size_of::<Self>() * 8) as u32
+    }
+    /// Truncates `value` to the width of the type.
+    fn from_u64(value: u64) -> Self;
+    /// Zero extends the value to 64 bits.
+    fn to_u64(self) -> u64;
+    /// Sign extends the value to 64 bits.
+    fn to_i64(self) -> i64;
 }
 
 macro_rules! data_type_impl {
     ($(($name:ident, $unsigned:ty, $signed:ty)),*) => ($(
-        #[derive(Copy, Clone)]
-        pub struct $name($unsigned);
+        #[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+        pub struct $name(pub $unsigned);
         impl DataType for $name {
             type Signed = $signed;
             type Unsigned = $unsigned;
@@ -34,6 +43,15 @@ macro_rules! data_type_impl {
             fn store_unsigned(&mut self, i: Self::Unsigned) {
                 self.0 = i;
             }
+            fn from_u64(value: u64) -> Self {
+                $name(value as $unsigned)
+            }
+            fn to_u64(self) -> u64 {
+                self.0 as u64
+            }
+            fn to_i64(self) -> i64 {
+                self.signed() as i64
+            }
         }
     )*)
 }
@@ -0,0 +1,174 @@
+//! Runs the `micro16` binary on small raw programs and the riscv-tests executables.
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const ENTRY_NOP: u32 = 0x01f01013;
+const EBREAK: u32 = 0x00100073;
+const EXIT_NOP: u32 = 0x40705013;
+
+fn micro16(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_micro16")).args(args).output().unwrap()
+}
+fn raw(name: &str, program: &[u32], data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("micro16-cli-{}-{}.bin", std::process::id(), name));
+    let mut bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+    bytes.resize(0x100, 0);
+    bytes.extend_from_slice(data);
+    fs::write(&path, bytes).unwrap();
+    path
+}
+
+#[test]
+fn exits_with_the_guest_code() {
+    let program = [
+        0x00400513, //li a0, SYS_WRITE0
+        0x10000593, //li a1, 0x100
+        ENTRY_NOP,
+        EBREAK,
+        EXIT_NOP,
+        0x01800513, //li a0, SYS_EXIT
+        0x000205b7, //lui a1, 0x20
+        0x02358593, //addi a1, a1, 0x23, a reason other than ApplicationExit
+        ENTRY_NOP,
+        EBREAK,
+        EXIT_NOP,
+    ];
+    let path = raw("exit", &program, b"hello\n\0");
+    let output = micro16(&["--arch", "rv32i", path.to_str().unwrap()]);
+    fs::remove_file(path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stdout, b"hello\n");
+    let test = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/riscv-tests/bin/rv64um-p-mul");
+    assert_eq!(micro16(&["--arch", "rv64im", test]).status.code(), Some(0));
+}
+#[test]
+fn faults_and_limits() {
+    //An all zero parcel is an illegal instruction
+    let path = raw("fault", &[0x00000013, 0], &[]);
+    let output = micro16(&["--arch", "rv64gc", &format!("{}@0x200", path.display()), "--ram", "0:4k"]);
+    assert_eq!(output.status.code(), Some(126));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("fault: IllegalInstruction(0) at pc 0x204"), "{}", stderr);
+    assert!(stderr.contains("  pc 0000000000000204"), "{}", stderr);
+    fs::remove_file(path).unwrap();
+
+    let path = raw("loop", &[0x0000006f], &[]);
+    let output = micro16(&["--arch", "rv32i", "--max-cycles", "10", "--trace", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(124));
+    assert_eq!(String::from_utf8_lossy(&output.stderr).matches("0x00000000  0000006f").count(), 10);
+    fs::remove_file(path).unwrap();
+
+    assert_eq!(micro16(&["--arch", "rv32i", "--ram", "0"]).status.code(), Some(125));
+}
+#[test]
+fn builds_memory_maps() {
+    let path = std::env::temp_dir().join(format!("micro16-cli-{}-map.toml", std::process::id()));
+    let region = |name: &str, base: u64| format!("[[region]]\nname = \"{}\"\nkind = \"ram\"\nbase = {:#x}\nsize = 0x1000\n", name, base);
+    fs::write(&path, region("ram", 0) + &region("stack", 0x800)).unwrap();
+    let output = micro16(&["--arch", "rv32i", "--map", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(125));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("regions `ram` and `stack` overlap"));
+
+    //SYS_EXIT with ADP_Stopped_ApplicationExit
+    let program = raw("map", &[0x01800513, 0x000205b7, 0x02658593, ENTRY_NOP, EBREAK, EXIT_NOP], &[]);
+    fs::write(&path, region("ram", 0)).unwrap();
+    let output = micro16(&["--arch", "rv32i", "--map", path.to_str().unwrap(), program.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    fs::remove_file(program).unwrap();
+    fs::remove_file(path).unwrap();
+}
+#[test]
+fn reports_uninitialized_reads() {
+    let program = [
+        0x40002283, //lw t0, 0x400(zero)
+        0x40502223, //sw t0, 0x404(zero)
+        0x40402283, //lw t0, 0x404(zero)
+        0x01800513,
+        0x000205b7,
+        0x02658593,
+        ENTRY_NOP,
+        EBREAK,
+        EXIT_NOP,
+    ];
+    let path = raw("strict", &program, &[]);
+    let output = micro16(&["--arch", "rv32i", "--strict", "--fill", "ff", path.to_str().unwrap()]);
+    fs::remove_file(path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "uninitialized read of 0x400 (4 bytes) at pc 0x0\n");
+}
+#[test]
+fn runs_6502_programs() {
+    let mut program = vec![
+        0xAD, 0x00, 0x03, //LDA $0300, never written
+        0xA2, 0x00, //LDX #0
+        0xBD, 0x20, 0x06, //LDA $0620,X
+        0xF0, 0x06, //BEQ $0610
+        0x8D, 0x00, 0xF0, //STA $F000
+        0xE8, //INX
+        0xD0, 0xF5, //BNE $0605
+        0xA9, 0x03, //LDA #3
+        0x8D, 0x01, 0xF0, //STA $F001
+    ];
+    program.resize(0x20, 0);
+    program.extend_from_slice(b"hi\n\0");
+    let path = std::env::temp_dir().join(format!("micro16-cli-{}-6502.bin", std::process::id()));
+    fs::write(&path, program).unwrap();
+    let output = micro16(&["--arch", "6502", "--trap", "0xf000", "--strict", &format!("{}@0x600", path.display())]);
+    fs::remove_file(path).unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"hi\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "uninitialized read of 0x300 (1 bytes) at pc 0x600\n");
+
+    for trap in ["0xffff", "0x10000"] {
+        let output = micro16(&["--arch", "6502", "--trap", trap, "--max-instructions", "1"]);
+        assert_eq!(output.status.code(), Some(125));
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "micro16: --trap has to be below 0xffff\n");
+    }
+    assert_eq!(micro16(&["--arch", "6502", "--trap", "0xfffe", "--max-instructions", "1"]).status.code(), Some(124));
+
+    //LDA #5; BRA +0; STA $F001, where BRA only exists on the 65C02
+    let path = std::env::temp_dir().join(format!("micro16-cli-{}-65c02.bin", std::process::id()));
+    fs::write(&path, [0xA9, 0x05, 0x80, 0x00, 0x8D, 0x01, 0xF0]).unwrap();
+    let load = format!("{}@0x600", path.display());
+    assert_eq!(micro16(&["--arch", "65c02", "--trap", "0xf000", &load]).status.code(), Some(5));
+    let output = micro16(&["--arch", "6502", "--trap", "0xf000", &load]);
+    fs::remove_file(path).unwrap();
+    assert_eq!(output.status.code(), Some(126));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("fault: UnrecognizedInstruction(128) at pc 0x602"));
+
+    //The timer's IRQ ends a JMP loop once CLI lets it in, with the handler at $0630 exiting 7
+    let mut program = vec![
+        0xA9, 0x30, 0x8D, 0xFE, 0xFF, //LDA #$30; STA $FFFE
+        0xA9, 0x06, 0x8D, 0xFF, 0xFF, //LDA #$06; STA $FFFF
+        0xA9, 0x0A, 0x8D, 0x04, 0xB0, //LDA #10; STA $B004, the compare value
+        0xA9, 0x00, 0x8D, 0x05, 0xB0, 0x8D, 0x06, 0xB0, 0x8D, 0x07, 0xB0, //LDA #0; STA $B005; STA $B006; STA $B007
+        0xA9, 0x01, 0x8D, 0x08, 0xB0, //LDA #1; STA $B008, enable
+        0x58, //CLI
+        0x4C, 0x20, 0x06, //JMP $0620
+    ];
+    program.resize(0x30, 0);
+    program.extend_from_slice(&[0xA9, 0x07, 0x8D, 0x01, 0xF0]); //LDA #7; STA $F001
+    let path = std::env::temp_dir().join(format!("micro16-cli-{}-irq.bin", std::process::id()));
+    fs::write(&path, program).unwrap();
+    let map = path.with_extension("toml");
+    let region = |name: &str, kind: &str, base: u64, size: u64| {
+        format!("[[region]]\nname = \"{}\"\n{}\nbase = {:#x}\nsize = {:#x}\n", name, kind, base, size)
+    };
+    fs::write(&map, region("ram", "kind = \"ram\"", 0, 0x8000) + &region("timer", "kind = \"device\"\ndevice = \"timer\"", 0xB000, 0x10)
+        + &region("trap", "kind = \"device\"\ndevice = \"trap\"", 0xF000, 2) + &region("vectors", "kind = \"ram\"", 0xFF00, 0x100)).unwrap();
+    let output = micro16(&["--arch", "6502", "--map", map.to_str().unwrap(), "--max-instructions", "1000", &format!("{}@0x600", path.display())]);
+    fs::remove_file(path).unwrap();
+    fs::remove_file(map).unwrap();
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+
+    //JMP $0600 takes three cycles, so the limit lands after the fourth
+    let path = std::env::temp_dir().join(format!("micro16-cli-{}-cycles.bin", std::process::id()));
+    fs::write(&path, [0x4C, 0x00, 0x06]).unwrap();
+    let output = micro16(&["--arch", "6502", "--max-cycles", "10", "--trace", &format!("{}@0x600", path.display())]);
+    fs::remove_file(path).unwrap();
+    assert_eq!(output.status.code(), Some(124));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("JMP $0600").count(), 4);
+    assert!(stderr.ends_with("stopped after 12 cycles\n"), "{}", stderr);
+}
@@ -0,0 +1,315 @@
+//! Random region layouts and accesses through `SparseAddressSpace` and every kind of region,
+//! checked against a flat model of the mapped bytes. No access may panic.
+use micro16::microvm::bits::Endianness;
+use micro16::microvm::image::Image;
+use micro16::microvm::memory::address::AddressType;
+use micro16::microvm::memory::address_space::{AddressSpace, DenseStaticMemory, Fill};
+use micro16::microvm::memory::bank::{BankSelect, BankedSpace};
+use micro16::microvm::memory::mirror::{Mirror, SharedSpace};
+use micro16::microvm::memory::overlay::Overlay;
+use micro16::microvm::memory::permissions::Access;
+use micro16::microvm::memory::profile::Profiled;
+use micro16::microvm::memory::rom::ROM;
+use micro16::microvm::memory::sparse::SparseAddressSpace;
+use micro16::microvm::memory::strict::{StrictRam, UninitializedReads};
+use micro16::microvm::memory::MemoryError;
+use micro16::microvm::mmu::AccessType;
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Read(u16, usize),
+    ReadMut(u16, usize),
+    ReadChecked(u16, usize),
+    Write(u16, Vec<u8>),
+    ReadU32(u16, Endianness),
+    WriteU16(u16, u16, Endianness),
+    //Switches every banked region to this bank, wrapped to the banks it has
+    Select(usize),
+}
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Ram,
+    Rom,
+    //The period, wrapped to the region's size
+    Mirror(u16),
+    Banked,
+    Overlay,
+    Profiled,
+    Strict,
+}
+//A mapped byte, held at `index` in the model's store
+#[derive(Clone, Copy)]
+struct Cell {
+    index: usize,
+    writable: bool,
+}
+struct Bank {
+    first: usize,
+    size: usize,
+    store: usize,
+    select: BankSelect,
+}
+//Every byte of the space, `None` where nothing is mapped, with the stores behind them
+struct Model {
+    cells: Vec<Option<Cell>>,
+    store: Vec<u8>,
+    banks: Vec<Bank>,
+}
+impl Model {
+    //Maps `len` bytes from `first` to new bytes in the store
+    fn map(&mut self, first: usize, bytes: &[u8], writable: bool) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.cells[first + i] = Some(Cell { index: self.store.len(), writable });
+            self.store.push(byte);
+        }
+    }
+    //The bytes of a `len` byte access, or the first address that isn't mapped
+    fn bytes(&self, address: u16, len: usize) -> Result<Vec<u8>, u64> {
+        (address as u64..address as u64 + len as u64)
+            .map(|address| self.cells.get(address as usize).cloned().flatten().map(|cell| self.store[cell.index]).ok_or(address))
+            .collect()
+    }
+    //Regions take their part of a write in order, so a write reaching read-only bytes keeps
+    //what went before them
+    fn write(&mut self, address: u16, bytes: &[u8]) -> Result<(), MemoryError> {
+        self.bytes(address, bytes.len()).map_err(|_| MemoryError::InvalidAccess)?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let cell = self.cells[address as usize + i].unwrap();
+            if !cell.writable {
+                return Err(MemoryError::ReadOnly);
+            }
+            self.store[cell.index] = byte;
+        }
+        Ok(())
+    }
+    fn select(&mut self, bank: usize) {
+        for window in self.banks.iter() {
+            let bank = bank % window.select.banks();
+            window.select.select(bank).unwrap();
+            for i in 0..window.size {
+                self.cells[window.first + i] = Some(Cell { index: window.store + bank * window.size + i, writable: true });
+            }
+        }
+    }
+}
+
+fn endianness() -> impl Strategy<Value = Endianness> {
+    prop_oneof![Just(Endianness::Little), Just(Endianness::Big)]
+}
+//Mostly addresses near the regions, some anywhere
+fn address() -> impl Strategy<Value = u16> {
+    prop_oneof![3 => 0..0x2400u16, 1 => any::<u16>(), 1 => 0xFFF0..=0xFFFFu16]
+}
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (address(), 0..0x20usize).prop_map(|(address, len)| Op::Read(address, len)),
+        (address(), 0..0x20usize).prop_map(|(address, len)| Op::ReadMut(address, len)),
+        (address(), 0..0x20usize).prop_map(|(address, len)| Op::ReadChecked(address, len)),
+        (address(), prop::collection::vec(any::<u8>(), 0..0x20)).prop_map(|(address, bytes)| Op::Write(address, bytes)),
+        (address(), endianness()).prop_map(|(address, endianness)| Op::ReadU32(address, endianness)),
+        (address(), any::<u16>(), endianness()).prop_map(|(address, value, endianness)| Op::WriteU16(address, value, endianness)),
+        (0..4usize).prop_map(Op::Select),
+    ]
+}
+fn kind() -> impl Strategy<Value = Kind> {
+    prop_oneof![
+        Just(Kind::Ram),
+        Just(Kind::Rom),
+        any::<u16>().prop_map(Kind::Mirror),
+        Just(Kind::Banked),
+        Just(Kind::Overlay),
+        Just(Kind::Profiled),
+        Just(Kind::Strict),
+    ]
+}
+//Bytes that differ from address to address, so reading the wrong one shows
+fn pattern(seed: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (seed + i * 7) as u8).collect()
+}
+//A `size` byte region of the kind at `base`, which is `offset + first` in the space
+fn region<Address: AddressType + 'static>(kind: Kind, size: u16, base: u64, first: usize, model: &mut Model) -> Box<dyn AddressSpace<Address>> {
+    let len = size as usize;
+    match kind {
+        Kind::Ram => {
+            model.map(first, &vec![0; len], true);
+            Box::new(DenseStaticMemory::new(size))
+        },
+        Kind::Rom => {
+            let bytes = pattern(first, len);
+            model.map(first, &bytes, false);
+            Box::new(ROM::from_image(&Image::from_raw(0, &bytes), 0, size).unwrap())
+        },
+        Kind::Mirror(period) => {
+            let period = period % size + 1;
+            let target = SharedSpace::new(Box::new(DenseStaticMemory::new(period)));
+            let store = model.store.len();
+            model.store.extend(vec![0; period as usize]);
+            for i in 0..len {
+                model.cells[first + i] = Some(Cell { index: store + i % period as usize, writable: true });
+            }
+            Box::new(Mirror::new(&target, size as u64, period as u64).unwrap())
+        },
+        Kind::Banked => {
+            //Three banks, showing the first
+            let bytes = pattern(first, len * 3);
+            let mut memory = DenseStaticMemory::new(size as u64 * 3);
+            memory.as_mut_slice().copy_from_slice(&bytes);
+            let store = model.store.len();
+            model.store.extend(bytes);
+            let window = BankedSpace::new(&SharedSpace::new(Box::new(memory)), size as u64).unwrap();
+            model.banks.push(Bank { first, size: len, store, select: window.select() });
+            for i in 0..len {
+                model.cells[first + i] = Some(Cell { index: store + i, writable: true });
+            }
+            Box::new(window)
+        },
+        Kind::Overlay => {
+            let bytes = pattern(first, len);
+            model.map(first, &bytes, true);
+            Box::new(Overlay::new(Box::new(ROM::from_image(&Image::from_raw(0, &bytes), 0, size).unwrap())))
+        },
+        Kind::Profiled => {
+            model.map(first, &vec![0; len], true);
+            Box::new(Profiled::new(DenseStaticMemory::new(size), base, 4))
+        },
+        Kind::Strict => {
+            let bytes: Vec<u8> = (0..len).map(|i| if i % 2 == 0 { 0x55 } else { 0xAA }).collect();
+            model.map(first, &bytes, true);
+            Box::new(StrictRam::new(size as u64, Fill::Checkerboard, base, &UninitializedReads::new()))
+        },
+    }
+}
+//Regions as gaps before them, sizes and kinds, laid out through the 64K from `offset` and
+//stopping at its top
+fn layout<Address: AddressType + 'static>(regions: &[(u16, u16, Kind)], offset: u64, space: &mut SparseAddressSpace<Address>) -> Model {
+    let mut model = Model { cells: vec![None; 0x10000], store: Vec::new(), banks: Vec::new() };
+    let mut base = 0u64;
+    for &(gap, size, kind) in regions.iter() {
+        base += gap as u64;
+        if base + size as u64 > 0x10000 {
+            break;
+        }
+        let region = region(kind, size, offset + base, base as usize, &mut model);
+        space.add_space(Address::from_u64(offset + base).unwrap(), region).unwrap();
+        base += size as u64;
+    }
+    if base < 0x10000 {
+        //A region reaching the last address
+        let size = (0x10000 - base).min(0x10);
+        space.add_space(Address::from_u64(offset + (0x10000 - size)).unwrap(), Box::new(DenseStaticMemory::new(size))).unwrap();
+        model.map((0x10000 - size) as usize, &vec![0; size as usize], true);
+    }
+    model
+}
+fn aligned(address: u16, size: u16) -> bool {
+    address.is_multiple_of(size)
+}
+//Runs the accesses on regions laid out from `offset`, which is 0 or the last 64K of the space
+fn matches_the_model<Address: AddressType + 'static>(mut space: SparseAddressSpace<Address>, offset: u64, regions: &[(u16, u16, Kind)],
+                                                     ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let mut model = layout(regions, offset, &mut space);
+    let at = |address: u16| Address::from_u64(offset + address as u64).unwrap();
+    for op in ops {
+        match op {
+            Op::Read(address, len) | Op::ReadMut(address, len) => {
+                let mut out = vec![0; len];
+                let result = match op {
+                    Op::Read(..) => space.read_bytes(at(address), &mut out),
+                    _ => space.read_bytes_mut(at(address), &mut out),
+                };
+                match model.bytes(address, len) {
+                    Ok(bytes) => prop_assert_eq!((result, out), (Ok(()), bytes)),
+                    Err(_) => prop_assert_eq!(result, Err(MemoryError::InvalidAccess)),
+                }
+            },
+            Op::ReadChecked(address, len) => {
+                let mut out = vec![0; len];
+                let access = Access { kind: AccessType::Load, privileged: false };
+                let result = space.read_bytes_checked(at(address), &mut out, access);
+                match model.bytes(address, len) {
+                    Ok(bytes) => prop_assert_eq!((result, out), (Ok(()), bytes)),
+                    //Past the top of a 64 bit space the address wraps to 0
+                    Err(address) => prop_assert_eq!(result, Err(MemoryError::AccessFault { address: offset.wrapping_add(address), kind: AccessType::Load })),
+                }
+            },
+            Op::Write(address, bytes) => {
+                let expected = model.write(address, &bytes);
+                prop_assert_eq!(space.write_bytes(at(address), &bytes), expected);
+            },
+            Op::ReadU32(address, endianness) => {
+                let expected = if !aligned(address, 4) {
+                    Err(MemoryError::Misaligned)
+                } else {
+                    model.bytes(address, 4).map(|bytes| endianness.read(&bytes) as u32).map_err(|_| MemoryError::InvalidAccess)
+                };
+                prop_assert_eq!(space.read_u32(at(address), endianness), expected);
+            },
+            Op::WriteU16(address, value, endianness) => {
+                let expected = if !aligned(address, 2) {
+                    Err(MemoryError::Misaligned)
+                } else {
+                    let mut bytes = [0; 2];
+                    endianness.write(value as u64, &mut bytes);
+                    model.write(address, &bytes)
+                };
+                prop_assert_eq!(space.write_u16(at(address), value, endianness), expected);
+            },
+            Op::Select(bank) => model.select(bank),
+        }
+    }
+    //Failed writes left nothing behind, both where the regions are laid out and at the top
+    for address in (0..0x3000).chain(0xFFF0..=u16::MAX) {
+        prop_assert_eq!(space.read_byte(at(address)).ok(), model.bytes(address, 1).ok().map(|bytes| bytes[0]));
+    }
+    Ok(())
+}
+
+fn regions() -> impl Strategy<Value = Vec<(u16, u16, Kind)>> {
+    prop::collection::vec((0..0x200u16, 1..0x400u16, kind()), 0..8)
+}
+
+proptest! {
+    #[test]
+    fn sparse_matches_a_flat_model(regions in regions(), ops in prop::collection::vec(op(), 1..64)) {
+        matches_the_model(SparseAddressSpace::<u16>::full(), 0, &regions, ops)?;
+    }
+
+    #[test]
+    fn the_top_of_a_64_bit_space_matches_a_flat_model(regions in regions(), ops in prop::collection::vec(op(), 1..64)) {
+        matches_the_model(SparseAddressSpace::<u64>::full(), u64::MAX - 0xFFFF, &regions, ops)?;
+    }
+
+    #[test]
+    fn dense_accesses_stay_in_bounds(size in 0..0x100u64, address in prop_oneof![0..0x200u64, any::<u64>()],
+                                     bytes in prop::collection::vec(any::<u8>(), 0..0x40)) {
+        let mut memory = DenseStaticMemory::new(size);
+        let fits = address.checked_add(bytes.len() as u64).is_some_and(|end| end <= size);
+        prop_assert_eq!(memory.write_bytes(address, &bytes).is_ok(), fits);
+        let mut out = vec![0; bytes.len()];
+        prop_assert_eq!(memory.read_bytes(address, &mut out).is_ok(), fits);
+        prop_assert_eq!(memory.read_bytes_mut(address, &mut out).is_ok(), fits);
+        if fits {
+            prop_assert_eq!(&out, &bytes);
+        }
+        prop_assert_eq!(memory.read_byte(address).is_ok(), address < size);
+        prop_assert_eq!(memory.write_byte(address, 0).is_ok(), address < size);
+        let _ = memory.read_u64(address, Endianness::Little);
+        let _ = memory.write_u64(address, 0, Endianness::Big);
+    }
+
+    #[test]
+    fn accesses_at_the_top_of_a_64_bit_space(back in 0..0x200u64, len in 0..0x20usize) {
+        let mut space = SparseAddressSpace::<u64>::full();
+        space.add_space(u64::MAX - 0xFF, Box::new(DenseStaticMemory::new(0x100u64))).unwrap();
+        let address = u64::MAX - back;
+        let fits = back < 0x100 && len as u64 <= back + 1;
+        let mut out = vec![0; len];
+        prop_assert_eq!(space.read_bytes(address, &mut out).is_ok(), fits || len == 0);
+        prop_assert_eq!(space.write_bytes(address, &out).is_ok(), fits || len == 0);
+        let access = Access { kind: AccessType::Store, privileged: true };
+        prop_assert_eq!(space.write_bytes_checked(address, &out, access).is_ok(), fits || len == 0);
+        let _ = space.read_u64(address, Endianness::Little);
+    }
+}
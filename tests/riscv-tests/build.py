@@ -0,0 +1,120 @@
+#!/usr/bin/env python3
+"""Rebuilds the vendored test executables in bin/ from the sources in isa/ and arch/.
+
+Needs llvm-mc, llvm-objcopy, llvm-nm and llvm-readelf. No linker is used: every test keeps
+code and data in one section and only references local symbols, so the assembler resolves
+every fixup itself. The section is then wrapped in an ELF executable loaded at BASE.
+"""
+import os
+import struct
+import subprocess
+import sys
+import tempfile
+
+# Where the official riscv-tests are linked
+BASE = 0x80000000
+EM_RISCV = 243
+HERE = os.path.dirname(os.path.abspath(__file__))
+# Extensions the assembler may use for each suite, C only where compressed code is wanted
+SUITES = {"ui": "", "um": "+m", "ua": "+a", "uc": "+c", "uf": "+f", "ud": "+f,+d"}
+# Tests of instructions RV32 doesn't have, which upstream only builds for rv64
+RV64_ONLY = {"addiw", "addw", "ld", "lwu", "sd", "slliw", "sllw", "sraiw", "sraw", "srliw", "srlw", "subw",
+             "divuw", "divw", "mulw", "remuw", "remw", "amoadd_d", "amoand_d", "amomax_d", "amomaxu_d",
+             "amomin_d", "amominu_d", "amoor_d", "amoswap_d", "amoxor_d"}
+
+
+def run(*args):
+    return subprocess.run(args, check=True, capture_output=True, text=True).stdout
+
+
+def assemble(source, xlen, mattr, work):
+    obj = os.path.join(work, "test.o")
+    flat = os.path.join(work, "test.bin")
+    # FP has the environment turn on the FPU
+    fp = ["-defsym", "FP=1"] if "+f" in mattr else []
+    run("llvm-mc", "-triple=riscv%d" % xlen, "-mattr=-relax" + ("," + mattr if mattr else ""),
+        "-defsym", "XLEN=%d" % xlen, *fp, "-I", os.path.join(HERE, "env"), "-filetype=obj", source, "-o", obj)
+    if "no relocations" not in run("llvm-readelf", "-r", obj):
+        sys.exit("%s: references a symbol the assembler could not resolve" % source)
+    run("llvm-objcopy", "-O", "binary", "-j", ".text.init", obj, flat)
+    with open(flat, "rb") as f:
+        code = f.read()
+    symbols = []
+    for line in run("llvm-nm", "--defined-only", obj).splitlines():
+        value, kind, name = line.split()
+        if kind.lower() == "a" or name.startswith(".L"):
+            continue
+        symbols.append((name, BASE + int(value, 16), kind.isupper()))
+    return code, symbols
+
+
+def elf(xlen, code, symbols):
+    wide = xlen == 64
+    word = "Q" if wide else "I"
+    ehsize, phsize, shsize, symsize = (64, 56, 64, 24) if wide else (52, 32, 40, 16)
+    # Locals first, as the symbol table's sh_info requires
+    symbols = sorted(symbols, key=lambda s: s[2])
+    strtab = b"\0"
+    symtab = b"\0" * symsize
+    for name, value, is_global in symbols:
+        # STB_GLOBAL or STB_LOCAL with STT_NOTYPE, defined in section 1
+        info = int(is_global) << 4
+        if wide:
+            symtab += struct.pack("<IBBHQQ", len(strtab), info, 0, 1, value, 0)
+        else:
+            symtab += struct.pack("<IIIBBH", len(strtab), value, 0, info, 0, 1)
+        strtab += name.encode() + b"\0"
+    shstrtab = b"\0.text.init\0.symtab\0.strtab\0.shstrtab\0"
+    code_offset = ehsize + phsize
+    symtab_offset = code_offset + len(code)
+    strtab_offset = symtab_offset + len(symtab)
+    shstrtab_offset = strtab_offset + len(strtab)
+    shoff = shstrtab_offset + len(shstrtab)
+    first_global = 1 + sum(1 for s in symbols if not s[2])
+
+    out = b"\x7fELF" + bytes([2 if wide else 1, 1, 1]) + b"\0" * 9
+    entry = next(value for name, value, _ in symbols if name == "_start")
+    out += struct.pack("<HHI" + word * 3 + "IHHHHHH", 2, EM_RISCV, 1, entry, ehsize, shoff, 0,
+                       ehsize, phsize, 1, shsize, 5, 4)
+    rwx = 7
+    if wide:
+        out += struct.pack("<IIQQQQQQ", 1, rwx, code_offset, BASE, BASE, len(code), len(code), 16)
+    else:
+        out += struct.pack("<IIIIIIII", 1, code_offset, BASE, BASE, len(code), len(code), rwx, 16)
+    out += code + symtab + strtab + shstrtab
+
+    def section(name, kind, flags, addr, offset, size, link, info, align, entsize):
+        if wide:
+            return struct.pack("<IIQQQQIIQQ", name, kind, flags, addr, offset, size, link, info, align, entsize)
+        return struct.pack("<IIIIIIIIII", name, kind, flags, addr, offset, size, link, info, align, entsize)
+    out += section(0, 0, 0, 0, 0, 0, 0, 0, 0, 0)
+    out += section(1, 1, rwx, BASE, code_offset, len(code), 0, 0, 16, 0)
+    out += section(12, 2, 0, 0, symtab_offset, len(symtab), 3, first_global, 8, symsize)
+    out += section(20, 3, 0, 0, strtab_offset, len(strtab), 0, 0, 1, 0)
+    out += section(28, 3, 0, 0, shstrtab_offset, len(shstrtab), 0, 0, 1, 0)
+    return out
+
+
+def main():
+    sources = []
+    for suite, mattr in sorted(SUITES.items()):
+        directory = os.path.join(HERE, "isa", suite)
+        for name in sorted(os.listdir(directory)):
+            sources.append((os.path.join(directory, name), suite, "p", name, mattr))
+    directory = os.path.join(HERE, "arch")
+    for name in sorted(os.listdir(directory)):
+        sources.append((os.path.join(directory, name), "i", "arch", name, ""))
+    with tempfile.TemporaryDirectory() as work:
+        for source, suite, env, name, mattr in sources:
+            for xlen in (32, 64):
+                if xlen == 32 and name[:-2] in RV64_ONLY:
+                    continue
+                code, symbols = assemble(source, xlen, mattr, work)
+                output = os.path.join(HERE, "bin", "rv%d%s-%s-%s" % (xlen, suite, env, name[:-2]))
+                with open(output, "wb") as f:
+                    f.write(elf(xlen, code, symbols))
+                print(os.path.relpath(output, HERE))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,72 @@
+//! Runs every executable in `tests/riscv-tests/bin` through the conformance harness. Names
+//! follow riscv-tests, `rv32ui-p-add` runs on an RV32 core with the extensions the suite
+//! letters name. Tests are linked at 0x80000000 and their segments mapped into a space covering
+//! every address. When `references/<name>.signature` exists the signature must match it.
+use micro16::microvm::memory::sparse::SparseAddressSpace;
+use micro16::risc_v_emu::conformance::{self, Outcome};
+use micro16::risc_v_emu::core::{Core, CoreSettings};
+use micro16::risc_v_emu::extensions::Extensions;
+use micro16::risc_v_emu::types::{DoubleWord, QuadWord};
+use std::fs;
+use std::path::Path;
+
+const MAX_STEPS: u64 = 1_000_000;
+const DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/riscv-tests");
+
+struct Rv32;
+impl CoreSettings for Rv32 {
+    type XLen = DoubleWord;
+    type RegType = DoubleWord;
+    type Address = u32;
+}
+struct Rv64;
+impl CoreSettings for Rv64 {
+    type XLen = QuadWord;
+    type RegType = QuadWord;
+    type Address = u64;
+}
+
+fn run<Settings: CoreSettings>(name: &str, elf: &[u8], extensions: Extensions) -> Result<(), String> {
+    let mut core = Core::<Settings>::new(SparseAddressSpace::full(), extensions);
+    let run = conformance::run(&mut core, elf, MAX_STEPS).map_err(|e| e.to_string())?;
+    match run.outcome {
+        Outcome::Pass => (),
+        Outcome::Fail(test) => return Err(format!("failed test {} at pc {:#x}", test, core.pc())),
+        Outcome::Timeout => return Err(format!("no result after {} steps, pc {:#x}", run.steps, core.pc())),
+    }
+    let reference = Path::new(DIRECTORY).join("references").join(format!("{}.signature", name));
+    if let Ok(expected) = fs::read_to_string(reference) {
+        let found = conformance::format_signature(&run.signature.unwrap_or_default());
+        if found != expected {
+            return Err(format!("signature mismatch, expected\n{}found\n{}", expected, found));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn riscv_tests() {
+    let mut names: Vec<String> = fs::read_dir(Path::new(DIRECTORY).join("bin")).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert!(!names.is_empty());
+    let mut failures = Vec::new();
+    for name in names.iter() {
+        let elf = fs::read(Path::new(DIRECTORY).join("bin").join(name)).unwrap();
+        //rv32ui-p-add is suite `ui` which runs with base `i`, rv64um with `m` and so on, D
+        //needs F too
+        let isa = name.split('-').next().unwrap();
+        let letters = isa[4..].trim_start_matches('u').replace('d', "fd");
+        let extensions = Extensions::from_letters(&letters).unwrap();
+        let result = match &isa[..4] {
+            "rv32" => run::<Rv32>(name, &elf, extensions),
+            "rv64" => run::<Rv64>(name, &elf, extensions),
+            _ => Err("unknown XLEN".to_string()),
+        };
+        if let Err(e) = result {
+            failures.push(format!("{}: {}", name, e));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}